@@ -0,0 +1,240 @@
+//! A stand-in for an OAuth provider, to try sign-in and token refreshes
+//! against without real provider credentials:
+//!
+//! ```sh
+//! cargo run --example mock_oauth
+//! OAUTH_GITHUB_CLIENT_ID=mock OAUTH_GITHUB_CLIENT_SECRET=mock \
+//!     OAUTH_GITHUB_AUTHORIZE_URL=http://localhost:4001/authorize \
+//!     OAUTH_GITHUB_TOKEN_URL=http://localhost:4001/token \
+//!     OAUTH_GITHUB_USERINFO_URL=http://localhost:4001/userinfo cargo run
+//! ```
+//!
+//! `/authorize` signs in straight away, as the account given by its
+//! `mock_account` parameter (`1` by default), or denies access if given
+//! `mock_deny`. Its codes only work once, with the PKCE verifier they were
+//! issued for. The tokens it issues are accepted by `/userinfo`, and come with
+//! an unsigned ID token like Apple's.
+//!
+//! How `/token` answers a refresh depends on how the refresh token starts:
+//!
+//! - `revoked`: rejected with `invalid_grant`, as for a user who revoked
+//!   access.
+//! - `flaky`: fails with a 503, as for a provider that's down.
+//! - `rotating`: succeeds with a new refresh token, as GitHub does.
+//! - Anything else: succeeds, keeping the same refresh token, as Google does.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use sha2::{Digest, Sha256};
+use warp::http::StatusCode;
+use warp::Filter;
+
+/// A code that's been issued, and what it can be exchanged for.
+struct Grant {
+    account: String,
+    code_challenge: String,
+    redirect_uri: String,
+}
+
+#[derive(Default)]
+struct State {
+    /// Accounts by the access tokens issued for them.
+    accounts: HashMap<String, String>,
+    grants: HashMap<String, Grant>,
+    issued: usize,
+}
+
+type Shared = Arc<Mutex<State>>;
+
+fn error(status: StatusCode, error: &str) -> (StatusCode, serde_json::Value) {
+    (status, serde_json::json!({ "error": error }))
+}
+
+fn authorize(query: HashMap<String, String>, shared: &Shared) -> String {
+    let param = |name: &str| query.get(name).cloned().unwrap_or_default();
+    let mut location = url::Url::parse(&param("redirect_uri")).expect("redirect_uri is a URL");
+
+    if query.contains_key("mock_deny") {
+        location
+            .query_pairs_mut()
+            .append_pair("error", "access_denied")
+            .append_pair("error_description", "the user denied access")
+            .append_pair("state", &param("state"));
+        return location.into();
+    }
+
+    let mut state = shared.lock().unwrap();
+    state.issued += 1;
+    let code = format!("mock-code-{}", state.issued);
+    state.grants.insert(
+        code.clone(),
+        Grant {
+            account: query
+                .get("mock_account")
+                .cloned()
+                .unwrap_or_else(|| "1".into()),
+            code_challenge: param("code_challenge"),
+            redirect_uri: param("redirect_uri"),
+        },
+    );
+
+    location
+        .query_pairs_mut()
+        .append_pair("code", &code)
+        .append_pair("state", &param("state"));
+    location.into()
+}
+
+/// Issues tokens for `account`, with an ID token whose signature is garbage.
+fn issue(state: &mut State, account: String, refresh_token: Option<String>) -> serde_json::Value {
+    state.issued += 1;
+    let access_token = format!("mock-access-{}", state.issued);
+    state.accounts.insert(access_token.clone(), account.clone());
+
+    let claims = serde_json::json!({ "iss": "mock", "sub": account });
+    let id_token = format!(
+        "e30.{}.mock",
+        base64::encode_config(claims.to_string(), base64::URL_SAFE_NO_PAD)
+    );
+
+    serde_json::json!({
+        "access_token": access_token,
+        "expires_in": 3600,
+        "id_token": id_token,
+        "refresh_token": refresh_token,
+        "token_type": "Bearer",
+    })
+}
+
+fn token(form: HashMap<String, String>, state: &Shared) -> (StatusCode, serde_json::Value) {
+    let param = |name: &str| form.get(name).map_or("", String::as_str);
+    let mut state = state.lock().unwrap();
+
+    match param("grant_type") {
+        "authorization_code" => {
+            let grant = match state.grants.remove(param("code")) {
+                Some(grant) => grant,
+                None => return error(StatusCode::BAD_REQUEST, "invalid_grant"),
+            };
+            let challenge = base64::encode_config(
+                Sha256::digest(param("code_verifier").as_bytes()),
+                base64::URL_SAFE_NO_PAD,
+            );
+            if challenge != grant.code_challenge || param("redirect_uri") != grant.redirect_uri {
+                return error(StatusCode::BAD_REQUEST, "invalid_grant");
+            }
+
+            let refresh_token = format!("mock-refresh-{}", state.issued);
+            (
+                StatusCode::OK,
+                issue(&mut state, grant.account, Some(refresh_token)),
+            )
+        }
+        "refresh_token" => {
+            let refresh_token = param("refresh_token");
+
+            if refresh_token.starts_with("revoked") {
+                (
+                    StatusCode::BAD_REQUEST,
+                    serde_json::json!({
+                        "error": "invalid_grant",
+                        "error_description": "the refresh token was revoked",
+                    }),
+                )
+            } else if refresh_token.starts_with("flaky") {
+                error(StatusCode::SERVICE_UNAVAILABLE, "temporarily_unavailable")
+            } else if refresh_token.starts_with("rotating") {
+                let rotated = format!("rotating-{}", state.issued);
+                (
+                    StatusCode::OK,
+                    issue(&mut state, refresh_token.to_owned(), Some(rotated)),
+                )
+            } else {
+                (
+                    StatusCode::OK,
+                    issue(&mut state, refresh_token.to_owned(), None),
+                )
+            }
+        }
+        _ => error(StatusCode::BAD_REQUEST, "unsupported_grant_type"),
+    }
+}
+
+fn userinfo(authorization: Option<String>, state: &Shared) -> (StatusCode, serde_json::Value) {
+    let access_token = authorization
+        .as_deref()
+        .and_then(|authorization| authorization.strip_prefix("Bearer "))
+        .unwrap_or_default();
+
+    match state.lock().unwrap().accounts.get(access_token) {
+        // GitHub's IDs are numbers, and everyone else's strings.
+        Some(account) => (
+            StatusCode::OK,
+            serde_json::json!({
+                "id": account
+                    .parse::<u64>()
+                    .map_or_else(|_| serde_json::json!(account), |id| serde_json::json!(id)),
+                "sub": account,
+            }),
+        ),
+        None => error(StatusCode::UNAUTHORIZED, "invalid_token"),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let port = std::env::var("MOCK_OAUTH_PORT")
+        .ok()
+        .and_then(|port| port.parse().ok())
+        .unwrap_or(4001);
+    let state = Shared::default();
+
+    let authorize = warp::path("authorize")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::query())
+        .map({
+            let state = state.clone();
+            move |query: HashMap<String, String>| {
+                let location = authorize(query, &state);
+                println!("authorized, redirecting to {}", location);
+                warp::reply::with_header(
+                    warp::reply::with_status(warp::reply(), StatusCode::SEE_OTHER),
+                    "location",
+                    location,
+                )
+            }
+        });
+
+    let token = warp::path("token")
+        .and(warp::path::end())
+        .and(warp::post())
+        .and(warp::body::form())
+        .map({
+            let state = state.clone();
+            move |form: HashMap<String, String>| {
+                println!(
+                    "{:?} grant requested by {:?}",
+                    form.get("grant_type"),
+                    form.get("client_id")
+                );
+                let (status, body) = token(form, &state);
+                warp::reply::with_status(warp::reply::json(&body), status)
+            }
+        });
+
+    let userinfo = warp::path("userinfo")
+        .and(warp::path::end())
+        .and(warp::get())
+        .and(warp::header::optional("authorization"))
+        .map(move |authorization: Option<String>| {
+            let (status, body) = userinfo(authorization, &state);
+            warp::reply::with_status(warp::reply::json(&body), status)
+        });
+
+    println!("mock OAuth provider at http://localhost:{}", port);
+    warp::serve(authorize.or(token).or(userinfo))
+        .run(([127, 0, 0, 1], port))
+        .await;
+}
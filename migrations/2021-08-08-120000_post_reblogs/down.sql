@@ -0,0 +1,3 @@
+ALTER TABLE "posts"
+    DROP COLUMN "parent_post_id",
+    DROP COLUMN "root_post_id";
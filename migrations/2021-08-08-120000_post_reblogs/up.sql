@@ -0,0 +1,6 @@
+ALTER TABLE "posts"
+    ADD COLUMN "parent_post_id" UUID REFERENCES "posts" ("id"),
+    ADD COLUMN "root_post_id" UUID REFERENCES "posts" ("id");
+
+CREATE INDEX ON "posts" ("parent_post_id");
+CREATE INDEX ON "posts" ("root_post_id");
@@ -0,0 +1,28 @@
+CREATE TABLE "tags" (
+    "_rowid" SERIAL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "deleted_at" TIMESTAMPTZ,
+    "id" UUID NOT NULL DEFAULT uuid_generate_v4(),
+    "name" TEXT NOT NULL,
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+
+    PRIMARY KEY ("id"),
+    UNIQUE ("name")
+);
+
+SELECT diesel_manage_updated_at('tags');
+
+CREATE INDEX ON "tags" ("name" text_pattern_ops);
+
+CREATE TABLE "post_tags" (
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "position" INTEGER NOT NULL,
+    "post_id" UUID NOT NULL,
+    "tag_id" UUID NOT NULL,
+
+    PRIMARY KEY ("post_id", "tag_id"),
+    FOREIGN KEY ("post_id") REFERENCES "posts" ("id"),
+    FOREIGN KEY ("tag_id") REFERENCES "tags" ("id")
+);
+
+CREATE INDEX ON "post_tags" ("tag_id", "post_id");
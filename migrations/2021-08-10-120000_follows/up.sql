@@ -0,0 +1,17 @@
+CREATE TABLE "follows" (
+    "_rowid" SERIAL,
+    "blog_id" UUID NOT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "id" UUID NOT NULL DEFAULT uuid_generate_v4(),
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "user_id" UUID NOT NULL,
+
+    PRIMARY KEY ("id"),
+    FOREIGN KEY ("blog_id") REFERENCES "blogs" ("id"),
+    FOREIGN KEY ("user_id") REFERENCES "users" ("id"),
+    UNIQUE ("user_id", "blog_id")
+);
+
+SELECT diesel_manage_updated_at('follows');
+
+CREATE INDEX ON "follows" ("blog_id");
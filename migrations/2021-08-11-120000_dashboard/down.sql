@@ -0,0 +1,3 @@
+DROP INDEX "posts_created_at_id_idx";
+
+DROP INDEX "posts_blog_id_created_at_id_idx";
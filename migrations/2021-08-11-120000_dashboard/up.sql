@@ -0,0 +1,8 @@
+-- Serves users following few blogs: walk each followed blog's newest posts.
+CREATE INDEX "posts_blog_id_created_at_id_idx" ON "posts" ("blog_id", "created_at" DESC, "id" DESC)
+    WHERE "deleted_at" IS NULL;
+
+-- Serves users following many blogs: walk all posts newest first and keep the
+-- followed ones.
+CREATE INDEX "posts_created_at_id_idx" ON "posts" ("created_at" DESC, "id" DESC)
+    WHERE "deleted_at" IS NULL;
@@ -0,0 +1,36 @@
+CREATE TABLE "likes" (
+    "_rowid" SERIAL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "id" UUID NOT NULL DEFAULT uuid_generate_v4(),
+    "post_id" UUID NOT NULL,
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "user_id" UUID NOT NULL,
+
+    PRIMARY KEY ("id"),
+    FOREIGN KEY ("post_id") REFERENCES "posts" ("id"),
+    FOREIGN KEY ("user_id") REFERENCES "users" ("id"),
+    UNIQUE ("post_id", "user_id")
+);
+
+SELECT diesel_manage_updated_at('likes');
+
+CREATE INDEX ON "likes" ("post_id", "created_at" DESC, "id" DESC);
+
+CREATE TABLE "replies" (
+    "_rowid" SERIAL,
+    "blog_id" UUID NOT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "deleted_at" TIMESTAMPTZ,
+    "id" UUID NOT NULL DEFAULT uuid_generate_v4(),
+    "post_id" UUID NOT NULL,
+    "text" TEXT NOT NULL,
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+
+    PRIMARY KEY ("id"),
+    FOREIGN KEY ("blog_id") REFERENCES "blogs" ("id"),
+    FOREIGN KEY ("post_id") REFERENCES "posts" ("id")
+);
+
+SELECT diesel_manage_updated_at('replies');
+
+CREATE INDEX ON "replies" ("post_id", "created_at" DESC, "id" DESC) WHERE "deleted_at" IS NULL;
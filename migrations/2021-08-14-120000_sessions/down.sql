@@ -0,0 +1,8 @@
+DROP TABLE "sessions";
+
+ALTER TABLE "oauth_accounts"
+    DROP CONSTRAINT "oauth_accounts_provider_provider_account_id_key";
+
+ALTER TABLE "email_accounts"
+    DROP CONSTRAINT "email_accounts_provider_account_id_key",
+    DROP COLUMN "password_hash";
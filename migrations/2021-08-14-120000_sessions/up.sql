@@ -0,0 +1,24 @@
+ALTER TABLE "email_accounts"
+    ADD COLUMN "password_hash" TEXT,
+    ADD UNIQUE ("provider_account_id");
+
+ALTER TABLE "oauth_accounts"
+    ADD UNIQUE ("provider", "provider_account_id");
+
+CREATE TABLE "sessions" (
+    "_rowid" SERIAL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "expires_at" TIMESTAMPTZ NOT NULL,
+    "id" UUID NOT NULL DEFAULT uuid_generate_v4(),
+    "token_hash" TEXT NOT NULL,
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "user_id" UUID NOT NULL,
+
+    PRIMARY KEY ("id"),
+    FOREIGN KEY ("user_id") REFERENCES "users" ("id"),
+    UNIQUE ("token_hash")
+);
+
+SELECT diesel_manage_updated_at('sessions');
+
+CREATE INDEX ON "sessions" ("user_id");
@@ -0,0 +1,7 @@
+ALTER TABLE "blogs"
+    DROP COLUMN "queue_interval_minutes",
+    DROP COLUMN "queue_published_at";
+
+ALTER TABLE "posts"
+    DROP COLUMN "publish_at",
+    DROP COLUMN "state";
@@ -0,0 +1,11 @@
+ALTER TABLE "posts"
+    ADD COLUMN "publish_at" TIMESTAMPTZ,
+    ADD COLUMN "state" TEXT NOT NULL DEFAULT 'PUBLISHED';
+
+UPDATE "posts" SET "publish_at" = "created_at";
+
+CREATE INDEX ON "posts" ("state", "publish_at");
+
+ALTER TABLE "blogs"
+    ADD COLUMN "queue_interval_minutes" INTEGER NOT NULL DEFAULT 60,
+    ADD COLUMN "queue_published_at" TIMESTAMPTZ;
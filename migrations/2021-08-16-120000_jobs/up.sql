@@ -0,0 +1,22 @@
+CREATE TABLE "jobs" (
+    "_rowid" SERIAL,
+    "attempts" INTEGER NOT NULL DEFAULT 0,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "id" UUID NOT NULL DEFAULT uuid_generate_v4(),
+    "kind" TEXT NOT NULL,
+    "last_error" TEXT,
+    "locked_until" TIMESTAMPTZ,
+    "max_attempts" INTEGER NOT NULL,
+    "payload" JSONB NOT NULL,
+    "run_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "state" TEXT NOT NULL DEFAULT 'PENDING',
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+
+    PRIMARY KEY ("id")
+);
+
+SELECT diesel_manage_updated_at('jobs');
+
+CREATE INDEX ON "jobs" ("run_at") WHERE "state" = 'PENDING';
+CREATE INDEX ON "jobs" ("locked_until") WHERE "state" = 'RUNNING';
+CREATE INDEX ON "jobs" ("state");
@@ -0,0 +1,4 @@
+ALTER TABLE "oauth_accounts"
+    DROP COLUMN "refresh_error",
+    DROP COLUMN "refresh_failed_at",
+    DROP COLUMN "refresh_queued_at";
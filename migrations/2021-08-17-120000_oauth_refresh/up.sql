@@ -0,0 +1,7 @@
+ALTER TABLE "oauth_accounts"
+    ADD COLUMN "refresh_error" TEXT,
+    ADD COLUMN "refresh_failed_at" TIMESTAMPTZ,
+    ADD COLUMN "refresh_queued_at" TIMESTAMPTZ;
+
+CREATE INDEX ON "oauth_accounts" ("provider_access_token_expires_at")
+    WHERE "deleted_at" IS NULL;
@@ -0,0 +1,4 @@
+DROP TABLE "email_tokens";
+
+ALTER TABLE "email_accounts"
+    DROP COLUMN "verified_at";
@@ -0,0 +1,23 @@
+ALTER TABLE "email_accounts"
+    ADD COLUMN "verified_at" TIMESTAMPTZ;
+
+CREATE TABLE "email_tokens" (
+    "_rowid" SERIAL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "email" TEXT NOT NULL,
+    "email_account_id" UUID NOT NULL,
+    "expires_at" TIMESTAMPTZ NOT NULL,
+    "id" UUID NOT NULL DEFAULT uuid_generate_v4(),
+    "purpose" TEXT NOT NULL,
+    "token_hash" TEXT NOT NULL,
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "used_at" TIMESTAMPTZ,
+
+    PRIMARY KEY ("id"),
+    FOREIGN KEY ("email_account_id") REFERENCES "email_accounts" ("id"),
+    UNIQUE ("token_hash")
+);
+
+SELECT diesel_manage_updated_at('email_tokens');
+
+CREATE INDEX ON "email_tokens" ("email_account_id", "purpose", "created_at");
@@ -0,0 +1,8 @@
+DROP TABLE "recovery_codes";
+
+DROP TABLE "login_challenges";
+
+ALTER TABLE "users"
+    DROP COLUMN "totp_secret",
+    DROP COLUMN "totp_last_step",
+    DROP COLUMN "totp_enabled_at";
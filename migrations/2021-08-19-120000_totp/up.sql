@@ -0,0 +1,40 @@
+ALTER TABLE "users"
+    ADD COLUMN "totp_enabled_at" TIMESTAMPTZ,
+    ADD COLUMN "totp_last_step" BIGINT,
+    ADD COLUMN "totp_secret" TEXT;
+
+CREATE TABLE "login_challenges" (
+    "_rowid" SERIAL,
+    "attempts" INTEGER NOT NULL DEFAULT 0,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "expires_at" TIMESTAMPTZ NOT NULL,
+    "id" UUID NOT NULL DEFAULT uuid_generate_v4(),
+    "token_hash" TEXT NOT NULL,
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "used_at" TIMESTAMPTZ,
+    "user_id" UUID NOT NULL,
+
+    PRIMARY KEY ("id"),
+    FOREIGN KEY ("user_id") REFERENCES "users" ("id"),
+    UNIQUE ("token_hash")
+);
+
+SELECT diesel_manage_updated_at('login_challenges');
+
+CREATE INDEX ON "login_challenges" ("user_id");
+
+CREATE TABLE "recovery_codes" (
+    "_rowid" SERIAL,
+    "code_hash" TEXT NOT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "id" UUID NOT NULL DEFAULT uuid_generate_v4(),
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "used_at" TIMESTAMPTZ,
+    "user_id" UUID NOT NULL,
+
+    PRIMARY KEY ("id"),
+    FOREIGN KEY ("user_id") REFERENCES "users" ("id"),
+    UNIQUE ("user_id", "code_hash")
+);
+
+SELECT diesel_manage_updated_at('recovery_codes');
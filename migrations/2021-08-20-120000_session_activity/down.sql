@@ -0,0 +1,8 @@
+DROP INDEX "login_challenges_expires_at_idx";
+
+DROP INDEX "sessions_expires_at_idx";
+
+ALTER TABLE "sessions"
+    DROP COLUMN "user_agent",
+    DROP COLUMN "last_seen_at",
+    DROP COLUMN "ip_address";
@@ -0,0 +1,10 @@
+ALTER TABLE "sessions"
+    ADD COLUMN "ip_address" TEXT,
+    ADD COLUMN "last_seen_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    ADD COLUMN "user_agent" TEXT;
+
+UPDATE "sessions" SET "last_seen_at" = "updated_at";
+
+CREATE INDEX ON "sessions" ("expires_at");
+
+CREATE INDEX ON "login_challenges" ("expires_at");
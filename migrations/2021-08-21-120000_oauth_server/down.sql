@@ -0,0 +1,5 @@
+DROP TABLE "access_tokens";
+
+DROP TABLE "oauth_authorization_codes";
+
+DROP TABLE "oauth_clients";
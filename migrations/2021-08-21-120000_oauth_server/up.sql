@@ -0,0 +1,69 @@
+CREATE TABLE "oauth_clients" (
+    "_rowid" SERIAL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "deleted_at" TIMESTAMPTZ,
+    "id" UUID NOT NULL DEFAULT uuid_generate_v4(),
+    "name" TEXT NOT NULL,
+    "redirect_uris" TEXT[] NOT NULL,
+    "secret_hash" TEXT,
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "user_id" UUID NOT NULL,
+
+    PRIMARY KEY ("id"),
+    FOREIGN KEY ("user_id") REFERENCES "users" ("id")
+);
+
+SELECT diesel_manage_updated_at('oauth_clients');
+
+CREATE INDEX ON "oauth_clients" ("user_id");
+
+CREATE TABLE "oauth_authorization_codes" (
+    "_rowid" SERIAL,
+    "client_id" UUID NOT NULL,
+    "code_challenge" TEXT NOT NULL,
+    "code_hash" TEXT NOT NULL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "expires_at" TIMESTAMPTZ NOT NULL,
+    "id" UUID NOT NULL DEFAULT uuid_generate_v4(),
+    "redirect_uri" TEXT NOT NULL,
+    "scopes" TEXT[] NOT NULL,
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "used_at" TIMESTAMPTZ,
+    "user_id" UUID NOT NULL,
+
+    PRIMARY KEY ("id"),
+    FOREIGN KEY ("client_id") REFERENCES "oauth_clients" ("id"),
+    FOREIGN KEY ("user_id") REFERENCES "users" ("id"),
+    UNIQUE ("code_hash")
+);
+
+SELECT diesel_manage_updated_at('oauth_authorization_codes');
+
+CREATE INDEX ON "oauth_authorization_codes" ("expires_at");
+
+CREATE TABLE "access_tokens" (
+    "_rowid" SERIAL,
+    "client_id" UUID,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "expires_at" TIMESTAMPTZ,
+    "id" UUID NOT NULL DEFAULT uuid_generate_v4(),
+    "last_used_at" TIMESTAMPTZ,
+    "name" TEXT,
+    "refresh_expires_at" TIMESTAMPTZ,
+    "refresh_token_hash" TEXT,
+    "scopes" TEXT[] NOT NULL,
+    "token_hash" TEXT NOT NULL,
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "user_id" UUID NOT NULL,
+
+    PRIMARY KEY ("id"),
+    FOREIGN KEY ("client_id") REFERENCES "oauth_clients" ("id"),
+    FOREIGN KEY ("user_id") REFERENCES "users" ("id"),
+    UNIQUE ("refresh_token_hash"),
+    UNIQUE ("token_hash")
+);
+
+SELECT diesel_manage_updated_at('access_tokens');
+
+CREATE INDEX ON "access_tokens" ("user_id");
+CREATE INDEX ON "access_tokens" ("client_id");
@@ -0,0 +1,7 @@
+DROP INDEX "users_username_key";
+
+ALTER TABLE "users"
+    DROP COLUMN "username",
+    DROP COLUMN "display_name",
+    DROP COLUMN "bio",
+    DROP COLUMN "avatar_url";
@@ -0,0 +1,8 @@
+ALTER TABLE "users"
+    ADD COLUMN "avatar_url" TEXT,
+    ADD COLUMN "bio" TEXT,
+    ADD COLUMN "display_name" TEXT,
+    ADD COLUMN "username" TEXT;
+
+-- Usernames keep the case they were chosen in, but are unique ignoring it.
+CREATE UNIQUE INDEX "users_username_key" ON "users" (lower("username"));
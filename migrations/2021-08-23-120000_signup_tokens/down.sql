@@ -0,0 +1,3 @@
+ALTER TABLE "users"
+    DROP COLUMN "signup_token_hash",
+    DROP COLUMN "signup_token_expires_at";
@@ -0,0 +1,3 @@
+ALTER TABLE "users"
+    ADD COLUMN "signup_token_expires_at" TIMESTAMPTZ,
+    ADD COLUMN "signup_token_hash" TEXT;
@@ -0,0 +1,3 @@
+DROP INDEX "oauth_accounts_provider_user_id_key";
+
+ALTER TABLE "oauth_accounts" ADD UNIQUE ("provider", "user_id");
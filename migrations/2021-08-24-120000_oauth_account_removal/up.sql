@@ -0,0 +1,7 @@
+-- Users can remove an OAuth account and link a different one with the same
+-- provider, so only live accounts need to be one per provider.
+ALTER TABLE "oauth_accounts" DROP CONSTRAINT "oauth_accounts_provider_user_id_key";
+
+CREATE UNIQUE INDEX "oauth_accounts_provider_user_id_key"
+    ON "oauth_accounts" ("provider", "user_id")
+    WHERE "deleted_at" IS NULL;
@@ -0,0 +1 @@
+DROP INDEX "jobs_state_updated_at_idx";
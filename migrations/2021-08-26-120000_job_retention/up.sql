@@ -0,0 +1,5 @@
+-- Finished jobs no longer keep the tokens they were queued with.
+UPDATE "jobs" SET "payload" = "payload" - 'token'
+WHERE "kind" = 'send_email_token' AND "state" IN ('COMPLETED', 'DEAD');
+
+CREATE INDEX ON "jobs" ("state", "updated_at");
@@ -0,0 +1 @@
+DROP TABLE "oauth_link_tokens";
@@ -0,0 +1,20 @@
+CREATE TABLE "oauth_link_tokens" (
+    "_rowid" SERIAL,
+    "created_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "expires_at" TIMESTAMPTZ NOT NULL,
+    "id" UUID NOT NULL DEFAULT uuid_generate_v4(),
+    -- Not a foreign key, since sessions are deleted when they end; a token
+    -- whose session is gone just doesn't work.
+    "session_id" UUID NOT NULL,
+    "token_hash" TEXT NOT NULL,
+    "updated_at" TIMESTAMPTZ NOT NULL DEFAULT current_timestamp,
+    "user_id" UUID NOT NULL,
+
+    PRIMARY KEY ("id"),
+    FOREIGN KEY ("user_id") REFERENCES "users" ("id"),
+    UNIQUE ("token_hash")
+);
+
+SELECT diesel_manage_updated_at('oauth_link_tokens');
+
+CREATE INDEX ON "oauth_link_tokens" ("user_id");
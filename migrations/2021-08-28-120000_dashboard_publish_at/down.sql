@@ -0,0 +1,9 @@
+DROP INDEX "posts_publish_at_id_idx";
+
+DROP INDEX "posts_blog_id_publish_at_id_idx";
+
+CREATE INDEX "posts_blog_id_created_at_id_idx" ON "posts" ("blog_id", "created_at" DESC, "id" DESC)
+    WHERE "deleted_at" IS NULL;
+
+CREATE INDEX "posts_created_at_id_idx" ON "posts" ("created_at" DESC, "id" DESC)
+    WHERE "deleted_at" IS NULL;
@@ -0,0 +1,13 @@
+-- The dashboard is keyed on when posts were published rather than created.
+DROP INDEX "posts_created_at_id_idx";
+
+DROP INDEX "posts_blog_id_created_at_id_idx";
+
+-- Serves users following few blogs: walk each followed blog's newest posts.
+CREATE INDEX "posts_blog_id_publish_at_id_idx" ON "posts" ("blog_id", "publish_at" DESC, "id" DESC)
+    WHERE "deleted_at" IS NULL AND "state" = 'PUBLISHED';
+
+-- Serves users following many blogs: walk all posts newest first and keep the
+-- followed ones.
+CREATE INDEX "posts_publish_at_id_idx" ON "posts" ("publish_at" DESC, "id" DESC)
+    WHERE "deleted_at" IS NULL AND "state" = 'PUBLISHED';
@@ -0,0 +1 @@
+DROP INDEX "posts_parent_post_id_publish_at_id_idx";
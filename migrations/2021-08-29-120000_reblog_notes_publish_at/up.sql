@@ -0,0 +1,3 @@
+-- Reblog notes are keyed on when the reblog was published.
+CREATE INDEX "posts_parent_post_id_publish_at_id_idx" ON "posts" ("parent_post_id", "publish_at" DESC, "id" DESC)
+    WHERE "deleted_at" IS NULL AND "state" = 'PUBLISHED';
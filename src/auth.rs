@@ -0,0 +1,388 @@
+use std::fmt::{self, Display};
+use std::net::SocketAddr;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use diesel::prelude::*;
+use graphql::Context;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use warp::Filter;
+
+use crate::models::{AccessToken, DateTime, LoginChallenge, Scope, Session, TotpError, User};
+use crate::schema::{access_tokens, sessions, users};
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+/// How often a session's `last_seen_at`, or an access token's
+/// `last_used_at`, is brought up to date as it's used. Writing on every
+/// request would be wasteful, and this is precise enough for showing when
+/// they were last used.
+pub const TOUCH_INTERVAL_SECONDS: i64 = 60;
+/// User agents are stored at most this long, since clients can send anything.
+pub const MAX_USER_AGENT_LENGTH: usize = 512;
+
+/// Access tokens, refresh tokens and client secrets start with these, so
+/// they're easy to tell apart from each other and from session tokens, by
+/// people and by secret scanners alike.
+pub const PERSONAL_ACCESS_TOKEN_PREFIX: &str = "tpat_";
+pub const OAUTH_ACCESS_TOKEN_PREFIX: &str = "toat_";
+pub const OAUTH_REFRESH_TOKEN_PREFIX: &str = "tort_";
+pub const OAUTH_CLIENT_SECRET_PREFIX: &str = "tocs_";
+
+/// How long sessions last, read from the environment by
+/// [`SessionConfig::from_env`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionConfig {
+    /// How long a session can last in all, however much it's used.
+    pub absolute_lifetime: chrono::Duration,
+    /// How long a session lasts unused. Each use pushes its expiry back to
+    /// this long from then, up to `absolute_lifetime` after it started.
+    pub idle_timeout: chrono::Duration,
+}
+
+impl Default for SessionConfig {
+    fn default() -> Self {
+        Self {
+            absolute_lifetime: chrono::Duration::days(30),
+            idle_timeout: chrono::Duration::days(7),
+        }
+    }
+}
+
+impl SessionConfig {
+    /// Reads `SESSION_LIFETIME_HOURS` and `SESSION_IDLE_TIMEOUT_HOURS`, using
+    /// the defaults for any that aren't set.
+    pub fn from_env() -> Result<Self, InvalidSessionConfigError> {
+        fn hours(
+            name: &'static str,
+            default: chrono::Duration,
+        ) -> Result<chrono::Duration, InvalidSessionConfigError> {
+            match std::env::var(name) {
+                Ok(value) => match value.parse() {
+                    Ok(hours) if hours > 0 => Ok(chrono::Duration::hours(hours)),
+                    _ => Err(InvalidSessionConfigError(name)),
+                },
+                Err(_) => Ok(default),
+            }
+        }
+
+        let default = Self::default();
+        Ok(Self {
+            absolute_lifetime: hours("SESSION_LIFETIME_HOURS", default.absolute_lifetime)?,
+            idle_timeout: hours("SESSION_IDLE_TIMEOUT_HOURS", default.idle_timeout)?,
+        })
+    }
+
+    /// When a session started at `created_at` and last used at `now` expires.
+    pub fn expires_at(&self, created_at: DateTime, now: DateTime) -> DateTime {
+        (now + self.idle_timeout).min(created_at + self.absolute_lifetime)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidSessionConfigError(&'static str);
+
+impl Display for InvalidSessionConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} must be a positive integer", self.0)
+    }
+}
+
+impl std::error::Error for InvalidSessionConfigError {}
+
+/// Where a request came from, as recorded on the sessions it starts and uses.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClientInfo {
+    pub ip_address: Option<String>,
+    pub user_agent: Option<String>,
+}
+
+/// Extracts the [`ClientInfo`] for a request from its remote address and
+/// `User-Agent` header.
+pub fn client_info() -> impl Filter<Extract = (ClientInfo,), Error = warp::Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("user-agent"))
+        .map(
+            |addr: Option<SocketAddr>, user_agent: Option<String>| ClientInfo {
+                ip_address: addr.map(|addr| addr.ip().to_string()),
+                user_agent: user_agent
+                    .map(|user_agent| user_agent.chars().take(MAX_USER_AGENT_LENGTH).collect()),
+            },
+        )
+}
+
+/// What a viewer authenticated with.
+#[derive(Debug, Clone)]
+pub enum Credential {
+    /// A personal access token, or one issued to an OAuth client, which can
+    /// only do what its scopes allow.
+    AccessToken(AccessToken),
+    /// A session, which can do anything its user can.
+    Session(Session),
+}
+
+/// The signed-in user and what they authenticated with, added to the request
+/// data by the warp filter when a valid session or access token is presented.
+#[derive(Debug, Clone)]
+pub struct Viewer {
+    pub credential: Credential,
+    pub user: User,
+}
+
+impl Viewer {
+    /// The session the viewer is using, unless they're using an access token.
+    pub fn session(&self) -> Option<&Session> {
+        match &self.credential {
+            Credential::AccessToken(_) => None,
+            Credential::Session(session) => Some(session),
+        }
+    }
+
+    /// Whether the viewer can act with `scope`. Sessions can act with any.
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        match &self.credential {
+            Credential::AccessToken(access_token) => access_token.scopes.contains(&scope),
+            Credential::Session(_) => true,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WeakPasswordError;
+
+impl Display for WeakPasswordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "passwords must be at least {} characters",
+            MIN_PASSWORD_LENGTH
+        )
+    }
+}
+
+impl std::error::Error for WeakPasswordError {}
+
+pub fn hash_password(password: &str) -> Result<String, argon2::password_hash::Error> {
+    let salt = SaltString::generate(&mut OsRng);
+
+    Ok(Argon2::default()
+        .hash_password(password.as_bytes(), &salt)?
+        .to_string())
+}
+
+pub fn verify_password(password: &str, hash: &str) -> bool {
+    PasswordHash::new(hash)
+        .and_then(|hash| Argon2::default().verify_password(password.as_bytes(), &hash))
+        .is_ok()
+}
+
+/// Email addresses are stored trimmed and lowercased, so sign-in is
+/// case-insensitive.
+pub fn normalize_email(email: &str) -> String {
+    email.trim().to_lowercase()
+}
+
+pub fn validate_password(password: &str) -> Result<(), WeakPasswordError> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        Err(WeakPasswordError)
+    } else {
+        Ok(())
+    }
+}
+
+/// Generates an opaque, URL-safe token with 256 bits of entropy.
+pub fn generate_token() -> String {
+    let mut bytes = [0; 32];
+    OsRng.fill_bytes(&mut bytes);
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+/// Generates a token like [`generate_token`], starting with `prefix`.
+pub fn generate_prefixed_token(prefix: &str) -> String {
+    format!("{}{}", prefix, generate_token())
+}
+
+/// Tokens are only ever stored hashed, so a leaked `sessions` table can't be
+/// replayed.
+pub fn hash_token(token: &str) -> String {
+    base64::encode_config(Sha256::digest(token.as_bytes()), base64::URL_SAFE_NO_PAD)
+}
+
+/// Whether `token` is the one `hash` was made from with [`hash_token`].
+/// Digests are compared rather than the tokens themselves so the comparison
+/// can't leak the expected token through timing.
+pub fn verify_token(token: &str, hash: &str) -> bool {
+    hash_token(token) == hash
+}
+
+/// Extracts the token from an `Authorization: Bearer <token>` header value.
+pub fn bearer_token(authorization: &str) -> Option<&str> {
+    let (scheme, token) = authorization.trim().split_once(' ')?;
+    if scheme.eq_ignore_ascii_case("bearer") && !token.trim().is_empty() {
+        Some(token.trim())
+    } else {
+        None
+    }
+}
+
+/// Starts a new session for `user_id`, returning it along with the plaintext
+/// token to hand to the client.
+pub fn create_session(
+    conn: &PgConnection,
+    config: &SessionConfig,
+    client: &ClientInfo,
+    user_id: uuid::Uuid,
+) -> QueryResult<(Session, String)> {
+    let now = chrono::Utc::now();
+    let token = generate_token();
+    let session = diesel::insert_into(sessions::table)
+        .values((
+            sessions::created_at.eq(now),
+            sessions::expires_at.eq(config.expires_at(now, now)),
+            sessions::ip_address.eq(&client.ip_address),
+            sessions::last_seen_at.eq(now),
+            sessions::token_hash.eq(hash_token(&token)),
+            sessions::user_agent.eq(&client.user_agent),
+            sessions::user_id.eq(user_id),
+        ))
+        .returning(sessions::all_columns)
+        .get_result(conn)?;
+
+    Ok((session, token))
+}
+
+/// Where signing in with a password or OAuth account leads.
+#[derive(Debug)]
+pub enum SignIn {
+    /// A new session, and its plaintext token.
+    Session(Session, String),
+    /// For users with two-factor authentication on, a challenge to answer
+    /// with a code before they get a session, and its plaintext token.
+    TotpRequired(LoginChallenge, String),
+}
+
+/// Signs `user` in after they've proven who they are with a password or OAuth
+/// account: straight away, or with a second step if they have two-factor
+/// authentication on. Users locked out of the second step get no new
+/// challenges until the lockout ends.
+pub fn sign_in(
+    conn: &PgConnection,
+    config: &SessionConfig,
+    client: &ClientInfo,
+    user: &User,
+) -> Result<SignIn, TotpError> {
+    if user.totp_enabled_at.is_some() {
+        if let Some(until) = user.second_factor_lockout(chrono::Utc::now()) {
+            return Err(TotpError::LockedOut(until));
+        }
+        let (challenge, token) = LoginChallenge::create(conn, user.id)?;
+        Ok(SignIn::TotpRequired(challenge, token))
+    } else {
+        let (session, token) = create_session(conn, config, client, user.id)?;
+        Ok(SignIn::Session(session, token))
+    }
+}
+
+/// Resolves a session or access token to its viewer. Unknown and expired
+/// tokens, and tokens for deleted users, resolve to `None`.
+///
+/// Using a session keeps it alive for another `idle_timeout`. Sessions and
+/// access tokens both record when they were last used (and sessions where
+/// from), at most every `TOUCH_INTERVAL_SECONDS`.
+pub fn authenticate(
+    conn: &PgConnection,
+    config: &SessionConfig,
+    client: &ClientInfo,
+    token: &str,
+) -> QueryResult<Option<Viewer>> {
+    if token.starts_with(PERSONAL_ACCESS_TOKEN_PREFIX)
+        || token.starts_with(OAUTH_ACCESS_TOKEN_PREFIX)
+    {
+        authenticate_access_token(conn, token)
+    } else {
+        authenticate_session(conn, config, client, token)
+    }
+}
+
+fn authenticate_access_token(conn: &PgConnection, token: &str) -> QueryResult<Option<Viewer>> {
+    let now = chrono::Utc::now();
+    let (mut access_token, user) = match access_tokens::table
+        .inner_join(users::table)
+        .filter(access_tokens::token_hash.eq(hash_token(token)))
+        .filter(
+            access_tokens::expires_at
+                .is_null()
+                .or(access_tokens::expires_at.gt(now)),
+        )
+        .filter(users::deleted_at.is_null())
+        .get_result::<(AccessToken, User)>(conn)
+        .optional()?
+    {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    if access_token.last_used_at.is_none_or(|last_used_at| {
+        now - last_used_at >= chrono::Duration::seconds(TOUCH_INTERVAL_SECONDS)
+    }) {
+        access_token = diesel::update(&access_token)
+            .set(access_tokens::last_used_at.eq(now))
+            .returning(access_tokens::all_columns)
+            .get_result(conn)?;
+    }
+
+    Ok(Some(Viewer {
+        credential: Credential::AccessToken(access_token),
+        user,
+    }))
+}
+
+fn authenticate_session(
+    conn: &PgConnection,
+    config: &SessionConfig,
+    client: &ClientInfo,
+    token: &str,
+) -> QueryResult<Option<Viewer>> {
+    let now = chrono::Utc::now();
+    let (mut session, user) = match sessions::table
+        .inner_join(users::table)
+        .filter(sessions::token_hash.eq(hash_token(token)))
+        .filter(sessions::expires_at.gt(now))
+        .filter(users::deleted_at.is_null())
+        .get_result::<(Session, User)>(conn)
+        .optional()?
+    {
+        Some(found) => found,
+        None => return Ok(None),
+    };
+
+    if now - session.last_seen_at >= chrono::Duration::seconds(TOUCH_INTERVAL_SECONDS) {
+        session = diesel::update(&session)
+            .set((
+                sessions::expires_at.eq(config.expires_at(session.created_at, now)),
+                sessions::ip_address.eq(&client.ip_address),
+                sessions::last_seen_at.eq(now),
+            ))
+            .returning(sessions::all_columns)
+            .get_result(conn)?;
+    }
+
+    Ok(Some(Viewer {
+        credential: Credential::Session(session),
+        user,
+    }))
+}
+
+/// Where the request came from, if the server recorded it.
+pub fn client(ctx: &Context<'_>) -> ClientInfo {
+    ctx.data_opt::<ClientInfo>().cloned().unwrap_or_default()
+}
+
+pub fn viewer<'a>(ctx: &Context<'a>) -> Option<&'a Viewer> {
+    ctx.data_opt::<Viewer>()
+}
+
+pub fn require_viewer<'a>(ctx: &Context<'a>) -> graphql::Result<&'a Viewer> {
+    viewer(ctx).ok_or_else(crate::policy::unauthenticated)
+}
@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use crate::db::Pool;
+use crate::jobs::Job;
+use crate::models::{AccessToken, LoginChallenge, OAuthAuthorizationCode, OAuthLinkToken, Session};
+
+/// How often expired rows are deleted.
+pub const INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Deletes expired sessions, login challenges, OAuth authorization codes, link
+/// tokens and access tokens, and jobs finished longer ago than they're kept
+/// for, every `INTERVAL`, for as long as the server runs, so they don't pile
+/// up. Errors are logged and retried on the next run.
+pub async fn run(pool: Pool) {
+    let mut interval = tokio::time::interval(INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let deleted = pool
+            .run(
+                |conn| -> Result<_, Box<dyn std::error::Error + Send + Sync>> {
+                    let now = chrono::Utc::now();
+                    Ok((
+                        Session::delete_expired(conn, now)?,
+                        LoginChallenge::delete_expired(conn, now)?,
+                        OAuthAuthorizationCode::delete_expired(conn, now)?,
+                        OAuthLinkToken::delete_expired(conn, now)?,
+                        AccessToken::delete_expired(conn, now)?,
+                        Job::delete_finished(conn, now)?,
+                    ))
+                },
+            )
+            .await;
+        match deleted {
+            Ok((0, 0, 0, 0, 0, 0)) => {}
+            Ok((sessions, challenges, codes, link_tokens, access_tokens, jobs)) => log::info!(
+                "deleted {} expired sessions, {} login challenges, {} authorization codes, {} link \
+                 tokens and {} access tokens, and {} finished jobs",
+                sessions,
+                challenges,
+                codes,
+                link_tokens,
+                access_tokens,
+                jobs
+            ),
+            Err(err) => log::error!("could not delete expired rows: {}", err),
+        }
+    }
+}
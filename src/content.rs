@@ -0,0 +1,719 @@
+use std::convert::TryFrom;
+use std::fmt::{self, Display};
+
+use diesel::pg::Pg;
+use diesel::sql_types::Jsonb;
+use diesel::types::{FromSql, ToSql};
+
+pub const MAX_BLOCKS: usize = 100;
+pub const MAX_CHAT_LINES: usize = 100;
+/// For alt text and link descriptions.
+pub const MAX_DESCRIPTION_LENGTH: usize = 1024;
+/// Across all of a block's `formatting`, or all its lines' for a chat.
+pub const MAX_FORMATTING_RANGES: usize = 256;
+pub const MAX_TEXT_LENGTH: usize = 4096;
+/// For titles, artists, attributions and chat speakers.
+pub const MAX_TITLE_LENGTH: usize = 256;
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, graphql::Union)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ContentBlock {
+    Audio(AudioBlock),
+    Chat(ChatBlock),
+    Heading(HeadingBlock),
+    Image(ImageBlock),
+    Link(LinkBlock),
+    Quote(QuoteBlock),
+    Text(TextBlock),
+    Video(VideoBlock),
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, graphql::SimpleObject)]
+pub struct AudioBlock {
+    pub artist: Option<String>,
+    pub title: Option<String>,
+    pub url: String,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, graphql::SimpleObject)]
+pub struct ChatBlock {
+    pub lines: Vec<ChatLine>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, graphql::SimpleObject)]
+pub struct ChatLine {
+    pub formatting: Vec<InlineFormat>,
+    pub speaker: Option<String>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, graphql::SimpleObject)]
+pub struct HeadingBlock {
+    pub formatting: Vec<InlineFormat>,
+    pub level: i32,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, graphql::SimpleObject)]
+pub struct ImageBlock {
+    pub alt_text: Option<String>,
+    pub height: Option<i32>,
+    pub url: String,
+    pub width: Option<i32>,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, graphql::SimpleObject)]
+pub struct LinkBlock {
+    pub description: Option<String>,
+    pub title: Option<String>,
+    pub url: String,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, graphql::SimpleObject)]
+pub struct QuoteBlock {
+    pub attribution: Option<String>,
+    pub formatting: Vec<InlineFormat>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, graphql::SimpleObject)]
+pub struct TextBlock {
+    pub formatting: Vec<InlineFormat>,
+    pub text: String,
+}
+
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, graphql::SimpleObject)]
+pub struct VideoBlock {
+    pub height: Option<i32>,
+    pub poster_url: Option<String>,
+    pub url: String,
+    pub width: Option<i32>,
+}
+
+/// A formatting range over the characters (not bytes) of a block's `text`, from
+/// `start` inclusive to `end` exclusive.
+#[derive(Debug, Clone, PartialEq, serde::Serialize, serde::Deserialize, graphql::SimpleObject)]
+pub struct InlineFormat {
+    pub end: i32,
+    pub start: i32,
+    #[graphql(name = "type")]
+    #[serde(rename = "type")]
+    pub ty: InlineFormatType,
+    pub url: Option<String>,
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize, graphql::Enum,
+)]
+pub enum InlineFormatType {
+    BOLD,
+    CODE,
+    ITALIC,
+    LINK,
+    SMALL,
+    STRIKETHROUGH,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, graphql::Enum)]
+pub enum ContentBlockType {
+    AUDIO,
+    CHAT,
+    HEADING,
+    IMAGE,
+    LINK,
+    QUOTE,
+    TEXT,
+    VIDEO,
+}
+
+/// GraphQL has no input unions, so every block kind shares one input object and
+/// the fields relevant to `type` are checked when converting to a
+/// [`ContentBlock`].
+#[derive(Debug, graphql::InputObject)]
+pub struct ContentBlockInput {
+    pub alt_text: Option<String>,
+    pub artist: Option<String>,
+    pub attribution: Option<String>,
+    pub description: Option<String>,
+    pub formatting: Option<Vec<InlineFormatInput>>,
+    pub height: Option<i32>,
+    pub level: Option<i32>,
+    pub lines: Option<Vec<ChatLineInput>>,
+    pub poster_url: Option<String>,
+    pub text: Option<String>,
+    pub title: Option<String>,
+    #[graphql(name = "type")]
+    pub ty: ContentBlockType,
+    pub url: Option<String>,
+    pub width: Option<i32>,
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct ChatLineInput {
+    pub formatting: Option<Vec<InlineFormatInput>>,
+    pub speaker: Option<String>,
+    pub text: String,
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct InlineFormatInput {
+    pub end: i32,
+    pub start: i32,
+    #[graphql(name = "type")]
+    pub ty: InlineFormatType,
+    pub url: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ContentError {
+    InvalidFormatRange {
+        start: i32,
+        end: i32,
+        len: usize,
+    },
+    InvalidHeadingLevel(i32),
+    InvalidMediaDimensions,
+    InvalidUrl(String),
+    MissingField {
+        block: ContentBlockType,
+        field: &'static str,
+    },
+    MissingFormatUrl,
+    TooLong(&'static str, usize),
+    TooManyBlocks,
+    TooManyChatLines,
+    TooManyFormattingRanges,
+    UnexpectedFormatUrl,
+}
+
+impl Display for ContentError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidFormatRange { start, end, len } => write!(
+                f,
+                "formatting range {}..{} is out of bounds for text of length {}",
+                start, end, len
+            ),
+            Self::InvalidHeadingLevel(level) => {
+                write!(f, "heading level must be 1 or 2, got {}", level)
+            }
+            Self::InvalidMediaDimensions => "media width and height must be positive".fmt(f),
+            Self::InvalidUrl(url) => write!(f, "{:?} is not an http(s) url", url),
+            Self::MissingField { block, field } => {
+                write!(f, "{:?} block is missing `{}`", block, field)
+            }
+            Self::MissingFormatUrl => "LINK formatting requires a `url`".fmt(f),
+            Self::TooLong(field, max) => write!(
+                f,
+                "`{}` exceeds the maximum length of {} characters",
+                field, max
+            ),
+            Self::TooManyBlocks => write!(f, "post content exceeds {} blocks", MAX_BLOCKS),
+            Self::TooManyChatLines => write!(f, "chat block exceeds {} lines", MAX_CHAT_LINES),
+            Self::TooManyFormattingRanges => write!(
+                f,
+                "block exceeds {} formatting ranges",
+                MAX_FORMATTING_RANGES
+            ),
+            Self::UnexpectedFormatUrl => "only LINK formatting may have a `url`".fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ContentError {}
+
+#[derive(Debug, Clone, Default, PartialEq, diesel::AsExpression, diesel::FromSqlRow)]
+#[sql_type = "Jsonb"]
+pub struct PostContent(pub Vec<ContentBlock>);
+
+impl PostContent {
+    pub fn validate(&self) -> Result<(), ContentError> {
+        if self.0.len() > MAX_BLOCKS {
+            return Err(ContentError::TooManyBlocks);
+        }
+
+        self.0.iter().try_for_each(ContentBlock::validate)
+    }
+}
+
+impl TryFrom<Vec<ContentBlockInput>> for PostContent {
+    type Error = ContentError;
+
+    fn try_from(input: Vec<ContentBlockInput>) -> Result<Self, Self::Error> {
+        if input.len() > MAX_BLOCKS {
+            return Err(ContentError::TooManyBlocks);
+        }
+
+        Ok(Self(
+            input
+                .into_iter()
+                .map(ContentBlock::try_from)
+                .collect::<Result<_, _>>()?,
+        ))
+    }
+}
+
+impl FromSql<Jsonb, Pg> for PostContent {
+    fn from_sql(bytes: Option<&[u8]>) -> diesel::deserialize::Result<Self> {
+        let value = <serde_json::Value as FromSql<Jsonb, Pg>>::from_sql(bytes)?;
+        Ok(Self(serde_json::from_value(value)?))
+    }
+}
+
+impl ToSql<Jsonb, Pg> for PostContent {
+    fn to_sql<W: std::io::Write>(
+        &self,
+        out: &mut diesel::serialize::Output<W, Pg>,
+    ) -> diesel::serialize::Result {
+        ToSql::<Jsonb, Pg>::to_sql(&serde_json::to_value(&self.0)?, out)
+    }
+}
+
+impl ContentBlock {
+    pub fn validate(&self) -> Result<(), ContentError> {
+        match self {
+            Self::Audio(block) => {
+                validate_length("artist", block.artist.as_deref(), MAX_TITLE_LENGTH)?;
+                validate_length("title", block.title.as_deref(), MAX_TITLE_LENGTH)?;
+                validate_url(&block.url)
+            }
+            Self::Chat(block) => {
+                if block.lines.len() > MAX_CHAT_LINES {
+                    return Err(ContentError::TooManyChatLines);
+                }
+                let ranges: usize = block.lines.iter().map(|line| line.formatting.len()).sum();
+                if ranges > MAX_FORMATTING_RANGES {
+                    return Err(ContentError::TooManyFormattingRanges);
+                }
+
+                block.lines.iter().try_for_each(|line| {
+                    validate_length("speaker", line.speaker.as_deref(), MAX_TITLE_LENGTH)?;
+                    validate_text(&line.text, &line.formatting)
+                })
+            }
+            Self::Heading(block) => {
+                if !(1..=2).contains(&block.level) {
+                    return Err(ContentError::InvalidHeadingLevel(block.level));
+                }
+
+                validate_text(&block.text, &block.formatting)
+            }
+            Self::Image(block) => {
+                validate_length("altText", block.alt_text.as_deref(), MAX_DESCRIPTION_LENGTH)?;
+                validate_dimensions(block.width, block.height)?;
+                validate_url(&block.url)
+            }
+            Self::Link(block) => {
+                validate_length(
+                    "description",
+                    block.description.as_deref(),
+                    MAX_DESCRIPTION_LENGTH,
+                )?;
+                validate_length("title", block.title.as_deref(), MAX_TITLE_LENGTH)?;
+                validate_url(&block.url)
+            }
+            Self::Quote(block) => {
+                validate_length(
+                    "attribution",
+                    block.attribution.as_deref(),
+                    MAX_TITLE_LENGTH,
+                )?;
+                validate_text(&block.text, &block.formatting)
+            }
+            Self::Text(block) => validate_text(&block.text, &block.formatting),
+            Self::Video(block) => {
+                validate_dimensions(block.width, block.height)?;
+                if let Some(poster_url) = &block.poster_url {
+                    validate_url(poster_url)?;
+                }
+
+                validate_url(&block.url)
+            }
+        }
+    }
+}
+
+impl TryFrom<ContentBlockInput> for ContentBlock {
+    type Error = ContentError;
+
+    fn try_from(input: ContentBlockInput) -> Result<Self, Self::Error> {
+        let ty = input.ty;
+        let required = |value: Option<String>, field| {
+            value.ok_or(ContentError::MissingField { block: ty, field })
+        };
+        let formatting = input
+            .formatting
+            .unwrap_or_default()
+            .into_iter()
+            .map(InlineFormat::from)
+            .collect::<Vec<_>>();
+
+        let block = match ty {
+            ContentBlockType::AUDIO => Self::Audio(AudioBlock {
+                artist: input.artist,
+                title: input.title,
+                url: required(input.url, "url")?,
+            }),
+            ContentBlockType::CHAT => Self::Chat(ChatBlock {
+                lines: input
+                    .lines
+                    .ok_or(ContentError::MissingField {
+                        block: ty,
+                        field: "lines",
+                    })?
+                    .into_iter()
+                    .map(ChatLine::from)
+                    .collect(),
+            }),
+            ContentBlockType::HEADING => Self::Heading(HeadingBlock {
+                formatting,
+                level: input.level.unwrap_or(1),
+                text: required(input.text, "text")?,
+            }),
+            ContentBlockType::IMAGE => Self::Image(ImageBlock {
+                alt_text: input.alt_text,
+                height: input.height,
+                url: required(input.url, "url")?,
+                width: input.width,
+            }),
+            ContentBlockType::LINK => Self::Link(LinkBlock {
+                description: input.description,
+                title: input.title,
+                url: required(input.url, "url")?,
+            }),
+            ContentBlockType::QUOTE => Self::Quote(QuoteBlock {
+                attribution: input.attribution,
+                formatting,
+                text: required(input.text, "text")?,
+            }),
+            ContentBlockType::TEXT => Self::Text(TextBlock {
+                formatting,
+                text: required(input.text, "text")?,
+            }),
+            ContentBlockType::VIDEO => Self::Video(VideoBlock {
+                height: input.height,
+                poster_url: input.poster_url,
+                url: required(input.url, "url")?,
+                width: input.width,
+            }),
+        };
+
+        block.validate()?;
+        Ok(block)
+    }
+}
+
+impl From<ChatLineInput> for ChatLine {
+    fn from(input: ChatLineInput) -> Self {
+        Self {
+            formatting: input
+                .formatting
+                .unwrap_or_default()
+                .into_iter()
+                .map(InlineFormat::from)
+                .collect(),
+            speaker: input.speaker,
+            text: input.text,
+        }
+    }
+}
+
+impl From<InlineFormatInput> for InlineFormat {
+    fn from(input: InlineFormatInput) -> Self {
+        Self {
+            end: input.end,
+            start: input.start,
+            ty: input.ty,
+            url: input.url,
+        }
+    }
+}
+
+fn validate_dimensions(width: Option<i32>, height: Option<i32>) -> Result<(), ContentError> {
+    if width.into_iter().chain(height).any(|n| n <= 0) {
+        Err(ContentError::InvalidMediaDimensions)
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_length(
+    field: &'static str,
+    value: Option<&str>,
+    max: usize,
+) -> Result<(), ContentError> {
+    if value.is_some_and(|value| value.chars().count() > max) {
+        Err(ContentError::TooLong(field, max))
+    } else {
+        Ok(())
+    }
+}
+
+fn validate_text(text: &str, formatting: &[InlineFormat]) -> Result<(), ContentError> {
+    let len = text.chars().count();
+    if len > MAX_TEXT_LENGTH {
+        return Err(ContentError::TooLong("text", MAX_TEXT_LENGTH));
+    }
+    if formatting.len() > MAX_FORMATTING_RANGES {
+        return Err(ContentError::TooManyFormattingRanges);
+    }
+
+    formatting.iter().try_for_each(|format| {
+        if format.start < 0 || format.start >= format.end || format.end as usize > len {
+            return Err(ContentError::InvalidFormatRange {
+                start: format.start,
+                end: format.end,
+                len,
+            });
+        }
+
+        match (format.ty, &format.url) {
+            (InlineFormatType::LINK, Some(url)) => validate_url(url),
+            (InlineFormatType::LINK, None) => Err(ContentError::MissingFormatUrl),
+            (_, Some(_)) => Err(ContentError::UnexpectedFormatUrl),
+            (_, None) => Ok(()),
+        }
+    })
+}
+
+fn validate_url(url: &str) -> Result<(), ContentError> {
+    validate_length("url", Some(url), MAX_TEXT_LENGTH)?;
+
+    match url::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => Ok(()),
+        _ => Err(ContentError::InvalidUrl(url.to_owned())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn text(text: &str, formatting: Vec<InlineFormat>) -> ContentBlock {
+        ContentBlock::Text(TextBlock {
+            formatting,
+            text: text.to_owned(),
+        })
+    }
+
+    fn format(start: i32, end: i32, ty: InlineFormatType, url: Option<&str>) -> InlineFormat {
+        InlineFormat {
+            end,
+            start,
+            ty,
+            url: url.map(str::to_owned),
+        }
+    }
+
+    fn link(url: &str) -> ContentBlock {
+        ContentBlock::Link(LinkBlock {
+            description: None,
+            title: None,
+            url: url.to_owned(),
+        })
+    }
+
+    #[test]
+    fn validate_accepts_well_formed_blocks() {
+        let blocks = [
+            text(
+                "héllo wörld",
+                vec![
+                    format(0, 5, InlineFormatType::BOLD, None),
+                    format(6, 11, InlineFormatType::LINK, Some("https://example.com/")),
+                ],
+            ),
+            link("http://example.com/a?b=c"),
+            ContentBlock::Chat(ChatBlock {
+                lines: vec![ChatLine {
+                    formatting: vec![],
+                    speaker: Some("Alice".to_owned()),
+                    text: "hi".to_owned(),
+                }],
+            }),
+            ContentBlock::Video(VideoBlock {
+                height: Some(480),
+                poster_url: Some("https://example.com/poster.png".to_owned()),
+                url: "https://example.com/video.mp4".to_owned(),
+                width: Some(640),
+            }),
+        ];
+
+        for block in &blocks {
+            assert_eq!(block.validate(), Ok(()), "{:?}", block);
+        }
+    }
+
+    #[test]
+    fn validate_counts_characters_rather_than_bytes() {
+        let at_limit = "é".repeat(MAX_TEXT_LENGTH);
+        assert_eq!(text(&at_limit, vec![]).validate(), Ok(()));
+        assert_eq!(
+            text(&format!("{}é", at_limit), vec![]).validate(),
+            Err(ContentError::TooLong("text", MAX_TEXT_LENGTH))
+        );
+
+        let url = format!("https://example.com/{}", "é".repeat(MAX_TEXT_LENGTH - 20));
+        assert_eq!(url.chars().count(), MAX_TEXT_LENGTH);
+        assert_eq!(link(&url).validate(), Ok(()));
+        assert_eq!(
+            link(&format!("{}é", url)).validate(),
+            Err(ContentError::TooLong("url", MAX_TEXT_LENGTH))
+        );
+    }
+
+    #[test]
+    fn validate_limits_short_fields() {
+        let long = "a".repeat(MAX_TITLE_LENGTH + 1);
+
+        let audio = ContentBlock::Audio(AudioBlock {
+            artist: Some(long.clone()),
+            title: None,
+            url: "https://example.com/a.mp3".to_owned(),
+        });
+        assert_eq!(
+            audio.validate(),
+            Err(ContentError::TooLong("artist", MAX_TITLE_LENGTH))
+        );
+
+        let quote = ContentBlock::Quote(QuoteBlock {
+            attribution: Some(long.clone()),
+            formatting: vec![],
+            text: "quoted".to_owned(),
+        });
+        assert_eq!(
+            quote.validate(),
+            Err(ContentError::TooLong("attribution", MAX_TITLE_LENGTH))
+        );
+
+        let chat = ContentBlock::Chat(ChatBlock {
+            lines: vec![ChatLine {
+                formatting: vec![],
+                speaker: Some(long),
+                text: "hi".to_owned(),
+            }],
+        });
+        assert_eq!(
+            chat.validate(),
+            Err(ContentError::TooLong("speaker", MAX_TITLE_LENGTH))
+        );
+
+        let image = ContentBlock::Image(ImageBlock {
+            alt_text: Some("a".repeat(MAX_DESCRIPTION_LENGTH + 1)),
+            height: None,
+            url: "https://example.com/a.png".to_owned(),
+            width: None,
+        });
+        assert_eq!(
+            image.validate(),
+            Err(ContentError::TooLong("altText", MAX_DESCRIPTION_LENGTH))
+        );
+    }
+
+    #[test]
+    fn validate_limits_formatting_ranges() {
+        let ranges = |n| vec![format(0, 1, InlineFormatType::BOLD, None); n];
+
+        assert_eq!(text("a", ranges(MAX_FORMATTING_RANGES)).validate(), Ok(()));
+        assert_eq!(
+            text("a", ranges(MAX_FORMATTING_RANGES + 1)).validate(),
+            Err(ContentError::TooManyFormattingRanges)
+        );
+
+        // Chat lines share the block's allowance.
+        let line = ChatLine {
+            formatting: ranges(MAX_FORMATTING_RANGES / 2 + 1),
+            speaker: None,
+            text: "a".to_owned(),
+        };
+        let chat = ContentBlock::Chat(ChatBlock {
+            lines: vec![line.clone(), line],
+        });
+        assert_eq!(chat.validate(), Err(ContentError::TooManyFormattingRanges));
+    }
+
+    #[test]
+    fn validate_rejects_bad_formatting() {
+        let cases = [
+            (
+                format(0, 3, InlineFormatType::BOLD, None),
+                ContentError::InvalidFormatRange {
+                    start: 0,
+                    end: 3,
+                    len: 2,
+                },
+            ),
+            (
+                format(1, 1, InlineFormatType::BOLD, None),
+                ContentError::InvalidFormatRange {
+                    start: 1,
+                    end: 1,
+                    len: 2,
+                },
+            ),
+            (
+                format(0, 1, InlineFormatType::LINK, None),
+                ContentError::MissingFormatUrl,
+            ),
+            (
+                format(0, 1, InlineFormatType::ITALIC, Some("https://example.com/")),
+                ContentError::UnexpectedFormatUrl,
+            ),
+        ];
+
+        for (format, error) in cases {
+            assert_eq!(text("hé", vec![format]).validate(), Err(error));
+        }
+    }
+
+    #[test]
+    fn validate_rejects_bad_urls() {
+        for url in [
+            "http://",
+            "ftp://example.com/",
+            "javascript:alert(1)",
+            "example.com",
+            "",
+        ] {
+            assert_eq!(
+                link(url).validate(),
+                Err(ContentError::InvalidUrl(url.to_owned())),
+                "{:?}",
+                url
+            );
+        }
+    }
+
+    #[test]
+    fn validate_rejects_bad_blocks() {
+        let heading = ContentBlock::Heading(HeadingBlock {
+            formatting: vec![],
+            level: 3,
+            text: "a".to_owned(),
+        });
+        assert_eq!(
+            heading.validate(),
+            Err(ContentError::InvalidHeadingLevel(3))
+        );
+
+        let image = ContentBlock::Image(ImageBlock {
+            alt_text: None,
+            height: Some(0),
+            url: "https://example.com/a.png".to_owned(),
+            width: Some(10),
+        });
+        assert_eq!(image.validate(), Err(ContentError::InvalidMediaDimensions));
+
+        let chat = ContentBlock::Chat(ChatBlock {
+            lines: vec![
+                ChatLine {
+                    formatting: vec![],
+                    speaker: None,
+                    text: "a".to_owned(),
+                };
+                MAX_CHAT_LINES + 1
+            ],
+        });
+        assert_eq!(chat.validate(), Err(ContentError::TooManyChatLines));
+    }
+}
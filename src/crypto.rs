@@ -0,0 +1,379 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use chacha20poly1305::aead::{Aead, NewAead};
+use chacha20poly1305::{Key, XChaCha20Poly1305, XNonce};
+use diesel::backend::Backend;
+use diesel::sql_types::Text;
+use diesel::types::{FromSql, ToSql};
+use rand::RngCore;
+
+/// Prefix of every sealed value, so values written before encryption (or
+/// under a future scheme) can be told apart.
+const SEALED_PREFIX: &str = "v1";
+const KEY_LENGTH: usize = 32;
+const NONCE_LENGTH: usize = 24;
+
+/// A secret encrypted at rest, stored as `v1.<key id>.<nonce + ciphertext>`.
+///
+/// Only a [`Keyring`] can recover the plaintext, and `Debug` never prints the
+/// stored value.
+#[derive(Clone, PartialEq, Eq, diesel::AsExpression, diesel::FromSqlRow)]
+#[sql_type = "Text"]
+pub struct Sealed(String);
+
+impl Sealed {
+    /// The stored form of the value, for keeping it somewhere other than the
+    /// database, like a cookie. It can only be opened with the keyring.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+
+    /// The id of the key this value was sealed with, or `None` for values that
+    /// were stored before encryption and are still plaintext.
+    pub fn key_id(&self) -> Option<&str> {
+        let mut parts = self.0.splitn(3, '.');
+        match (parts.next(), parts.next(), parts.next()) {
+            (Some(SEALED_PREFIX), Some(key_id), Some(_)) => Some(key_id),
+            _ => None,
+        }
+    }
+}
+
+impl From<String> for Sealed {
+    fn from(stored: String) -> Self {
+        Self(stored)
+    }
+}
+
+impl fmt::Debug for Sealed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Sealed(..)")
+    }
+}
+
+impl<DB> FromSql<Text, DB> for Sealed
+where
+    DB: Backend,
+    String: FromSql<Text, DB>,
+{
+    fn from_sql(bytes: Option<&DB::RawValue>) -> diesel::deserialize::Result<Self> {
+        Ok(Self(String::from_sql(bytes)?))
+    }
+}
+
+impl<DB> ToSql<Text, DB> for Sealed
+where
+    DB: Backend,
+    String: ToSql<Text, DB>,
+{
+    fn to_sql<W: std::io::Write>(
+        &self,
+        out: &mut diesel::serialize::Output<W, DB>,
+    ) -> diesel::serialize::Result {
+        self.0.to_sql(out)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CryptoError {
+    InvalidKey(String),
+    MalformedValue,
+    NoKeys,
+    UnknownKey(String),
+    Unsealed,
+    WrongKey,
+}
+
+impl Display for CryptoError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidKey(key_id) => write!(
+                f,
+                "encryption key {:?} must be an id and {} base64 bytes, as `id:key`",
+                key_id, KEY_LENGTH
+            ),
+            Self::MalformedValue => "sealed value is malformed".fmt(f),
+            Self::NoKeys => "at least one encryption key is required".fmt(f),
+            Self::UnknownKey(key_id) => write!(f, "no encryption key with id {:?}", key_id),
+            Self::Unsealed => "value has not been sealed".fmt(f),
+            Self::WrongKey => "sealed value could not be decrypted".fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for CryptoError {}
+
+/// The server-side keys used to seal secrets at rest.
+///
+/// The first key is the primary key and seals every new value; the rest are
+/// kept only to open values sealed before a rotation. To rotate, put the new
+/// key first and keep the old one until [`Keyring::reseal`] has been run over
+/// every stored value.
+#[derive(Clone)]
+pub struct Keyring {
+    keys: HashMap<String, XChaCha20Poly1305>,
+    primary: String,
+}
+
+impl Keyring {
+    pub fn new<I>(keys: I) -> Result<Self, CryptoError>
+    where
+        I: IntoIterator<Item = (String, [u8; KEY_LENGTH])>,
+    {
+        let mut primary = None;
+        let mut map = HashMap::new();
+
+        for (key_id, key) in keys {
+            if key_id.is_empty() || key_id.contains(['.', ':']) {
+                return Err(CryptoError::InvalidKey(key_id));
+            }
+            primary.get_or_insert_with(|| key_id.clone());
+            map.insert(key_id, XChaCha20Poly1305::new(Key::from_slice(&key)));
+        }
+
+        Ok(Self {
+            keys: map,
+            primary: primary.ok_or(CryptoError::NoKeys)?,
+        })
+    }
+
+    pub fn primary_key_id(&self) -> &str {
+        &self.primary
+    }
+
+    pub fn seal(&self, plaintext: &str) -> Sealed {
+        let mut nonce = [0; NONCE_LENGTH];
+        rand::rngs::OsRng.fill_bytes(&mut nonce);
+
+        let mut payload = nonce.to_vec();
+        payload.extend(
+            self.keys[&self.primary]
+                .encrypt(XNonce::from_slice(&nonce), plaintext.as_bytes())
+                .expect("encryption is infallible for in-memory buffers"),
+        );
+
+        Sealed(format!(
+            "{}.{}.{}",
+            SEALED_PREFIX,
+            self.primary,
+            base64::encode_config(payload, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    pub fn open(&self, sealed: &Sealed) -> Result<String, CryptoError> {
+        let key_id = sealed.key_id().ok_or(CryptoError::Unsealed)?;
+        let key = self
+            .keys
+            .get(key_id)
+            .ok_or_else(|| CryptoError::UnknownKey(key_id.to_owned()))?;
+
+        let payload = sealed.0.rsplit('.').next().unwrap_or_default();
+        let payload = base64::decode_config(payload, base64::URL_SAFE_NO_PAD)
+            .map_err(|_| CryptoError::MalformedValue)?;
+        if payload.len() < NONCE_LENGTH {
+            return Err(CryptoError::MalformedValue);
+        }
+
+        let (nonce, ciphertext) = payload.split_at(NONCE_LENGTH);
+        let plaintext = key
+            .decrypt(XNonce::from_slice(nonce), ciphertext)
+            .map_err(|_| CryptoError::WrongKey)?;
+
+        String::from_utf8(plaintext).map_err(|_| CryptoError::MalformedValue)
+    }
+
+    /// Re-seals `sealed` under the primary key, returning `None` when it's
+    /// already sealed with it. Plaintext left over from before encryption was
+    /// introduced is sealed as-is.
+    pub fn reseal(&self, sealed: &Sealed) -> Result<Option<Sealed>, CryptoError> {
+        match sealed.key_id() {
+            Some(key_id) if key_id == self.primary => Ok(None),
+            Some(_) => Ok(Some(self.seal(&self.open(sealed)?))),
+            None => Ok(Some(self.seal(&sealed.0))),
+        }
+    }
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Keyring")
+            .field("keys", &self.keys.keys().collect::<Vec<_>>())
+            .field("primary", &self.primary)
+            .finish()
+    }
+}
+
+/// Parses a comma-separated list of `id:key` pairs, with each key encoded as
+/// 32 bytes of base64, primary key first.
+impl FromStr for Keyring {
+    type Err = CryptoError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let keys = s
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(|key| {
+                let (key_id, key) = key.split_once(':').unwrap_or((key, ""));
+                let invalid = || CryptoError::InvalidKey(key_id.to_owned());
+
+                let bytes = base64::decode(key)
+                    .or_else(|_| base64::decode_config(key, base64::URL_SAFE_NO_PAD))
+                    .map_err(|_| invalid())?;
+                let mut key = [0; KEY_LENGTH];
+                if bytes.len() != KEY_LENGTH {
+                    return Err(invalid());
+                }
+                key.copy_from_slice(&bytes);
+
+                Ok((key_id.to_owned(), key))
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        Self::new(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_keys(keys: &[(&str, u8)]) -> Keyring {
+        Keyring::new(
+            keys.iter()
+                .map(|&(key_id, byte)| (key_id.to_owned(), [byte; KEY_LENGTH])),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn open_round_trips_sealed_values() {
+        let keyring = with_keys(&[("a", 1)]);
+        let sealed = keyring.seal("secret ☃");
+
+        assert_eq!(sealed.key_id(), Some("a"));
+        assert!(!sealed.as_str().contains("secret"));
+        assert_eq!(keyring.open(&sealed).unwrap(), "secret ☃");
+        // Every seal gets a fresh nonce.
+        assert_ne!(keyring.seal("secret ☃"), sealed);
+    }
+
+    #[test]
+    fn reseal_moves_values_to_the_new_primary_key() {
+        let old = with_keys(&[("a", 1)]);
+        let rotated = with_keys(&[("b", 2), ("a", 1)]);
+        let sealed = old.seal("secret");
+
+        assert_eq!(rotated.primary_key_id(), "b");
+        assert_eq!(rotated.open(&sealed).unwrap(), "secret");
+
+        let resealed = rotated.reseal(&sealed).unwrap().unwrap();
+        assert_eq!(resealed.key_id(), Some("b"));
+        assert_eq!(rotated.open(&resealed).unwrap(), "secret");
+        assert_eq!(rotated.reseal(&resealed).unwrap(), None);
+
+        // Once the old key is dropped, only the resealed value opens.
+        let retired = with_keys(&[("b", 2)]);
+        assert_eq!(retired.open(&resealed).unwrap(), "secret");
+        assert_eq!(
+            retired.open(&sealed).unwrap_err(),
+            CryptoError::UnknownKey("a".to_owned())
+        );
+    }
+
+    #[test]
+    fn reseal_seals_plaintext_left_over_from_before_encryption() {
+        let keyring = with_keys(&[("a", 1)]);
+        let plaintext = Sealed::from("legacy-token".to_owned());
+
+        assert_eq!(keyring.open(&plaintext).unwrap_err(), CryptoError::Unsealed);
+
+        let resealed = keyring.reseal(&plaintext).unwrap().unwrap();
+        assert_eq!(keyring.open(&resealed).unwrap(), "legacy-token");
+    }
+
+    #[test]
+    fn open_rejects_values_it_cannot_decrypt() {
+        let keyring = with_keys(&[("a", 1)]);
+        let sealed = keyring.seal("secret");
+
+        // Same key id, different key material.
+        let impostor = with_keys(&[("a", 2)]);
+        assert_eq!(impostor.open(&sealed).unwrap_err(), CryptoError::WrongKey);
+
+        let payload = sealed.as_str().rsplit('.').next().unwrap();
+        let bytes = base64::decode_config(payload, base64::URL_SAFE_NO_PAD).unwrap();
+        let with_payload = |bytes: &[u8]| {
+            Sealed::from(format!(
+                "v1.a.{}",
+                base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+            ))
+        };
+
+        assert_eq!(
+            keyring
+                .open(&with_payload(&bytes[..bytes.len() - 1]))
+                .unwrap_err(),
+            CryptoError::WrongKey
+        );
+        assert_eq!(
+            keyring
+                .open(&with_payload(&bytes[..NONCE_LENGTH - 1]))
+                .unwrap_err(),
+            CryptoError::MalformedValue
+        );
+        assert_eq!(
+            keyring
+                .open(&Sealed::from("v1.a.!!!".to_owned()))
+                .unwrap_err(),
+            CryptoError::MalformedValue
+        );
+        assert_eq!(
+            keyring
+                .open(&Sealed::from(sealed.as_str().replacen(".a.", ".z.", 1)))
+                .unwrap_err(),
+            CryptoError::UnknownKey("z".to_owned())
+        );
+    }
+
+    #[test]
+    fn from_str_parses_keys_primary_first() {
+        let a = base64::encode([1; KEY_LENGTH]);
+        let b = base64::encode_config([2; KEY_LENGTH], base64::URL_SAFE_NO_PAD);
+        let keyring: Keyring = format!(" b:{} , a:{},", b, a).parse().unwrap();
+
+        assert_eq!(keyring.primary_key_id(), "b");
+        let sealed = keyring.seal("secret");
+        assert_eq!(sealed.key_id(), Some("b"));
+        assert_eq!(with_keys(&[("b", 2)]).open(&sealed).unwrap(), "secret");
+    }
+
+    #[test]
+    fn from_str_rejects_invalid_keys() {
+        let key = base64::encode([1; KEY_LENGTH]);
+        let short = base64::encode([1; KEY_LENGTH - 1]);
+        let cases = [
+            (String::new(), CryptoError::NoKeys),
+            (key.clone(), CryptoError::InvalidKey(key.clone())),
+            (
+                format!("a:{}", short),
+                CryptoError::InvalidKey("a".to_owned()),
+            ),
+            (
+                "a:not base64".to_owned(),
+                CryptoError::InvalidKey("a".to_owned()),
+            ),
+            (format!(":{}", key), CryptoError::InvalidKey(String::new())),
+            (
+                format!("a.b:{}", key),
+                CryptoError::InvalidKey("a.b".to_owned()),
+            ),
+        ];
+
+        for (keys, error) in cases {
+            assert_eq!(keys.parse::<Keyring>().unwrap_err(), error, "{:?}", keys);
+        }
+    }
+}
@@ -1,28 +1,194 @@
-use std::ops::{Deref, DerefMut};
+use std::fmt::{self, Display};
+use std::sync::Arc;
+use std::time::Duration;
 
 use diesel::r2d2::{self, ConnectionManager};
 use diesel::PgConnection;
+use tokio::sync::Semaphore;
 
-pub struct Pool(r2d2::Pool<ConnectionManager<PgConnection>>);
+/// Sizes and timeouts for [`Pool`], read from the environment by
+/// [`PoolConfig::from_env`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// How long to wait for a connection before giving up.
+    pub connection_timeout: Duration,
+    /// How many connections to open, and so how many queries run at once.
+    pub max_size: u32,
+    /// How many queries may wait for a connection before new ones are turned
+    /// away with [`RunError::Busy`].
+    pub queue_size: usize,
+}
+
+impl Default for PoolConfig {
+    fn default() -> Self {
+        Self {
+            connection_timeout: Duration::from_secs(5),
+            max_size: 10,
+            queue_size: 100,
+        }
+    }
+}
+
+impl PoolConfig {
+    /// Reads `DATABASE_POOL_SIZE`, `DATABASE_CONNECTION_TIMEOUT_MS` and
+    /// `DATABASE_QUEUE_SIZE`, using the defaults for any that aren't set.
+    pub fn from_env() -> Result<Self, InvalidPoolConfigError> {
+        fn var<T: std::str::FromStr>(
+            name: &'static str,
+        ) -> Result<Option<T>, InvalidPoolConfigError> {
+            match std::env::var(name) {
+                Ok(value) => value
+                    .parse()
+                    .map(Some)
+                    .map_err(|_| InvalidPoolConfigError(name)),
+                Err(_) => Ok(None),
+            }
+        }
+
+        let default = Self::default();
+        let config = Self {
+            connection_timeout: var("DATABASE_CONNECTION_TIMEOUT_MS")?
+                .map(Duration::from_millis)
+                .unwrap_or(default.connection_timeout),
+            max_size: var("DATABASE_POOL_SIZE")?.unwrap_or(default.max_size),
+            queue_size: var("DATABASE_QUEUE_SIZE")?.unwrap_or(default.queue_size),
+        };
+
+        if config.max_size == 0 {
+            Err(InvalidPoolConfigError("DATABASE_POOL_SIZE"))
+        } else if config.connection_timeout.is_zero() {
+            Err(InvalidPoolConfigError("DATABASE_CONNECTION_TIMEOUT_MS"))
+        } else {
+            Ok(config)
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidPoolConfigError(&'static str);
+
+impl Display for InvalidPoolConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} must be a positive integer", self.0)
+    }
+}
+
+impl std::error::Error for InvalidPoolConfigError {}
+
+#[derive(Debug)]
+pub enum RunError {
+    Busy,
+    Connection(r2d2::PoolError),
+    Panicked,
+}
+
+impl Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Busy => "the database is busy, try again later".fmt(f),
+            Self::Connection(err) => write!(f, "could not connect to the database: {}", err),
+            Self::Panicked => "database query panicked".fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RunError {}
+
+/// Database connections, used through [`Pool::run`] so that Diesel's blocking
+/// calls never run on the async runtime.
+#[derive(Clone)]
+pub struct Pool {
+    connections: r2d2::Pool<ConnectionManager<PgConnection>>,
+    /// Permits for queries that are running or waiting to.
+    queue: Arc<Semaphore>,
+    /// Permits for queries that are running, one per connection.
+    workers: Arc<Semaphore>,
+}
 
 impl Pool {
-    pub fn new<S: Into<String>>(database_url: S) -> Result<Self, r2d2::PoolError> {
+    pub fn new<S: Into<String>>(
+        database_url: S,
+        config: PoolConfig,
+    ) -> Result<Self, r2d2::PoolError> {
+        Self::build(database_url, config, r2d2::Pool::builder())
+    }
+
+    /// A pool of one connection that never commits, so that whatever a test
+    /// writes is rolled back once it's done.
+    #[cfg(test)]
+    pub fn test<S: Into<String>>(database_url: S) -> Result<Self, r2d2::PoolError> {
+        let config = PoolConfig {
+            max_size: 1,
+            ..PoolConfig::default()
+        };
+        let builder = r2d2::Pool::builder().connection_customizer(Box::new(TestTransaction));
+        Self::build(database_url, config, builder)
+    }
+
+    fn build<S: Into<String>>(
+        database_url: S,
+        config: PoolConfig,
+        builder: r2d2::Builder<ConnectionManager<PgConnection>>,
+    ) -> Result<Self, r2d2::PoolError> {
         let manager = ConnectionManager::<PgConnection>::new(database_url);
-        let pool = r2d2::Pool::new(manager)?;
-        Ok(Self(pool))
+        let connections = builder
+            .connection_timeout(config.connection_timeout)
+            .max_size(config.max_size)
+            .build(manager)?;
+
+        Ok(Self {
+            connections,
+            queue: Arc::new(Semaphore::new(config.max_size as usize + config.queue_size)),
+            workers: Arc::new(Semaphore::new(config.max_size as usize)),
+        })
     }
-}
 
-impl Deref for Pool {
-    type Target = r2d2::Pool<ConnectionManager<PgConnection>>;
+    /// Runs `f` with a connection on the blocking thread pool.
+    ///
+    /// At most one query per connection runs at a time, and up to the
+    /// configured queue size more wait their turn without tying up a thread.
+    /// Beyond that, `run` fails straight away with [`RunError::Busy`].
+    pub async fn run<F, T, E>(&self, f: F) -> Result<T, E>
+    where
+        F: FnOnce(&PgConnection) -> Result<T, E> + Send + 'static,
+        T: Send + 'static,
+        E: From<RunError> + Send + 'static,
+    {
+        let _queued = self
+            .queue
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| RunError::Busy)?;
+        let running = self
+            .workers
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the worker semaphore is never closed");
 
-    fn deref(&self) -> &Self::Target {
-        &self.0
+        // The worker permit moves into the task, so a query keeps its slot
+        // until it finishes even if the request waiting on it goes away.
+        let connections = self.connections.clone();
+        tokio::task::spawn_blocking(move || {
+            let _running = running;
+            let conn = connections.get().map_err(RunError::Connection)?;
+            f(&conn)
+        })
+        .await
+        .map_err(|_| RunError::Panicked)?
     }
 }
 
-impl DerefMut for Pool {
-    fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut self.0
+#[cfg(test)]
+#[derive(Debug)]
+struct TestTransaction;
+
+#[cfg(test)]
+impl r2d2::CustomizeConnection<PgConnection, r2d2::Error> for TestTransaction {
+    fn on_acquire(&self, conn: &mut PgConnection) -> Result<(), r2d2::Error> {
+        use diesel::Connection;
+
+        conn.begin_test_transaction()
+            .map_err(r2d2::Error::QueryError)
     }
 }
@@ -0,0 +1,452 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use diesel::backend::Backend;
+use diesel::prelude::*;
+use diesel::sql_types::Text;
+use diesel::types::{FromSql, ToSql};
+use graphql::futures_util::future::BoxFuture;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use tokio::sync::Semaphore;
+
+use crate::db::Pool;
+use crate::models::DateTime;
+use crate::pagination::{Node, Position};
+use crate::schema::jobs;
+
+/// How many jobs a worker runs at once.
+pub const CONCURRENCY: i64 = 4;
+/// How long a worker has to finish a job it claimed. Jobs still running after
+/// this are assumed to have been lost along with their worker and are claimed
+/// again, so handlers must be safe to run more than once.
+pub const LEASE: Duration = Duration::from_secs(5 * 60);
+/// How long a handler gets before its attempt is given up on and counted as a
+/// failure. It's shorter than `LEASE`, so a job is never run by two workers at
+/// once.
+pub const TIMEOUT: Duration = Duration::from_secs(4 * 60);
+/// How long a worker waits before looking again when no jobs are due.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the first retry of a failed job waits. Each retry after that waits
+/// twice as long as the last, up to `MAX_BACKOFF`.
+pub const BASE_BACKOFF: Duration = Duration::from_secs(10);
+pub const MAX_BACKOFF: Duration = Duration::from_secs(60 * 60);
+/// How long completed jobs are kept, for the admin `jobs` query, before
+/// `cleanup::run` deletes them.
+pub const COMPLETED_RETENTION_DAYS: i64 = 7;
+/// Dead jobs are kept for longer, to leave time to look into why they failed.
+pub const DEAD_RETENTION_DAYS: i64 = 30;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Claims up to `$3` jobs that are due at `$1`, or whose worker's lease ran
+/// out, leasing them until `$2`. Rows other workers are claiming are skipped
+/// rather than waited on.
+const CLAIM_QUERY: &str = r#"
+UPDATE "jobs" SET
+    "attempts" = "jobs"."attempts" + 1,
+    "locked_until" = $2,
+    "state" = 'RUNNING'
+WHERE "jobs"."id" IN (
+    SELECT "jobs"."id" FROM "jobs"
+    WHERE ("jobs"."state" = 'PENDING' AND "jobs"."run_at" <= $1)
+    OR ("jobs"."state" = 'RUNNING' AND "jobs"."locked_until" <= $1)
+    ORDER BY "jobs"."run_at"
+    LIMIT $3
+    FOR UPDATE SKIP LOCKED
+)
+RETURNING "jobs".*
+"#;
+
+/// The payload of a kind of job, stored as JSON until a worker runs it.
+pub trait JobPayload: Serialize + DeserializeOwned + Send + 'static {
+    /// Identifies the kind of job in the `jobs` table, so it must not change
+    /// while any are still queued.
+    const KIND: &'static str;
+    /// How many times to try a job before giving up and marking it `DEAD`.
+    const MAX_ATTEMPTS: i32 = 5;
+    /// Top-level payload fields holding secrets, which are removed once the
+    /// job is `COMPLETED` or `DEAD`, since finished jobs are kept for a while.
+    const SECRET_FIELDS: &'static [&'static str] = &[];
+}
+
+/// Runs jobs of one kind. Handlers hold whatever they need to do so, e.g. a
+/// `Pool`, and are registered with a `Registry` when the server starts.
+#[graphql::async_trait::async_trait]
+pub trait JobHandler: Send + Sync + 'static {
+    type Payload: JobPayload;
+
+    async fn run(&self, payload: Self::Payload) -> Result<(), BoxError>;
+}
+
+type ErasedHandler =
+    dyn Fn(serde_json::Value) -> BoxFuture<'static, Result<(), BoxError>> + Send + Sync;
+
+struct Registered {
+    handler: Arc<ErasedHandler>,
+    secret_fields: &'static [&'static str],
+}
+
+/// The handlers a worker runs jobs with, by kind.
+#[derive(Clone, Default)]
+pub struct Registry(HashMap<&'static str, Arc<Registered>>);
+
+impl Registry {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs jobs of `H::Payload::KIND` with `handler`, replacing any handler
+    /// registered for that kind before.
+    pub fn register<H: JobHandler>(&mut self, handler: H) {
+        let handler = Arc::new(handler);
+
+        self.0.insert(
+            H::Payload::KIND,
+            Arc::new(Registered {
+                handler: Arc::new(move |payload| {
+                    let handler = handler.clone();
+                    Box::pin(async move { handler.run(serde_json::from_value(payload)?).await })
+                }),
+                secret_fields: H::Payload::SECRET_FIELDS,
+            }),
+        );
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, diesel::AsExpression, diesel::FromSqlRow, graphql::Enum,
+)]
+#[sql_type = "Text"]
+pub enum JobState {
+    COMPLETED,
+    /// Failed on every attempt, and won't be tried again.
+    DEAD,
+    /// Waiting for its `runAt`, either for the first time or to be retried.
+    PENDING,
+    RUNNING,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseJobStateError;
+
+impl Display for ParseJobStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "unrecognized JobState variant".fmt(f)
+    }
+}
+
+impl std::error::Error for ParseJobStateError {}
+
+impl FromStr for JobState {
+    type Err = ParseJobStateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "COMPLETED" => Ok(Self::COMPLETED),
+            "DEAD" => Ok(Self::DEAD),
+            "PENDING" => Ok(Self::PENDING),
+            "RUNNING" => Ok(Self::RUNNING),
+            _ => Err(ParseJobStateError),
+        }
+    }
+}
+
+impl Display for JobState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (match self {
+            Self::COMPLETED => "COMPLETED",
+            Self::DEAD => "DEAD",
+            Self::PENDING => "PENDING",
+            Self::RUNNING => "RUNNING",
+        })
+        .fmt(f)
+    }
+}
+
+impl<DB> FromSql<Text, DB> for JobState
+where
+    DB: Backend,
+    String: FromSql<Text, DB>,
+{
+    fn from_sql(bytes: Option<&DB::RawValue>) -> diesel::deserialize::Result<Self> {
+        Ok(String::from_sql(bytes)?.parse()?)
+    }
+}
+
+impl<DB> ToSql<Text, DB> for JobState
+where
+    DB: Backend,
+    String: ToSql<Text, DB>,
+{
+    fn to_sql<W: std::io::Write>(
+        &self,
+        out: &mut diesel::serialize::Output<W, DB>,
+    ) -> diesel::serialize::Result {
+        self.to_string().to_sql(out)
+    }
+}
+
+#[derive(
+    Debug,
+    Clone,
+    diesel::Identifiable,
+    diesel::Queryable,
+    diesel::QueryableByName,
+    graphql::SimpleObject,
+)]
+#[graphql(complex)]
+#[table_name = "jobs"]
+pub struct Job {
+    #[graphql(skip)]
+    pub _rowid: i32,
+    pub attempts: i32,
+    pub created_at: DateTime,
+    pub id: uuid::Uuid,
+    pub kind: String,
+    /// The error from the most recent failed attempt.
+    pub last_error: Option<String>,
+    /// When a running job's worker is assumed lost, if it hasn't finished.
+    pub locked_until: Option<DateTime>,
+    pub max_attempts: i32,
+    #[graphql(skip)]
+    pub payload: serde_json::Value,
+    /// When the job is due, or was last due.
+    pub run_at: DateTime,
+    pub state: JobState,
+    pub updated_at: DateTime,
+}
+
+#[graphql::ComplexObject]
+impl Job {
+    pub async fn payload(&self) -> graphql::Json<serde_json::Value> {
+        graphql::Json(self.payload.clone())
+    }
+}
+
+impl Job {
+    /// Queues `payload` to run as soon as a worker is free.
+    pub fn enqueue<P: JobPayload>(conn: &PgConnection, payload: &P) -> QueryResult<Self> {
+        Self::enqueue_at(conn, payload, chrono::Utc::now())
+    }
+
+    /// Queues `payload` to run at `run_at`.
+    pub fn enqueue_at<P: JobPayload>(
+        conn: &PgConnection,
+        payload: &P,
+        run_at: DateTime,
+    ) -> QueryResult<Self> {
+        let payload = serde_json::to_value(payload)
+            .map_err(|err| diesel::result::Error::SerializationError(Box::new(err)))?;
+
+        diesel::insert_into(jobs::table)
+            .values((
+                jobs::kind.eq(P::KIND),
+                jobs::max_attempts.eq(P::MAX_ATTEMPTS),
+                jobs::payload.eq(payload),
+                jobs::run_at.eq(run_at),
+            ))
+            .returning(jobs::all_columns)
+            .get_result(conn)
+    }
+
+    fn claim(conn: &PgConnection, limit: i64) -> QueryResult<Vec<Self>> {
+        use diesel::sql_types::{BigInt, Timestamptz};
+
+        let now = chrono::Utc::now();
+        let locked_until = now + chrono::Duration::from_std(LEASE).expect("LEASE is in range");
+
+        diesel::sql_query(CLAIM_QUERY)
+            .bind::<Timestamptz, _>(now)
+            .bind::<Timestamptz, _>(locked_until)
+            .bind::<BigInt, _>(limit)
+            .load(conn)
+    }
+
+    /// Records the outcome of the attempt at `self`, unless the job's lease ran
+    /// out and another worker has claimed it since. Jobs that won't run again
+    /// have `secret_fields` removed from their payload.
+    fn finish(
+        &self,
+        conn: &PgConnection,
+        result: Result<(), String>,
+        secret_fields: &[&str],
+    ) -> QueryResult<()> {
+        let claimed = jobs::table
+            .find(self.id)
+            .filter(jobs::state.eq(JobState::RUNNING))
+            .filter(jobs::attempts.eq(self.attempts));
+        let scrubbed = || {
+            let mut payload = self.payload.clone();
+            if let Some(fields) = payload.as_object_mut() {
+                for field in secret_fields {
+                    fields.remove(*field);
+                }
+            }
+            payload
+        };
+
+        match result {
+            Ok(()) => diesel::update(claimed)
+                .set((
+                    jobs::locked_until.eq(None::<DateTime>),
+                    jobs::payload.eq(scrubbed()),
+                    jobs::state.eq(JobState::COMPLETED),
+                ))
+                .execute(conn)?,
+            Err(error) if self.attempts >= self.max_attempts => diesel::update(claimed)
+                .set((
+                    jobs::last_error.eq(error),
+                    jobs::locked_until.eq(None::<DateTime>),
+                    jobs::payload.eq(scrubbed()),
+                    jobs::state.eq(JobState::DEAD),
+                ))
+                .execute(conn)?,
+            Err(error) => diesel::update(claimed)
+                .set((
+                    jobs::last_error.eq(error),
+                    jobs::locked_until.eq(None::<DateTime>),
+                    jobs::run_at.eq(chrono::Utc::now() + backoff(self.attempts)),
+                    jobs::state.eq(JobState::PENDING),
+                ))
+                .execute(conn)?,
+        };
+
+        Ok(())
+    }
+
+    /// Deletes jobs that completed, or died, longer ago than they're kept for,
+    /// returning how many.
+    pub fn delete_finished(conn: &PgConnection, now: DateTime) -> QueryResult<usize> {
+        diesel::delete(
+            jobs::table.filter(
+                jobs::state
+                    .eq(JobState::COMPLETED)
+                    .and(
+                        jobs::updated_at.le(now - chrono::Duration::days(COMPLETED_RETENTION_DAYS)),
+                    )
+                    .or(jobs::state.eq(JobState::DEAD).and(
+                        jobs::updated_at.le(now - chrono::Duration::days(DEAD_RETENTION_DAYS)),
+                    )),
+            ),
+        )
+        .execute(conn)
+    }
+}
+
+impl Node for Job {
+    fn position(&self) -> Position {
+        Position {
+            _rowid: self._rowid,
+            created_at: None,
+            id: None,
+            publish_at: None,
+        }
+    }
+}
+
+/// How long to wait before retrying a job that has failed `attempts` times.
+fn backoff(attempts: i32) -> chrono::Duration {
+    let backoff = BASE_BACKOFF
+        .checked_mul(1 << (attempts - 1).clamp(0, 16))
+        .map_or(MAX_BACKOFF, |backoff| backoff.min(MAX_BACKOFF));
+
+    chrono::Duration::from_std(backoff).expect("MAX_BACKOFF is in range")
+}
+
+/// Claims and runs due jobs with the handlers in `registry`, for as long as
+/// the server runs. Up to `CONCURRENCY` run at once, and more are claimed as
+/// soon as any finish.
+pub async fn run(pool: Pool, registry: Registry) {
+    let registry = Arc::new(registry);
+    let slots = Arc::new(Semaphore::new(CONCURRENCY as usize));
+
+    loop {
+        // This loop is the only one taking slots, so those free now are still
+        // free once the jobs to fill them are claimed.
+        let slot = slots
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("the slot semaphore is never closed");
+        let free = 1 + slots.available_permits() as i64;
+
+        let claimed = pool
+            .run(move |conn| -> Result<_, BoxError> { Ok(Job::claim(conn, free)?) })
+            .await;
+        let jobs = match claimed {
+            Ok(jobs) => jobs,
+            Err(err) => {
+                log::error!("could not claim jobs: {}", err);
+                Vec::new()
+            }
+        };
+
+        if jobs.is_empty() {
+            drop(slot);
+            tokio::time::sleep(POLL_INTERVAL).await;
+            continue;
+        }
+
+        let mut slot = Some(slot);
+        for job in jobs {
+            let slot = match slot.take() {
+                Some(slot) => slot,
+                None => slots
+                    .clone()
+                    .try_acquire_owned()
+                    .expect("a slot is free for each job claimed"),
+            };
+            let (pool, registry) = (pool.clone(), registry.clone());
+            tokio::spawn(async move {
+                run_job(&pool, &registry, job).await;
+                drop(slot);
+            });
+        }
+    }
+}
+
+async fn run_job(pool: &Pool, registry: &Registry, job: Job) {
+    let registered = registry.0.get(job.kind.as_str()).cloned();
+    let result = match &registered {
+        // Spawned so that a panicking handler fails its job rather than taking
+        // the worker down with it.
+        Some(registered) => {
+            let mut handle = tokio::spawn((registered.handler)(job.payload.clone()));
+            match tokio::time::timeout(TIMEOUT, &mut handle).await {
+                Ok(result) => result.unwrap_or_else(|_| Err("the job's handler panicked".into())),
+                Err(_) => {
+                    handle.abort();
+                    Err(format!("the job's handler took longer than {:?}", TIMEOUT).into())
+                }
+            }
+        }
+        None => Err(format!("no handler is registered for {:?} jobs", job.kind).into()),
+    }
+    .map_err(|err| err.to_string());
+
+    if let Err(err) = &result {
+        log::warn!(
+            "{} job {} failed on attempt {} of {}: {}",
+            job.kind,
+            job.id,
+            job.attempts,
+            job.max_attempts,
+            err
+        );
+    }
+
+    let id = job.id;
+    let finished = pool
+        .run(move |conn| -> Result<_, BoxError> {
+            let secret_fields = registered.map_or(&[][..], |registered| registered.secret_fields);
+            Ok(job.finish(conn, result, secret_fields)?)
+        })
+        .await;
+    if let Err(err) = finished {
+        log::error!("could not record the outcome of job {}: {}", id, err);
+    }
+}
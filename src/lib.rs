@@ -1,14 +1,37 @@
 #[macro_use]
 extern crate diesel;
 
+pub mod auth;
+pub mod cleanup;
+pub mod content;
+pub mod crypto;
 pub mod db;
+pub mod email;
+pub mod jobs;
+pub mod loaders;
 pub mod models;
+pub mod node;
+pub mod oauth;
+pub mod oauth_server;
+pub mod pagination;
+pub mod policy;
+pub mod publisher;
 pub mod schema;
+pub mod totp;
+
+use std::convert::TryFrom;
 
 use diesel::prelude::*;
-use graphql::Context;
+use diesel::Connection as _;
+use graphql::futures_util::future::try_join_all;
+use graphql::guard::Guard as _;
+use graphql::{Context, ErrorExtensions};
 
+use crate::jobs::{Job, JobState};
 use crate::models::{Connection, *};
+use crate::node::{AnyNode, GlobalId};
+use crate::pagination::{paginate, rowid_window};
+use crate::policy::RoleGuard;
 use crate::schema::*;
 
 pub type Schema = graphql::Schema<QueryRoot, MutationRoot, SubscriptionRoot>;
@@ -21,99 +44,373 @@ impl QueryRoot {
     async fn blog(&self, ctx: &Context<'_>, id: uuid::Uuid) -> graphql::Result<Option<Blog>> {
         let pool = ctx.data_unchecked::<crate::db::Pool>();
 
-        Ok(crate::schema::blogs::table
-            .find(id)
-            .get_result(&pool.get()?)
-            .optional()?)
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(crate::schema::blogs::table
+                .find(id)
+                .filter(blogs::deleted_at.is_null())
+                .get_result(conn)
+                .optional()?)
+        })
+        .await
     }
 
     async fn blogs(
         &self,
         ctx: &Context<'_>,
-        first: i64,
+        first: Option<i64>,
         after: Option<Cursor>,
+        last: Option<i64>,
+        before: Option<Cursor>,
     ) -> graphql::Result<Connection<Blog>> {
-        let after = after.unwrap_or_default();
+        let page = PageArgs::new(first, after, last, before)?;
 
-        let pool = ctx.data_unchecked::<crate::db::Pool>();
-        let nodes: Vec<Blog> = blogs::table
-            .filter(blogs::_rowid.gt(after._rowid))
-            .limit(first)
-            .get_results(&pool.get()?)?;
+        let blogs = || blogs::table.filter(blogs::deleted_at.is_null());
 
-        Ok(Connection {
-            edges: nodes.into_iter().map(Edge::from).collect(),
-            page_info: PageInfo {
-                has_next_page: blogs::table.count().get_result::<i64>(&pool.get()?)?
-                    > (after._rowid as i64 + 1),
-                has_previous_page: after._rowid > 0,
+        paginate(
+            ctx,
+            &page,
+            None,
+            Order::RowidAsc,
+            move |conn, window| {
+                rowid_window(blogs().into_boxed(), blogs::_rowid, window).get_results(conn)
             },
+            move |conn| blogs().count().get_result(conn),
+        )
+        .await
+    }
+
+    /// Posts from the blogs the viewer follows, newest first.
+    async fn dashboard(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<i64>,
+        after: Option<Cursor>,
+        last: Option<i64>,
+        before: Option<Cursor>,
+    ) -> graphql::Result<Connection<Post>> {
+        let page = PageArgs::new(first, after, last, before)?;
+
+        policy::require_scope(ctx, Scope::BLOGS_READ)?;
+        let user_id = auth::require_viewer(ctx)?.user.id;
+
+        paginate(
+            ctx,
+            &page,
+            None,
+            Order::PublishAtDesc,
+            move |conn, window| Post::dashboard(conn, user_id, window),
+            move |conn| Post::dashboard_count(conn, user_id),
+        )
+        .await
+    }
+
+    /// Background jobs, newest first. Jobs in any state are included unless
+    /// `states` is given.
+    #[graphql(guard(RoleGuard(role = "UserRole::ADMIN")))]
+    async fn jobs(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<i64>,
+        after: Option<Cursor>,
+        last: Option<i64>,
+        before: Option<Cursor>,
+        states: Option<Vec<JobState>>,
+    ) -> graphql::Result<Connection<Job>> {
+        let page = PageArgs::new(first, after, last, before)?;
+
+        let jobs = move || {
+            let mut query = schema::jobs::table.into_boxed();
+            if let Some(states) = states.clone() {
+                query = query.filter(schema::jobs::state.eq_any(states));
+            }
+            query
+        };
+        let count_jobs = jobs.clone();
+
+        paginate(
+            ctx,
+            &page,
+            None,
+            Order::RowidDesc,
+            move |conn, window| {
+                rowid_window(jobs(), schema::jobs::_rowid, window).get_results(conn)
+            },
+            move |conn| count_jobs().count().get_result(conn),
+        )
+        .await
+    }
+
+    /// Fetches any object by its global ID.
+    async fn node(&self, ctx: &Context<'_>, id: graphql::ID) -> graphql::Result<Option<AnyNode>> {
+        let id = id.parse::<GlobalId>().map_err(|err| err.extend())?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+        let node = pool
+            .run(move |conn| -> graphql::Result<_> { Ok(AnyNode::load(conn, &[id])?) })
+            .await?
+            .pop()
+            .flatten();
+
+        match node {
+            Some(node) if node.is_visible(ctx).await? => Ok(Some(node)),
+            _ => Ok(None),
+        }
+    }
+
+    /// Fetches objects by their global IDs, in the order given. Objects that
+    /// can't be found are returned as `null`.
+    async fn nodes(
+        &self,
+        ctx: &Context<'_>,
+        ids: Vec<graphql::ID>,
+    ) -> graphql::Result<Vec<Option<AnyNode>>> {
+        if ids.len() as i64 > pagination::MAX_PAGE_SIZE {
+            return Err(format!(
+                "ids must have at most {} entries",
+                pagination::MAX_PAGE_SIZE
+            )
+            .into());
+        }
+        let ids = ids
+            .iter()
+            .map(|id| id.parse::<GlobalId>())
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|err| err.extend())?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+        let nodes = pool
+            .run(move |conn| -> graphql::Result<_> { Ok(AnyNode::load(conn, &ids)?) })
+            .await?;
+
+        try_join_all(nodes.into_iter().map(|node| async move {
+            match node {
+                Some(node) if node.is_visible(ctx).await? => Ok(Some(node)),
+                _ => Ok(None),
+            }
+        }))
+        .await
+    }
+
+    /// An OAuth client that hasn't been deleted, e.g. for the consent page to
+    /// show which app is asking for access.
+    async fn oauth_client(
+        &self,
+        ctx: &Context<'_>,
+        id: uuid::Uuid,
+    ) -> graphql::Result<Option<OAuthClient>> {
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(oauth_clients::table
+                .find(id)
+                .filter(oauth_clients::deleted_at.is_null())
+                .get_result(conn)
+                .optional()?)
         })
+        .await
     }
 
     async fn post(&self, ctx: &Context<'_>, id: uuid::Uuid) -> graphql::Result<Option<Post>> {
         let pool = ctx.data_unchecked::<crate::db::Pool>();
 
-        Ok(posts::table.find(id).get_result(&pool.get()?).optional()?)
+        let post: Option<Post> = pool
+            .run(move |conn| -> graphql::Result<_> {
+                Ok(posts::table
+                    .find(id)
+                    .filter(posts::deleted_at.is_null())
+                    .get_result(conn)
+                    .optional()?)
+            })
+            .await?;
+
+        match post {
+            Some(post) if policy::can_see_post(ctx, &post).await? => Ok(Some(post)),
+            _ => Ok(None),
+        }
     }
 
     async fn posts(
         &self,
         ctx: &Context<'_>,
-        first: i64,
+        first: Option<i64>,
         after: Option<Cursor>,
+        last: Option<i64>,
+        before: Option<Cursor>,
     ) -> graphql::Result<Connection<Post>> {
-        let after = after.unwrap_or_default();
+        let page = PageArgs::new(first, after, last, before)?;
 
-        let pool = ctx.data_unchecked::<crate::db::Pool>();
-        let nodes: Vec<Post> = posts::table
-            .filter(posts::_rowid.gt(after._rowid))
-            .limit(first)
-            .get_results(&pool.get()?)?;
+        // Published posts, plus any on the viewer's own blogs if they can see
+        // unpublished posts.
+        let viewer_id = policy::scoped_viewer(ctx, Scope::POSTS_READ).map(|viewer| viewer.user.id);
+        let posts = move || {
+            posts::table.filter(posts::deleted_at.is_null()).filter(
+                posts::state
+                    .eq(PostState::PUBLISHED)
+                    .or(posts::blog_id.eq_any(
+                        blogs::table
+                            .filter(blogs::user_id.nullable().eq(viewer_id))
+                            .select(blogs::id),
+                    )),
+            )
+        };
+
+        paginate(
+            ctx,
+            &page,
+            None,
+            Order::RowidAsc,
+            move |conn, window| {
+                rowid_window(posts().into_boxed(), posts::_rowid, window).get_results(conn)
+            },
+            move |conn| posts().count().get_result(conn),
+        )
+        .await
+    }
+
+    /// Recent posts with the given tag, newest first.
+    async fn tag(
+        &self,
+        ctx: &Context<'_>,
+        name: String,
+        first: Option<i64>,
+        after: Option<Cursor>,
+        last: Option<i64>,
+        before: Option<Cursor>,
+    ) -> graphql::Result<Connection<Post>> {
+        let page = PageArgs::new(first, after, last, before)?;
+        let name = normalize_tag(&name)?;
+
+        // Published posts, plus any on the viewer's own blogs if they can see
+        // unpublished posts.
+        let viewer_id = policy::scoped_viewer(ctx, Scope::POSTS_READ).map(|viewer| viewer.user.id);
+        let posts = move || {
+            posts::table
+                .inner_join(post_tags::table.inner_join(tags::table))
+                .filter(tags::name.eq(name.clone()))
+                .filter(posts::deleted_at.is_null())
+                .filter(
+                    posts::state
+                        .eq(PostState::PUBLISHED)
+                        .or(posts::blog_id.eq_any(
+                            blogs::table
+                                .filter(blogs::user_id.nullable().eq(viewer_id))
+                                .select(blogs::id),
+                        )),
+                )
+        };
+        let count_posts = posts.clone();
 
-        Ok(Connection {
-            edges: nodes.into_iter().map(Edge::from).collect(),
-            page_info: PageInfo {
-                has_next_page: posts::table.count().get_result::<i64>(&pool.get()?)?
-                    > (after._rowid as i64 + 1),
-                has_previous_page: after._rowid > 0,
+        paginate(
+            ctx,
+            &page,
+            None,
+            Order::RowidDesc,
+            move |conn, window| {
+                rowid_window(
+                    posts().select(posts::all_columns).into_boxed(),
+                    posts::_rowid,
+                    window,
+                )
+                .get_results(conn)
             },
+            move |conn| count_posts().count().get_result(conn),
+        )
+        .await
+    }
+
+    /// Tags whose normalized name starts with `query`, alphabetically, at most
+    /// `first` of them.
+    async fn tag_search(
+        &self,
+        ctx: &Context<'_>,
+        query: String,
+        first: i64,
+    ) -> graphql::Result<Vec<Tag>> {
+        if !(1..=pagination::MAX_PAGE_SIZE).contains(&first) {
+            return Err(
+                format!("first must be between 1 and {}", pagination::MAX_PAGE_SIZE).into(),
+            );
+        }
+        let query = normalize_tag(&query)?;
+        let pattern = format!(
+            "{}%",
+            query
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(tags::table
+                .filter(tags::name.like(pattern))
+                .filter(tags::deleted_at.is_null())
+                .order_by(tags::name.asc())
+                .limit(first)
+                .get_results(conn)?)
         })
+        .await
     }
 
     async fn user(&self, ctx: &Context<'_>, id: uuid::Uuid) -> graphql::Result<Option<User>> {
         let pool = ctx.data_unchecked::<crate::db::Pool>();
 
-        Ok(users::table.find(id).get_result(&pool.get()?).optional()?)
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(users::table
+                .find(id)
+                .filter(users::deleted_at.is_null())
+                .get_result(conn)
+                .optional()?)
+        })
+        .await
     }
 
+    /// Looks up a user by username, ignoring case.
+    async fn user_by_username(
+        &self,
+        ctx: &Context<'_>,
+        username: String,
+    ) -> graphql::Result<Option<User>> {
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> { Ok(User::find_by_username(conn, &username)?) })
+            .await
+    }
+
+    #[graphql(guard(RoleGuard(role = "UserRole::ADMIN")))]
     async fn users(
         &self,
         ctx: &Context<'_>,
-        first: i64,
+        first: Option<i64>,
         after: Option<Cursor>,
+        last: Option<i64>,
+        before: Option<Cursor>,
     ) -> graphql::Result<Connection<User>> {
-        let after = after.unwrap_or_default();
+        let page = PageArgs::new(first, after, last, before)?;
 
-        let pool = ctx.data_unchecked::<crate::db::Pool>();
-        let nodes: Vec<User> = users::table
-            .filter(users::_rowid.gt(after._rowid))
-            .limit(first)
-            .get_results(&pool.get()?)?;
+        let users = || users::table.filter(users::deleted_at.is_null());
 
-        Ok(Connection {
-            edges: nodes.into_iter().map(Edge::from).collect(),
-            page_info: PageInfo {
-                has_next_page: users::table.count().get_result::<i64>(&pool.get()?)?
-                    > (after._rowid as i64 + 1),
-                has_previous_page: after._rowid > 0,
+        paginate(
+            ctx,
+            &page,
+            None,
+            Order::RowidAsc,
+            move |conn, window| {
+                rowid_window(users().into_boxed(), users::_rowid, window).get_results(conn)
             },
-        })
+            move |conn| users().count().get_result(conn),
+        )
+        .await
     }
 
     async fn version(&self) -> &'static str {
         concat!("v", env!("CARGO_PKG_VERSION"))
     }
+
+    async fn viewer(&self, ctx: &Context<'_>) -> Option<User> {
+        auth::viewer(ctx).map(|viewer| viewer.user.clone())
+    }
 }
 
 #[derive(Debug, Default)]
@@ -121,19 +418,220 @@ pub struct MutationRoot;
 
 #[graphql::Object]
 impl MutationRoot {
+    /// Creates a personal access token for the viewer, for scripts and tools
+    /// to use the API as them within its scopes.
+    async fn access_token_create(
+        &self,
+        ctx: &Context<'_>,
+        access_token: AccessTokenCreateInput,
+    ) -> graphql::Result<AccessTokenCreateOutput> {
+        policy::require_session(ctx)?;
+        let user_id = auth::require_viewer(ctx)?.user.id;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            let (access_token, token) = AccessToken::create_personal(conn, user_id, access_token)?;
+            Ok(AccessTokenCreateOutput {
+                access_token,
+                token,
+            })
+        })
+        .await
+    }
+
+    /// Revokes a personal access token, or an OAuth client's access along
+    /// with its refresh token.
+    async fn access_token_revoke(
+        &self,
+        ctx: &Context<'_>,
+        access_token: AccessTokenRevokeInput,
+    ) -> graphql::Result<AccessTokenRevokeOutput> {
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        let id = access_token.id;
+        let user_id: uuid::Uuid = pool
+            .run(move |conn| -> graphql::Result<_> {
+                Ok(access_tokens::table
+                    .find(id)
+                    .select(access_tokens::user_id)
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or("access token not found")?)
+            })
+            .await?;
+        policy::require_user_session(ctx, user_id)?;
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(AccessTokenRevokeOutput {
+                access_token: diesel::delete(access_tokens::table.find(id))
+                    .returning(access_tokens::all_columns)
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or("access token not found")?,
+            })
+        })
+        .await
+    }
+
     async fn blog_create(
         &self,
         ctx: &Context<'_>,
         blog: BlogCreateInput,
     ) -> graphql::Result<BlogCreateOutput> {
+        policy::require_scope(ctx, Scope::BLOGS_WRITE)?;
+        policy::require_user(ctx, blog.user_id)?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(BlogCreateOutput {
+                blog: diesel::insert_into(blogs::table)
+                    .values(&blog)
+                    .returning(blogs::all_columns)
+                    .get_result(conn)?,
+            })
+        })
+        .await
+    }
+
+    async fn blog_delete(
+        &self,
+        ctx: &Context<'_>,
+        blog: BlogDeleteInput,
+    ) -> graphql::Result<BlogDeleteOutput> {
+        policy::require_scope(ctx, Scope::BLOGS_WRITE)?;
+        policy::require_blog_owner(ctx, blog.id).await?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(BlogDeleteOutput {
+                blog: Blog::delete(conn, blog.id)?,
+            })
+        })
+        .await
+    }
+
+    async fn blog_follow(
+        &self,
+        ctx: &Context<'_>,
+        follow: BlogFollowInput,
+    ) -> graphql::Result<BlogFollowOutput> {
+        policy::require_scope(ctx, Scope::BLOGS_WRITE)?;
+        policy::require_user(ctx, follow.user_id)?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            blogs::table
+                .find(follow.blog_id)
+                .filter(blogs::deleted_at.is_null())
+                .select(blogs::id)
+                .get_result::<uuid::Uuid>(conn)
+                .optional()?
+                .ok_or("blog not found")?;
+
+            diesel::insert_into(follows::table)
+                .values(&follow)
+                .on_conflict((follows::user_id, follows::blog_id))
+                .do_nothing()
+                .execute(conn)?;
+
+            Ok(BlogFollowOutput {
+                follow: follows::table
+                    .filter(follows::blog_id.eq(follow.blog_id))
+                    .filter(follows::user_id.eq(follow.user_id))
+                    .get_result(conn)?,
+            })
+        })
+        .await
+    }
+
+    /// Restores a deleted blog and the posts deleted along with it.
+    async fn blog_restore(
+        &self,
+        ctx: &Context<'_>,
+        blog: BlogRestoreInput,
+    ) -> graphql::Result<BlogRestoreOutput> {
+        policy::require_scope(ctx, Scope::BLOGS_WRITE)?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        let (blog, user): (Blog, User) = pool
+            .run(move |conn| -> graphql::Result<_> {
+                Ok(blogs::table
+                    .find(blog.id)
+                    .inner_join(users::table)
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or("blog not found")?)
+            })
+            .await?;
+        policy::require_user(ctx, blog.user_id)?;
+        if user.deleted_at.is_some() {
+            return Err("blogs of deleted users can't be restored".into());
+        }
+        check_restorable(blog.deleted_at)?;
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(BlogRestoreOutput {
+                blog: Blog::restore(conn, &blog)?,
+            })
+        })
+        .await
+    }
+
+    async fn blog_unfollow(
+        &self,
+        ctx: &Context<'_>,
+        follow: BlogUnfollowInput,
+    ) -> graphql::Result<BlogUnfollowOutput> {
+        policy::require_scope(ctx, Scope::BLOGS_WRITE)?;
+        policy::require_user(ctx, follow.user_id)?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            let blog = blogs::table
+                .find(follow.blog_id)
+                .filter(blogs::deleted_at.is_null())
+                .get_result(conn)
+                .optional()?
+                .ok_or("blog not found")?;
+
+            diesel::delete(
+                follows::table
+                    .filter(follows::blog_id.eq(follow.blog_id))
+                    .filter(follows::user_id.eq(follow.user_id)),
+            )
+            .execute(conn)?;
+
+            Ok(BlogUnfollowOutput { blog })
+        })
+        .await
+    }
+
+    async fn blog_update(
+        &self,
+        ctx: &Context<'_>,
+        blog: BlogUpdateInput,
+    ) -> graphql::Result<BlogUpdateOutput> {
+        policy::require_scope(ctx, Scope::BLOGS_WRITE)?;
+        policy::require_blog_owner(ctx, blog.id).await?;
+
+        let (id, expected_updated_at) = (blog.id, blog.expected_updated_at);
+        let changeset = BlogChangeset::try_from(blog)?;
+
         let pool = ctx.data_unchecked::<crate::db::Pool>();
 
-        Ok(BlogCreateOutput {
-            blog: diesel::insert_into(blogs::table)
-                .values(&blog)
-                .returning(blogs::all_columns)
-                .get_result(&pool.get()?)?,
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(BlogUpdateOutput {
+                blog: Blog::update(conn, id, expected_updated_at, &changeset)?
+                    .ok_or_else(|| ConflictError.extend())?,
+            })
         })
+        .await
     }
 
     async fn email_account_create(
@@ -141,29 +639,451 @@ impl MutationRoot {
         ctx: &Context<'_>,
         email_account: EmailAccountCreateInput,
     ) -> graphql::Result<EmailAccountCreateOutput> {
+        let signup_token = email_account.signup_token.clone();
+        policy::require_account_owner(ctx, email_account.user_id, signup_token.as_deref()).await?;
+
+        auth::validate_password(&email_account.password)?;
+
+        let keyring = ctx.data_unchecked::<crate::crypto::Keyring>().clone();
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            // Hashed here, off the async runtime, since it takes a while.
+            let email_account = NewEmailAccount {
+                password_hash: auth::hash_password(&email_account.password)?,
+                provider_account_id: auth::normalize_email(&email_account.provider_account_id),
+                user_id: email_account.user_id,
+            };
+
+            conn.transaction(|| {
+                User::lock(conn, email_account.user_id)
+                    .optional()?
+                    .ok_or("user not found")?;
+                if let Some(token) = &signup_token {
+                    if !User::redeem_signup_token(conn, email_account.user_id, token)? {
+                        return Err("the sign-up token is invalid or has expired".into());
+                    }
+                }
+                let email_account: EmailAccount = diesel::insert_into(email_accounts::table)
+                    .values(&email_account)
+                    .returning(email_accounts::all_columns)
+                    .get_result(conn)?;
+                EmailToken::send(
+                    conn,
+                    &keyring,
+                    &email_account,
+                    EmailTokenPurpose::VERIFICATION,
+                )?;
+
+                Ok(EmailAccountCreateOutput { email_account })
+            })
+        })
+        .await
+    }
+
+    /// Changes the address or password, given the current password. The
+    /// user's other sessions are ended, and every personal access token and
+    /// app they'd authorized is revoked.
+    async fn email_account_update(
+        &self,
+        ctx: &Context<'_>,
+        email_account: EmailAccountUpdateInput,
+    ) -> graphql::Result<EmailAccountUpdateOutput> {
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        let id = email_account.id;
+        let user_id: uuid::Uuid = pool
+            .run(move |conn| -> graphql::Result<_> {
+                Ok(email_accounts::table
+                    .find(id)
+                    .filter(email_accounts::deleted_at.is_null())
+                    .select(email_accounts::user_id)
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or("email account not found")?)
+            })
+            .await?;
+        policy::require_user_session(ctx, user_id)?;
+        let session_id = policy::require_session(ctx)?.id;
+
+        let password = required_field("password", email_account.password)?;
+        if let Some(password) = &password {
+            auth::validate_password(password)?;
+        }
+        let provider_account_id =
+            required_field("providerAccountId", email_account.provider_account_id)?
+                .map(|email| auth::normalize_email(&email));
+        if password.is_none() && provider_account_id.is_none() {
+            return Err(PatchError::Empty.into());
+        }
+
+        let keyring = ctx.data_unchecked::<crate::crypto::Keyring>().clone();
+        let (current_password, expected_updated_at) = (
+            email_account.current_password,
+            email_account.expected_updated_at,
+        );
+        pool.run(move |conn| -> graphql::Result<_> {
+            // Hashed here, off the async runtime, since it takes a while.
+            let password_hash = password
+                .map(|password| auth::hash_password(&password))
+                .transpose()?;
+            let changeset = EmailAccountChangeset {
+                password_hash,
+                // A new address has to be verified all over again.
+                verified_at: provider_account_id.as_ref().map(|_| None),
+                provider_account_id,
+            };
+
+            conn.transaction(|| {
+                let email_account = EmailAccount::update(
+                    conn,
+                    id,
+                    expected_updated_at,
+                    &current_password,
+                    &changeset,
+                    session_id,
+                )?
+                .ok_or_else(|| ConflictError.extend())?;
+                if changeset.provider_account_id.is_some() {
+                    EmailToken::send(
+                        conn,
+                        &keyring,
+                        &email_account,
+                        EmailTokenPurpose::VERIFICATION,
+                    )?;
+                }
+
+                Ok(EmailAccountUpdateOutput { email_account })
+            })
+        })
+        .await
+    }
+
+    /// Sends another verification email, for when the first didn't arrive or
+    /// its link expired.
+    async fn email_verification_request(
+        &self,
+        ctx: &Context<'_>,
+        email_account: EmailVerificationRequestInput,
+    ) -> graphql::Result<EmailVerificationRequestOutput> {
+        let keyring = ctx.data_unchecked::<crate::crypto::Keyring>().clone();
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        let id = email_account.id;
+        let email_account: EmailAccount = pool
+            .run(move |conn| -> graphql::Result<_> {
+                Ok(email_accounts::table
+                    .find(id)
+                    .filter(email_accounts::deleted_at.is_null())
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or("email account not found")?)
+            })
+            .await?;
+        policy::require_user_session(ctx, email_account.user_id)?;
+
+        if email_account.verified_at.is_some() {
+            return Err("this email address is already verified".into());
+        }
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            if EmailToken::sent_recently(conn, id, EmailTokenPurpose::VERIFICATION)? {
+                return Err("a verification email was just sent; try again in a minute".into());
+            }
+            EmailToken::send(
+                conn,
+                &keyring,
+                &email_account,
+                EmailTokenPurpose::VERIFICATION,
+            )?;
+
+            Ok(EmailVerificationRequestOutput { email_account })
+        })
+        .await
+    }
+
+    /// Verifies an email address with the token from its verification email.
+    async fn email_verify(
+        &self,
+        ctx: &Context<'_>,
+        verification: EmailVerifyInput,
+    ) -> graphql::Result<EmailVerifyOutput> {
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(EmailVerifyOutput {
+                email_account: EmailAccount::verify(conn, &verification.token)?
+                    .ok_or("this verification link has expired or was already used")?,
+            })
+        })
+        .await
+    }
+
+    async fn login(
+        &self,
+        ctx: &Context<'_>,
+        credentials: LoginInput,
+    ) -> graphql::Result<LoginOutput> {
+        let client = auth::client(ctx);
+        let config = *ctx.data_unchecked::<auth::SessionConfig>();
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            let account: Option<(EmailAccount, User)> = email_accounts::table
+                .inner_join(users::table)
+                .filter(
+                    email_accounts::provider_account_id
+                        .eq(auth::normalize_email(&credentials.email)),
+                )
+                .filter(email_accounts::deleted_at.is_null())
+                .filter(users::deleted_at.is_null())
+                .get_result(conn)
+                .optional()?;
+
+            let user = match account {
+                Some((account, user))
+                    if account
+                        .password_hash
+                        .as_deref()
+                        .is_some_and(|hash| auth::verify_password(&credentials.password, hash)) =>
+                {
+                    user
+                }
+                _ => return Err("invalid email or password".into()),
+            };
+
+            Ok(LoginOutput::new(
+                auth::sign_in(conn, &config, &client, &user)?,
+                user,
+            ))
+        })
+        .await
+    }
+
+    /// Finishes signing in for users with two-factor authentication on.
+    async fn login_totp(
+        &self,
+        ctx: &Context<'_>,
+        challenge: LoginTotpInput,
+    ) -> graphql::Result<LoginOutput> {
+        let client = auth::client(ctx);
+        let config = *ctx.data_unchecked::<auth::SessionConfig>();
+        let keyring = ctx.data_unchecked::<crate::crypto::Keyring>().clone();
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            let user =
+                LoginChallenge::answer(conn, &keyring, &challenge.challenge, &challenge.code)?;
+            let (session, token) = auth::create_session(conn, &config, &client, user.id)?;
+            Ok(LoginOutput::new(
+                auth::SignIn::Session(session, token),
+                user,
+            ))
+        })
+        .await
+    }
+
+    async fn logout(&self, ctx: &Context<'_>) -> graphql::Result<LogoutOutput> {
+        let session_id = policy::require_session(ctx)?.id;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(LogoutOutput {
+                session: diesel::delete(sessions::table.find(session_id))
+                    .returning(sessions::all_columns)
+                    .get_result(conn)?,
+            })
+        })
+        .await
+    }
+
+    async fn oauth_account_delete(
+        &self,
+        ctx: &Context<'_>,
+        oauth_account: OAuthAccountDeleteInput,
+    ) -> graphql::Result<OAuthAccountDeleteOutput> {
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        let id = oauth_account.id;
+        let user_id: uuid::Uuid = pool
+            .run(move |conn| -> graphql::Result<_> {
+                Ok(oauth_accounts::table
+                    .find(id)
+                    .filter(oauth_accounts::deleted_at.is_null())
+                    .select(oauth_accounts::user_id)
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or("OAuth account not found")?)
+            })
+            .await?;
+        policy::require_user_session(ctx, user_id)?;
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(OAuthAccountDeleteOutput {
+                oauth_account: OAuthAccount::delete(conn, id)?,
+            })
+        })
+        .await
+    }
+
+    /// Authorizes an OAuth client to act as the viewer, from the web app's
+    /// consent page that `/oauth/authorize` sends users to. Send the viewer on
+    /// to `redirectUri`. If they decline, send them to the client's redirect
+    /// URI with `error=access_denied` and the `state` instead.
+    async fn oauth_authorize(
+        &self,
+        ctx: &Context<'_>,
+        authorization: OAuthAuthorizeInput,
+    ) -> graphql::Result<OAuthAuthorizeOutput> {
+        policy::require_session(ctx)?;
+        let user_id = auth::require_viewer(ctx)?.user.id;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            let (_, code) = OAuthAuthorizationCode::create(conn, user_id, &authorization)?;
+
+            let mut params = vec![("code", code.as_str())];
+            if let Some(state) = &authorization.state {
+                params.push(("state", state));
+            }
+            Ok(OAuthAuthorizeOutput {
+                redirect_uri: oauth_server::redirect_uri(&authorization.redirect_uri, &params)
+                    .into(),
+            })
+        })
+        .await
+    }
+
+    /// Registers an OAuth client owned by the viewer, for an app that acts
+    /// for the users who authorize it.
+    async fn oauth_client_create(
+        &self,
+        ctx: &Context<'_>,
+        oauth_client: OAuthClientCreateInput,
+    ) -> graphql::Result<OAuthClientCreateOutput> {
+        policy::require_session(ctx)?;
+        let user_id = auth::require_viewer(ctx)?.user.id;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            let (oauth_client, client_secret) = OAuthClient::create(conn, user_id, oauth_client)?;
+            Ok(OAuthClientCreateOutput {
+                client_secret,
+                oauth_client,
+            })
+        })
+        .await
+    }
+
+    /// Deletes an OAuth client, revoking its access for every user who
+    /// authorized it.
+    async fn oauth_client_delete(
+        &self,
+        ctx: &Context<'_>,
+        oauth_client: OAuthClientDeleteInput,
+    ) -> graphql::Result<OAuthClientDeleteOutput> {
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        let id = oauth_client.id;
+        let user_id: uuid::Uuid = pool
+            .run(move |conn| -> graphql::Result<_> {
+                Ok(oauth_clients::table
+                    .find(id)
+                    .filter(oauth_clients::deleted_at.is_null())
+                    .select(oauth_clients::user_id)
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or("OAuth client not found")?)
+            })
+            .await?;
+        policy::require_user_session(ctx, user_id)?;
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(OAuthClientDeleteOutput {
+                oauth_client: OAuthClient::delete(conn, id)?,
+            })
+        })
+        .await
+    }
+
+    /// Issues a short-lived token to link another OAuth account to the
+    /// viewer, by opening `/auth/:provider/start?link_token=...` in a browser.
+    async fn oauth_link_token_create(
+        &self,
+        ctx: &Context<'_>,
+    ) -> graphql::Result<OAuthLinkTokenCreateOutput> {
+        let session = policy::require_session(ctx)?.clone();
+
         let pool = ctx.data_unchecked::<crate::db::Pool>();
 
-        Ok(EmailAccountCreateOutput {
-            email_account: diesel::insert_into(email_accounts::table)
-                .values(&email_account)
-                .returning(email_accounts::all_columns)
-                .get_result(&pool.get()?)?,
+        pool.run(move |conn| -> graphql::Result<_> {
+            let (link_token, token) = OAuthLinkToken::create(conn, &session)?;
+            Ok(OAuthLinkTokenCreateOutput {
+                expires_at: link_token.expires_at,
+                token,
+            })
         })
+        .await
     }
 
-    async fn oauth_account_create(
+    /// Sets a new password with the token from a password reset email. Every
+    /// session the user had is ended, so they have to sign in again, and
+    /// every personal access token and app they'd authorized is revoked.
+    async fn password_reset_confirm(
         &self,
         ctx: &Context<'_>,
-        oauth_account: OAuthAccountCreateInput,
-    ) -> graphql::Result<OAuthAccountCreateOutput> {
+        reset: PasswordResetConfirmInput,
+    ) -> graphql::Result<PasswordResetConfirmOutput> {
+        auth::validate_password(&reset.password)?;
+
         let pool = ctx.data_unchecked::<crate::db::Pool>();
 
-        Ok(OAuthAccountCreateOutput {
-            oauth_account: diesel::insert_into(oauth_accounts::table)
-                .values(&oauth_account)
-                .returning(oauth_accounts::all_columns)
-                .get_result(&pool.get()?)?,
+        pool.run(move |conn| -> graphql::Result<_> {
+            // Hashed here, off the async runtime, since it takes a while.
+            let password_hash = auth::hash_password(&reset.password)?;
+            Ok(PasswordResetConfirmOutput {
+                email_account: EmailAccount::reset_password(conn, &reset.token, &password_hash)?
+                    .ok_or("this password reset link has expired or was already used")?,
+            })
         })
+        .await
+    }
+
+    /// Emails a password reset link to the address, if it belongs to an
+    /// account.
+    async fn password_reset_request(
+        &self,
+        ctx: &Context<'_>,
+        request: PasswordResetRequestInput,
+    ) -> graphql::Result<PasswordResetRequestOutput> {
+        let email = auth::normalize_email(&request.email);
+
+        let keyring = ctx.data_unchecked::<crate::crypto::Keyring>().clone();
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            let account: Option<EmailAccount> = email_accounts::table
+                .inner_join(users::table)
+                .filter(email_accounts::provider_account_id.eq(&email))
+                .filter(email_accounts::deleted_at.is_null())
+                .filter(users::deleted_at.is_null())
+                .select(email_accounts::all_columns)
+                .get_result(conn)
+                .optional()?;
+
+            if let Some(account) = account {
+                if !EmailToken::sent_recently(conn, account.id, EmailTokenPurpose::PASSWORD_RESET)?
+                {
+                    EmailToken::send(conn, &keyring, &account, EmailTokenPurpose::PASSWORD_RESET)?;
+                }
+            }
+
+            Ok(PasswordResetRequestOutput { email })
+        })
+        .await
     }
 
     async fn post_create(
@@ -171,14 +1091,471 @@ impl MutationRoot {
         ctx: &Context<'_>,
         post: PostCreateInput,
     ) -> graphql::Result<PostCreateOutput> {
+        policy::require_scope(ctx, Scope::POSTS_WRITE)?;
+        policy::require_blog_owner(ctx, post.blog_id).await?;
+
+        let tags = normalize_tags(post.tags.as_deref().unwrap_or_default())?;
+        let new_post = NewPost::try_from(post)?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            if new_post.state == PostState::QUEUED
+                && Blog::queue_len(conn, new_post.blog_id)? >= MAX_QUEUE_SIZE
+            {
+                return Err(QueueError::Full.into());
+            }
+
+            Ok(PostCreateOutput {
+                post: Post::create(conn, &new_post, &tags)?,
+            })
+        })
+        .await
+    }
+
+    async fn post_delete(
+        &self,
+        ctx: &Context<'_>,
+        post: PostDeleteInput,
+    ) -> graphql::Result<PostDeleteOutput> {
+        policy::require_scope(ctx, Scope::POSTS_WRITE)?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        let id = post.id;
+        let blog_id: uuid::Uuid = pool
+            .run(move |conn| -> graphql::Result<_> {
+                Ok(posts::table
+                    .find(id)
+                    .filter(posts::deleted_at.is_null())
+                    .select(posts::blog_id)
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or("post not found")?)
+            })
+            .await?;
+        policy::require_blog_owner(ctx, blog_id).await?;
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(PostDeleteOutput {
+                post: Post::delete(conn, id)?,
+            })
+        })
+        .await
+    }
+
+    async fn post_like(
+        &self,
+        ctx: &Context<'_>,
+        like: PostLikeInput,
+    ) -> graphql::Result<PostLikeOutput> {
+        policy::require_scope(ctx, Scope::POSTS_WRITE)?;
+        policy::require_user(ctx, like.user_id)?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            posts::table
+                .find(like.post_id)
+                .filter(posts::deleted_at.is_null())
+                .filter(posts::state.eq(PostState::PUBLISHED))
+                .select(posts::id)
+                .get_result::<uuid::Uuid>(conn)
+                .optional()?
+                .ok_or("post not found")?;
+
+            diesel::insert_into(likes::table)
+                .values(&like)
+                .on_conflict((likes::post_id, likes::user_id))
+                .do_nothing()
+                .execute(conn)?;
+
+            Ok(PostLikeOutput {
+                like: likes::table
+                    .filter(likes::post_id.eq(like.post_id))
+                    .filter(likes::user_id.eq(like.user_id))
+                    .get_result(conn)?,
+            })
+        })
+        .await
+    }
+
+    async fn post_reblog(
+        &self,
+        ctx: &Context<'_>,
+        reblog: PostReblogInput,
+    ) -> graphql::Result<PostReblogOutput> {
+        policy::require_scope(ctx, Scope::POSTS_WRITE)?;
+        policy::require_blog_owner(ctx, reblog.blog_id).await?;
+
+        let tags = normalize_tags(reblog.tags.as_deref().unwrap_or_default())?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            let parent: Post = posts::table
+                .find(reblog.parent_post_id)
+                .filter(posts::deleted_at.is_null())
+                .filter(posts::state.eq(PostState::PUBLISHED))
+                .get_result(conn)
+                .optional()?
+                .ok_or("parent post not found")?;
+            let new_post = NewPost::reblog(&parent, reblog)?;
+            if new_post.state == PostState::QUEUED
+                && Blog::queue_len(conn, new_post.blog_id)? >= MAX_QUEUE_SIZE
+            {
+                return Err(QueueError::Full.into());
+            }
+
+            Ok(PostReblogOutput {
+                post: Post::create(conn, &new_post, &tags)?,
+            })
+        })
+        .await
+    }
+
+    async fn post_reply(
+        &self,
+        ctx: &Context<'_>,
+        reply: PostReplyInput,
+    ) -> graphql::Result<PostReplyOutput> {
+        policy::require_scope(ctx, Scope::POSTS_WRITE)?;
+        if reply.text.trim().is_empty() {
+            return Err("reply text must not be empty".into());
+        }
+        if reply.text.chars().count() > crate::content::MAX_TEXT_LENGTH {
+            return Err(crate::content::ContentError::TooLong(
+                "text",
+                crate::content::MAX_TEXT_LENGTH,
+            )
+            .into());
+        }
+
+        policy::require_blog_owner(ctx, reply.blog_id).await?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            posts::table
+                .find(reply.post_id)
+                .filter(posts::deleted_at.is_null())
+                .filter(posts::state.eq(PostState::PUBLISHED))
+                .select(posts::id)
+                .get_result::<uuid::Uuid>(conn)
+                .optional()?
+                .ok_or("post not found")?;
+
+            Ok(PostReplyOutput {
+                reply: diesel::insert_into(replies::table)
+                    .values(&reply)
+                    .returning(replies::all_columns)
+                    .get_result(conn)?,
+            })
+        })
+        .await
+    }
+
+    async fn post_restore(
+        &self,
+        ctx: &Context<'_>,
+        post: PostRestoreInput,
+    ) -> graphql::Result<PostRestoreOutput> {
+        policy::require_scope(ctx, Scope::POSTS_WRITE)?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        let post: Post = pool
+            .run(move |conn| -> graphql::Result<_> {
+                Ok(posts::table
+                    .find(post.id)
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or("post not found")?)
+            })
+            .await?;
+        // Fails for posts of deleted blogs, which are restored with the blog.
+        policy::require_blog_owner(ctx, post.blog_id).await?;
+        check_restorable(post.deleted_at)?;
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(PostRestoreOutput {
+                post: Post::restore(conn, post.id)?,
+            })
+        })
+        .await
+    }
+
+    /// Moves a post to another state, e.g. to publish a draft or queue it.
+    async fn post_transition(
+        &self,
+        ctx: &Context<'_>,
+        post: PostTransitionInput,
+    ) -> graphql::Result<PostTransitionOutput> {
+        policy::require_scope(ctx, Scope::POSTS_WRITE)?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        let id = post.id;
+        let current: Post = pool
+            .run(move |conn| -> graphql::Result<_> {
+                Ok(posts::table
+                    .find(id)
+                    .filter(posts::deleted_at.is_null())
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or("post not found")?)
+            })
+            .await?;
+        policy::require_blog_owner(ctx, current.blog_id).await?;
+
+        let publish_at =
+            current
+                .state
+                .transition(current.publish_at, post.state, post.publish_at)?;
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            if post.state == PostState::QUEUED
+                && Blog::queue_len(conn, current.blog_id)? >= MAX_QUEUE_SIZE
+            {
+                return Err(QueueError::Full.into());
+            }
+
+            Ok(PostTransitionOutput {
+                post: Post::transition(conn, &current, post.state, publish_at)?
+                    .ok_or_else(|| ConflictError.extend())?,
+            })
+        })
+        .await
+    }
+
+    async fn post_unlike(
+        &self,
+        ctx: &Context<'_>,
+        like: PostUnlikeInput,
+    ) -> graphql::Result<PostUnlikeOutput> {
+        policy::require_scope(ctx, Scope::POSTS_WRITE)?;
+        policy::require_user(ctx, like.user_id)?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        let post_id = like.post_id;
+        let post: Post = pool
+            .run(move |conn| -> graphql::Result<_> {
+                Ok(posts::table
+                    .find(post_id)
+                    .filter(posts::deleted_at.is_null())
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or("post not found")?)
+            })
+            .await?;
+        if !policy::can_see_post(ctx, &post).await? {
+            return Err("post not found".into());
+        }
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            diesel::delete(
+                likes::table
+                    .filter(likes::post_id.eq(like.post_id))
+                    .filter(likes::user_id.eq(like.user_id)),
+            )
+            .execute(conn)?;
+
+            Ok(PostUnlikeOutput { post })
+        })
+        .await
+    }
+
+    async fn post_update(
+        &self,
+        ctx: &Context<'_>,
+        post: PostUpdateInput,
+    ) -> graphql::Result<PostUpdateOutput> {
+        policy::require_scope(ctx, Scope::POSTS_WRITE)?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        let (id, expected_updated_at) = (post.id, post.expected_updated_at);
+        let blog_id: uuid::Uuid = pool
+            .run(move |conn| -> graphql::Result<_> {
+                Ok(posts::table
+                    .find(id)
+                    .filter(posts::deleted_at.is_null())
+                    .select(posts::blog_id)
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or("post not found")?)
+            })
+            .await?;
+        policy::require_blog_owner(ctx, blog_id).await?;
+
+        let (changeset, tags) = PostChangeset::new(post)?;
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(PostUpdateOutput {
+                post: Post::update(conn, id, expected_updated_at, &changeset, tags.as_deref())?
+                    .ok_or_else(|| ConflictError.extend())?,
+            })
+        })
+        .await
+    }
+
+    /// Replaces the viewer's recovery codes, for when they've used most of
+    /// them or think they've been seen.
+    async fn recovery_codes_regenerate(
+        &self,
+        ctx: &Context<'_>,
+        codes: RecoveryCodesRegenerateInput,
+    ) -> graphql::Result<RecoveryCodesRegenerateOutput> {
+        policy::require_session(ctx)?;
+        let user_id = auth::require_viewer(ctx)?.user.id;
+
+        let keyring = ctx.data_unchecked::<crate::crypto::Keyring>().clone();
         let pool = ctx.data_unchecked::<crate::db::Pool>();
 
-        Ok(PostCreateOutput {
-            post: diesel::insert_into(posts::table)
-                .values(&post)
-                .returning(posts::all_columns)
-                .get_result(&pool.get()?)?,
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(RecoveryCodesRegenerateOutput {
+                recovery_codes: User::regenerate_recovery_codes(
+                    conn,
+                    &keyring,
+                    user_id,
+                    &codes.code,
+                )?,
+            })
         })
+        .await
+    }
+
+    /// Signs a session out, e.g. one on a lost device.
+    async fn session_revoke(
+        &self,
+        ctx: &Context<'_>,
+        session: SessionRevokeInput,
+    ) -> graphql::Result<SessionRevokeOutput> {
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        let id = session.id;
+        let user_id: uuid::Uuid = pool
+            .run(move |conn| -> graphql::Result<_> {
+                Ok(sessions::table
+                    .find(id)
+                    .select(sessions::user_id)
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or("session not found")?)
+            })
+            .await?;
+        policy::require_user_session(ctx, user_id)?;
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(SessionRevokeOutput {
+                session: diesel::delete(sessions::table.find(id))
+                    .returning(sessions::all_columns)
+                    .get_result(conn)
+                    .optional()?
+                    .ok_or("session not found")?,
+            })
+        })
+        .await
+    }
+
+    /// Signs the viewer out everywhere but the session they're using.
+    async fn session_revoke_all_others(
+        &self,
+        ctx: &Context<'_>,
+    ) -> graphql::Result<SessionRevokeAllOthersOutput> {
+        let session = policy::require_session(ctx)?.clone();
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            let count = diesel::delete(
+                sessions::table
+                    .filter(sessions::user_id.eq(session.user_id))
+                    .filter(sessions::id.ne(session.id)),
+            )
+            .execute(conn)?;
+
+            Ok(SessionRevokeAllOthersOutput {
+                count: count as i64,
+            })
+        })
+        .await
+    }
+
+    /// Turns on two-factor authentication for the viewer, once they've added
+    /// the secret from `totpEnroll` to their authenticator app.
+    async fn totp_confirm(
+        &self,
+        ctx: &Context<'_>,
+        totp: TotpConfirmInput,
+    ) -> graphql::Result<TotpConfirmOutput> {
+        policy::require_session(ctx)?;
+        let user_id = auth::require_viewer(ctx)?.user.id;
+
+        let keyring = ctx.data_unchecked::<crate::crypto::Keyring>().clone();
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            let (user, recovery_codes) = User::confirm_totp(conn, &keyring, user_id, &totp.code)?;
+            Ok(TotpConfirmOutput {
+                recovery_codes,
+                user,
+            })
+        })
+        .await
+    }
+
+    /// Turns off two-factor authentication. Every personal access token and
+    /// app the user had authorized is revoked.
+    async fn totp_disable(
+        &self,
+        ctx: &Context<'_>,
+        totp: TotpDisableInput,
+    ) -> graphql::Result<TotpDisableOutput> {
+        policy::require_session(ctx)?;
+        let user_id = auth::require_viewer(ctx)?.user.id;
+
+        let keyring = ctx.data_unchecked::<crate::crypto::Keyring>().clone();
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(TotpDisableOutput {
+                user: User::disable_totp(conn, &keyring, user_id, &totp.code)?,
+            })
+        })
+        .await
+    }
+
+    /// Starts turning on two-factor authentication for the viewer, with a new
+    /// secret to add to their authenticator app. It's turned on by
+    /// `totpConfirm`.
+    async fn totp_enroll(&self, ctx: &Context<'_>) -> graphql::Result<TotpEnrollOutput> {
+        policy::require_session(ctx)?;
+        let user_id = auth::require_viewer(ctx)?.user.id;
+
+        let keyring = ctx.data_unchecked::<crate::crypto::Keyring>().clone();
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            let (user, secret) = User::enroll_totp(conn, &keyring, user_id)?;
+            // Authenticator apps list the account by its email address, if it
+            // has one.
+            let account = email_accounts::table
+                .filter(email_accounts::user_id.eq(user_id))
+                .filter(email_accounts::deleted_at.is_null())
+                .select(email_accounts::provider_account_id)
+                .get_result(conn)
+                .optional()?
+                .unwrap_or_else(|| user_id.to_string());
+
+            Ok(TotpEnrollOutput {
+                otpauth_uri: totp::otpauth_uri(&secret, &account).into(),
+                secret: totp::base32(&secret),
+                user,
+            })
+        })
+        .await
     }
 
     async fn user_create(
@@ -186,14 +1563,86 @@ impl MutationRoot {
         ctx: &Context<'_>,
         user: UserCreateInput,
     ) -> graphql::Result<UserCreateOutput> {
+        // Anyone may sign up, but only admins may create other admins.
+        if user.role == UserRole::ADMIN && !auth::require_viewer(ctx).map(policy::is_admin)? {
+            return Err(policy::forbidden());
+        }
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        let user = NewUser::try_from(user)?;
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            let (user, signup_token) = User::create(conn, &user)?;
+            Ok(UserCreateOutput { signup_token, user })
+        })
+        .await
+    }
+
+    async fn user_delete(
+        &self,
+        ctx: &Context<'_>,
+        user: UserDeleteInput,
+    ) -> graphql::Result<UserDeleteOutput> {
+        policy::require_user_session(ctx, user.id)?;
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(UserDeleteOutput {
+                user: User::delete(conn, user.id)?,
+            })
+        })
+        .await
+    }
+
+    /// Restores a deleted user along with everything deleted with them. Deleted
+    /// users can't sign in, so only admins can do this.
+    #[graphql(guard(RoleGuard(role = "UserRole::ADMIN")))]
+    async fn user_restore(
+        &self,
+        ctx: &Context<'_>,
+        user: UserRestoreInput,
+    ) -> graphql::Result<UserRestoreOutput> {
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            let user: User = users::table
+                .find(user.id)
+                .get_result(conn)
+                .optional()?
+                .ok_or("user not found")?;
+            check_restorable(user.deleted_at)?;
+
+            Ok(UserRestoreOutput {
+                user: User::restore(conn, &user)?,
+            })
+        })
+        .await
+    }
+
+    async fn user_update(
+        &self,
+        ctx: &Context<'_>,
+        user: UserUpdateInput,
+    ) -> graphql::Result<UserUpdateOutput> {
+        let viewer = policy::require_user_session(ctx, user.id)?;
+        if !user.role.is_undefined() && !policy::is_admin(viewer) {
+            return Err(policy::forbidden());
+        }
+
+        let (id, expected_updated_at) = (user.id, user.expected_updated_at);
+        let changeset = UserChangeset::try_from(user)?;
+
         let pool = ctx.data_unchecked::<crate::db::Pool>();
 
-        Ok(UserCreateOutput {
-            user: diesel::insert_into(users::table)
-                .values(&user)
-                .returning(users::all_columns)
-                .get_result(&pool.get()?)?,
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(UserUpdateOutput {
+                user: User::update(conn, id, expected_updated_at, &changeset)?
+                    .ok_or_else(|| ConflictError.extend())?,
+            })
         })
+        .await
     }
 }
 
@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::sync::Arc;
+
+use diesel::prelude::*;
+use graphql::dataloader::Loader;
+
+use crate::db::{Pool, RunError};
+use crate::models::{Blog, EmailAccount, OAuthAccount, OAuthClient, Post, Tag, User};
+use crate::schema::{
+    blogs, email_accounts, oauth_accounts, oauth_clients, post_tags, posts, tags, users,
+};
+
+/// Follower counts of blogs, not counting deleted users.
+const FOLLOWER_COUNTS_QUERY: &str = r#"
+SELECT "follows"."blog_id" AS "id", COUNT(*) AS "count" FROM "follows"
+INNER JOIN "users" ON "users"."id" = "follows"."user_id"
+WHERE "follows"."blog_id" = ANY($1) AND "users"."deleted_at" IS NULL
+GROUP BY "follows"."blog_id"
+"#;
+
+/// How many blogs users follow, not counting deleted blogs.
+const FOLLOWING_COUNTS_QUERY: &str = r#"
+SELECT "follows"."user_id" AS "id", COUNT(*) AS "count" FROM "follows"
+INNER JOIN "blogs" ON "blogs"."id" = "follows"."blog_id"
+WHERE "follows"."user_id" = ANY($1) AND "blogs"."deleted_at" IS NULL
+GROUP BY "follows"."user_id"
+"#;
+
+/// Likes, reblogs and replies of posts, as counted by `Note::count_for_post`.
+const NOTE_COUNTS_QUERY: &str = r#"
+SELECT "notes"."id", COUNT(*) AS "count" FROM (
+    SELECT "likes"."post_id" AS "id" FROM "likes"
+    INNER JOIN "users" ON "users"."id" = "likes"."user_id"
+    WHERE "likes"."post_id" = ANY($1) AND "users"."deleted_at" IS NULL
+    UNION ALL
+    SELECT "posts"."parent_post_id" AS "id" FROM "posts"
+    WHERE "posts"."parent_post_id" = ANY($1)
+    AND "posts"."deleted_at" IS NULL
+    AND "posts"."state" = 'PUBLISHED'
+    UNION ALL
+    SELECT "replies"."post_id" AS "id" FROM "replies"
+    WHERE "replies"."post_id" = ANY($1) AND "replies"."deleted_at" IS NULL
+) AS "notes"
+GROUP BY "notes"."id"
+"#;
+
+/// A database error shared between every resolver waiting on a batch.
+#[derive(Debug, Clone)]
+pub struct LoadError(Arc<dyn std::error::Error + Send + Sync>);
+
+impl Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+impl std::error::Error for LoadError {}
+
+impl From<diesel::result::Error> for LoadError {
+    fn from(err: diesel::result::Error) -> Self {
+        Self(Arc::new(err))
+    }
+}
+
+impl From<RunError> for LoadError {
+    fn from(err: RunError) -> Self {
+        Self(Arc::new(err))
+    }
+}
+
+#[derive(diesel::QueryableByName)]
+struct Count {
+    #[sql_type = "diesel::sql_types::Uuid"]
+    id: uuid::Uuid,
+    #[sql_type = "diesel::sql_types::BigInt"]
+    count: i64,
+}
+
+/// Runs one of the `*_COUNTS_QUERY`s, which count rows per ID in `$1`. IDs
+/// with nothing to count are left out of the result.
+async fn load_counts(
+    pool: &Pool,
+    query: &'static str,
+    ids: &[uuid::Uuid],
+) -> Result<HashMap<uuid::Uuid, i64>, LoadError> {
+    use diesel::sql_types::{Array, Uuid};
+
+    let ids = ids.to_vec();
+
+    pool.run(move |conn| -> Result<_, LoadError> {
+        Ok(diesel::sql_query(query)
+            .bind::<Array<Uuid>, _>(ids)
+            .load::<Count>(conn)?
+            .into_iter()
+            .map(|row| (row.id, row.count))
+            .collect())
+    })
+    .await
+}
+
+/// Loads blogs by ID, including deleted ones.
+pub struct BlogLoader(Pool);
+
+impl BlogLoader {
+    pub fn new(pool: Pool) -> Self {
+        Self(pool)
+    }
+}
+
+#[graphql::async_trait::async_trait]
+impl Loader<uuid::Uuid> for BlogLoader {
+    type Value = Blog;
+    type Error = LoadError;
+
+    async fn load(&self, ids: &[uuid::Uuid]) -> Result<HashMap<uuid::Uuid, Blog>, LoadError> {
+        let ids = ids.to_vec();
+
+        self.0
+            .run(move |conn| -> Result<_, LoadError> {
+                Ok(blogs::table
+                    .filter(blogs::id.eq_any(ids))
+                    .load::<Blog>(conn)?
+                    .into_iter()
+                    .map(|blog| (blog.id, blog))
+                    .collect())
+            })
+            .await
+    }
+}
+
+/// Loads a user's email account, if they have one that hasn't been deleted.
+pub struct EmailAccountLoader(Pool);
+
+impl EmailAccountLoader {
+    pub fn new(pool: Pool) -> Self {
+        Self(pool)
+    }
+}
+
+#[graphql::async_trait::async_trait]
+impl Loader<uuid::Uuid> for EmailAccountLoader {
+    type Value = EmailAccount;
+    type Error = LoadError;
+
+    async fn load(
+        &self,
+        user_ids: &[uuid::Uuid],
+    ) -> Result<HashMap<uuid::Uuid, EmailAccount>, LoadError> {
+        let user_ids = user_ids.to_vec();
+
+        self.0
+            .run(move |conn| -> Result<_, LoadError> {
+                Ok(email_accounts::table
+                    .filter(email_accounts::user_id.eq_any(user_ids))
+                    .filter(email_accounts::deleted_at.is_null())
+                    .load::<EmailAccount>(conn)?
+                    .into_iter()
+                    .map(|account| (account.user_id, account))
+                    .collect())
+            })
+            .await
+    }
+}
+
+/// Loads how many users follow a blog.
+pub struct FollowerCountLoader(Pool);
+
+impl FollowerCountLoader {
+    pub fn new(pool: Pool) -> Self {
+        Self(pool)
+    }
+}
+
+#[graphql::async_trait::async_trait]
+impl Loader<uuid::Uuid> for FollowerCountLoader {
+    type Value = i64;
+    type Error = LoadError;
+
+    async fn load(&self, blog_ids: &[uuid::Uuid]) -> Result<HashMap<uuid::Uuid, i64>, LoadError> {
+        load_counts(&self.0, FOLLOWER_COUNTS_QUERY, blog_ids).await
+    }
+}
+
+/// Loads how many blogs a user follows.
+pub struct FollowingCountLoader(Pool);
+
+impl FollowingCountLoader {
+    pub fn new(pool: Pool) -> Self {
+        Self(pool)
+    }
+}
+
+#[graphql::async_trait::async_trait]
+impl Loader<uuid::Uuid> for FollowingCountLoader {
+    type Value = i64;
+    type Error = LoadError;
+
+    async fn load(&self, user_ids: &[uuid::Uuid]) -> Result<HashMap<uuid::Uuid, i64>, LoadError> {
+        load_counts(&self.0, FOLLOWING_COUNTS_QUERY, user_ids).await
+    }
+}
+
+/// Loads how many notes a post has, of any type.
+pub struct NoteCountLoader(Pool);
+
+impl NoteCountLoader {
+    pub fn new(pool: Pool) -> Self {
+        Self(pool)
+    }
+}
+
+#[graphql::async_trait::async_trait]
+impl Loader<uuid::Uuid> for NoteCountLoader {
+    type Value = i64;
+    type Error = LoadError;
+
+    async fn load(&self, post_ids: &[uuid::Uuid]) -> Result<HashMap<uuid::Uuid, i64>, LoadError> {
+        load_counts(&self.0, NOTE_COUNTS_QUERY, post_ids).await
+    }
+}
+
+/// Loads a user's OAuth accounts that haven't been deleted, oldest first.
+/// Users without any are left out of the result.
+pub struct OAuthAccountsLoader(Pool);
+
+impl OAuthAccountsLoader {
+    pub fn new(pool: Pool) -> Self {
+        Self(pool)
+    }
+}
+
+#[graphql::async_trait::async_trait]
+impl Loader<uuid::Uuid> for OAuthAccountsLoader {
+    type Value = Vec<OAuthAccount>;
+    type Error = LoadError;
+
+    async fn load(
+        &self,
+        user_ids: &[uuid::Uuid],
+    ) -> Result<HashMap<uuid::Uuid, Vec<OAuthAccount>>, LoadError> {
+        let user_ids = user_ids.to_vec();
+
+        self.0
+            .run(move |conn| -> Result<_, LoadError> {
+                let mut accounts = HashMap::<_, Vec<_>>::new();
+                for account in oauth_accounts::table
+                    .filter(oauth_accounts::user_id.eq_any(user_ids))
+                    .filter(oauth_accounts::deleted_at.is_null())
+                    .order_by(oauth_accounts::created_at.asc())
+                    .load::<OAuthAccount>(conn)?
+                {
+                    accounts.entry(account.user_id).or_default().push(account);
+                }
+
+                Ok(accounts)
+            })
+            .await
+    }
+}
+
+/// Loads OAuth clients by ID, including deleted ones.
+pub struct OAuthClientLoader(Pool);
+
+impl OAuthClientLoader {
+    pub fn new(pool: Pool) -> Self {
+        Self(pool)
+    }
+}
+
+#[graphql::async_trait::async_trait]
+impl Loader<uuid::Uuid> for OAuthClientLoader {
+    type Value = OAuthClient;
+    type Error = LoadError;
+
+    async fn load(
+        &self,
+        ids: &[uuid::Uuid],
+    ) -> Result<HashMap<uuid::Uuid, OAuthClient>, LoadError> {
+        let ids = ids.to_vec();
+
+        self.0
+            .run(move |conn| -> Result<_, LoadError> {
+                Ok(oauth_clients::table
+                    .filter(oauth_clients::id.eq_any(ids))
+                    .load::<OAuthClient>(conn)?
+                    .into_iter()
+                    .map(|client| (client.id, client))
+                    .collect())
+            })
+            .await
+    }
+}
+
+/// Loads posts by ID, including deleted ones.
+pub struct PostLoader(Pool);
+
+impl PostLoader {
+    pub fn new(pool: Pool) -> Self {
+        Self(pool)
+    }
+}
+
+#[graphql::async_trait::async_trait]
+impl Loader<uuid::Uuid> for PostLoader {
+    type Value = Post;
+    type Error = LoadError;
+
+    async fn load(&self, ids: &[uuid::Uuid]) -> Result<HashMap<uuid::Uuid, Post>, LoadError> {
+        let ids = ids.to_vec();
+
+        self.0
+            .run(move |conn| -> Result<_, LoadError> {
+                Ok(posts::table
+                    .filter(posts::id.eq_any(ids))
+                    .load::<Post>(conn)?
+                    .into_iter()
+                    .map(|post| (post.id, post))
+                    .collect())
+            })
+            .await
+    }
+}
+
+/// Loads a post's tags that haven't been deleted, in the order they were
+/// given. Posts without any are left out of the result.
+pub struct PostTagsLoader(Pool);
+
+impl PostTagsLoader {
+    pub fn new(pool: Pool) -> Self {
+        Self(pool)
+    }
+}
+
+#[graphql::async_trait::async_trait]
+impl Loader<uuid::Uuid> for PostTagsLoader {
+    type Value = Vec<Tag>;
+    type Error = LoadError;
+
+    async fn load(
+        &self,
+        post_ids: &[uuid::Uuid],
+    ) -> Result<HashMap<uuid::Uuid, Vec<Tag>>, LoadError> {
+        let post_ids = post_ids.to_vec();
+
+        self.0
+            .run(move |conn| -> Result<_, LoadError> {
+                let mut tags = HashMap::<_, Vec<_>>::new();
+                for (post_id, tag) in post_tags::table
+                    .filter(post_tags::post_id.eq_any(post_ids))
+                    .inner_join(tags::table)
+                    .filter(tags::deleted_at.is_null())
+                    .order_by((post_tags::post_id, post_tags::position.asc()))
+                    .select((post_tags::post_id, tags::all_columns))
+                    .load::<(uuid::Uuid, Tag)>(conn)?
+                {
+                    tags.entry(post_id).or_default().push(tag);
+                }
+
+                Ok(tags)
+            })
+            .await
+    }
+}
+
+/// Loads users by ID, including deleted ones.
+pub struct UserLoader(Pool);
+
+impl UserLoader {
+    pub fn new(pool: Pool) -> Self {
+        Self(pool)
+    }
+}
+
+#[graphql::async_trait::async_trait]
+impl Loader<uuid::Uuid> for UserLoader {
+    type Value = User;
+    type Error = LoadError;
+
+    async fn load(&self, ids: &[uuid::Uuid]) -> Result<HashMap<uuid::Uuid, User>, LoadError> {
+        let ids = ids.to_vec();
+
+        self.0
+            .run(move |conn| -> Result<_, LoadError> {
+                Ok(users::table
+                    .filter(users::id.eq_any(ids))
+                    .load::<User>(conn)?
+                    .into_iter()
+                    .map(|user| (user.id, user))
+                    .collect())
+            })
+            .await
+    }
+}
@@ -1,26 +1,160 @@
 use std::convert::Infallible;
 
+use graphql::dataloader::DataLoader;
 use graphql::http::graphiql_source;
+use tumblr::loaders::{
+    BlogLoader, EmailAccountLoader, FollowerCountLoader, FollowingCountLoader, NoteCountLoader,
+    OAuthAccountsLoader, OAuthClientLoader, PostLoader, PostTagsLoader, UserLoader,
+};
 use warp::Filter;
 
+/// Executes a GraphQL request, first resolving its bearer token (a session or
+/// access token, if any) to a viewer in the request data.
+async fn execute(
+    pool: tumblr::db::Pool,
+    sessions: tumblr::auth::SessionConfig,
+    schema: tumblr::Schema,
+    request: graphql::Request,
+    authorization: Option<String>,
+    client: tumblr::auth::ClientInfo,
+) -> Result<graphql_warp::Response, Infallible> {
+    let viewer = match authorization
+        .as_deref()
+        .and_then(tumblr::auth::bearer_token)
+    {
+        Some(token) => {
+            let (client, token) = (client.clone(), token.to_owned());
+            pool.run(move |conn| -> graphql::Result<_> {
+                Ok(tumblr::auth::authenticate(
+                    conn, &sessions, &client, &token,
+                )?)
+            })
+            .await
+            .map_err(|err| err.message)
+        }
+        None => Ok(None),
+    };
+
+    let request = request.data(client);
+    let response = match viewer {
+        Ok(Some(viewer)) => schema.execute(request.data(viewer)).await,
+        Ok(None) => schema.execute(request).await,
+        Err(message) => {
+            graphql::Response::from_errors(vec![graphql::ServerError::new(message, None)])
+        }
+    };
+
+    Ok(response.into())
+}
+
 #[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
+async fn main() -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
     dotenv::dotenv().ok();
     env_logger::try_init()?;
 
-    let pool = tumblr::db::Pool::new(std::env::var("DATABASE_URL")?)?;
+    let pool = tumblr::db::Pool::new(
+        std::env::var("DATABASE_URL")?,
+        tumblr::db::PoolConfig::from_env()?,
+    )?;
+    let sessions = tumblr::auth::SessionConfig::from_env()?;
+    let keyring: tumblr::crypto::Keyring = std::env::var("TOKEN_ENCRYPTION_KEYS")?.parse()?;
+    let cursor_key: tumblr::pagination::CursorKey = std::env::var("CURSOR_SIGNING_KEY")?.parse()?;
+
+    let resealed = pool
+        .run({
+            let keyring = keyring.clone();
+            move |conn| tumblr::models::OAuthAccount::reseal_tokens(conn, &keyring)
+        })
+        .await?;
+    if resealed > 0 {
+        log::info!(
+            "re-sealed tokens for {} OAuth accounts with key {:?}",
+            resealed,
+            keyring.primary_key_id()
+        );
+    }
+
+    let resealed = pool
+        .run({
+            let keyring = keyring.clone();
+            move |conn| tumblr::models::User::reseal_totp_secrets(conn, &keyring)
+        })
+        .await?;
+    if resealed > 0 {
+        log::info!(
+            "re-sealed TOTP secrets for {} users with key {:?}",
+            resealed,
+            keyring.primary_key_id()
+        );
+    }
+
+    let providers = tumblr::oauth::Providers::from_env()?;
+
+    let app_url: url::Url = std::env::var("APP_URL")
+        .unwrap_or_else(|_| "http://localhost:3000".to_owned())
+        .parse()?;
+    let mailer = tumblr::email::mailer_from_env()?;
+
+    let mut jobs = tumblr::jobs::Registry::new();
+    jobs.register(tumblr::email::SendEmailTokenHandler {
+        app_url: app_url.clone(),
+        keyring: keyring.clone(),
+        mailer,
+        pool: pool.clone(),
+    });
+    jobs.register(tumblr::oauth::RefreshOAuthTokenHandler {
+        keyring: keyring.clone(),
+        pool: pool.clone(),
+        providers: providers.clone(),
+    });
+
+    tokio::spawn(tumblr::cleanup::run(pool.clone()));
+    tokio::spawn(tumblr::jobs::run(pool.clone(), jobs));
+    tokio::spawn(tumblr::oauth::run(pool.clone(), providers.clone()));
+    tokio::spawn(tumblr::publisher::run(pool.clone()));
+
+    let oauth = tumblr::oauth::routes(pool.clone(), keyring.clone(), providers, sessions);
+    let oauth_server = tumblr::oauth_server::routes(pool.clone(), app_url);
+
     let schema = tumblr::Schema::build(Default::default(), Default::default(), Default::default())
-        .data(pool)
+        .data(cursor_key)
+        .data(keyring)
+        .data(DataLoader::new(BlogLoader::new(pool.clone())))
+        .data(DataLoader::new(EmailAccountLoader::new(pool.clone())))
+        .data(DataLoader::new(FollowerCountLoader::new(pool.clone())))
+        .data(DataLoader::new(FollowingCountLoader::new(pool.clone())))
+        .data(DataLoader::new(NoteCountLoader::new(pool.clone())))
+        .data(DataLoader::new(OAuthAccountsLoader::new(pool.clone())))
+        .data(DataLoader::new(OAuthClientLoader::new(pool.clone())))
+        .data(DataLoader::new(PostLoader::new(pool.clone())))
+        .data(DataLoader::new(PostTagsLoader::new(pool.clone())))
+        .data(DataLoader::new(UserLoader::new(pool.clone())))
+        .data(pool.clone())
+        .data(sessions)
         .finish();
 
     let filter = warp::path::end()
         .and(warp::get())
         .map(|| warp::reply::html(graphiql_source("/", None)))
-        .or(graphql_warp::graphql(schema).and_then(
-            |(schema, request): (tumblr::Schema, graphql::Request)| async move {
-                Ok::<graphql_warp::Response, Infallible>(schema.execute(request).await.into())
-            },
-        ))
+        .or(oauth)
+        .or(oauth_server)
+        .or(graphql_warp::graphql(schema)
+            .and(warp::header::optional::<String>("authorization"))
+            .and(tumblr::auth::client_info())
+            .and_then(
+                move |(schema, request): (tumblr::Schema, graphql::Request),
+                      authorization: Option<String>,
+                      client: tumblr::auth::ClientInfo| {
+                    execute(
+                        pool.clone(),
+                        sessions,
+                        schema,
+                        request,
+                        authorization,
+                        client,
+                    )
+                },
+            ))
         .with(warp::log(env!("CARGO_PKG_NAME")));
 
     warp::serve(filter).run(([0, 0, 0, 0], 4000)).await;
@@ -1,3 +1,5 @@
+use std::collections::HashMap;
+use std::convert::TryFrom;
 use std::fmt::{self, Display};
 use std::str::FromStr;
 
@@ -5,227 +7,3707 @@ use diesel::backend::Backend;
 use diesel::prelude::*;
 use diesel::sql_types::Text;
 use diesel::types::{FromSql, ToSql};
-use graphql::Context;
+use diesel::Connection as _;
+use graphql::dataloader::DataLoader;
+use graphql::{Context, ErrorExtensions, MaybeUndefined};
 
+use crate::auth;
+use crate::content::{ContentBlock, ContentBlockInput, PostContent};
+use crate::crypto::{CryptoError, Keyring, Sealed};
+use crate::email::SendEmailToken;
+use crate::jobs::Job;
+use crate::loaders::{
+    BlogLoader, EmailAccountLoader, FollowerCountLoader, FollowingCountLoader, NoteCountLoader,
+    OAuthAccountsLoader, OAuthClientLoader, PostLoader, PostTagsLoader, UserLoader,
+};
+use crate::node::{GlobalId, NodeType};
+use crate::oauth::{self, RefreshOAuthToken, Tokens};
+use crate::pagination::{paginate, rowid_window};
+pub use crate::pagination::{Connection, Cursor, Edge, Node, Order, PageArgs, Position, Window};
+use crate::schema::access_tokens;
 use crate::schema::blogs;
 use crate::schema::email_accounts;
+use crate::schema::email_tokens;
+use crate::schema::follows;
+use crate::schema::likes;
+use crate::schema::login_challenges;
 use crate::schema::oauth_accounts;
+use crate::schema::oauth_authorization_codes;
+use crate::schema::oauth_clients;
+use crate::schema::oauth_link_tokens;
+use crate::schema::post_tags;
 use crate::schema::posts;
+use crate::schema::recovery_codes;
+use crate::schema::replies;
+use crate::schema::sessions;
+use crate::schema::tags;
 use crate::schema::users;
+use crate::totp;
 
-pub trait Node
+sql_function!(fn lower(x: diesel::sql_types::Nullable<Text>) -> diesel::sql_types::Nullable<Text>);
+
+/// How long soft-deleted blogs, posts and users can still be restored.
+pub const DELETE_GRACE_PERIOD_DAYS: i64 = 30;
+
+/// How long links in verification emails work for.
+pub const EMAIL_VERIFICATION_LIFETIME_HOURS: i64 = 72;
+/// How long links in password reset emails work for.
+pub const PASSWORD_RESET_LIFETIME_HOURS: i64 = 1;
+/// How long to wait before sending another email of the same kind to the same
+/// account, so the mutations that send them can't be used to flood inboxes.
+pub const EMAIL_TOKEN_RESEND_SECONDS: i64 = 60;
+
+/// How long users with two-factor authentication on have to enter a code
+/// after their password.
+pub const LOGIN_CHALLENGE_LIFETIME_MINUTES: i64 = 5;
+/// How many wrong codes a login challenge takes before it stops accepting
+/// any, so that codes can't be guessed.
+pub const LOGIN_CHALLENGE_MAX_ATTEMPTS: i32 = 5;
+/// How many wrong second-factor codes in a row, across every challenge and
+/// mutation that takes one, lock a user out of two-factor sign-in. A new
+/// challenge doesn't bring more guesses.
+pub const SECOND_FACTOR_MAX_FAILURES: i32 = 5;
+/// How long the first lockout lasts. Each further run of wrong codes doubles
+/// it, up to `SECOND_FACTOR_MAX_LOCKOUT_HOURS`.
+pub const SECOND_FACTOR_LOCKOUT_MINUTES: i64 = 15;
+pub const SECOND_FACTOR_MAX_LOCKOUT_HOURS: i64 = 24;
+
+/// How long access tokens issued to OAuth clients last. Clients get new ones
+/// with their refresh token.
+pub const OAUTH_ACCESS_TOKEN_LIFETIME_MINUTES: i64 = 60;
+/// How long an OAuth client's refresh token lasts. Each refresh replaces it
+/// with a new one, so clients in use stay authorized.
+pub const OAUTH_REFRESH_TOKEN_LIFETIME_DAYS: i64 = 30;
+/// How long OAuth clients have to exchange an authorization code for tokens.
+pub const OAUTH_AUTHORIZATION_CODE_LIFETIME_MINUTES: i64 = 10;
+pub const MAX_OAUTH_CLIENT_REDIRECT_URIS: usize = 10;
+/// How long a token from `oauthLinkTokenCreate` can be used to start linking
+/// an OAuth account.
+pub const OAUTH_LINK_TOKEN_LIFETIME_MINUTES: i64 = 5;
+
+/// How long the sign-up token from `userCreate` can be used to attach the new
+/// user's first sign-in account.
+pub const SIGNUP_TOKEN_LIFETIME_MINUTES: i64 = 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RestoreError {
+    Expired,
+    NotDeleted,
+}
+
+impl Display for RestoreError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Expired => write!(
+                f,
+                "deleted items can only be restored within {} days",
+                DELETE_GRACE_PERIOD_DAYS
+            ),
+            Self::NotDeleted => "only deleted items can be restored".fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for RestoreError {}
+
+/// Checks that a row deleted at `deleted_at` is still within the grace
+/// window, returning the deletion time to restore by.
+pub fn check_restorable(deleted_at: Option<DateTime>) -> Result<DateTime, RestoreError> {
+    let deleted_at = deleted_at.ok_or(RestoreError::NotDeleted)?;
+
+    if chrono::Utc::now() - deleted_at > chrono::Duration::days(DELETE_GRACE_PERIOD_DAYS) {
+        Err(RestoreError::Expired)
+    } else {
+        Ok(deleted_at)
+    }
+}
+
+/// How many posts a blog's queue can hold.
+pub const MAX_QUEUE_SIZE: i64 = 300;
+/// The longest a blog can wait between publishing posts from its queue: a
+/// week.
+pub const MAX_QUEUE_INTERVAL_MINUTES: i32 = 7 * 24 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueueError {
+    Full,
+    InvalidInterval,
+}
+
+impl Display for QueueError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Full => write!(
+                f,
+                "a blog's queue can hold at most {} posts",
+                MAX_QUEUE_SIZE
+            ),
+            Self::InvalidInterval => write!(
+                f,
+                "queueIntervalMinutes must be between 1 and {}",
+                MAX_QUEUE_INTERVAL_MINUTES
+            ),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+/// Returned by `*Update` mutations when the row's `updatedAt` no longer
+/// matches the `expectedUpdatedAt` the client sent, i.e. someone else changed
+/// it first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ConflictError;
+
+impl Display for ConflictError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "this item has changed since it was fetched; fetch it again and retry".fmt(f)
+    }
+}
+
+impl std::error::Error for ConflictError {}
+
+impl ErrorExtensions for ConflictError {
+    fn extend(&self) -> graphql::Error {
+        graphql::Error::new(self.to_string()).extend_with(|_, e| e.set("code", "CONFLICT"))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchError {
+    Empty,
+    Null(&'static str),
+}
+
+impl Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => "at least one field must be given to update".fmt(f),
+            Self::Null(field) => write!(f, "{} can't be null", field),
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+/// Reads a patch field for a column that can't be null: leaving it out keeps
+/// the current value, and an explicit `null` is an error.
+pub fn required_field<T>(
+    field: &'static str,
+    value: MaybeUndefined<T>,
+) -> Result<Option<T>, PatchError> {
+    if value.is_null() {
+        Err(PatchError::Null(field))
+    } else {
+        Ok(value.take())
+    }
+}
+
+/// Reads a patch field for a column that can be null: leaving it out keeps
+/// the current value, and an explicit `null` clears it.
+pub fn nullable_field<T>(value: MaybeUndefined<T>) -> Option<Option<T>> {
+    match value {
+        MaybeUndefined::Undefined => None,
+        MaybeUndefined::Null => Some(None),
+        MaybeUndefined::Value(value) => Some(Some(value)),
+    }
+}
+
+/// A token for acting as a user through the API, limited to its scopes:
+/// either a personal access token the user created, or one issued to an OAuth
+/// client they authorized.
+#[derive(
+    Debug,
+    Clone,
+    diesel::Associations,
+    diesel::Identifiable,
+    diesel::Queryable,
+    graphql::SimpleObject,
+)]
+#[belongs_to(User)]
+#[graphql(complex)]
+pub struct AccessToken {
+    #[graphql(skip)]
+    pub _rowid: i32,
+    #[graphql(skip)]
+    pub client_id: Option<uuid::Uuid>,
+    pub created_at: DateTime,
+    /// Null for personal access tokens that work until they're revoked.
+    pub expires_at: Option<DateTime>,
+    pub id: uuid::Uuid,
+    pub last_used_at: Option<DateTime>,
+    /// What the user called a personal access token. Null for tokens issued
+    /// to OAuth clients.
+    pub name: Option<String>,
+    #[graphql(skip)]
+    pub refresh_expires_at: Option<DateTime>,
+    #[graphql(skip)]
+    pub refresh_token_hash: Option<String>,
+    pub scopes: Vec<Scope>,
+    #[graphql(skip)]
+    pub token_hash: String,
+    pub updated_at: DateTime,
+    #[graphql(skip)]
+    pub user_id: uuid::Uuid,
+}
+
+#[graphql::ComplexObject]
+impl AccessToken {
+    /// The OAuth client the token was issued to. Null for personal access
+    /// tokens.
+    pub async fn client(&self, ctx: &Context<'_>) -> graphql::Result<Option<OAuthClient>> {
+        let client_id = match self.client_id {
+            Some(client_id) => client_id,
+            None => return Ok(None),
+        };
+        let loader = ctx.data_unchecked::<DataLoader<OAuthClientLoader>>();
+
+        Ok(loader.load_one(client_id).await?)
+    }
+}
+
+impl AccessToken {
+    /// Creates a personal access token for `user_id`, returning it along with
+    /// the plaintext token to hand to the user.
+    pub fn create_personal(
+        conn: &PgConnection,
+        user_id: uuid::Uuid,
+        input: AccessTokenCreateInput,
+    ) -> Result<(Self, String), AccessTokenError> {
+        let name = input.name.trim();
+        if name.is_empty() {
+            return Err(AccessTokenError::BlankName);
+        }
+        if input.scopes.is_empty() {
+            return Err(AccessTokenError::NoScopes);
+        }
+        if input
+            .expires_at
+            .is_some_and(|expires_at| expires_at <= chrono::Utc::now())
+        {
+            return Err(AccessTokenError::Expired);
+        }
+
+        let token = auth::generate_prefixed_token(auth::PERSONAL_ACCESS_TOKEN_PREFIX);
+        let access_token = diesel::insert_into(access_tokens::table)
+            .values((
+                access_tokens::expires_at.eq(input.expires_at),
+                access_tokens::name.eq(Some(name)),
+                access_tokens::scopes.eq(normalize_scopes(&input.scopes)),
+                access_tokens::token_hash.eq(auth::hash_token(&token)),
+                access_tokens::user_id.eq(user_id),
+            ))
+            .returning(access_tokens::all_columns)
+            .get_result(conn)?;
+
+        Ok((access_token, token))
+    }
+
+    /// Revokes every personal access token and OAuth grant `user_id` has,
+    /// including authorization codes not yet exchanged, returning how many
+    /// tokens were revoked.
+    pub fn delete_for_user(conn: &PgConnection, user_id: uuid::Uuid) -> QueryResult<usize> {
+        diesel::delete(
+            oauth_authorization_codes::table.filter(oauth_authorization_codes::user_id.eq(user_id)),
+        )
+        .execute(conn)?;
+        diesel::delete(access_tokens::table.filter(access_tokens::user_id.eq(user_id)))
+            .execute(conn)
+    }
+
+    /// Issues an access token and a refresh token to OAuth client
+    /// `client_id`, acting as `user_id` within `scopes`. Returns the access
+    /// token along with both plaintext tokens.
+    pub fn issue(
+        conn: &PgConnection,
+        client_id: uuid::Uuid,
+        user_id: uuid::Uuid,
+        scopes: &[Scope],
+    ) -> QueryResult<(Self, String, String)> {
+        let now = chrono::Utc::now();
+        let token = auth::generate_prefixed_token(auth::OAUTH_ACCESS_TOKEN_PREFIX);
+        let refresh_token = auth::generate_prefixed_token(auth::OAUTH_REFRESH_TOKEN_PREFIX);
+        let access_token = diesel::insert_into(access_tokens::table)
+            .values((
+                access_tokens::client_id.eq(Some(client_id)),
+                access_tokens::expires_at.eq(Some(
+                    now + chrono::Duration::minutes(OAUTH_ACCESS_TOKEN_LIFETIME_MINUTES),
+                )),
+                access_tokens::refresh_expires_at.eq(Some(
+                    now + chrono::Duration::days(OAUTH_REFRESH_TOKEN_LIFETIME_DAYS),
+                )),
+                access_tokens::refresh_token_hash.eq(Some(auth::hash_token(&refresh_token))),
+                access_tokens::scopes.eq(normalize_scopes(scopes)),
+                access_tokens::token_hash.eq(auth::hash_token(&token)),
+                access_tokens::user_id.eq(user_id),
+            ))
+            .returning(access_tokens::all_columns)
+            .get_result(conn)?;
+
+        Ok((access_token, token, refresh_token))
+    }
+
+    /// Exchanges a refresh token issued to `client_id` for new tokens with the
+    /// same scopes, or only some of them if `scopes` is given. The old access
+    /// and refresh tokens stop working, so each refresh token works only once.
+    pub fn refresh(
+        conn: &PgConnection,
+        client_id: uuid::Uuid,
+        refresh_token: &str,
+        scopes: Option<&[Scope]>,
+    ) -> Result<(Self, String, String), OAuthGrantError> {
+        conn.transaction(|| {
+            let old: Self = access_tokens::table
+                .inner_join(users::table)
+                .filter(access_tokens::client_id.eq(Some(client_id)))
+                .filter(access_tokens::refresh_token_hash.eq(Some(auth::hash_token(refresh_token))))
+                .filter(access_tokens::refresh_expires_at.gt(Some(chrono::Utc::now())))
+                .filter(users::deleted_at.is_null())
+                .select(access_tokens::all_columns)
+                .for_update()
+                .get_result(conn)
+                .optional()?
+                .ok_or(OAuthGrantError::Invalid)?;
+
+            let scopes = match scopes {
+                Some(scopes) if scopes.iter().all(|scope| old.scopes.contains(scope)) => {
+                    scopes.to_vec()
+                }
+                Some(_) => return Err(OAuthGrantError::InvalidScope),
+                None => old.scopes.clone(),
+            };
+
+            diesel::delete(&old).execute(conn)?;
+            Ok(Self::issue(conn, client_id, old.user_id, &scopes)?)
+        })
+    }
+
+    /// Deletes tokens that expired before `now` and can't be refreshed,
+    /// returning how many.
+    pub fn delete_expired(conn: &PgConnection, now: DateTime) -> QueryResult<usize> {
+        diesel::delete(
+            access_tokens::table
+                .filter(access_tokens::expires_at.le(Some(now)))
+                .filter(
+                    access_tokens::refresh_expires_at
+                        .is_null()
+                        .or(access_tokens::refresh_expires_at.le(Some(now))),
+                ),
+        )
+        .execute(conn)
+    }
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct AccessTokenCreateInput {
+    /// When the token stops working. Without one, it works until it's
+    /// revoked.
+    pub expires_at: Option<DateTime>,
+    /// What the token is for, to tell it apart from the user's others.
+    pub name: String,
+    pub scopes: Vec<Scope>,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct AccessTokenCreateOutput {
+    pub access_token: AccessToken,
+    /// The bearer token. It is only ever returned here.
+    pub token: String,
+}
+
+#[derive(Debug)]
+pub enum AccessTokenError {
+    BlankName,
+    Expired,
+    NoScopes,
+    Query(diesel::result::Error),
+}
+
+impl Display for AccessTokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BlankName => "name must not be blank".fmt(f),
+            Self::Expired => "expiresAt must be in the future".fmt(f),
+            Self::NoScopes => "scopes must not be empty".fmt(f),
+            Self::Query(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for AccessTokenError {}
+
+impl From<diesel::result::Error> for AccessTokenError {
+    fn from(err: diesel::result::Error) -> Self {
+        Self::Query(err)
+    }
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct AccessTokenRevokeInput {
+    pub id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct AccessTokenRevokeOutput {
+    pub access_token: AccessToken,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    diesel::Associations,
+    diesel::Identifiable,
+    diesel::Queryable,
+    graphql::SimpleObject,
+)]
+#[belongs_to(User)]
+#[graphql(complex)]
+pub struct Blog {
+    #[graphql(skip)]
+    pub _rowid: i32,
+    pub created_at: DateTime,
+    #[graphql(skip)]
+    pub deleted_at: Option<DateTime>,
+    #[graphql(skip)]
+    pub id: uuid::Uuid,
+    pub slug: String,
+    pub title: String,
+    pub updated_at: DateTime,
+    #[graphql(skip)]
+    pub user_id: uuid::Uuid,
+    /// How many minutes apart posts in the blog's queue are published.
+    pub queue_interval_minutes: i32,
+    #[graphql(skip)]
+    pub queue_published_at: Option<DateTime>,
+}
+
+/// Spaces out the queued posts of blog `$1` `queue_interval_minutes` apart,
+/// keeping their order, from the first slot after both `$2` (now) and the last
+/// post the queue published. Newly queued posts have no `publish_at` yet, so
+/// they go to the back.
+const RESLOT_QUEUE_QUERY: &str = r#"
+UPDATE "posts" SET "publish_at" = "slots"."publish_at" FROM (
+    SELECT
+        "posts"."id",
+        GREATEST(
+            $2,
+            "blogs"."queue_published_at" + "blogs"."queue_interval_minutes" * INTERVAL '1 minute'
+        ) + (
+            ROW_NUMBER() OVER (ORDER BY "posts"."publish_at" NULLS LAST, "posts"."_rowid") - 1
+        ) * "blogs"."queue_interval_minutes" * INTERVAL '1 minute' AS "publish_at"
+    FROM "posts"
+    INNER JOIN "blogs" ON "blogs"."id" = "posts"."blog_id"
+    WHERE "posts"."blog_id" = $1
+    AND "posts"."state" = 'QUEUED'
+    AND "posts"."deleted_at" IS NULL
+) AS "slots"
+WHERE "posts"."id" = "slots"."id"
+"#;
+
+#[graphql::ComplexObject]
+impl Blog {
+    /// A globally unique ID for fetching this blog with `node`.
+    pub async fn id(&self, _ctx: &Context<'_>) -> graphql::Result<graphql::ID> {
+        Ok(GlobalId::new(NodeType::Blog, self.id).into())
+    }
+
+    /// The blog's posts in any of `states`, or in any state if it isn't given.
+    /// Only the blog's owner can see posts that aren't published, and access
+    /// tokens need the `posts:read` scope to.
+    pub async fn posts(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<i64>,
+        after: Option<Cursor>,
+        last: Option<i64>,
+        before: Option<Cursor>,
+        states: Option<Vec<PostState>>,
+    ) -> graphql::Result<Connection<Post>> {
+        let page = PageArgs::new(first, after, last, before)?;
+
+        let is_owner = crate::policy::is_user(
+            crate::policy::scoped_viewer(ctx, Scope::POSTS_READ),
+            self.user_id,
+        );
+        let states: Vec<_> = states
+            .unwrap_or_else(PostState::all)
+            .into_iter()
+            .filter(|state| is_owner || *state == PostState::PUBLISHED)
+            .collect();
+
+        let blog_id = self.id;
+        let posts = move || {
+            posts::table
+                .filter(posts::blog_id.eq(blog_id))
+                .filter(posts::deleted_at.is_null())
+                .filter(posts::state.eq_any(states.clone()))
+        };
+        let count_posts = posts.clone();
+
+        paginate(
+            ctx,
+            &page,
+            Some(self.id),
+            Order::RowidAsc,
+            move |conn, window| {
+                rowid_window(posts().into_boxed(), posts::_rowid, window).get_results(conn)
+            },
+            move |conn| count_posts().count().get_result(conn),
+        )
+        .await
+    }
+
+    /// Posts waiting in the blog's queue, in the order they'll be published.
+    /// Only the blog's owner can see its queue.
+    pub async fn queue(&self, ctx: &Context<'_>) -> graphql::Result<Vec<Post>> {
+        crate::policy::require_scope(ctx, Scope::POSTS_READ)?;
+        crate::policy::require_user(ctx, self.user_id)?;
+
+        let blog_id = self.id;
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(posts::table
+                .filter(posts::blog_id.eq(blog_id))
+                .filter(posts::state.eq(PostState::QUEUED))
+                .filter(posts::deleted_at.is_null())
+                .order_by((posts::publish_at.asc(), posts::_rowid.asc()))
+                .get_results(conn)?)
+        })
+        .await
+    }
+
+    pub async fn follower_count(&self, ctx: &Context<'_>) -> graphql::Result<i64> {
+        let loader = ctx.data_unchecked::<DataLoader<FollowerCountLoader>>();
+
+        Ok(loader.load_one(self.id).await?.unwrap_or_default())
+    }
+
+    pub async fn followers(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<i64>,
+        after: Option<Cursor>,
+        last: Option<i64>,
+        before: Option<Cursor>,
+    ) -> graphql::Result<Connection<User>> {
+        let page = PageArgs::new(first, after, last, before)?;
+
+        let blog_id = self.id;
+        let followers = move || {
+            follows::table
+                .filter(follows::blog_id.eq(blog_id))
+                .inner_join(users::table)
+                .filter(users::deleted_at.is_null())
+        };
+
+        paginate(
+            ctx,
+            &page,
+            Some(self.id),
+            Order::RowidAsc,
+            move |conn, window| {
+                rowid_window(
+                    followers().select(users::all_columns).into_boxed(),
+                    users::_rowid,
+                    window,
+                )
+                .get_results(conn)
+            },
+            move |conn| followers().count().get_result(conn),
+        )
+        .await
+    }
+
+    pub async fn user(&self, ctx: &Context<'_>) -> graphql::Result<User> {
+        let loader = ctx.data_unchecked::<DataLoader<UserLoader>>();
+
+        Ok(loader
+            .load_one(self.user_id)
+            .await?
+            .ok_or("user not found")?)
+    }
+
+    /// The blog's UUID, as taken by mutation inputs.
+    pub async fn uuid(&self) -> uuid::Uuid {
+        self.id
+    }
+}
+
+impl Blog {
+    /// Soft-deletes a blog along with its posts and replies. Everything is
+    /// stamped with the blog's `deleted_at`, so `restore` can tell cascaded
+    /// deletes from posts that were deleted on their own.
+    pub fn delete(conn: &PgConnection, id: uuid::Uuid) -> QueryResult<Self> {
+        conn.transaction(|| {
+            let blog: Self =
+                diesel::update(blogs::table.find(id).filter(blogs::deleted_at.is_null()))
+                    .set(blogs::deleted_at.eq(Some(chrono::Utc::now())))
+                    .returning(blogs::all_columns)
+                    .get_result(conn)?;
+            Self::cascade(conn, &[blog.id], None, blog.deleted_at)?;
+            Ok(blog)
+        })
+    }
+
+    /// Applies `changeset` unless the blog has changed since
+    /// `expected_updated_at`, returning `None` if it has.
+    pub fn update(
+        conn: &PgConnection,
+        id: uuid::Uuid,
+        expected_updated_at: DateTime,
+        changeset: &BlogChangeset,
+    ) -> QueryResult<Option<Self>> {
+        conn.transaction(|| {
+            let blog: Option<Self> = diesel::update(
+                blogs::table
+                    .find(id)
+                    .filter(blogs::deleted_at.is_null())
+                    .filter(blogs::updated_at.eq(expected_updated_at)),
+            )
+            .set(changeset)
+            .returning(blogs::all_columns)
+            .get_result(conn)
+            .optional()?;
+
+            if blog.is_some() && changeset.queue_interval_minutes.is_some() {
+                Self::reslot_queue(conn, id)?;
+            }
+
+            Ok(blog)
+        })
+    }
+
+    /// Locks the blog's row until the end of the transaction. Changes to a
+    /// blog's queue take this lock before touching its posts, so they take
+    /// turns rather than deadlocking with each other or the publisher.
+    pub fn lock(conn: &PgConnection, id: uuid::Uuid) -> QueryResult<()> {
+        blogs::table
+            .find(id)
+            .select(blogs::id)
+            .for_update()
+            .get_result::<uuid::Uuid>(conn)
+            .map(|_| ())
+    }
+
+    /// How many posts are waiting in the blog's queue.
+    pub fn queue_len(conn: &PgConnection, id: uuid::Uuid) -> QueryResult<i64> {
+        posts::table
+            .filter(posts::blog_id.eq(id))
+            .filter(posts::state.eq(PostState::QUEUED))
+            .filter(posts::deleted_at.is_null())
+            .count()
+            .get_result(conn)
+    }
+
+    /// Gives the blog's queued posts fresh `publish_at` slots after its queue
+    /// changes. Must be called inside a transaction, which keeps the blog
+    /// locked until it ends.
+    pub fn reslot_queue(conn: &PgConnection, id: uuid::Uuid) -> QueryResult<()> {
+        use diesel::sql_types::{Timestamptz, Uuid};
+
+        Self::lock(conn, id)?;
+        diesel::sql_query(RESLOT_QUEUE_QUERY)
+            .bind::<Uuid, _>(id)
+            .bind::<Timestamptz, _>(chrono::Utc::now())
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    pub fn restore(conn: &PgConnection, blog: &Self) -> QueryResult<Self> {
+        conn.transaction(|| {
+            Self::cascade(conn, &[blog.id], blog.deleted_at, None)?;
+            diesel::update(blogs::table.find(blog.id))
+                .set(blogs::deleted_at.eq(None::<DateTime>))
+                .returning(blogs::all_columns)
+                .get_result(conn)
+        })
+    }
+
+    /// Moves the posts and replies of `blog_ids` from `from` to `to`
+    /// `deleted_at`.
+    fn cascade(
+        conn: &PgConnection,
+        blog_ids: &[uuid::Uuid],
+        from: Option<DateTime>,
+        to: Option<DateTime>,
+    ) -> QueryResult<()> {
+        diesel::update(
+            posts::table
+                .filter(posts::blog_id.eq_any(blog_ids))
+                .filter(posts::deleted_at.is_not_distinct_from(from)),
+        )
+        .set(posts::deleted_at.eq(to))
+        .execute(conn)?;
+        diesel::update(
+            replies::table
+                .filter(replies::blog_id.eq_any(blog_ids))
+                .filter(replies::deleted_at.is_not_distinct_from(from)),
+        )
+        .set(replies::deleted_at.eq(to))
+        .execute(conn)?;
+
+        Ok(())
+    }
+}
+
+impl Node for Blog {
+    fn position(&self) -> Position {
+        Position {
+            _rowid: self._rowid,
+            created_at: None,
+            id: None,
+            publish_at: None,
+        }
+    }
+}
+
+#[derive(Debug, diesel::Insertable, graphql::InputObject)]
+#[table_name = "blogs"]
+pub struct BlogCreateInput {
+    pub slug: String,
+    pub title: String,
+    pub user_id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct BlogCreateOutput {
+    pub blog: Blog,
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct BlogDeleteInput {
+    pub id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct BlogDeleteOutput {
+    pub blog: Blog,
+}
+
+#[derive(Debug, diesel::Insertable, graphql::InputObject)]
+#[table_name = "follows"]
+pub struct BlogFollowInput {
+    pub blog_id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct BlogFollowOutput {
+    pub follow: Follow,
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct BlogRestoreInput {
+    pub id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct BlogRestoreOutput {
+    pub blog: Blog,
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct BlogUnfollowInput {
+    pub blog_id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct BlogUnfollowOutput {
+    pub blog: Blog,
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct BlogUpdateInput {
+    pub expected_updated_at: DateTime,
+    pub id: uuid::Uuid,
+    pub queue_interval_minutes: MaybeUndefined<i32>,
+    pub slug: MaybeUndefined<String>,
+    pub title: MaybeUndefined<String>,
+}
+
+#[derive(Debug, Default, PartialEq, diesel::AsChangeset)]
+#[table_name = "blogs"]
+pub struct BlogChangeset {
+    pub queue_interval_minutes: Option<i32>,
+    pub slug: Option<String>,
+    pub title: Option<String>,
+}
+
+impl TryFrom<BlogUpdateInput> for BlogChangeset {
+    type Error = graphql::Error;
+
+    fn try_from(input: BlogUpdateInput) -> Result<Self, Self::Error> {
+        let changeset = Self {
+            queue_interval_minutes: required_field(
+                "queueIntervalMinutes",
+                input.queue_interval_minutes,
+            )?,
+            slug: required_field("slug", input.slug)?,
+            title: required_field("title", input.title)?,
+        };
+
+        if changeset
+            .queue_interval_minutes
+            .is_some_and(|minutes| !(1..=MAX_QUEUE_INTERVAL_MINUTES).contains(&minutes))
+        {
+            Err(QueueError::InvalidInterval.into())
+        } else if changeset == Self::default() {
+            Err(PatchError::Empty.into())
+        } else {
+            Ok(changeset)
+        }
+    }
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct BlogUpdateOutput {
+    pub blog: Blog,
+}
+
+pub type DateTime = chrono::DateTime<chrono::Utc>;
+
+#[derive(
+    Debug,
+    Clone,
+    diesel::Associations,
+    diesel::Identifiable,
+    diesel::Queryable,
+    graphql::SimpleObject,
+)]
+#[belongs_to(User)]
+#[graphql(complex)]
+pub struct EmailAccount {
+    #[graphql(skip)]
+    pub _rowid: i32,
+    pub created_at: DateTime,
+    #[graphql(skip)]
+    pub deleted_at: Option<DateTime>,
+    #[graphql(skip)]
+    pub id: uuid::Uuid,
+    pub provider_account_id: String,
+    pub updated_at: DateTime,
+    #[graphql(skip)]
+    pub user_id: uuid::Uuid,
+    #[graphql(skip)]
+    pub password_hash: Option<String>,
+    /// When the user proved they receive email at the address, by following
+    /// a link sent to it. Changing the address unverifies it.
+    pub verified_at: Option<DateTime>,
+}
+
+#[graphql::ComplexObject]
+impl EmailAccount {
+    /// A globally unique ID for fetching this email account with `node`.
+    pub async fn id(&self, _ctx: &Context<'_>) -> graphql::Result<graphql::ID> {
+        Ok(GlobalId::new(NodeType::EmailAccount, self.id).into())
+    }
+
+    /// The email account's UUID, as taken by mutation inputs.
+    pub async fn uuid(&self) -> uuid::Uuid {
+        self.id
+    }
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct EmailAccountCreateInput {
+    pub password: String,
+    pub provider_account_id: String,
+    /// From `userCreate`, when attaching a new user's first account before
+    /// they can sign in.
+    pub signup_token: Option<String>,
+    pub user_id: uuid::Uuid,
+}
+
+#[derive(Debug, diesel::Insertable)]
+#[table_name = "email_accounts"]
+pub struct NewEmailAccount {
+    pub password_hash: String,
+    pub provider_account_id: String,
+    pub user_id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct EmailAccountCreateOutput {
+    pub email_account: EmailAccount,
+}
+
+impl EmailAccount {
+    /// Sets the password of the account a password reset `token` was sent
+    /// to, returning the account, or `None` if the token isn't valid. The
+    /// address is verified too, since the token was received there. Every
+    /// session, personal access token and OAuth grant the user had is revoked,
+    /// since whoever needed the reset may have been locked out by them.
+    pub fn reset_password(
+        conn: &PgConnection,
+        token: &str,
+        password_hash: &str,
+    ) -> QueryResult<Option<Self>> {
+        conn.transaction(|| {
+            let account = match EmailToken::redeem(conn, token, EmailTokenPurpose::PASSWORD_RESET)?
+            {
+                Some(account) => account,
+                None => return Ok(None),
+            };
+
+            diesel::delete(sessions::table.filter(sessions::user_id.eq(account.user_id)))
+                .execute(conn)?;
+            AccessToken::delete_for_user(conn, account.user_id)?;
+
+            diesel::update(email_accounts::table.find(account.id))
+                .set((
+                    email_accounts::password_hash.eq(password_hash),
+                    email_accounts::verified_at
+                        .eq(account.verified_at.or_else(|| Some(chrono::Utc::now()))),
+                ))
+                .returning(email_accounts::all_columns)
+                .get_result(conn)
+                .map(Some)
+        })
+    }
+
+    /// Applies `changeset` if `current_password` is the account's password,
+    /// returning the account, or `None` if it changed since
+    /// `expected_updated_at`. Every other session the user has, and every
+    /// personal access token and OAuth grant, is revoked, since whoever knew
+    /// the old password or read the old address may have been using them.
+    /// Only `session_id`, the one making the change, is kept.
+    pub fn update(
+        conn: &PgConnection,
+        id: uuid::Uuid,
+        expected_updated_at: DateTime,
+        current_password: &str,
+        changeset: &EmailAccountChangeset,
+        session_id: uuid::Uuid,
+    ) -> Result<Option<Self>, EmailAccountUpdateError> {
+        conn.transaction(|| {
+            let unchanged = email_accounts::table
+                .find(id)
+                .filter(email_accounts::deleted_at.is_null())
+                .filter(email_accounts::updated_at.eq(expected_updated_at));
+
+            let account: Self = match unchanged.for_update().get_result(conn).optional()? {
+                Some(account) => account,
+                None => return Ok(None),
+            };
+            // Accounts without a password get one with a password reset.
+            if !account
+                .password_hash
+                .as_deref()
+                .is_some_and(|hash| auth::verify_password(current_password, hash))
+            {
+                return Err(EmailAccountUpdateError::WrongPassword);
+            }
+
+            let account: Self = diesel::update(unchanged)
+                .set(changeset)
+                .returning(email_accounts::all_columns)
+                .get_result(conn)?;
+            diesel::delete(
+                sessions::table
+                    .filter(sessions::user_id.eq(account.user_id))
+                    .filter(sessions::id.ne(session_id)),
+            )
+            .execute(conn)?;
+            AccessToken::delete_for_user(conn, account.user_id)?;
+
+            Ok(Some(account))
+        })
+    }
+
+    /// Verifies the address a verification `token` was sent to, returning its
+    /// account, or `None` if the token isn't valid.
+    pub fn verify(conn: &PgConnection, token: &str) -> QueryResult<Option<Self>> {
+        conn.transaction(|| {
+            let account = match EmailToken::redeem(conn, token, EmailTokenPurpose::VERIFICATION)? {
+                Some(account) => account,
+                None => return Ok(None),
+            };
+
+            diesel::update(email_accounts::table.find(account.id))
+                .set(email_accounts::verified_at.eq(chrono::Utc::now()))
+                .returning(email_accounts::all_columns)
+                .get_result(conn)
+                .map(Some)
+        })
+    }
+}
+
+#[derive(Debug)]
+pub enum EmailAccountUpdateError {
+    WrongPassword,
+    Query(diesel::result::Error),
+}
+
+impl Display for EmailAccountUpdateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::WrongPassword => "currentPassword is incorrect".fmt(f),
+            Self::Query(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for EmailAccountUpdateError {}
+
+impl From<diesel::result::Error> for EmailAccountUpdateError {
+    fn from(err: diesel::result::Error) -> Self {
+        Self::Query(err)
+    }
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct EmailAccountUpdateInput {
+    /// The account's password as it is now, required to change anything.
+    pub current_password: String,
+    pub expected_updated_at: DateTime,
+    pub id: uuid::Uuid,
+    pub password: MaybeUndefined<String>,
+    pub provider_account_id: MaybeUndefined<String>,
+}
+
+#[derive(Debug, Default, PartialEq, diesel::AsChangeset)]
+#[table_name = "email_accounts"]
+pub struct EmailAccountChangeset {
+    pub password_hash: Option<String>,
+    pub provider_account_id: Option<String>,
+    pub verified_at: Option<Option<DateTime>>,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct EmailAccountUpdateOutput {
+    pub email_account: EmailAccount,
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct EmailVerificationRequestInput {
+    pub id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct EmailVerificationRequestOutput {
+    pub email_account: EmailAccount,
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct EmailVerifyInput {
+    pub token: String,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct EmailVerifyOutput {
+    pub email_account: EmailAccount,
+}
+
+/// A single-use token emailed to an address, to prove the user receives email
+/// there. Only its hash is stored.
+#[derive(Debug, Clone, diesel::Associations, diesel::Identifiable, diesel::Queryable)]
+#[belongs_to(EmailAccount)]
+pub struct EmailToken {
+    pub _rowid: i32,
+    pub created_at: DateTime,
+    /// The address the token was sent to. It's only good while the account
+    /// still has that address.
+    pub email: String,
+    pub email_account_id: uuid::Uuid,
+    pub expires_at: DateTime,
+    pub id: uuid::Uuid,
+    pub purpose: EmailTokenPurpose,
+    pub token_hash: String,
+    pub updated_at: DateTime,
+    pub used_at: Option<DateTime>,
+}
+
+impl EmailToken {
+    /// Issues a token for `purpose` to `account`'s address, and queues an
+    /// email to send it. Earlier tokens for the same purpose stop working, so
+    /// only the most recent email's link does.
+    pub fn send(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        account: &EmailAccount,
+        purpose: EmailTokenPurpose,
+    ) -> QueryResult<Self> {
+        let token = auth::generate_token();
+
+        conn.transaction(|| {
+            let now = chrono::Utc::now();
+
+            diesel::update(
+                email_tokens::table
+                    .filter(email_tokens::email_account_id.eq(account.id))
+                    .filter(email_tokens::purpose.eq(purpose))
+                    .filter(email_tokens::used_at.is_null()),
+            )
+            .set(email_tokens::used_at.eq(now))
+            .execute(conn)?;
+
+            let email_token: Self = diesel::insert_into(email_tokens::table)
+                .values((
+                    email_tokens::email.eq(&account.provider_account_id),
+                    email_tokens::email_account_id.eq(account.id),
+                    email_tokens::expires_at.eq(now + purpose.lifetime()),
+                    email_tokens::purpose.eq(purpose),
+                    email_tokens::token_hash.eq(auth::hash_token(&token)),
+                ))
+                .returning(email_tokens::all_columns)
+                .get_result(conn)?;
+
+            // The token is sealed so that it can't be read from the queue.
+            Job::enqueue(
+                conn,
+                &SendEmailToken {
+                    email_token_id: email_token.id,
+                    token: keyring.seal(&token).as_str().to_owned(),
+                },
+            )?;
+
+            Ok(email_token)
+        })
+    }
+
+    /// Whether a token for `purpose` was sent to `email_account_id` within the
+    /// last `EMAIL_TOKEN_RESEND_SECONDS`, in which case another isn't sent.
+    pub fn sent_recently(
+        conn: &PgConnection,
+        email_account_id: uuid::Uuid,
+        purpose: EmailTokenPurpose,
+    ) -> QueryResult<bool> {
+        diesel::select(diesel::dsl::exists(
+            email_tokens::table
+                .filter(email_tokens::email_account_id.eq(email_account_id))
+                .filter(email_tokens::purpose.eq(purpose))
+                .filter(
+                    email_tokens::created_at
+                        .gt(chrono::Utc::now()
+                            - chrono::Duration::seconds(EMAIL_TOKEN_RESEND_SECONDS)),
+                ),
+        ))
+        .get_result(conn)
+    }
+
+    /// Uses up the token for `purpose`, returning the account it was sent to.
+    /// Tokens that have expired or been used, and ones for deleted accounts or
+    /// accounts whose address has changed since, give `None`.
+    fn redeem(
+        conn: &PgConnection,
+        token: &str,
+        purpose: EmailTokenPurpose,
+    ) -> QueryResult<Option<EmailAccount>> {
+        let found: Option<(Self, EmailAccount)> = email_tokens::table
+            .inner_join(email_accounts::table)
+            .filter(email_tokens::token_hash.eq(auth::hash_token(token)))
+            .filter(email_tokens::purpose.eq(purpose))
+            .filter(email_tokens::used_at.is_null())
+            .filter(email_tokens::expires_at.gt(chrono::Utc::now()))
+            .filter(email_accounts::deleted_at.is_null())
+            .for_update()
+            .get_result(conn)
+            .optional()?;
+
+        match found {
+            Some((email_token, account)) if email_token.email == account.provider_account_id => {
+                diesel::update(email_tokens::table.find(email_token.id))
+                    .set(email_tokens::used_at.eq(chrono::Utc::now()))
+                    .execute(conn)?;
+                Ok(Some(account))
+            }
+            _ => Ok(None),
+        }
+    }
+}
+
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    diesel::AsExpression,
+    diesel::FromSqlRow,
+    serde::Deserialize,
+    serde::Serialize,
+)]
+#[allow(non_camel_case_types)]
+#[sql_type = "Text"]
+pub enum EmailTokenPurpose {
+    PASSWORD_RESET,
+    VERIFICATION,
+}
+
+impl EmailTokenPurpose {
+    /// How long tokens for the purpose work for.
+    pub fn lifetime(self) -> chrono::Duration {
+        match self {
+            Self::PASSWORD_RESET => chrono::Duration::hours(PASSWORD_RESET_LIFETIME_HOURS),
+            Self::VERIFICATION => chrono::Duration::hours(EMAIL_VERIFICATION_LIFETIME_HOURS),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseEmailTokenPurposeError;
+
+impl Display for ParseEmailTokenPurposeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "unrecognized EmailTokenPurpose variant".fmt(f)
+    }
+}
+
+impl std::error::Error for ParseEmailTokenPurposeError {}
+
+impl FromStr for EmailTokenPurpose {
+    type Err = ParseEmailTokenPurposeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "PASSWORD_RESET" => Ok(Self::PASSWORD_RESET),
+            "VERIFICATION" => Ok(Self::VERIFICATION),
+            _ => Err(ParseEmailTokenPurposeError),
+        }
+    }
+}
+
+impl Display for EmailTokenPurpose {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (match self {
+            Self::PASSWORD_RESET => "PASSWORD_RESET",
+            Self::VERIFICATION => "VERIFICATION",
+        })
+        .fmt(f)
+    }
+}
+
+impl<DB> FromSql<Text, DB> for EmailTokenPurpose
+where
+    DB: Backend,
+    String: FromSql<Text, DB>,
+{
+    fn from_sql(bytes: Option<&DB::RawValue>) -> diesel::deserialize::Result<Self> {
+        Ok(String::from_sql(bytes)?.parse()?)
+    }
+}
+
+impl<DB> ToSql<Text, DB> for EmailTokenPurpose
+where
+    DB: Backend,
+    String: ToSql<Text, DB>,
+{
+    fn to_sql<W: std::io::Write>(
+        &self,
+        out: &mut diesel::serialize::Output<W, DB>,
+    ) -> diesel::serialize::Result {
+        self.to_string().to_sql(out)
+    }
+}
+
+#[derive(
+    Debug, diesel::Associations, diesel::Identifiable, diesel::Queryable, graphql::SimpleObject,
+)]
+#[belongs_to(Blog)]
+#[belongs_to(User)]
+#[graphql(complex)]
+pub struct Follow {
+    #[graphql(skip)]
+    pub _rowid: i32,
+    #[graphql(skip)]
+    pub blog_id: uuid::Uuid,
+    pub created_at: DateTime,
+    pub id: uuid::Uuid,
+    pub updated_at: DateTime,
+    #[graphql(skip)]
+    pub user_id: uuid::Uuid,
+}
+
+#[graphql::ComplexObject]
+impl Follow {
+    pub async fn blog(&self, ctx: &Context<'_>) -> graphql::Result<Blog> {
+        let loader = ctx.data_unchecked::<DataLoader<BlogLoader>>();
+
+        Ok(loader
+            .load_one(self.blog_id)
+            .await?
+            .ok_or("blog not found")?)
+    }
+
+    pub async fn user(&self, ctx: &Context<'_>) -> graphql::Result<User> {
+        let loader = ctx.data_unchecked::<DataLoader<UserLoader>>();
+
+        Ok(loader
+            .load_one(self.user_id)
+            .await?
+            .ok_or("user not found")?)
+    }
+}
+
+/// The second step of signing in, for users with two-factor authentication
+/// on.
+#[derive(Debug, Clone, diesel::Associations, diesel::Identifiable, diesel::Queryable)]
+#[belongs_to(User)]
+pub struct LoginChallenge {
+    pub _rowid: i32,
+    pub attempts: i32,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+    pub id: uuid::Uuid,
+    pub token_hash: String,
+    pub updated_at: DateTime,
+    pub used_at: Option<DateTime>,
+    pub user_id: uuid::Uuid,
+}
+
+impl LoginChallenge {
+    /// Issues a challenge for `user_id`, returning it along with the plaintext
+    /// token to hand to the client.
+    pub fn create(conn: &PgConnection, user_id: uuid::Uuid) -> QueryResult<(Self, String)> {
+        let token = auth::generate_token();
+        let challenge = diesel::insert_into(login_challenges::table)
+            .values((
+                login_challenges::expires_at.eq(chrono::Utc::now()
+                    + chrono::Duration::minutes(LOGIN_CHALLENGE_LIFETIME_MINUTES)),
+                login_challenges::token_hash.eq(auth::hash_token(&token)),
+                login_challenges::user_id.eq(user_id),
+            ))
+            .returning(login_challenges::all_columns)
+            .get_result(conn)?;
+
+        Ok((challenge, token))
+    }
+
+    /// Answers the challenge for `token` with a TOTP or recovery code,
+    /// returning its user. A challenge can only be answered once, and stops
+    /// accepting codes after `LOGIN_CHALLENGE_MAX_ATTEMPTS` wrong ones; the
+    /// user is locked out after `SECOND_FACTOR_MAX_FAILURES` across all of
+    /// theirs.
+    pub fn answer(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        token: &str,
+        code: &str,
+    ) -> Result<User, TotpError> {
+        // Wrong codes are counted rather than rolled back, so the transaction
+        // commits either way and the outcome is reported after.
+        let user = conn.transaction(|| -> Result<_, TotpError> {
+            let (challenge, user): (Self, User) = login_challenges::table
+                .inner_join(users::table)
+                .filter(login_challenges::token_hash.eq(auth::hash_token(token)))
+                .filter(login_challenges::used_at.is_null())
+                .filter(login_challenges::expires_at.gt(diesel::dsl::now))
+                .filter(login_challenges::attempts.lt(LOGIN_CHALLENGE_MAX_ATTEMPTS))
+                .filter(users::deleted_at.is_null())
+                .for_update()
+                .get_result(conn)
+                .optional()?
+                .ok_or(TotpError::ChallengeExpired)?;
+
+            if User::check_second_factor(conn, keyring, &user, code)? {
+                diesel::update(&challenge)
+                    .set(login_challenges::used_at.eq(Some(chrono::Utc::now())))
+                    .execute(conn)?;
+                Ok(Some(user))
+            } else {
+                diesel::update(&challenge)
+                    .set(login_challenges::attempts.eq(login_challenges::attempts + 1))
+                    .execute(conn)?;
+                Ok(None)
+            }
+        })?;
+
+        user.ok_or(TotpError::InvalidCode)
+    }
+
+    /// Deletes challenges that expired before `now`, returning how many.
+    pub fn delete_expired(conn: &PgConnection, now: DateTime) -> QueryResult<usize> {
+        diesel::delete(login_challenges::table.filter(login_challenges::expires_at.le(now)))
+            .execute(conn)
+    }
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct LoginInput {
+    pub email: String,
+    pub password: String,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct LoginOutput {
+    /// Null when the user has two-factor authentication on, until
+    /// `totpChallenge` is answered with `loginTotp`.
+    pub session: Option<Session>,
+    /// The bearer token for the new session. It is only ever returned here.
+    pub token: Option<String>,
+    /// Returned instead of a session when the user has two-factor
+    /// authentication on. Pass it to `loginTotp` along with a code from their
+    /// authenticator app, or one of their recovery codes, to finish signing
+    /// in.
+    pub totp_challenge: Option<String>,
+    pub user: User,
+}
+
+impl LoginOutput {
+    pub fn new(sign_in: auth::SignIn, user: User) -> Self {
+        match sign_in {
+            auth::SignIn::Session(session, token) => Self {
+                session: Some(session),
+                token: Some(token),
+                totp_challenge: None,
+                user,
+            },
+            auth::SignIn::TotpRequired(_, totp_challenge) => Self {
+                session: None,
+                token: None,
+                totp_challenge: Some(totp_challenge),
+                user,
+            },
+        }
+    }
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct LoginTotpInput {
+    /// The `totpChallenge` from `login` or `/auth/:provider/callback`.
+    pub challenge: String,
+    /// A code from the user's authenticator app, or one of their recovery
+    /// codes.
+    pub code: String,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct LogoutOutput {
+    pub session: Session,
+}
+
+#[derive(
+    Debug, diesel::Associations, diesel::Identifiable, diesel::Queryable, graphql::SimpleObject,
+)]
+#[belongs_to(Post)]
+#[belongs_to(User)]
+#[graphql(complex)]
+pub struct Like {
+    #[graphql(skip)]
+    pub _rowid: i32,
+    pub created_at: DateTime,
+    pub id: uuid::Uuid,
+    #[graphql(skip)]
+    pub post_id: uuid::Uuid,
+    pub updated_at: DateTime,
+    #[graphql(skip)]
+    pub user_id: uuid::Uuid,
+}
+
+#[graphql::ComplexObject]
+impl Like {
+    pub async fn post(&self, ctx: &Context<'_>) -> graphql::Result<Post> {
+        let loader = ctx.data_unchecked::<DataLoader<PostLoader>>();
+
+        Ok(loader
+            .load_one(self.post_id)
+            .await?
+            .ok_or("post not found")?)
+    }
+
+    pub async fn user(&self, ctx: &Context<'_>) -> graphql::Result<User> {
+        let loader = ctx.data_unchecked::<DataLoader<UserLoader>>();
+
+        Ok(loader
+            .load_one(self.user_id)
+            .await?
+            .ok_or("user not found")?)
+    }
+}
+
+impl Node for Like {
+    fn position(&self) -> Position {
+        Position {
+            _rowid: self._rowid,
+            created_at: Some(self.created_at),
+            id: Some(self.id),
+            publish_at: None,
+        }
+    }
+}
+
+/// Likes, replies and reblogs of a post, newest first, between optional
+/// `(created_at, id)` keysets. Reblogs are keyed by when they were published,
+/// so a queued or scheduled reblog lands at the head of the notes rather than
+/// behind pages already read. `{direction}` is `DESC`, or `ASC` to load a page
+/// backward.
+const NOTES_QUERY: &str = r#"
+SELECT "notes"."id", "notes"."type" FROM (
+    SELECT "likes"."created_at", "likes"."id", 'LIKE' AS "type" FROM "likes"
+    INNER JOIN "users" ON "users"."id" = "likes"."user_id"
+    WHERE "likes"."post_id" = $1 AND "users"."deleted_at" IS NULL
+    UNION ALL
+    SELECT "posts"."publish_at" AS "created_at", "posts"."id", 'REBLOG' AS "type"
+    FROM "posts"
+    WHERE "posts"."parent_post_id" = $1
+    AND "posts"."deleted_at" IS NULL
+    AND "posts"."state" = 'PUBLISHED'
+    AND "posts"."publish_at" IS NOT NULL
+    UNION ALL
+    SELECT "replies"."created_at", "replies"."id", 'REPLY' AS "type" FROM "replies"
+    WHERE "replies"."post_id" = $1 AND "replies"."deleted_at" IS NULL
+) AS "notes"
+WHERE "notes"."type" = ANY($2)
+AND ($3::timestamptz IS NULL OR ("notes"."created_at", "notes"."id") < ($3, $4))
+AND ($5::timestamptz IS NULL OR ("notes"."created_at", "notes"."id") > ($5, $6))
+ORDER BY "notes"."created_at" {direction}, "notes"."id" {direction}
+LIMIT $7
+"#;
+
+#[derive(Debug, graphql::Union)]
+pub enum Note {
+    Like(Like),
+    Reblog(Post),
+    Reply(Reply),
+}
+
+impl Note {
+    pub fn for_post(
+        conn: &PgConnection,
+        post_id: uuid::Uuid,
+        types: &[NoteType],
+        window: Window<'_>,
+    ) -> QueryResult<Vec<Self>> {
+        use diesel::sql_types::{Array, BigInt, Nullable, Text, Timestamptz, Uuid};
+
+        #[derive(diesel::QueryableByName)]
+        struct NoteKey {
+            #[sql_type = "Uuid"]
+            id: uuid::Uuid,
+            #[sql_type = "Text"]
+            #[column_name = "type"]
+            ty: String,
+        }
+
+        let direction = if window.backward { "ASC" } else { "DESC" };
+        let keys: Vec<NoteKey> = diesel::sql_query(NOTES_QUERY.replace("{direction}", direction))
+            .bind::<Uuid, _>(post_id)
+            .bind::<Array<Text>, _>(types.iter().map(ToString::to_string).collect::<Vec<_>>())
+            .bind::<Nullable<Timestamptz>, _>(window.after.and_then(|after| after.created_at))
+            .bind::<Nullable<Uuid>, _>(window.after.and_then(|after| after.id))
+            .bind::<Nullable<Timestamptz>, _>(window.before.and_then(|before| before.created_at))
+            .bind::<Nullable<Uuid>, _>(window.before.and_then(|before| before.id))
+            .bind::<BigInt, _>(window.limit)
+            .load(conn)?;
+        let ids = |ty: NoteType| {
+            keys.iter()
+                .filter(|key| key.ty == ty.to_string())
+                .map(|key| key.id)
+                .collect::<Vec<_>>()
+        };
+
+        let mut likes: HashMap<uuid::Uuid, Like> = likes::table
+            .filter(likes::id.eq_any(ids(NoteType::LIKE)))
+            .get_results::<Like>(conn)?
+            .into_iter()
+            .map(|like| (like.id, like))
+            .collect();
+        let mut reblogs: HashMap<uuid::Uuid, Post> = posts::table
+            .filter(posts::id.eq_any(ids(NoteType::REBLOG)))
+            .get_results::<Post>(conn)?
+            .into_iter()
+            .map(|post| (post.id, post))
+            .collect();
+        let mut replies: HashMap<uuid::Uuid, Reply> = replies::table
+            .filter(replies::id.eq_any(ids(NoteType::REPLY)))
+            .get_results::<Reply>(conn)?
+            .into_iter()
+            .map(|reply| (reply.id, reply))
+            .collect();
+
+        Ok(keys
+            .into_iter()
+            .filter_map(|key| match key.ty.parse() {
+                Ok(NoteType::LIKE) => likes.remove(&key.id).map(Self::Like),
+                Ok(NoteType::REBLOG) => reblogs.remove(&key.id).map(Self::Reblog),
+                Ok(NoteType::REPLY) => replies.remove(&key.id).map(Self::Reply),
+                Err(_) => None,
+            })
+            .collect())
+    }
+}
+
+impl Note {
+    pub fn count_for_post(
+        conn: &PgConnection,
+        post_id: uuid::Uuid,
+        types: &[NoteType],
+    ) -> QueryResult<i64> {
+        let mut count = 0;
+
+        if types.contains(&NoteType::LIKE) {
+            count += likes::table
+                .inner_join(users::table)
+                .filter(likes::post_id.eq(post_id))
+                .filter(users::deleted_at.is_null())
+                .count()
+                .get_result::<i64>(conn)?;
+        }
+        if types.contains(&NoteType::REBLOG) {
+            count += posts::table
+                .filter(posts::parent_post_id.eq(post_id))
+                .filter(posts::deleted_at.is_null())
+                .filter(posts::state.eq(PostState::PUBLISHED))
+                .count()
+                .get_result::<i64>(conn)?;
+        }
+        if types.contains(&NoteType::REPLY) {
+            count += replies::table
+                .filter(replies::post_id.eq(post_id))
+                .filter(replies::deleted_at.is_null())
+                .count()
+                .get_result::<i64>(conn)?;
+        }
+
+        Ok(count)
+    }
+}
+
+impl Node for Note {
+    fn position(&self) -> Position {
+        match self {
+            Self::Like(like) => like.position(),
+            Self::Reblog(post) => Position {
+                created_at: post.publish_at,
+                ..post.position()
+            },
+            Self::Reply(reply) => reply.position(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, graphql::Enum)]
+pub enum NoteType {
+    LIKE,
+    REBLOG,
+    REPLY,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseNoteTypeError;
+
+impl Display for ParseNoteTypeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "unrecognized NoteType variant".fmt(f)
+    }
+}
+
+impl std::error::Error for ParseNoteTypeError {}
+
+impl FromStr for NoteType {
+    type Err = ParseNoteTypeError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "LIKE" => Ok(Self::LIKE),
+            "REBLOG" => Ok(Self::REBLOG),
+            "REPLY" => Ok(Self::REPLY),
+            _ => Err(ParseNoteTypeError),
+        }
+    }
+}
+
+impl Display for NoteType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (match self {
+            Self::LIKE => "LIKE",
+            Self::REBLOG => "REBLOG",
+            Self::REPLY => "REPLY",
+        })
+        .fmt(f)
+    }
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, diesel::AsExpression, diesel::FromSqlRow, graphql::Enum,
+)]
+#[graphql(name = "OAuthAccountProvider")]
+#[sql_type = "Text"]
+pub enum OAuthAccountProvider {
+    APPLE,
+    GITHUB,
+    GOOGLE,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseOAuthAccountProviderError;
+
+impl Display for ParseOAuthAccountProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "unrecognized OAuthAccountProvider variant".fmt(f)
+    }
+}
+
+impl std::error::Error for ParseOAuthAccountProviderError {}
+
+impl OAuthAccountProvider {
+    pub fn all() -> Vec<Self> {
+        vec![Self::APPLE, Self::GITHUB, Self::GOOGLE]
+    }
+}
+
+impl FromStr for OAuthAccountProvider {
+    type Err = ParseOAuthAccountProviderError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "APPLE" => Ok(Self::APPLE),
+            "GITHUB" => Ok(Self::GITHUB),
+            "GOOGLE" => Ok(Self::GOOGLE),
+            _ => Err(ParseOAuthAccountProviderError),
+        }
+    }
+}
+
+impl Display for OAuthAccountProvider {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (match self {
+            Self::APPLE => "APPLE",
+            Self::GITHUB => "GITHUB",
+            Self::GOOGLE => "GOOGLE",
+        })
+        .fmt(f)
+    }
+}
+
+impl<DB> FromSql<Text, DB> for OAuthAccountProvider
+where
+    DB: Backend,
+    String: FromSql<Text, DB>,
+{
+    fn from_sql(bytes: Option<&DB::RawValue>) -> diesel::deserialize::Result<Self> {
+        Ok(String::from_sql(bytes)?.parse()?)
+    }
+}
+
+impl<DB> ToSql<Text, DB> for OAuthAccountProvider
 where
-    Self: graphql::OutputType,
+    DB: Backend,
+    String: ToSql<Text, DB>,
 {
-    fn cursor(&self) -> Cursor;
+    fn to_sql<W: std::io::Write>(
+        &self,
+        out: &mut diesel::serialize::Output<W, DB>,
+    ) -> diesel::serialize::Result {
+        self.to_string().to_sql(out)
+    }
+}
+
+#[derive(Debug)]
+pub enum OAuthSignInError {
+    /// The provider account is linked to a different user.
+    AlreadyLinked(OAuthAccountProvider),
+    /// The user already has a different account with the provider linked.
+    ProviderTaken(OAuthAccountProvider),
+    Query(diesel::result::Error),
+    UserDeleted,
+}
+
+impl Display for OAuthSignInError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyLinked(provider) => {
+                write!(f, "this {} account is linked to another user", provider)
+            }
+            Self::ProviderTaken(provider) => {
+                write!(f, "a different {} account is already linked", provider)
+            }
+            Self::Query(err) => err.fmt(f),
+            Self::UserDeleted => "this account's user has been deleted".fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for OAuthSignInError {}
+
+impl From<diesel::result::Error> for OAuthSignInError {
+    fn from(err: diesel::result::Error) -> Self {
+        Self::Query(err)
+    }
+}
+
+#[derive(
+    Debug,
+    Clone,
+    diesel::Associations,
+    diesel::Identifiable,
+    diesel::Queryable,
+    graphql::SimpleObject,
+)]
+#[belongs_to(User)]
+#[graphql(complex, name = "OAuthAccount")]
+#[table_name = "oauth_accounts"]
+pub struct OAuthAccount {
+    #[graphql(skip)]
+    pub _rowid: i32,
+    pub created_at: DateTime,
+    #[graphql(skip)]
+    pub deleted_at: Option<DateTime>,
+    #[graphql(skip)]
+    pub id: uuid::Uuid,
+    pub provider: OAuthAccountProvider,
+    #[graphql(skip)]
+    pub provider_access_token: Sealed,
+    pub provider_access_token_expires_at: DateTime,
+    pub provider_account_id: String,
+    #[graphql(skip)]
+    pub provider_refresh_token: Sealed,
+    /// Why the most recent attempt to refresh the access token failed, if it
+    /// did.
+    pub refresh_error: Option<String>,
+    pub refresh_failed_at: Option<DateTime>,
+    /// When a refresh was last queued, cleared once one succeeds.
+    #[graphql(skip)]
+    pub refresh_queued_at: Option<DateTime>,
+    pub updated_at: DateTime,
+    #[graphql(skip)]
+    pub user_id: uuid::Uuid,
+}
+
+#[graphql::ComplexObject]
+impl OAuthAccount {
+    /// A globally unique ID for fetching this OAuth account with `node`.
+    pub async fn id(&self, _ctx: &Context<'_>) -> graphql::Result<graphql::ID> {
+        Ok(GlobalId::new(NodeType::OAuthAccount, self.id).into())
+    }
+
+    /// The OAuth account's UUID, as taken by mutation inputs.
+    pub async fn uuid(&self) -> uuid::Uuid {
+        self.id
+    }
+}
+
+impl OAuthAccount {
+    /// Removes an OAuth account, so it no longer signs in as its user, e.g.
+    /// because it was compromised. Users have to keep at least one way to sign
+    /// in.
+    pub fn delete(conn: &PgConnection, id: uuid::Uuid) -> Result<Self, OAuthAccountDeleteError> {
+        conn.transaction(|| {
+            let account: Self = oauth_accounts::table
+                .find(id)
+                .filter(oauth_accounts::deleted_at.is_null())
+                .get_result(conn)
+                .optional()?
+                .ok_or(OAuthAccountDeleteError::NotFound)?;
+            User::lock(conn, account.user_id)?;
+
+            let has_email_account = diesel::select(diesel::dsl::exists(
+                email_accounts::table
+                    .filter(email_accounts::user_id.eq(account.user_id))
+                    .filter(email_accounts::deleted_at.is_null()),
+            ))
+            .get_result::<bool>(conn)?;
+            let has_other_oauth_account = diesel::select(diesel::dsl::exists(
+                oauth_accounts::table
+                    .filter(oauth_accounts::user_id.eq(account.user_id))
+                    .filter(oauth_accounts::id.ne(id))
+                    .filter(oauth_accounts::deleted_at.is_null()),
+            ))
+            .get_result::<bool>(conn)?;
+            if !has_email_account && !has_other_oauth_account {
+                return Err(OAuthAccountDeleteError::LastSignInMethod);
+            }
+
+            Ok(diesel::update(oauth_accounts::table.find(id))
+                .set(oauth_accounts::deleted_at.eq(Some(chrono::Utc::now())))
+                .returning(oauth_accounts::all_columns)
+                .get_result(conn)?)
+        })
+    }
+
+    /// Re-seals every stored provider token that isn't sealed with the
+    /// keyring's primary key, returning how many accounts were updated. Run at
+    /// startup, so rotating keys (or upgrading from plaintext tokens) only
+    /// takes a restart.
+    pub fn reseal_tokens(
+        conn: &PgConnection,
+        keyring: &Keyring,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let sealed_prefix = format!("v1.{}.%", keyring.primary_key_id());
+        let accounts: Vec<(uuid::Uuid, Sealed, Sealed)> = oauth_accounts::table
+            .filter(
+                oauth_accounts::provider_access_token
+                    .not_like(&sealed_prefix)
+                    .or(oauth_accounts::provider_refresh_token.not_like(&sealed_prefix)),
+            )
+            .select((
+                oauth_accounts::id,
+                oauth_accounts::provider_access_token,
+                oauth_accounts::provider_refresh_token,
+            ))
+            .get_results(conn)?;
+
+        for (id, access_token, refresh_token) in &accounts {
+            let access_token = keyring.reseal(access_token)?;
+            let refresh_token = keyring.reseal(refresh_token)?;
+
+            diesel::update(oauth_accounts::table.find(id))
+                .set((
+                    access_token.map(|token| oauth_accounts::provider_access_token.eq(token)),
+                    refresh_token.map(|token| oauth_accounts::provider_refresh_token.eq(token)),
+                ))
+                .execute(conn)?;
+        }
+
+        Ok(accounts.len())
+    }
+
+    /// Queues a `RefreshOAuthToken` job for each account of one of
+    /// `providers` whose access token expires within `oauth::REFRESH_WINDOW`
+    /// of `now`, returning how many were queued. Accounts already queued
+    /// aren't queued again until `oauth::REQUEUE_AFTER` has passed, so that
+    /// accounts whose refresh keeps failing are only retried now and then.
+    pub fn queue_refreshes(
+        conn: &PgConnection,
+        providers: &[OAuthAccountProvider],
+        now: DateTime,
+    ) -> QueryResult<usize> {
+        let window =
+            chrono::Duration::from_std(oauth::REFRESH_WINDOW).expect("REFRESH_WINDOW is in range");
+        let requeue_after =
+            chrono::Duration::from_std(oauth::REQUEUE_AFTER).expect("REQUEUE_AFTER is in range");
+
+        conn.transaction(|| {
+            let ids: Vec<uuid::Uuid> = oauth_accounts::table
+                .filter(oauth_accounts::provider.eq_any(providers))
+                .filter(oauth_accounts::provider_access_token_expires_at.le(now + window))
+                .filter(
+                    oauth_accounts::refresh_queued_at
+                        .is_null()
+                        .or(oauth_accounts::refresh_queued_at.le(now - requeue_after)),
+                )
+                .filter(oauth_accounts::deleted_at.is_null())
+                .select(oauth_accounts::id)
+                .for_update()
+                .skip_locked()
+                .load(conn)?;
+
+            diesel::update(oauth_accounts::table.filter(oauth_accounts::id.eq_any(&ids)))
+                .set(oauth_accounts::refresh_queued_at.eq(now))
+                .execute(conn)?;
+            for &oauth_account_id in &ids {
+                Job::enqueue(conn, &RefreshOAuthToken { oauth_account_id })?;
+            }
+
+            Ok(ids.len())
+        })
+    }
+
+    /// Stores the tokens from a successful refresh of `self`'s access token,
+    /// unless its tokens were replaced while the refresh was in flight.
+    pub fn record_refresh(
+        &self,
+        conn: &PgConnection,
+        keyring: &Keyring,
+        tokens: &Tokens,
+    ) -> QueryResult<()> {
+        diesel::update(
+            oauth_accounts::table
+                .find(self.id)
+                .filter(oauth_accounts::provider_refresh_token.eq(&self.provider_refresh_token)),
+        )
+        .set((
+            oauth_accounts::provider_access_token.eq(keyring.seal(&tokens.access_token)),
+            oauth_accounts::provider_access_token_expires_at.eq(tokens.expires_at),
+            tokens
+                .refresh_token
+                .as_ref()
+                .map(|token| oauth_accounts::provider_refresh_token.eq(keyring.seal(token))),
+            oauth_accounts::refresh_error.eq(None::<String>),
+            oauth_accounts::refresh_failed_at.eq(None::<DateTime>),
+            oauth_accounts::refresh_queued_at.eq(None::<DateTime>),
+        ))
+        .execute(conn)?;
+
+        Ok(())
+    }
+
+    pub fn record_refresh_failure(
+        conn: &PgConnection,
+        id: uuid::Uuid,
+        error: &str,
+    ) -> QueryResult<()> {
+        diesel::update(oauth_accounts::table.find(id))
+            .set((
+                oauth_accounts::refresh_error.eq(error),
+                oauth_accounts::refresh_failed_at.eq(chrono::Utc::now()),
+            ))
+            .execute(conn)?;
+
+        Ok(())
+    }
+
+    /// Signs in as the user the provider account `provider_account_id` belongs
+    /// to, replacing its tokens with `tokens`. Provider accounts seen for the
+    /// first time, or that their user removed, are linked to `link_user_id` if
+    /// given, or to a new user.
+    ///
+    /// Providers don't always issue a refresh token, in which case an empty
+    /// one is stored and refreshes fail until the user signs in again.
+    pub fn sign_in(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        provider: OAuthAccountProvider,
+        provider_account_id: &str,
+        tokens: &Tokens,
+        link_user_id: Option<uuid::Uuid>,
+    ) -> Result<User, OAuthSignInError> {
+        conn.transaction(|| {
+            let existing: Option<Self> = oauth_accounts::table
+                .filter(oauth_accounts::provider.eq(provider))
+                .filter(oauth_accounts::provider_account_id.eq(provider_account_id))
+                .for_update()
+                .get_result(conn)
+                .optional()?;
+
+            let user_id = match (&existing, link_user_id) {
+                (Some(account), Some(user_id))
+                    if account.deleted_at.is_none() && account.user_id != user_id =>
+                {
+                    return Err(OAuthSignInError::AlreadyLinked(provider));
+                }
+                (_, Some(user_id)) => user_id,
+                (Some(account), None) if account.deleted_at.is_none() => account.user_id,
+                // Accounts deleted along with their user come back if the user
+                // is restored, so they're still the user's.
+                (Some(account), None)
+                    if users::table
+                        .find(account.user_id)
+                        .select(users::deleted_at)
+                        .get_result::<Option<DateTime>>(conn)?
+                        == account.deleted_at =>
+                {
+                    return Err(OAuthSignInError::UserDeleted);
+                }
+                // An account its user removed, perhaps because it was
+                // compromised, no longer signs in as them: it's as good as
+                // unknown, and only comes back if they link it again.
+                (_, None) => diesel::insert_into(users::table)
+                    .values(users::role.eq(UserRole::USER))
+                    .returning(users::id)
+                    .get_result(conn)?,
+            };
+
+            let user: User = users::table.find(user_id).get_result(conn)?;
+            if user.deleted_at.is_some() {
+                return Err(OAuthSignInError::UserDeleted);
+            }
+
+            let taken = oauth_accounts::table
+                .filter(oauth_accounts::provider.eq(provider))
+                .filter(oauth_accounts::user_id.eq(user_id))
+                .filter(oauth_accounts::provider_account_id.ne(provider_account_id))
+                .filter(oauth_accounts::deleted_at.is_null())
+                .select(oauth_accounts::id)
+                .first::<uuid::Uuid>(conn)
+                .optional()?;
+            if taken.is_some() {
+                return Err(OAuthSignInError::ProviderTaken(provider));
+            }
+
+            let access_token = keyring.seal(&tokens.access_token);
+            match existing {
+                Some(account) => {
+                    diesel::update(oauth_accounts::table.find(account.id))
+                        .set((
+                            oauth_accounts::deleted_at.eq(None::<DateTime>),
+                            oauth_accounts::provider_access_token.eq(access_token),
+                            oauth_accounts::provider_access_token_expires_at.eq(tokens.expires_at),
+                            tokens.refresh_token.as_ref().map(|token| {
+                                oauth_accounts::provider_refresh_token.eq(keyring.seal(token))
+                            }),
+                            oauth_accounts::refresh_error.eq(None::<String>),
+                            oauth_accounts::refresh_failed_at.eq(None::<DateTime>),
+                            oauth_accounts::refresh_queued_at.eq(None::<DateTime>),
+                            oauth_accounts::user_id.eq(user_id),
+                        ))
+                        .execute(conn)?;
+                }
+                None => {
+                    diesel::insert_into(oauth_accounts::table)
+                        .values(&NewOAuthAccount {
+                            provider,
+                            provider_access_token: access_token,
+                            provider_access_token_expires_at: tokens.expires_at,
+                            provider_account_id: provider_account_id.to_owned(),
+                            provider_refresh_token: keyring
+                                .seal(tokens.refresh_token.as_deref().unwrap_or_default()),
+                            user_id,
+                        })
+                        .execute(conn)?;
+                }
+            }
+
+            Ok(user)
+        })
+    }
+}
+
+#[derive(Debug, diesel::Insertable)]
+#[table_name = "oauth_accounts"]
+pub struct NewOAuthAccount {
+    pub provider: OAuthAccountProvider,
+    pub provider_access_token: Sealed,
+    pub provider_access_token_expires_at: DateTime,
+    pub provider_account_id: String,
+    pub provider_refresh_token: Sealed,
+    pub user_id: uuid::Uuid,
+}
+
+#[derive(Debug)]
+pub enum OAuthAccountDeleteError {
+    LastSignInMethod,
+    NotFound,
+    Query(diesel::result::Error),
+}
+
+impl Display for OAuthAccountDeleteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LastSignInMethod => "this is the only way left to sign in".fmt(f),
+            Self::NotFound => "OAuth account not found".fmt(f),
+            Self::Query(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for OAuthAccountDeleteError {}
+
+impl From<diesel::result::Error> for OAuthAccountDeleteError {
+    fn from(err: diesel::result::Error) -> Self {
+        Self::Query(err)
+    }
+}
+
+#[derive(Debug, graphql::InputObject)]
+#[graphql(name = "OAuthAccountDeleteInput")]
+pub struct OAuthAccountDeleteInput {
+    pub id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+#[graphql(name = "OAuthAccountDeleteOutput")]
+pub struct OAuthAccountDeleteOutput {
+    pub oauth_account: OAuthAccount,
+}
+
+/// A code issued to an OAuth client once a user authorizes it, which the
+/// client exchanges for tokens at `/oauth/token`.
+#[derive(Debug, Clone, diesel::Associations, diesel::Identifiable, diesel::Queryable)]
+#[belongs_to(User)]
+#[table_name = "oauth_authorization_codes"]
+pub struct OAuthAuthorizationCode {
+    pub _rowid: i32,
+    pub client_id: uuid::Uuid,
+    /// The PKCE challenge (RFC 7636) the code was issued for. Only the client
+    /// that asked for the code knows the verifier it was derived from.
+    pub code_challenge: String,
+    pub code_hash: String,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+    pub id: uuid::Uuid,
+    pub redirect_uri: String,
+    pub scopes: Vec<Scope>,
+    pub updated_at: DateTime,
+    pub used_at: Option<DateTime>,
+    pub user_id: uuid::Uuid,
+}
+
+impl OAuthAuthorizationCode {
+    /// Issues a code for the client `authorization` is for, to act as
+    /// `user_id`. Returns it along with the plaintext code.
+    pub fn create(
+        conn: &PgConnection,
+        user_id: uuid::Uuid,
+        authorization: &OAuthAuthorizeInput,
+    ) -> Result<(Self, String), OAuthAuthorizeError> {
+        let client = OAuthClient::find_for_redirect(
+            conn,
+            authorization.client_id,
+            &authorization.redirect_uri,
+        )?
+        .ok_or(OAuthAuthorizeError::UnknownClient)?;
+        if authorization.scopes.is_empty() {
+            return Err(OAuthAuthorizeError::NoScopes);
+        }
+        if !is_code_challenge(&authorization.code_challenge) {
+            return Err(OAuthAuthorizeError::InvalidCodeChallenge);
+        }
+
+        let code = auth::generate_token();
+        let authorization_code = diesel::insert_into(oauth_authorization_codes::table)
+            .values((
+                oauth_authorization_codes::client_id.eq(client.id),
+                oauth_authorization_codes::code_challenge.eq(&authorization.code_challenge),
+                oauth_authorization_codes::code_hash.eq(auth::hash_token(&code)),
+                oauth_authorization_codes::expires_at.eq(chrono::Utc::now()
+                    + chrono::Duration::minutes(OAUTH_AUTHORIZATION_CODE_LIFETIME_MINUTES)),
+                oauth_authorization_codes::redirect_uri.eq(&authorization.redirect_uri),
+                oauth_authorization_codes::scopes.eq(normalize_scopes(&authorization.scopes)),
+                oauth_authorization_codes::user_id.eq(user_id),
+            ))
+            .returning(oauth_authorization_codes::all_columns)
+            .get_result(conn)?;
+
+        Ok((authorization_code, code))
+    }
+
+    /// Exchanges `code` for tokens. Codes only work once, for the client and
+    /// redirect URI they were issued to, and with the PKCE verifier for their
+    /// challenge.
+    pub fn exchange(
+        conn: &PgConnection,
+        client_id: uuid::Uuid,
+        code: &str,
+        redirect_uri: &str,
+        code_verifier: &str,
+    ) -> Result<(AccessToken, String, String), OAuthGrantError> {
+        conn.transaction(|| {
+            let authorization_code: Self = oauth_authorization_codes::table
+                .inner_join(users::table)
+                .filter(oauth_authorization_codes::client_id.eq(client_id))
+                .filter(oauth_authorization_codes::code_hash.eq(auth::hash_token(code)))
+                .filter(oauth_authorization_codes::expires_at.gt(diesel::dsl::now))
+                .filter(oauth_authorization_codes::used_at.is_null())
+                .filter(users::deleted_at.is_null())
+                .select(oauth_authorization_codes::all_columns)
+                .for_update()
+                .get_result(conn)
+                .optional()?
+                .ok_or(OAuthGrantError::Invalid)?;
+
+            // S256 challenges are the same digest tokens are stored as.
+            if authorization_code.redirect_uri != redirect_uri
+                || !auth::verify_token(code_verifier, &authorization_code.code_challenge)
+            {
+                return Err(OAuthGrantError::Invalid);
+            }
+
+            diesel::update(&authorization_code)
+                .set(oauth_authorization_codes::used_at.eq(Some(chrono::Utc::now())))
+                .execute(conn)?;
+            Ok(AccessToken::issue(
+                conn,
+                client_id,
+                authorization_code.user_id,
+                &authorization_code.scopes,
+            )?)
+        })
+    }
+
+    /// Deletes codes that expired before `now`, returning how many.
+    pub fn delete_expired(conn: &PgConnection, now: DateTime) -> QueryResult<usize> {
+        diesel::delete(
+            oauth_authorization_codes::table.filter(oauth_authorization_codes::expires_at.le(now)),
+        )
+        .execute(conn)
+    }
+}
+
+/// Whether `challenge` looks like a PKCE S256 challenge (RFC 7636 section
+/// 4.2): an unpadded URL-safe base64 SHA-256 digest. Plain challenges aren't
+/// accepted.
+pub fn is_code_challenge(challenge: &str) -> bool {
+    challenge.len() == 43
+        && challenge
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+}
+
+/// Why an authorization code couldn't be issued.
+#[derive(Debug)]
+pub enum OAuthAuthorizeError {
+    InvalidCodeChallenge,
+    NoScopes,
+    /// The client doesn't exist, or the redirect URI isn't one of its.
+    UnknownClient,
+    Query(diesel::result::Error),
+}
+
+impl Display for OAuthAuthorizeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCodeChallenge => "codeChallenge must be a PKCE S256 challenge".fmt(f),
+            Self::NoScopes => "scopes must not be empty".fmt(f),
+            Self::UnknownClient => "no such OAuth client, or it has no such redirect URI".fmt(f),
+            Self::Query(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for OAuthAuthorizeError {}
+
+impl From<diesel::result::Error> for OAuthAuthorizeError {
+    fn from(err: diesel::result::Error) -> Self {
+        Self::Query(err)
+    }
+}
+
+/// The parameters `/oauth/authorize` passes on to the web app's consent page,
+/// once the viewer agrees to authorize the client.
+#[derive(Debug, graphql::InputObject)]
+#[graphql(name = "OAuthAuthorizeInput")]
+pub struct OAuthAuthorizeInput {
+    pub client_id: uuid::Uuid,
+    pub code_challenge: String,
+    pub redirect_uri: String,
+    pub scopes: Vec<Scope>,
+    pub state: Option<String>,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+#[graphql(name = "OAuthAuthorizeOutput")]
+pub struct OAuthAuthorizeOutput {
+    /// The client's redirect URI with the code and state added, to send the
+    /// viewer on to.
+    pub redirect_uri: String,
+}
+
+/// An app registered to act for users through the API, within the scopes
+/// each user authorizes it for.
+#[derive(
+    Debug,
+    Clone,
+    diesel::Associations,
+    diesel::Identifiable,
+    diesel::Queryable,
+    graphql::SimpleObject,
+)]
+#[belongs_to(User)]
+#[graphql(complex, name = "OAuthClient")]
+#[table_name = "oauth_clients"]
+pub struct OAuthClient {
+    #[graphql(skip)]
+    pub _rowid: i32,
+    pub created_at: DateTime,
+    #[graphql(skip)]
+    pub deleted_at: Option<DateTime>,
+    /// The `client_id` the app identifies itself with.
+    pub id: uuid::Uuid,
+    /// Shown to users when they're asked to authorize the app.
+    pub name: String,
+    /// Where users can be sent back to once they've decided whether to
+    /// authorize the app. Each authorization names one of these exactly.
+    pub redirect_uris: Vec<String>,
+    #[graphql(skip)]
+    pub secret_hash: Option<String>,
+    pub updated_at: DateTime,
+    #[graphql(skip)]
+    pub user_id: uuid::Uuid,
+}
+
+#[graphql::ComplexObject]
+impl OAuthClient {
+    /// Whether the app authenticates with a client secret. Apps that can't
+    /// keep one secret, like mobile and single-page apps, rely on PKCE alone.
+    pub async fn is_confidential(&self) -> bool {
+        self.secret_hash.is_some()
+    }
+}
+
+impl OAuthClient {
+    /// Registers a client for `user_id`, returning it along with its plaintext
+    /// secret if it's confidential.
+    pub fn create(
+        conn: &PgConnection,
+        user_id: uuid::Uuid,
+        input: OAuthClientCreateInput,
+    ) -> Result<(Self, Option<String>), OAuthClientError> {
+        let name = input.name.trim();
+        if name.is_empty() {
+            return Err(OAuthClientError::BlankName);
+        }
+        if input.redirect_uris.is_empty()
+            || input.redirect_uris.len() > MAX_OAUTH_CLIENT_REDIRECT_URIS
+        {
+            return Err(OAuthClientError::RedirectUriCount);
+        }
+        for redirect_uri in &input.redirect_uris {
+            validate_redirect_uri(redirect_uri)?;
+        }
+
+        let secret = if input.confidential {
+            Some(auth::generate_prefixed_token(
+                auth::OAUTH_CLIENT_SECRET_PREFIX,
+            ))
+        } else {
+            None
+        };
+        let client = diesel::insert_into(oauth_clients::table)
+            .values((
+                oauth_clients::name.eq(name),
+                oauth_clients::redirect_uris.eq(&input.redirect_uris),
+                oauth_clients::secret_hash.eq(secret.as_deref().map(auth::hash_token)),
+                oauth_clients::user_id.eq(user_id),
+            ))
+            .returning(oauth_clients::all_columns)
+            .get_result(conn)?;
+
+        Ok((client, secret))
+    }
+
+    /// Finds client `client_id`, if it hasn't been deleted and `redirect_uri`
+    /// is one of its redirect URIs.
+    pub fn find_for_redirect(
+        conn: &PgConnection,
+        client_id: uuid::Uuid,
+        redirect_uri: &str,
+    ) -> QueryResult<Option<Self>> {
+        Ok(oauth_clients::table
+            .find(client_id)
+            .filter(oauth_clients::deleted_at.is_null())
+            .get_result::<Self>(conn)
+            .optional()?
+            .filter(|client| client.redirect_uris.iter().any(|uri| uri == redirect_uri)))
+    }
+
+    /// Whether `secret` is the client's secret. Public clients have none, and
+    /// are authenticated by sending none.
+    pub fn authenticate(&self, secret: Option<&str>) -> bool {
+        match (&self.secret_hash, secret) {
+            (Some(secret_hash), Some(secret)) => auth::verify_token(secret, secret_hash),
+            (None, None) => true,
+            _ => false,
+        }
+    }
+
+    /// Soft-deletes the client, revoking the tokens and codes issued to it.
+    pub fn delete(conn: &PgConnection, id: uuid::Uuid) -> QueryResult<Self> {
+        conn.transaction(|| {
+            diesel::delete(access_tokens::table.filter(access_tokens::client_id.eq(Some(id))))
+                .execute(conn)?;
+            diesel::delete(
+                oauth_authorization_codes::table
+                    .filter(oauth_authorization_codes::client_id.eq(id))
+                    .filter(oauth_authorization_codes::used_at.is_null()),
+            )
+            .execute(conn)?;
+
+            diesel::update(
+                oauth_clients::table
+                    .find(id)
+                    .filter(oauth_clients::deleted_at.is_null()),
+            )
+            .set(oauth_clients::deleted_at.eq(Some(chrono::Utc::now())))
+            .returning(oauth_clients::all_columns)
+            .get_result(conn)
+        })
+    }
+}
+
+/// Checks `uri` can be registered as a redirect URI: it must be absolute, with
+/// no fragment, and use `https`, `http` on the loopback interface, or a
+/// private scheme as native apps do (RFC 8252).
+fn validate_redirect_uri(uri: &str) -> Result<(), OAuthClientError> {
+    let url = url::Url::parse(uri)
+        .ok()
+        .filter(|url| url.fragment().is_none() && !UNSAFE_REDIRECT_SCHEMES.contains(&url.scheme()))
+        .ok_or_else(|| OAuthClientError::InvalidRedirectUri(uri.to_owned()))?;
+
+    let is_loopback = match url.host() {
+        Some(url::Host::Domain(domain)) => domain == "localhost",
+        Some(url::Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(url::Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
+    };
+    if url.scheme() == "http" && !is_loopback {
+        return Err(OAuthClientError::InsecureRedirectUri(uri.to_owned()));
+    }
+
+    Ok(())
+}
+
+/// Schemes that would run or show something in the browser itself rather than
+/// hand the code to the client.
+const UNSAFE_REDIRECT_SCHEMES: &[&str] = &["data", "file", "javascript", "vbscript"];
+
+#[derive(Debug, graphql::InputObject)]
+#[graphql(name = "OAuthClientCreateInput")]
+pub struct OAuthClientCreateInput {
+    /// Whether the app gets a client secret. Apps that can't keep one secret,
+    /// like mobile and single-page apps, shouldn't.
+    pub confidential: bool,
+    pub name: String,
+    /// `https` URLs, `http` ones on the loopback interface, or URLs with a
+    /// private scheme for native apps. None can have a fragment.
+    pub redirect_uris: Vec<String>,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+#[graphql(name = "OAuthClientCreateOutput")]
+pub struct OAuthClientCreateOutput {
+    /// The client secret, for confidential apps. It is only ever returned
+    /// here.
+    pub client_secret: Option<String>,
+    pub oauth_client: OAuthClient,
+}
+
+#[derive(Debug, graphql::InputObject)]
+#[graphql(name = "OAuthClientDeleteInput")]
+pub struct OAuthClientDeleteInput {
+    pub id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+#[graphql(name = "OAuthClientDeleteOutput")]
+pub struct OAuthClientDeleteOutput {
+    pub oauth_client: OAuthClient,
+}
+
+#[derive(Debug)]
+pub enum OAuthClientError {
+    BlankName,
+    InsecureRedirectUri(String),
+    InvalidRedirectUri(String),
+    RedirectUriCount,
+    Query(diesel::result::Error),
+}
+
+impl Display for OAuthClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BlankName => "name must not be blank".fmt(f),
+            Self::InsecureRedirectUri(uri) => write!(
+                f,
+                "redirect URI {:?} must use https, unless it's on localhost",
+                uri
+            ),
+            Self::InvalidRedirectUri(uri) => write!(
+                f,
+                "redirect URI {:?} must be an absolute URL without a fragment",
+                uri
+            ),
+            Self::RedirectUriCount => write!(
+                f,
+                "there must be between 1 and {} redirect URIs",
+                MAX_OAUTH_CLIENT_REDIRECT_URIS
+            ),
+            Self::Query(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for OAuthClientError {}
+
+impl From<diesel::result::Error> for OAuthClientError {
+    fn from(err: diesel::result::Error) -> Self {
+        Self::Query(err)
+    }
+}
+
+/// Why an OAuth client couldn't exchange an authorization code or refresh
+/// token for tokens.
+#[derive(Debug)]
+pub enum OAuthGrantError {
+    /// The code or refresh token is unknown, expired or already used, or was
+    /// issued to another client or redirect URI, or the PKCE verifier is
+    /// wrong. Clients aren't told which.
+    Invalid,
+    /// A refresh asked for scopes the original authorization didn't grant.
+    InvalidScope,
+    Query(diesel::result::Error),
+}
+
+impl Display for OAuthGrantError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Invalid => "the grant is invalid, expired or already used".fmt(f),
+            Self::InvalidScope => "a refresh can only ask for scopes already granted".fmt(f),
+            Self::Query(err) => err.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for OAuthGrantError {}
+
+impl From<diesel::result::Error> for OAuthGrantError {
+    fn from(err: diesel::result::Error) -> Self {
+        Self::Query(err)
+    }
+}
+
+/// Lets a browser navigation to `/auth/:provider/start`, which can't carry a
+/// bearer token, link an OAuth account to the user of the session that issued
+/// it.
+#[derive(Debug, Clone, diesel::Associations, diesel::Identifiable, diesel::Queryable)]
+#[belongs_to(User)]
+#[table_name = "oauth_link_tokens"]
+pub struct OAuthLinkToken {
+    pub _rowid: i32,
+    pub created_at: DateTime,
+    pub expires_at: DateTime,
+    pub id: uuid::Uuid,
+    pub session_id: uuid::Uuid,
+    pub token_hash: String,
+    pub updated_at: DateTime,
+    pub user_id: uuid::Uuid,
+}
+
+impl OAuthLinkToken {
+    /// Issues a link token for `session`, returning it along with the
+    /// plaintext token to hand to the client.
+    pub fn create(conn: &PgConnection, session: &Session) -> QueryResult<(Self, String)> {
+        let token = auth::generate_token();
+        let link_token = diesel::insert_into(oauth_link_tokens::table)
+            .values((
+                oauth_link_tokens::expires_at.eq(chrono::Utc::now()
+                    + chrono::Duration::minutes(OAUTH_LINK_TOKEN_LIFETIME_MINUTES)),
+                oauth_link_tokens::session_id.eq(session.id),
+                oauth_link_tokens::token_hash.eq(auth::hash_token(&token)),
+                oauth_link_tokens::user_id.eq(session.user_id),
+            ))
+            .returning(oauth_link_tokens::all_columns)
+            .get_result(conn)?;
+
+        Ok((link_token, token))
+    }
+
+    /// Deletes tokens that expired before `now`, returning how many.
+    pub fn delete_expired(conn: &PgConnection, now: DateTime) -> QueryResult<usize> {
+        diesel::delete(oauth_link_tokens::table.filter(oauth_link_tokens::expires_at.le(now)))
+            .execute(conn)
+    }
+
+    /// Uses up `token`, returning the ID of the user to link to, or `None` if
+    /// the token is unknown, expired or already used, or the session that
+    /// issued it has ended.
+    pub fn redeem(conn: &PgConnection, token: &str) -> QueryResult<Option<uuid::Uuid>> {
+        conn.transaction(|| {
+            let link_token: Self = match diesel::delete(
+                oauth_link_tokens::table
+                    .filter(oauth_link_tokens::token_hash.eq(auth::hash_token(token)))
+                    .filter(oauth_link_tokens::expires_at.gt(diesel::dsl::now)),
+            )
+            .returning(oauth_link_tokens::all_columns)
+            .get_result(conn)
+            .optional()?
+            {
+                Some(link_token) => link_token,
+                None => return Ok(None),
+            };
+
+            sessions::table
+                .inner_join(users::table)
+                .filter(sessions::id.eq(link_token.session_id))
+                .filter(sessions::expires_at.gt(diesel::dsl::now))
+                .filter(users::deleted_at.is_null())
+                .select(users::id)
+                .get_result(conn)
+                .optional()
+        })
+    }
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+#[graphql(name = "OAuthLinkTokenCreateOutput")]
+pub struct OAuthLinkTokenCreateOutput {
+    pub expires_at: DateTime,
+    /// Pass it to `/auth/:provider/start` as `link_token` to link an account
+    /// from that provider. It works once, and is only ever returned here.
+    pub token: String,
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct PasswordResetConfirmInput {
+    pub password: String,
+    pub token: String,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct PasswordResetConfirmOutput {
+    pub email_account: EmailAccount,
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct PasswordResetRequestInput {
+    pub email: String,
+}
+
+/// Says nothing about whether an account with the address exists, so the
+/// mutation can't be used to find out.
+#[derive(Debug, graphql::SimpleObject)]
+pub struct PasswordResetRequestOutput {
+    pub email: String,
+}
+
+#[derive(
+    Debug,
+    Clone,
+    diesel::Associations,
+    diesel::Identifiable,
+    diesel::Queryable,
+    diesel::QueryableByName,
+    graphql::SimpleObject,
+)]
+#[belongs_to(Blog)]
+#[graphql(complex)]
+#[table_name = "posts"]
+pub struct Post {
+    #[graphql(skip)]
+    pub _rowid: i32,
+    #[graphql(skip)]
+    pub blog_id: uuid::Uuid,
+    pub created_at: DateTime,
+    #[graphql(skip)]
+    pub deleted_at: Option<DateTime>,
+    #[graphql(skip)]
+    pub id: uuid::Uuid,
+    pub slug: String,
+    pub updated_at: DateTime,
+    #[graphql(skip)]
+    pub content: PostContent,
+    #[graphql(skip)]
+    pub parent_post_id: Option<uuid::Uuid>,
+    #[graphql(skip)]
+    pub root_post_id: Option<uuid::Uuid>,
+    /// When the post was or will be published. Drafts, and private posts that
+    /// were never published, have none.
+    pub publish_at: Option<DateTime>,
+    pub state: PostState,
+}
+
+/// Posts from the blogs a user follows, most recently published first,
+/// between optional `(publish_at, id)` keysets. Posts are keyed by when they
+/// went live rather than when they were written, so drafts, queued and
+/// scheduled posts published later don't land behind pages already read.
+/// `{direction}` is `DESC`, or `ASC` to load a page backward.
+const DASHBOARD_QUERY: &str = r#"
+SELECT "posts".* FROM "posts"
+WHERE "posts"."blog_id" IN (
+    SELECT "follows"."blog_id" FROM "follows" WHERE "follows"."user_id" = $1
+)
+AND "posts"."deleted_at" IS NULL
+AND "posts"."state" = 'PUBLISHED'
+AND "posts"."publish_at" IS NOT NULL
+AND ($2::timestamptz IS NULL OR ("posts"."publish_at", "posts"."id") < ($2, $3))
+AND ($4::timestamptz IS NULL OR ("posts"."publish_at", "posts"."id") > ($4, $5))
+ORDER BY "posts"."publish_at" {direction}, "posts"."id" {direction}
+LIMIT $6
+"#;
+
+/// Walks `parent_post_id` from a post up to the root of its reblog chain,
+/// returning the chain ordered root first.
+const TRAIL_QUERY: &str = r#"
+WITH RECURSIVE "trail" AS (
+    SELECT "posts".*, 0 AS "depth" FROM "posts" WHERE "posts"."id" = $1
+    UNION ALL
+    SELECT "posts".*, "trail"."depth" + 1 FROM "posts"
+    INNER JOIN "trail" ON "posts"."id" = "trail"."parent_post_id"
+)
+SELECT * FROM "trail" ORDER BY "depth" DESC
+"#;
+
+#[graphql::ComplexObject]
+impl Post {
+    pub async fn blog(&self, ctx: &Context<'_>) -> graphql::Result<Blog> {
+        let loader = ctx.data_unchecked::<DataLoader<BlogLoader>>();
+
+        Ok(loader
+            .load_one(self.blog_id)
+            .await?
+            .ok_or("blog not found")?)
+    }
+
+    pub async fn content(&self) -> &[ContentBlock] {
+        &self.content.0
+    }
+
+    /// A globally unique ID for fetching this post with `node`.
+    pub async fn id(&self, _ctx: &Context<'_>) -> graphql::Result<graphql::ID> {
+        Ok(GlobalId::new(NodeType::Post, self.id).into())
+    }
+
+    pub async fn note_count(&self, ctx: &Context<'_>) -> graphql::Result<i64> {
+        let loader = ctx.data_unchecked::<DataLoader<NoteCountLoader>>();
+
+        Ok(loader.load_one(self.id).await?.unwrap_or_default())
+    }
+
+    /// Likes, reblogs and replies of this post, newest first. All note types
+    /// are included unless `types` is given.
+    pub async fn notes(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<i64>,
+        after: Option<Cursor>,
+        last: Option<i64>,
+        before: Option<Cursor>,
+        types: Option<Vec<NoteType>>,
+    ) -> graphql::Result<Connection<Note>> {
+        let page = PageArgs::new(first, after, last, before)?;
+
+        let types =
+            types.unwrap_or_else(|| vec![NoteType::LIKE, NoteType::REBLOG, NoteType::REPLY]);
+
+        let post_id = self.id;
+        let count_types = types.clone();
+
+        paginate(
+            ctx,
+            &page,
+            Some(self.id),
+            Order::CreatedAtDesc,
+            move |conn, window| Note::for_post(conn, post_id, &types, window),
+            move |conn| Note::count_for_post(conn, post_id, &count_types),
+        )
+        .await
+    }
+
+    pub async fn parent_post(&self, ctx: &Context<'_>) -> graphql::Result<Option<Post>> {
+        let loader = ctx.data_unchecked::<DataLoader<PostLoader>>();
+
+        Ok(match self.parent_post_id {
+            Some(id) => loader
+                .load_one(id)
+                .await?
+                .filter(|post| post.deleted_at.is_none() && post.state == PostState::PUBLISHED),
+            None => None,
+        })
+    }
+
+    pub async fn root_post(&self, ctx: &Context<'_>) -> graphql::Result<Option<Post>> {
+        let loader = ctx.data_unchecked::<DataLoader<PostLoader>>();
+
+        Ok(match self.root_post_id {
+            Some(id) => loader
+                .load_one(id)
+                .await?
+                .filter(|post| post.deleted_at.is_none() && post.state == PostState::PUBLISHED),
+            None => None,
+        })
+    }
+
+    pub async fn tags(&self, ctx: &Context<'_>) -> graphql::Result<Vec<Tag>> {
+        let loader = ctx.data_unchecked::<DataLoader<PostTagsLoader>>();
+
+        Ok(loader.load_one(self.id).await?.unwrap_or_default())
+    }
+
+    pub async fn trail(&self, ctx: &Context<'_>) -> graphql::Result<Vec<TrailItem>> {
+        let parent_post_id = match self.parent_post_id {
+            Some(id) => id,
+            None => return Ok(Vec::new()),
+        };
+
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+        let (ancestors, blogs) = pool
+            .run(move |conn| -> graphql::Result<_> {
+                let ancestors: Vec<Post> = diesel::sql_query(TRAIL_QUERY)
+                    .bind::<diesel::sql_types::Uuid, _>(parent_post_id)
+                    .load(conn)?;
+                let blogs: HashMap<uuid::Uuid, Blog> = blogs::table
+                    .filter(blogs::id.eq_any(ancestors.iter().map(|post| post.blog_id)))
+                    .get_results::<Blog>(conn)?
+                    .into_iter()
+                    .map(|blog| (blog.id, blog))
+                    .collect();
+
+                Ok((ancestors, blogs))
+            })
+            .await?;
+
+        Ok(ancestors
+            .into_iter()
+            .map(|post| {
+                let blog = blogs
+                    .get(&post.blog_id)
+                    .filter(|blog| blog.deleted_at.is_none())
+                    .cloned();
+                // Posts that were made private since being reblogged are
+                // hidden the same way as deleted ones.
+                let is_deleted = blog.is_none()
+                    || post.deleted_at.is_some()
+                    || post.state != PostState::PUBLISHED;
+
+                TrailItem {
+                    blog,
+                    is_deleted,
+                    post: if is_deleted { None } else { Some(post) },
+                }
+            })
+            .collect())
+    }
+
+    /// The post's UUID, as taken by mutation inputs.
+    pub async fn uuid(&self) -> uuid::Uuid {
+        self.id
+    }
+}
+
+impl Post {
+    /// Inserts `post` with already-normalized `tags`. Queued posts go to the
+    /// back of their blog's queue.
+    pub fn create(conn: &PgConnection, post: &NewPost, tags: &[String]) -> QueryResult<Self> {
+        conn.transaction(|| {
+            if post.state == PostState::QUEUED {
+                Blog::lock(conn, post.blog_id)?;
+            }
+
+            let post: Self = diesel::insert_into(posts::table)
+                .values(post)
+                .returning(posts::all_columns)
+                .get_result(conn)?;
+            PostTag::attach(conn, post.id, tags)?;
+
+            if post.state == PostState::QUEUED {
+                Blog::reslot_queue(conn, post.blog_id)?;
+                posts::table.find(post.id).get_result(conn)
+            } else {
+                Ok(post)
+            }
+        })
+    }
+
+    pub fn dashboard(
+        conn: &PgConnection,
+        user_id: uuid::Uuid,
+        window: Window<'_>,
+    ) -> QueryResult<Vec<Self>> {
+        use diesel::sql_types::{BigInt, Nullable, Timestamptz, Uuid};
+
+        let direction = if window.backward { "ASC" } else { "DESC" };
+        diesel::sql_query(DASHBOARD_QUERY.replace("{direction}", direction))
+            .bind::<Uuid, _>(user_id)
+            .bind::<Nullable<Timestamptz>, _>(window.after.and_then(|after| after.publish_at))
+            .bind::<Nullable<Uuid>, _>(window.after.and_then(|after| after.id))
+            .bind::<Nullable<Timestamptz>, _>(window.before.and_then(|before| before.publish_at))
+            .bind::<Nullable<Uuid>, _>(window.before.and_then(|before| before.id))
+            .bind::<BigInt, _>(window.limit)
+            .load(conn)
+    }
+
+    pub fn dashboard_count(conn: &PgConnection, user_id: uuid::Uuid) -> QueryResult<i64> {
+        posts::table
+            .filter(
+                posts::blog_id.eq_any(
+                    follows::table
+                        .filter(follows::user_id.eq(user_id))
+                        .select(follows::blog_id),
+                ),
+            )
+            .filter(posts::deleted_at.is_null())
+            .filter(posts::state.eq(PostState::PUBLISHED))
+            .filter(posts::publish_at.is_not_null())
+            .count()
+            .get_result(conn)
+    }
+
+    pub fn delete(conn: &PgConnection, id: uuid::Uuid) -> QueryResult<Self> {
+        diesel::update(posts::table.find(id).filter(posts::deleted_at.is_null()))
+            .set(posts::deleted_at.eq(Some(chrono::Utc::now())))
+            .returning(posts::all_columns)
+            .get_result(conn)
+    }
+
+    /// Publishes scheduled posts that are due, and the next due post in each
+    /// blog's queue, returning how many were published. Only one queued post
+    /// per blog goes out per call, so a backlog that built up while nothing
+    /// was publishing still goes out at the blog's pace.
+    pub fn publish_due(conn: &PgConnection, now: DateTime) -> QueryResult<usize> {
+        conn.transaction(|| {
+            let mut published = diesel::update(
+                posts::table
+                    .filter(posts::state.eq(PostState::SCHEDULED))
+                    .filter(posts::publish_at.le(now))
+                    .filter(posts::deleted_at.is_null()),
+            )
+            .set((
+                posts::publish_at.eq(Some(now)),
+                posts::state.eq(PostState::PUBLISHED),
+            ))
+            .execute(conn)?;
+
+            let blog_ids: Vec<uuid::Uuid> = posts::table
+                .filter(posts::state.eq(PostState::QUEUED))
+                .filter(posts::publish_at.le(now))
+                .filter(posts::deleted_at.is_null())
+                .select(posts::blog_id)
+                .distinct()
+                .load(conn)?;
+            for blog_id in blog_ids {
+                // Leave blogs whose queue is being changed for the next run.
+                let locked = blogs::table
+                    .find(blog_id)
+                    .select(blogs::id)
+                    .for_update()
+                    .skip_locked()
+                    .get_result::<uuid::Uuid>(conn)
+                    .optional()?;
+                if locked.is_none() {
+                    continue;
+                }
+
+                let next: Option<uuid::Uuid> = posts::table
+                    .filter(posts::blog_id.eq(blog_id))
+                    .filter(posts::state.eq(PostState::QUEUED))
+                    .filter(posts::publish_at.le(now))
+                    .filter(posts::deleted_at.is_null())
+                    .order_by((posts::publish_at.asc(), posts::_rowid.asc()))
+                    .select(posts::id)
+                    .first(conn)
+                    .optional()?;
+                if let Some(id) = next {
+                    published += diesel::update(posts::table.find(id))
+                        .set((
+                            posts::publish_at.eq(Some(now)),
+                            posts::state.eq(PostState::PUBLISHED),
+                        ))
+                        .execute(conn)?;
+                    diesel::update(blogs::table.find(blog_id))
+                        .set(blogs::queue_published_at.eq(now))
+                        .execute(conn)?;
+                    Blog::reslot_queue(conn, blog_id)?;
+                }
+            }
+
+            Ok(published)
+        })
+    }
+
+    pub fn restore(conn: &PgConnection, id: uuid::Uuid) -> QueryResult<Self> {
+        diesel::update(posts::table.find(id))
+            .set(posts::deleted_at.eq(None::<DateTime>))
+            .returning(posts::all_columns)
+            .get_result(conn)
+    }
+
+    /// Moves `post` to `state` with `publish_at`, as worked out by
+    /// `PostState::transition`, unless its state has changed since it was
+    /// fetched, returning `None` if it has. The blog's queue is reslotted when
+    /// the post joins or leaves it.
+    pub fn transition(
+        conn: &PgConnection,
+        post: &Self,
+        state: PostState,
+        publish_at: Option<DateTime>,
+    ) -> QueryResult<Option<Self>> {
+        let changes_queue = post.state == PostState::QUEUED || state == PostState::QUEUED;
+
+        conn.transaction(|| {
+            if changes_queue {
+                Blog::lock(conn, post.blog_id)?;
+            }
+
+            let updated: Option<Self> = diesel::update(
+                posts::table
+                    .find(post.id)
+                    .filter(posts::deleted_at.is_null())
+                    .filter(posts::state.eq(post.state)),
+            )
+            .set((posts::publish_at.eq(publish_at), posts::state.eq(state)))
+            .returning(posts::all_columns)
+            .get_result(conn)
+            .optional()?;
+
+            if updated.is_some() && changes_queue {
+                Blog::reslot_queue(conn, post.blog_id)?;
+                posts::table.find(post.id).get_result(conn).optional()
+            } else {
+                Ok(updated)
+            }
+        })
+    }
+
+    /// Applies `changeset`, and replaces the post's tags if `tags` is given,
+    /// unless the post has changed since `expected_updated_at`, returning
+    /// `None` if it has.
+    pub fn update(
+        conn: &PgConnection,
+        id: uuid::Uuid,
+        expected_updated_at: DateTime,
+        changeset: &PostChangeset,
+        tags: Option<&[String]>,
+    ) -> QueryResult<Option<Self>> {
+        conn.transaction(|| {
+            let post: Option<Self> = diesel::update(
+                posts::table
+                    .find(id)
+                    .filter(posts::deleted_at.is_null())
+                    .filter(posts::updated_at.eq(expected_updated_at)),
+            )
+            .set(changeset)
+            .returning(posts::all_columns)
+            .get_result(conn)
+            .optional()?;
+
+            if let (Some(post), Some(tags)) = (&post, tags) {
+                diesel::delete(post_tags::table.filter(post_tags::post_id.eq(post.id)))
+                    .execute(conn)?;
+                PostTag::attach(conn, post.id, tags)?;
+            }
+
+            Ok(post)
+        })
+    }
+}
+
+impl Node for Post {
+    fn position(&self) -> Position {
+        Position {
+            _rowid: self._rowid,
+            created_at: Some(self.created_at),
+            id: Some(self.id),
+            publish_at: self.publish_at,
+        }
+    }
+}
+
+/// Posts are published straight away unless `state` says otherwise;
+/// `publishAt` is required for scheduled posts.
+#[derive(Debug, graphql::InputObject)]
+pub struct PostCreateInput {
+    pub blog_id: uuid::Uuid,
+    pub content: Vec<ContentBlockInput>,
+    pub publish_at: Option<DateTime>,
+    pub slug: String,
+    pub state: Option<PostState>,
+    pub tags: Option<Vec<String>>,
+}
+
+#[derive(Debug, diesel::Insertable)]
+#[table_name = "posts"]
+pub struct NewPost {
+    pub blog_id: uuid::Uuid,
+    pub content: PostContent,
+    pub parent_post_id: Option<uuid::Uuid>,
+    pub publish_at: Option<DateTime>,
+    pub root_post_id: Option<uuid::Uuid>,
+    pub slug: String,
+    pub state: PostState,
+}
+
+impl NewPost {
+    pub fn reblog(parent: &Post, input: PostReblogInput) -> Result<Self, graphql::Error> {
+        let state = input.state.unwrap_or(PostState::PUBLISHED);
+
+        Ok(Self {
+            blog_id: input.blog_id,
+            content: match input.content {
+                Some(content) => PostContent::try_from(content)?,
+                None => PostContent::default(),
+            },
+            parent_post_id: Some(parent.id),
+            publish_at: state.publish_at(input.publish_at)?,
+            root_post_id: Some(parent.root_post_id.unwrap_or(parent.id)),
+            slug: input.slug,
+            state,
+        })
+    }
+}
+
+impl TryFrom<PostCreateInput> for NewPost {
+    type Error = graphql::Error;
+
+    fn try_from(input: PostCreateInput) -> Result<Self, Self::Error> {
+        let state = input.state.unwrap_or(PostState::PUBLISHED);
+
+        Ok(Self {
+            blog_id: input.blog_id,
+            content: PostContent::try_from(input.content)?,
+            parent_post_id: None,
+            publish_at: state.publish_at(input.publish_at)?,
+            root_post_id: None,
+            slug: input.slug,
+            state,
+        })
+    }
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct PostCreateOutput {
+    pub post: Post,
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct PostDeleteInput {
+    pub id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct PostDeleteOutput {
+    pub post: Post,
+}
+
+#[derive(Debug, diesel::Insertable, graphql::InputObject)]
+#[table_name = "likes"]
+pub struct PostLikeInput {
+    pub post_id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct PostLikeOutput {
+    pub like: Like,
+}
+
+/// Reblogs are published straight away unless `state` says otherwise, as with
+/// `PostCreateInput`.
+#[derive(Debug, graphql::InputObject)]
+pub struct PostReblogInput {
+    pub blog_id: uuid::Uuid,
+    pub content: Option<Vec<ContentBlockInput>>,
+    pub parent_post_id: uuid::Uuid,
+    pub publish_at: Option<DateTime>,
+    pub slug: String,
+    pub state: Option<PostState>,
+    pub tags: Option<Vec<String>>,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct PostReblogOutput {
+    pub post: Post,
+}
+
+#[derive(Debug, diesel::Insertable, graphql::InputObject)]
+#[table_name = "replies"]
+pub struct PostReplyInput {
+    pub blog_id: uuid::Uuid,
+    pub post_id: uuid::Uuid,
+    pub text: String,
 }
 
+#[derive(Debug, graphql::SimpleObject)]
+pub struct PostReplyOutput {
+    pub reply: Reply,
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct PostRestoreInput {
+    pub id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct PostRestoreOutput {
+    pub post: Post,
+}
+
+/// Where a post is in its life. Only published posts are visible to anyone
+/// but the blog's owner; queued and scheduled ones are published by the
+/// server when their `publishAt` comes.
 #[derive(
-    Debug, diesel::Associations, diesel::Identifiable, diesel::Queryable, graphql::SimpleObject,
+    Debug, Clone, Copy, PartialEq, Eq, diesel::AsExpression, diesel::FromSqlRow, graphql::Enum,
 )]
-#[belongs_to(User)]
-#[graphql(complex)]
-pub struct Blog {
-    #[graphql(skip)]
-    pub _rowid: i32,
-    pub created_at: DateTime,
-    #[graphql(skip)]
-    pub deleted_at: Option<DateTime>,
-    pub id: uuid::Uuid,
-    pub slug: String,
-    pub title: String,
-    pub updated_at: DateTime,
-    #[graphql(skip)]
-    pub user_id: uuid::Uuid,
+#[sql_type = "Text"]
+pub enum PostState {
+    DRAFT,
+    PRIVATE,
+    PUBLISHED,
+    QUEUED,
+    SCHEDULED,
 }
 
-#[graphql::ComplexObject]
-impl Blog {
-    pub async fn posts(
-        &self,
-        ctx: &Context<'_>,
-        first: i64,
-        after: Option<Cursor>,
-    ) -> graphql::Result<Connection<Post>> {
-        let after = after.unwrap_or_default();
+impl PostState {
+    pub fn all() -> Vec<Self> {
+        vec![
+            Self::DRAFT,
+            Self::PRIVATE,
+            Self::PUBLISHED,
+            Self::QUEUED,
+            Self::SCHEDULED,
+        ]
+    }
 
-        let pool = ctx.data_unchecked::<crate::db::Pool>();
-        let nodes: Vec<Post> = Post::belonging_to(self)
-            .filter(crate::schema::posts::_rowid.gt(after._rowid))
-            .limit(first)
-            .get_results(&pool.get()?)?;
-
-        Ok(Connection {
-            edges: nodes.into_iter().map(Edge::from).collect(),
-            page_info: PageInfo {
-                has_next_page: crate::schema::posts::table
-                    .count()
-                    .get_result::<i64>(&pool.get()?)?
-                    > (after._rowid as i64 + 1),
-                has_previous_page: after._rowid > 0,
-            },
+    /// Works out the `publish_at` of a new post in this state from the one
+    /// asked for, which only scheduled posts take and must be in the future.
+    /// Queued posts get theirs when their blog's queue is reslotted.
+    pub fn publish_at(
+        self,
+        publish_at: Option<DateTime>,
+    ) -> Result<Option<DateTime>, PostStateError> {
+        match (self, publish_at) {
+            (Self::SCHEDULED, Some(at)) if at > chrono::Utc::now() => Ok(Some(at)),
+            (Self::SCHEDULED, Some(_)) => Err(PostStateError::PublishAtInPast),
+            (Self::SCHEDULED, None) => Err(PostStateError::PublishAtRequired),
+            (_, Some(_)) => Err(PostStateError::PublishAtNotAllowed),
+            (Self::PUBLISHED, None) => Ok(Some(chrono::Utc::now())),
+            (_, None) => Ok(None),
+        }
+    }
+
+    /// Checks that a post in this state, with `current` as its `publish_at`,
+    /// can move to `to`, and works out its new `publish_at`.
+    ///
+    /// Published posts can only be made private, which keeps the time they
+    /// were published for when they're made public again. Scheduled posts can
+    /// be rescheduled.
+    pub fn transition(
+        self,
+        current: Option<DateTime>,
+        to: Self,
+        publish_at: Option<DateTime>,
+    ) -> Result<Option<DateTime>, PostStateError> {
+        let allowed = match (self, to) {
+            (Self::PUBLISHED, to) => to == Self::PRIVATE,
+            (Self::SCHEDULED, Self::SCHEDULED) => true,
+            (from, to) => from != to,
+        };
+        if !allowed {
+            return Err(PostStateError::InvalidTransition(self, to));
+        }
+
+        let publish_at = to.publish_at(publish_at)?;
+        Ok(match (self, to) {
+            (Self::PUBLISHED, Self::PRIVATE) => current,
+            (Self::PRIVATE, Self::PUBLISHED) => current.or(publish_at),
+            _ => publish_at,
         })
     }
+}
 
-    pub async fn user(&self, ctx: &Context<'_>) -> graphql::Result<User> {
-        let pool = ctx.data_unchecked::<crate::db::Pool>();
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParsePostStateError;
 
-        Ok(users::table.find(self.user_id).get_result(&pool.get()?)?)
+impl Display for ParsePostStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        "unrecognized PostState variant".fmt(f)
     }
 }
 
-impl Node for Blog {
-    fn cursor(&self) -> Cursor {
-        Cursor {
-            _rowid: self._rowid,
-            ty: String::from("Blog"),
+impl std::error::Error for ParsePostStateError {}
+
+impl FromStr for PostState {
+    type Err = ParsePostStateError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "DRAFT" => Ok(Self::DRAFT),
+            "PRIVATE" => Ok(Self::PRIVATE),
+            "PUBLISHED" => Ok(Self::PUBLISHED),
+            "QUEUED" => Ok(Self::QUEUED),
+            "SCHEDULED" => Ok(Self::SCHEDULED),
+            _ => Err(ParsePostStateError),
         }
     }
 }
 
-#[derive(Debug, diesel::Insertable, graphql::InputObject)]
-#[table_name = "blogs"]
-pub struct BlogCreateInput {
-    pub slug: String,
-    pub title: String,
-    pub user_id: uuid::Uuid,
+impl Display for PostState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        (match self {
+            Self::DRAFT => "DRAFT",
+            Self::PRIVATE => "PRIVATE",
+            Self::PUBLISHED => "PUBLISHED",
+            Self::QUEUED => "QUEUED",
+            Self::SCHEDULED => "SCHEDULED",
+        })
+        .fmt(f)
+    }
 }
 
-#[derive(Debug, graphql::SimpleObject)]
-pub struct BlogCreateOutput {
-    pub blog: Blog,
+impl<DB> FromSql<Text, DB> for PostState
+where
+    DB: Backend,
+    String: FromSql<Text, DB>,
+{
+    fn from_sql(bytes: Option<&DB::RawValue>) -> diesel::deserialize::Result<Self> {
+        Ok(String::from_sql(bytes)?.parse()?)
+    }
 }
 
-#[derive(Debug, graphql::SimpleObject)]
-#[graphql(concrete(name = "BlogConnection", params(Blog)))]
-#[graphql(concrete(name = "PostConnection", params(Post)))]
-#[graphql(concrete(name = "UserConnection", params(User)))]
-pub struct Connection<T: Node>
+impl<DB> ToSql<Text, DB> for PostState
 where
-    Edge<T>: graphql::OutputType,
+    DB: Backend,
+    String: ToSql<Text, DB>,
 {
-    pub edges: Vec<Edge<T>>,
-    pub page_info: PageInfo,
+    fn to_sql<W: std::io::Write>(
+        &self,
+        out: &mut diesel::serialize::Output<W, DB>,
+    ) -> diesel::serialize::Result {
+        self.to_string().to_sql(out)
+    }
 }
 
-#[derive(Debug, Default, serde::Serialize, serde::Deserialize)]
-pub struct Cursor {
-    pub _rowid: i32,
-    pub ty: String,
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PostStateError {
+    InvalidTransition(PostState, PostState),
+    PublishAtInPast,
+    PublishAtNotAllowed,
+    PublishAtRequired,
 }
 
-#[graphql::Scalar]
-impl graphql::ScalarType for Cursor {
-    fn parse(value: graphql::Value) -> graphql::InputValueResult<Self> {
-        if let graphql::Value::String(value) = value {
-            let value = base64::decode(value)?;
-            Ok(serde_json::from_slice(&*value)?)
-        } else {
-            Err(graphql::InputValueError::expected_type(value))
+impl Display for PostStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidTransition(from, to) => write!(f, "{} posts can't be made {}", from, to),
+            Self::PublishAtInPast => "publishAt must be in the future".fmt(f),
+            Self::PublishAtNotAllowed => "publishAt can only be given for SCHEDULED posts".fmt(f),
+            Self::PublishAtRequired => "publishAt is required for SCHEDULED posts".fmt(f),
         }
     }
+}
 
-    fn to_value(&self) -> graphql::Value {
-        graphql::Value::String(base64::encode(serde_json::to_vec(&self).unwrap()))
-    }
+impl std::error::Error for PostStateError {}
+
+/// `publishAt` is required when scheduling a post, and can't be given
+/// otherwise.
+#[derive(Debug, graphql::InputObject)]
+pub struct PostTransitionInput {
+    pub id: uuid::Uuid,
+    pub publish_at: Option<DateTime>,
+    pub state: PostState,
 }
 
-pub type DateTime = chrono::DateTime<chrono::Utc>;
+#[derive(Debug, graphql::SimpleObject)]
+pub struct PostTransitionOutput {
+    pub post: Post,
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct PostUnlikeInput {
+    pub post_id: uuid::Uuid,
+    pub user_id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct PostUnlikeOutput {
+    pub post: Post,
+}
+
+/// `tags` replaces the post's tags when given; `null` removes them all.
+#[derive(Debug, graphql::InputObject)]
+pub struct PostUpdateInput {
+    pub content: MaybeUndefined<Vec<ContentBlockInput>>,
+    pub expected_updated_at: DateTime,
+    pub id: uuid::Uuid,
+    pub slug: MaybeUndefined<String>,
+    pub tags: MaybeUndefined<Vec<String>>,
+}
+
+#[derive(Debug, Default, PartialEq, diesel::AsChangeset)]
+#[table_name = "posts"]
+pub struct PostChangeset {
+    pub content: Option<PostContent>,
+    pub slug: Option<String>,
+    /// Only set when nothing else on the row changes (i.e. only the tags do),
+    /// since the `updated_at` trigger ignores no-op updates.
+    pub updated_at: Option<DateTime>,
+}
+
+impl PostChangeset {
+    /// Splits `input` into the changes to the post's row and its new tags, if
+    /// they change.
+    pub fn new(input: PostUpdateInput) -> Result<(Self, Option<Vec<String>>), graphql::Error> {
+        let tags = if input.tags.is_undefined() {
+            None
+        } else {
+            Some(normalize_tags(&input.tags.take().unwrap_or_default())?)
+        };
+
+        let mut changeset = Self {
+            content: required_field("content", input.content)?
+                .map(PostContent::try_from)
+                .transpose()?,
+            slug: required_field("slug", input.slug)?,
+            updated_at: None,
+        };
+
+        if changeset == Self::default() {
+            if tags.is_none() {
+                return Err(PatchError::Empty.into());
+            }
+            changeset.updated_at = Some(chrono::Utc::now());
+        }
+
+        Ok((changeset, tags))
+    }
+}
 
 #[derive(Debug, graphql::SimpleObject)]
-#[graphql(concrete(name = "BlogEdge", params(Blog)))]
-#[graphql(concrete(name = "PostEdge", params(Post)))]
-#[graphql(concrete(name = "UserEdge", params(User)))]
-pub struct Edge<T: Node> {
-    pub cursor: Cursor,
-    pub node: T,
+pub struct PostUpdateOutput {
+    pub post: Post,
+}
+
+#[derive(Debug, diesel::Queryable)]
+pub struct PostTag {
+    pub created_at: DateTime,
+    pub position: i32,
+    pub post_id: uuid::Uuid,
+    pub tag_id: uuid::Uuid,
 }
 
-impl<T: Node> From<T> for Edge<T> {
-    fn from(node: T) -> Self {
-        Self {
-            cursor: node.cursor(),
-            node,
+impl PostTag {
+    /// Attaches already-normalized tag names to a post, creating any tags that
+    /// don't exist yet. Tags keep the order they were given in.
+    pub fn attach(conn: &PgConnection, post_id: uuid::Uuid, names: &[String]) -> QueryResult<()> {
+        if names.is_empty() {
+            return Ok(());
         }
+
+        diesel::insert_into(tags::table)
+            .values(
+                names
+                    .iter()
+                    .map(|name| tags::name.eq(name))
+                    .collect::<Vec<_>>(),
+            )
+            .on_conflict_do_nothing()
+            .execute(conn)?;
+
+        let ids: HashMap<String, uuid::Uuid> = tags::table
+            .filter(tags::name.eq_any(names))
+            .select((tags::name, tags::id))
+            .get_results(conn)?
+            .into_iter()
+            .collect();
+
+        diesel::insert_into(post_tags::table)
+            .values(
+                names
+                    .iter()
+                    .enumerate()
+                    .map(|(position, name)| {
+                        (
+                            post_tags::position.eq(position as i32),
+                            post_tags::post_id.eq(post_id),
+                            post_tags::tag_id.eq(ids[name]),
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .execute(conn)?;
+
+        Ok(())
     }
 }
 
+/// Takes a code from the user's authenticator app, or one of their current
+/// recovery codes.
+#[derive(Debug, graphql::InputObject)]
+pub struct RecoveryCodesRegenerateInput {
+    pub code: String,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct RecoveryCodesRegenerateOutput {
+    /// The new codes, which replace all the old ones. They're only ever
+    /// returned here.
+    pub recovery_codes: Vec<String>,
+}
+
 #[derive(
     Debug, diesel::Associations, diesel::Identifiable, diesel::Queryable, graphql::SimpleObject,
 )]
-#[belongs_to(User)]
-pub struct EmailAccount {
+#[belongs_to(Blog)]
+#[belongs_to(Post)]
+#[graphql(complex)]
+#[table_name = "replies"]
+pub struct Reply {
     #[graphql(skip)]
     pub _rowid: i32,
+    #[graphql(skip)]
+    pub blog_id: uuid::Uuid,
     pub created_at: DateTime,
     #[graphql(skip)]
     pub deleted_at: Option<DateTime>,
     pub id: uuid::Uuid,
-    pub provider_account_id: String,
-    pub updated_at: DateTime,
     #[graphql(skip)]
-    pub user_id: uuid::Uuid,
+    pub post_id: uuid::Uuid,
+    pub text: String,
+    pub updated_at: DateTime,
 }
 
-#[derive(Debug, diesel::Insertable, graphql::InputObject)]
-#[table_name = "email_accounts"]
-pub struct EmailAccountCreateInput {
-    pub provider_account_id: String,
-    pub user_id: uuid::Uuid,
+#[graphql::ComplexObject]
+impl Reply {
+    pub async fn blog(&self, ctx: &Context<'_>) -> graphql::Result<Blog> {
+        let loader = ctx.data_unchecked::<DataLoader<BlogLoader>>();
+
+        Ok(loader
+            .load_one(self.blog_id)
+            .await?
+            .ok_or("blog not found")?)
+    }
+
+    pub async fn post(&self, ctx: &Context<'_>) -> graphql::Result<Post> {
+        let loader = ctx.data_unchecked::<DataLoader<PostLoader>>();
+
+        Ok(loader
+            .load_one(self.post_id)
+            .await?
+            .ok_or("post not found")?)
+    }
 }
 
-#[derive(Debug, graphql::SimpleObject)]
-pub struct EmailAccountCreateOutput {
-    pub email_account: EmailAccount,
+impl Node for Reply {
+    fn position(&self) -> Position {
+        Position {
+            _rowid: self._rowid,
+            created_at: Some(self.created_at),
+            id: Some(self.id),
+            publish_at: None,
+        }
+    }
 }
 
+/// What an access token can do beyond what anyone signed out can. Sessions can
+/// do everything, but access tokens can only do what their scopes allow, and
+/// never manage how their user signs in or what else can act for them.
 #[derive(
-    Debug, Clone, Copy, PartialEq, Eq, diesel::AsExpression, diesel::FromSqlRow, graphql::Enum,
+    Debug,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    PartialOrd,
+    Ord,
+    diesel::AsExpression,
+    diesel::FromSqlRow,
+    graphql::Enum,
 )]
-#[graphql(name = "OAuthAccountProvider")]
+#[allow(non_camel_case_types)]
 #[sql_type = "Text"]
-pub enum OAuthAccountProvider {
-    APPLE,
-    GITHUB,
-    GOOGLE,
+pub enum Scope {
+    /// `blogs:read`: read the dashboard.
+    BLOGS_READ,
+    /// `blogs:write`: create, update, delete, restore and follow blogs.
+    BLOGS_WRITE,
+    /// `posts:read`: see unpublished posts, including blogs' queues.
+    POSTS_READ,
+    /// `posts:write`: create, update, delete and restore posts, and like,
+    /// reblog and reply to them.
+    POSTS_WRITE,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct ParseOAuthAccountProviderError;
+pub struct ParseScopeError;
 
-impl Display for ParseOAuthAccountProviderError {
+impl Display for ParseScopeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        "unrecognized OAuthAccountProvider variant".fmt(f)
+        "unrecognized Scope variant".fmt(f)
     }
 }
 
-impl std::error::Error for ParseOAuthAccountProviderError {}
+impl std::error::Error for ParseScopeError {}
 
-impl FromStr for OAuthAccountProvider {
-    type Err = ParseOAuthAccountProviderError;
+impl FromStr for Scope {
+    type Err = ParseScopeError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s {
-            "APPLE" => Ok(Self::APPLE),
-            "GITHUB" => Ok(Self::GITHUB),
-            "GOOGLE" => Ok(Self::GOOGLE),
-            _ => Err(ParseOAuthAccountProviderError),
+            "blogs:read" => Ok(Self::BLOGS_READ),
+            "blogs:write" => Ok(Self::BLOGS_WRITE),
+            "posts:read" => Ok(Self::POSTS_READ),
+            "posts:write" => Ok(Self::POSTS_WRITE),
+            _ => Err(ParseScopeError),
         }
     }
 }
 
-impl Display for OAuthAccountProvider {
+impl Display for Scope {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         (match self {
-            Self::APPLE => "APPLE",
-            Self::GITHUB => "GITHUB",
-            Self::GOOGLE => "GOOGLE",
+            Self::BLOGS_READ => "blogs:read",
+            Self::BLOGS_WRITE => "blogs:write",
+            Self::POSTS_READ => "posts:read",
+            Self::POSTS_WRITE => "posts:write",
         })
         .fmt(f)
     }
 }
 
-impl<DB> FromSql<Text, DB> for OAuthAccountProvider
+impl<DB> FromSql<Text, DB> for Scope
 where
     DB: Backend,
     String: FromSql<Text, DB>,
@@ -235,7 +3717,7 @@ where
     }
 }
 
-impl<DB> ToSql<Text, DB> for OAuthAccountProvider
+impl<DB> ToSql<Text, DB> for Scope
 where
     DB: Backend,
     String: ToSql<Text, DB>,
@@ -248,99 +3730,252 @@ where
     }
 }
 
+/// Sorts and deduplicates scopes, as they're stored.
+pub fn normalize_scopes(scopes: &[Scope]) -> Vec<Scope> {
+    let mut scopes = scopes.to_vec();
+    scopes.sort();
+    scopes.dedup();
+    scopes
+}
+
 #[derive(
-    Debug, diesel::Associations, diesel::Identifiable, diesel::Queryable, graphql::SimpleObject,
+    Debug,
+    Clone,
+    diesel::Associations,
+    diesel::Identifiable,
+    diesel::Queryable,
+    graphql::SimpleObject,
 )]
 #[belongs_to(User)]
-#[graphql(name = "OAuthAccount")]
-#[table_name = "oauth_accounts"]
-pub struct OAuthAccount {
+#[graphql(complex)]
+pub struct Session {
     #[graphql(skip)]
     pub _rowid: i32,
     pub created_at: DateTime,
-    #[graphql(skip)]
-    pub deleted_at: Option<DateTime>,
+    /// Pushed back each time the session is used, up to a fixed time after it
+    /// started.
+    pub expires_at: DateTime,
     pub id: uuid::Uuid,
-    pub provider: OAuthAccountProvider,
-    pub provider_access_token: String,
-    pub provider_access_token_expires_at: DateTime,
-    pub provider_account_id: String,
-    pub provider_refresh_token: String,
+    #[graphql(skip)]
+    pub token_hash: String,
     pub updated_at: DateTime,
     #[graphql(skip)]
     pub user_id: uuid::Uuid,
+    /// The IP address the session was last used from.
+    pub ip_address: Option<String>,
+    pub last_seen_at: DateTime,
+    /// The user agent of the client that started the session.
+    pub user_agent: Option<String>,
+}
+
+#[graphql::ComplexObject]
+impl Session {
+    /// Whether this is the session the request was made with.
+    pub async fn is_current(&self, ctx: &Context<'_>) -> bool {
+        auth::viewer(ctx)
+            .and_then(auth::Viewer::session)
+            .is_some_and(|session| session.id == self.id)
+    }
+}
+
+impl Session {
+    /// Deletes sessions that expired before `now`, returning how many.
+    pub fn delete_expired(conn: &PgConnection, now: DateTime) -> QueryResult<usize> {
+        diesel::delete(sessions::table.filter(sessions::expires_at.le(now))).execute(conn)
+    }
+}
+
+impl Node for Session {
+    fn position(&self) -> Position {
+        Position {
+            _rowid: self._rowid,
+            created_at: None,
+            id: None,
+            publish_at: None,
+        }
+    }
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct SessionRevokeAllOthersOutput {
+    /// How many sessions were revoked.
+    pub count: i64,
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct SessionRevokeInput {
+    pub id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct SessionRevokeOutput {
+    pub session: Session,
+}
+
+#[derive(Debug, Clone, diesel::Identifiable, diesel::Queryable, graphql::SimpleObject)]
+pub struct Tag {
+    #[graphql(skip)]
+    pub _rowid: i32,
+    pub created_at: DateTime,
+    #[graphql(skip)]
+    pub deleted_at: Option<DateTime>,
+    pub id: uuid::Uuid,
+    pub name: String,
+    pub updated_at: DateTime,
+}
+
+pub const MAX_TAGS: usize = 30;
+pub const MAX_TAG_LENGTH: usize = 140;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidTagError {
+    Empty,
+    TooLong(String),
+    TooMany,
+}
+
+impl Display for InvalidTagError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => "tags must not be empty".fmt(f),
+            Self::TooLong(name) => write!(
+                f,
+                "tag {:?} exceeds the maximum length of {} characters",
+                name, MAX_TAG_LENGTH
+            ),
+            Self::TooMany => write!(f, "posts may have at most {} tags", MAX_TAGS),
+        }
+    }
+}
+
+impl std::error::Error for InvalidTagError {}
+
+/// Normalizes a tag name for storage and lookup: a leading `#` is dropped,
+/// whitespace is trimmed and collapsed, and the result is lowercased.
+pub fn normalize_tag(name: &str) -> Result<String, InvalidTagError> {
+    let name = name
+        .trim_start_matches(|c: char| c == '#' || c.is_whitespace())
+        .split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase();
+
+    if name.is_empty() {
+        Err(InvalidTagError::Empty)
+    } else if name.chars().count() > MAX_TAG_LENGTH {
+        Err(InvalidTagError::TooLong(name))
+    } else {
+        Ok(name)
+    }
+}
+
+/// Normalizes a post's tags, dropping duplicates but otherwise keeping the
+/// order they were given in.
+pub fn normalize_tags(names: &[String]) -> Result<Vec<String>, InvalidTagError> {
+    let mut normalized = Vec::with_capacity(names.len());
+    for name in names {
+        let name = normalize_tag(name)?;
+        if !normalized.contains(&name) {
+            normalized.push(name);
+        }
+    }
+
+    if normalized.len() > MAX_TAGS {
+        Err(InvalidTagError::TooMany)
+    } else {
+        Ok(normalized)
+    }
 }
 
-#[derive(Debug, diesel::Insertable, graphql::InputObject)]
-#[graphql(name = "OAuthAccountCreateInput")]
-#[table_name = "oauth_accounts"]
-pub struct OAuthAccountCreateInput {
-    pub provider: OAuthAccountProvider,
-    pub provider_access_token: String,
-    pub provider_access_token_expires_at: DateTime,
-    pub provider_account_id: String,
-    pub provider_refresh_token: String,
-    pub user_id: uuid::Uuid,
+#[derive(Debug, graphql::InputObject)]
+pub struct TotpConfirmInput {
+    /// A code from the authenticator app the secret was added to.
+    pub code: String,
 }
 
 #[derive(Debug, graphql::SimpleObject)]
-#[graphql(name = "OAuthAccountCreateOutput")]
-pub struct OAuthAccountCreateOutput {
-    pub oauth_account: OAuthAccount,
+pub struct TotpConfirmOutput {
+    /// One-time codes for signing in without the authenticator app. They're
+    /// only ever returned here.
+    pub recovery_codes: Vec<String>,
+    pub user: User,
+}
+
+/// Takes a code from the user's authenticator app, or one of their recovery
+/// codes.
+#[derive(Debug, graphql::InputObject)]
+pub struct TotpDisableInput {
+    pub code: String,
 }
 
 #[derive(Debug, graphql::SimpleObject)]
-pub struct PageInfo {
-    pub has_next_page: bool,
-    pub has_previous_page: bool,
+pub struct TotpDisableOutput {
+    pub user: User,
 }
 
-#[derive(
-    Debug, diesel::Associations, diesel::Identifiable, diesel::Queryable, graphql::SimpleObject,
-)]
-#[belongs_to(Blog)]
-#[graphql(complex)]
-pub struct Post {
-    #[graphql(skip)]
-    pub _rowid: i32,
-    #[graphql(skip)]
-    pub blog_id: uuid::Uuid,
-    pub created_at: DateTime,
-    #[graphql(skip)]
-    pub deleted_at: Option<DateTime>,
-    pub id: uuid::Uuid,
-    pub slug: String,
-    pub updated_at: DateTime,
+#[derive(Debug, graphql::SimpleObject)]
+pub struct TotpEnrollOutput {
+    /// The `otpauth://` URI to show as a QR code for authenticator apps to
+    /// scan.
+    pub otpauth_uri: String,
+    /// The secret in base32, for entering into authenticator apps by hand.
+    pub secret: String,
+    pub user: User,
 }
 
-#[graphql::ComplexObject]
-impl Post {
-    pub async fn blog(&self, ctx: &Context<'_>) -> graphql::Result<Blog> {
-        let pool = ctx.data_unchecked::<crate::db::Pool>();
+#[derive(Debug)]
+pub enum TotpError {
+    AlreadyEnabled,
+    ChallengeExpired,
+    Crypto(CryptoError),
+    InvalidCode,
+    /// Too many wrong codes in a row; no more are checked until then.
+    LockedOut(DateTime),
+    NotEnabled,
+    NotEnrolled,
+    Query(diesel::result::Error),
+}
 
-        Ok(blogs::table.find(self.blog_id).get_result(&pool.get()?)?)
+impl Display for TotpError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::AlreadyEnabled => "two-factor authentication is already on".fmt(f),
+            Self::ChallengeExpired => "this sign-in has expired; sign in again".fmt(f),
+            Self::Crypto(err) => err.fmt(f),
+            Self::InvalidCode => "invalid code".fmt(f),
+            Self::LockedOut(until) => write!(
+                f,
+                "too many wrong codes; try again after {}",
+                until.format("%-d %B %Y, %H:%M UTC")
+            ),
+            Self::NotEnabled => "two-factor authentication is not on".fmt(f),
+            Self::NotEnrolled => "call totpEnroll first".fmt(f),
+            Self::Query(err) => err.fmt(f),
+        }
     }
 }
 
-impl Node for Post {
-    fn cursor(&self) -> Cursor {
-        Cursor {
-            _rowid: self._rowid,
-            ty: String::from("Post"),
-        }
+impl std::error::Error for TotpError {}
+
+impl From<CryptoError> for TotpError {
+    fn from(err: CryptoError) -> Self {
+        Self::Crypto(err)
     }
 }
 
-#[derive(Debug, diesel::Insertable, graphql::InputObject)]
-#[table_name = "posts"]
-pub struct PostCreateInput {
-    pub blog_id: uuid::Uuid,
-    pub slug: String,
+impl From<diesel::result::Error> for TotpError {
+    fn from(err: diesel::result::Error) -> Self {
+        Self::Query(err)
+    }
 }
 
+/// One ancestor in a reblog trail. `post` is null when the ancestor post or its
+/// blog has been deleted.
 #[derive(Debug, graphql::SimpleObject)]
-pub struct PostCreateOutput {
-    pub post: Post,
+pub struct TrailItem {
+    pub blog: Option<Blog>,
+    pub is_deleted: bool,
+    pub post: Option<Post>,
 }
 
 #[derive(
@@ -408,7 +4043,7 @@ where
     }
 }
 
-#[derive(Debug, diesel::Identifiable, diesel::Queryable, graphql::SimpleObject)]
+#[derive(Debug, Clone, diesel::Identifiable, diesel::Queryable, graphql::SimpleObject)]
 #[graphql(complex)]
 pub struct User {
     #[graphql(skip)]
@@ -416,72 +4051,1007 @@ pub struct User {
     pub created_at: DateTime,
     #[graphql(skip)]
     pub deleted_at: Option<DateTime>,
+    #[graphql(skip)]
     pub id: uuid::Uuid,
     pub role: UserRole,
     pub updated_at: DateTime,
+    #[graphql(skip)]
+    pub totp_enabled_at: Option<DateTime>,
+    /// The time step of the last TOTP code used, which can't be used again.
+    #[graphql(skip)]
+    pub totp_last_step: Option<i64>,
+    /// Set by `totpEnroll`, but only in use once `totp_enabled_at` is.
+    #[graphql(skip)]
+    pub totp_secret: Option<Sealed>,
+    /// A link to the user's profile picture, which is hosted elsewhere.
+    pub avatar_url: Option<String>,
+    pub bio: Option<String>,
+    pub display_name: Option<String>,
+    /// Unique ignoring case, but shown in the case it was chosen in. Users who
+    /// signed up with an OAuth provider have none until they pick one.
+    pub username: Option<String>,
+    #[graphql(skip)]
+    pub signup_token_expires_at: Option<DateTime>,
+    /// Cleared once the token is used to attach the user's first account.
+    #[graphql(skip)]
+    pub signup_token_hash: Option<String>,
+    /// Wrong second-factor codes since the last right one.
+    #[graphql(skip)]
+    pub second_factor_failures: i32,
+    #[graphql(skip)]
+    pub second_factor_locked_until: Option<DateTime>,
 }
 
 #[graphql::ComplexObject]
 impl User {
+    /// The user's access tokens that still work, newest first: personal
+    /// access tokens, and those issued to OAuth clients they've authorized.
+    pub async fn access_tokens(&self, ctx: &Context<'_>) -> graphql::Result<Vec<AccessToken>> {
+        crate::policy::require_user_session(ctx, self.id)?;
+
+        let user_id = self.id;
+        let pool = ctx.data_unchecked::<crate::db::Pool>();
+
+        pool.run(move |conn| -> graphql::Result<_> {
+            let now = chrono::Utc::now();
+            Ok(access_tokens::table
+                .filter(access_tokens::user_id.eq(user_id))
+                .filter(
+                    access_tokens::expires_at
+                        .is_null()
+                        .or(access_tokens::expires_at.gt(now))
+                        .or(access_tokens::refresh_expires_at.gt(now)),
+                )
+                .order(access_tokens::_rowid.desc())
+                .get_results(conn)?)
+        })
+        .await
+    }
+
     pub async fn blogs(
         &self,
         ctx: &Context<'_>,
-        first: i64,
+        first: Option<i64>,
         after: Option<Cursor>,
+        last: Option<i64>,
+        before: Option<Cursor>,
     ) -> graphql::Result<Connection<Blog>> {
-        let after = after.unwrap_or_default();
+        let page = PageArgs::new(first, after, last, before)?;
 
-        let pool = ctx.data_unchecked::<crate::db::Pool>();
-        let nodes: Vec<Blog> = Blog::belonging_to(self)
-            .filter(crate::schema::blogs::_rowid.gt(after._rowid))
-            .limit(first)
-            .get_results(&pool.get()?)?;
-
-        Ok(Connection {
-            edges: nodes.into_iter().map(Edge::from).collect(),
-            page_info: PageInfo {
-                has_next_page: crate::schema::blogs::table
-                    .count()
-                    .get_result::<i64>(&pool.get()?)?
-                    > (after._rowid as i64 + 1),
-                has_previous_page: after._rowid > 0,
+        let user_id = self.id;
+        let blogs = move || {
+            blogs::table
+                .filter(blogs::user_id.eq(user_id))
+                .filter(blogs::deleted_at.is_null())
+        };
+
+        paginate(
+            ctx,
+            &page,
+            Some(self.id),
+            Order::RowidAsc,
+            move |conn, window| {
+                rowid_window(blogs().into_boxed(), blogs::_rowid, window).get_results(conn)
             },
-        })
+            move |conn| blogs().count().get_result(conn),
+        )
+        .await
     }
 
     pub async fn email_account(&self, ctx: &Context<'_>) -> graphql::Result<Option<EmailAccount>> {
-        let pool = ctx.data_unchecked::<crate::db::Pool>();
+        crate::policy::require_user_session(ctx, self.id)?;
+
+        let loader = ctx.data_unchecked::<DataLoader<EmailAccountLoader>>();
+
+        Ok(loader.load_one(self.id).await?)
+    }
+
+    pub async fn following(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<i64>,
+        after: Option<Cursor>,
+        last: Option<i64>,
+        before: Option<Cursor>,
+    ) -> graphql::Result<Connection<Blog>> {
+        let page = PageArgs::new(first, after, last, before)?;
+
+        let user_id = self.id;
+        let following = move || {
+            follows::table
+                .filter(follows::user_id.eq(user_id))
+                .inner_join(blogs::table)
+                .filter(blogs::deleted_at.is_null())
+        };
+
+        paginate(
+            ctx,
+            &page,
+            Some(self.id),
+            Order::RowidAsc,
+            move |conn, window| {
+                rowid_window(
+                    following().select(blogs::all_columns).into_boxed(),
+                    blogs::_rowid,
+                    window,
+                )
+                .get_results(conn)
+            },
+            move |conn| following().count().get_result(conn),
+        )
+        .await
+    }
+
+    pub async fn following_count(&self, ctx: &Context<'_>) -> graphql::Result<i64> {
+        let loader = ctx.data_unchecked::<DataLoader<FollowingCountLoader>>();
+
+        Ok(loader.load_one(self.id).await?.unwrap_or_default())
+    }
 
-        Ok(EmailAccount::belonging_to(self)
-            .get_result(&pool.get()?)
-            .optional()?)
+    /// A globally unique ID for fetching this user with `node`.
+    pub async fn id(&self, _ctx: &Context<'_>) -> graphql::Result<graphql::ID> {
+        Ok(GlobalId::new(NodeType::User, self.id).into())
     }
 
     pub async fn oauth_accounts(&self, ctx: &Context<'_>) -> graphql::Result<Vec<OAuthAccount>> {
+        crate::policy::require_user_session(ctx, self.id)?;
+
+        let loader = ctx.data_unchecked::<DataLoader<OAuthAccountsLoader>>();
+
+        Ok(loader.load_one(self.id).await?.unwrap_or_default())
+    }
+
+    /// The OAuth clients the user has registered, oldest first.
+    pub async fn oauth_clients(&self, ctx: &Context<'_>) -> graphql::Result<Vec<OAuthClient>> {
+        crate::policy::require_user_session(ctx, self.id)?;
+
+        let user_id = self.id;
         let pool = ctx.data_unchecked::<crate::db::Pool>();
 
-        Ok(OAuthAccount::belonging_to(self)
-            .order_by(oauth_accounts::created_at.asc())
-            .get_results(&pool.get()?)?)
+        pool.run(move |conn| -> graphql::Result<_> {
+            Ok(oauth_clients::table
+                .filter(oauth_clients::user_id.eq(user_id))
+                .filter(oauth_clients::deleted_at.is_null())
+                .order(oauth_clients::_rowid.asc())
+                .get_results(conn)?)
+        })
+        .await
+    }
+
+    /// The user's sessions that haven't expired, newest first.
+    pub async fn sessions(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<i64>,
+        after: Option<Cursor>,
+        last: Option<i64>,
+        before: Option<Cursor>,
+    ) -> graphql::Result<Connection<Session>> {
+        crate::policy::require_user_session(ctx, self.id)?;
+
+        let page = PageArgs::new(first, after, last, before)?;
+
+        let user_id = self.id;
+        let sessions = move || {
+            sessions::table
+                .filter(sessions::user_id.eq(user_id))
+                .filter(sessions::expires_at.gt(diesel::dsl::now))
+        };
+
+        paginate(
+            ctx,
+            &page,
+            Some(self.id),
+            Order::RowidDesc,
+            move |conn, window| {
+                rowid_window(sessions().into_boxed(), sessions::_rowid, window).get_results(conn)
+            },
+            move |conn| sessions().count().get_result(conn),
+        )
+        .await
+    }
+
+    /// Whether signing in takes a code from an authenticator app as well.
+    pub async fn totp_enabled(&self, ctx: &Context<'_>) -> graphql::Result<bool> {
+        crate::policy::require_user_session(ctx, self.id)?;
+
+        Ok(self.totp_enabled_at.is_some())
+    }
+
+    /// The user's UUID, as taken by mutation inputs.
+    pub async fn uuid(&self) -> uuid::Uuid {
+        self.id
+    }
+}
+
+impl User {
+    /// Inserts `user`, returning it along with the plaintext sign-up token to
+    /// hand to the client, which stands in for a session when attaching the
+    /// user's first sign-in account.
+    pub fn create(conn: &PgConnection, user: &NewUser) -> Result<(Self, String), UserProfileError> {
+        let token = auth::generate_token();
+        let user = diesel::insert_into(users::table)
+            .values((
+                user,
+                users::signup_token_expires_at.eq(Some(
+                    chrono::Utc::now() + chrono::Duration::minutes(SIGNUP_TOKEN_LIFETIME_MINUTES),
+                )),
+                users::signup_token_hash.eq(Some(auth::hash_token(&token))),
+            ))
+            .returning(users::all_columns)
+            .get_result(conn)?;
+
+        Ok((user, token))
+    }
+
+    /// Uses up `user_id`'s sign-up token, returning whether `token` was it and
+    /// still good. Deleted users' tokens never are.
+    pub fn redeem_signup_token(
+        conn: &PgConnection,
+        user_id: uuid::Uuid,
+        token: &str,
+    ) -> QueryResult<bool> {
+        let redeemed = diesel::update(
+            users::table
+                .find(user_id)
+                .filter(users::deleted_at.is_null())
+                .filter(users::signup_token_hash.eq(auth::hash_token(token)))
+                .filter(users::signup_token_expires_at.gt(chrono::Utc::now())),
+        )
+        .set((
+            users::signup_token_expires_at.eq(None::<DateTime>),
+            users::signup_token_hash.eq(None::<String>),
+        ))
+        .execute(conn)?;
+
+        Ok(redeemed == 1)
+    }
+
+    /// Finds the user with `username`, ignoring case. Deleted users aren't
+    /// found, though their usernames stay taken in case they're restored.
+    pub fn find_by_username(conn: &PgConnection, username: &str) -> QueryResult<Option<Self>> {
+        users::table
+            .filter(lower(users::username).eq(username.trim().to_lowercase()))
+            .filter(users::deleted_at.is_null())
+            .get_result(conn)
+            .optional()
+    }
+
+    /// Soft-deletes a user along with their blogs (cascading to those blogs'
+    /// posts and replies) and sign-in accounts, and signs them out everywhere.
+    pub fn delete(conn: &PgConnection, id: uuid::Uuid) -> QueryResult<Self> {
+        conn.transaction(|| {
+            let user: Self =
+                diesel::update(users::table.find(id).filter(users::deleted_at.is_null()))
+                    .set(users::deleted_at.eq(Some(chrono::Utc::now())))
+                    .returning(users::all_columns)
+                    .get_result(conn)?;
+            Self::cascade(conn, user.id, None, user.deleted_at)?;
+            diesel::delete(sessions::table.filter(sessions::user_id.eq(user.id))).execute(conn)?;
+            Ok(user)
+        })
+    }
+
+    /// Turns on two-factor authentication, once the user shows they've added
+    /// the secret from `enroll_totp` to their authenticator app by entering a
+    /// code from it. Returns a fresh set of recovery codes.
+    pub fn confirm_totp(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        id: uuid::Uuid,
+        code: &str,
+    ) -> Result<(Self, Vec<String>), TotpError> {
+        conn.transaction(|| {
+            let user = Self::lock(conn, id)?;
+            if user.totp_enabled_at.is_some() {
+                return Err(TotpError::AlreadyEnabled);
+            }
+            let secret = user.totp_secret.as_ref().ok_or(TotpError::NotEnrolled)?;
+
+            let step = totp::verify(
+                &open_totp_secret(keyring, secret)?,
+                code,
+                chrono::Utc::now(),
+                None,
+            )
+            .ok_or(TotpError::InvalidCode)?;
+            let user = diesel::update(users::table.find(id))
+                .set((
+                    users::totp_enabled_at.eq(Some(chrono::Utc::now())),
+                    users::totp_last_step.eq(Some(step)),
+                ))
+                .returning(users::all_columns)
+                .get_result(conn)?;
+            let recovery_codes = Self::replace_recovery_codes(conn, id)?;
+
+            Ok((user, recovery_codes))
+        })
+    }
+
+    /// Turns off two-factor authentication, given a current code or a
+    /// recovery code. Personal access tokens and OAuth grants are revoked,
+    /// since they may have been issued to whoever is turning it off.
+    pub fn disable_totp(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        id: uuid::Uuid,
+        code: &str,
+    ) -> Result<Self, TotpError> {
+        // As in `LoginChallenge::answer`, wrong codes commit so they count.
+        let user = conn.transaction(|| -> Result<_, TotpError> {
+            let user = Self::lock(conn, id)?;
+            if user.totp_enabled_at.is_none() {
+                return Err(TotpError::NotEnabled);
+            }
+            if !Self::check_second_factor(conn, keyring, &user, code)? {
+                return Ok(None);
+            }
+
+            diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(id)))
+                .execute(conn)?;
+            AccessToken::delete_for_user(conn, id)?;
+            Ok(Some(
+                diesel::update(users::table.find(id))
+                    .set((
+                        users::totp_enabled_at.eq(None::<DateTime>),
+                        users::totp_last_step.eq(None::<i64>),
+                        users::totp_secret.eq(None::<Sealed>),
+                    ))
+                    .returning(users::all_columns)
+                    .get_result(conn)?,
+            ))
+        })?;
+
+        user.ok_or(TotpError::InvalidCode)
+    }
+
+    /// Generates a new TOTP secret for the user, returning it unsealed. It's
+    /// only used once `confirm_totp` is called; until then, enrolling again
+    /// replaces it.
+    pub fn enroll_totp(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        id: uuid::Uuid,
+    ) -> Result<(Self, Vec<u8>), TotpError> {
+        conn.transaction(|| {
+            if Self::lock(conn, id)?.totp_enabled_at.is_some() {
+                return Err(TotpError::AlreadyEnabled);
+            }
+
+            let secret = totp::generate_secret();
+            let user = diesel::update(users::table.find(id))
+                .set((
+                    users::totp_last_step.eq(None::<i64>),
+                    users::totp_secret.eq(Some(keyring.seal(&base64::encode(&secret)))),
+                ))
+                .returning(users::all_columns)
+                .get_result(conn)?;
+
+            Ok((user, secret))
+        })
+    }
+
+    /// Replaces the user's recovery codes, given a current code or one of the
+    /// old recovery codes.
+    pub fn regenerate_recovery_codes(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        id: uuid::Uuid,
+        code: &str,
+    ) -> Result<Vec<String>, TotpError> {
+        // As in `LoginChallenge::answer`, wrong codes commit so they count.
+        let codes = conn.transaction(|| -> Result<_, TotpError> {
+            let user = Self::lock(conn, id)?;
+            if user.totp_enabled_at.is_none() {
+                return Err(TotpError::NotEnabled);
+            }
+            if !Self::check_second_factor(conn, keyring, &user, code)? {
+                return Ok(None);
+            }
+
+            Ok(Some(Self::replace_recovery_codes(conn, id)?))
+        })?;
+
+        codes.ok_or(TotpError::InvalidCode)
+    }
+
+    /// Re-seals every TOTP secret that isn't sealed with the keyring's primary
+    /// key, returning how many users were updated, as
+    /// `OAuthAccount::reseal_tokens` does for provider tokens.
+    pub fn reseal_totp_secrets(
+        conn: &PgConnection,
+        keyring: &Keyring,
+    ) -> Result<usize, Box<dyn std::error::Error + Send + Sync>> {
+        let sealed_prefix = format!("v1.{}.%", keyring.primary_key_id());
+        let secrets: Vec<(uuid::Uuid, Option<Sealed>)> = users::table
+            .filter(users::totp_secret.not_like(&sealed_prefix))
+            .select((users::id, users::totp_secret))
+            .get_results(conn)?;
+
+        for (id, secret) in &secrets {
+            if let Some(secret) = secret
+                .as_ref()
+                .map(|secret| keyring.reseal(secret))
+                .transpose()?
+                .flatten()
+            {
+                diesel::update(users::table.find(id))
+                    .set(users::totp_secret.eq(Some(secret)))
+                    .execute(conn)?;
+            }
+        }
+
+        Ok(secrets.len())
+    }
+
+    pub fn update(
+        conn: &PgConnection,
+        id: uuid::Uuid,
+        expected_updated_at: DateTime,
+        changeset: &UserChangeset,
+    ) -> Result<Option<Self>, UserProfileError> {
+        Ok(diesel::update(
+            users::table
+                .find(id)
+                .filter(users::deleted_at.is_null())
+                .filter(users::updated_at.eq(expected_updated_at)),
+        )
+        .set(changeset)
+        .returning(users::all_columns)
+        .get_result(conn)
+        .optional()?)
+    }
+
+    /// Sign-in accounts and sessions created while the user was deleted can't
+    /// be theirs, so they're removed rather than brought back with the rest.
+    pub fn restore(conn: &PgConnection, user: &Self) -> QueryResult<Self> {
+        conn.transaction(|| {
+            if let Some(deleted_at) = user.deleted_at {
+                let now = chrono::Utc::now();
+                diesel::update(
+                    email_accounts::table
+                        .filter(email_accounts::user_id.eq(user.id))
+                        .filter(email_accounts::deleted_at.is_null())
+                        .filter(email_accounts::created_at.ge(deleted_at)),
+                )
+                .set(email_accounts::deleted_at.eq(Some(now)))
+                .execute(conn)?;
+                diesel::update(
+                    oauth_accounts::table
+                        .filter(oauth_accounts::user_id.eq(user.id))
+                        .filter(oauth_accounts::deleted_at.is_null())
+                        .filter(oauth_accounts::created_at.ge(deleted_at)),
+                )
+                .set(oauth_accounts::deleted_at.eq(Some(now)))
+                .execute(conn)?;
+                diesel::delete(
+                    sessions::table
+                        .filter(sessions::user_id.eq(user.id))
+                        .filter(sessions::created_at.ge(deleted_at)),
+                )
+                .execute(conn)?;
+            }
+
+            Self::cascade(conn, user.id, user.deleted_at, None)?;
+            diesel::update(users::table.find(user.id))
+                .set(users::deleted_at.eq(None::<DateTime>))
+                .returning(users::all_columns)
+                .get_result(conn)
+        })
+    }
+
+    /// When the user's lockout from two-factor sign-in ends, if they're in
+    /// one.
+    pub fn second_factor_lockout(&self, now: DateTime) -> Option<DateTime> {
+        self.second_factor_locked_until.filter(|&until| until > now)
+    }
+
+    /// Checks a second factor for `user`, which must be locked: a TOTP code
+    /// that hasn't been used yet, or an unused recovery code. Either is used up
+    /// if it matches.
+    ///
+    /// Wrong codes count towards a lockout, which callers must commit even
+    /// though the check failed; while it lasts, nothing is checked.
+    fn check_second_factor(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        user: &Self,
+        code: &str,
+    ) -> Result<bool, TotpError> {
+        let now = chrono::Utc::now();
+        if let Some(until) = user.second_factor_lockout(now) {
+            return Err(TotpError::LockedOut(until));
+        }
+
+        let matched = Self::match_second_factor(conn, keyring, user, code)?;
+        if matched {
+            if user.second_factor_failures > 0 {
+                diesel::update(users::table.find(user.id))
+                    .set((
+                        users::second_factor_failures.eq(0),
+                        users::second_factor_locked_until.eq(None::<DateTime>),
+                    ))
+                    .execute(conn)?;
+            }
+        } else {
+            let failures = user.second_factor_failures + 1;
+            let locked_until = if failures % SECOND_FACTOR_MAX_FAILURES == 0 {
+                let doublings = (failures / SECOND_FACTOR_MAX_FAILURES - 1).min(16) as u32;
+                let minutes = (SECOND_FACTOR_LOCKOUT_MINUTES << doublings)
+                    .min(SECOND_FACTOR_MAX_LOCKOUT_HOURS * 60);
+                Some(now + chrono::Duration::minutes(minutes))
+            } else {
+                user.second_factor_locked_until
+            };
+            diesel::update(users::table.find(user.id))
+                .set((
+                    users::second_factor_failures.eq(failures),
+                    users::second_factor_locked_until.eq(locked_until),
+                ))
+                .execute(conn)?;
+        }
+
+        Ok(matched)
+    }
+
+    fn match_second_factor(
+        conn: &PgConnection,
+        keyring: &Keyring,
+        user: &Self,
+        code: &str,
+    ) -> Result<bool, TotpError> {
+        let secret = match (&user.totp_enabled_at, &user.totp_secret) {
+            (Some(_), Some(secret)) => open_totp_secret(keyring, secret)?,
+            _ => return Ok(false),
+        };
+
+        if totp::is_totp_code(code) {
+            let step = match totp::verify(&secret, code, chrono::Utc::now(), user.totp_last_step) {
+                Some(step) => step,
+                None => return Ok(false),
+            };
+            diesel::update(users::table.find(user.id))
+                .set(users::totp_last_step.eq(Some(step)))
+                .execute(conn)?;
+            return Ok(true);
+        }
+
+        let used = diesel::update(
+            recovery_codes::table
+                .filter(recovery_codes::user_id.eq(user.id))
+                .filter(
+                    recovery_codes::code_hash
+                        .eq(auth::hash_token(&totp::normalize_recovery_code(code))),
+                )
+                .filter(recovery_codes::used_at.is_null()),
+        )
+        .set(recovery_codes::used_at.eq(Some(chrono::Utc::now())))
+        .execute(conn)?;
+        Ok(used > 0)
+    }
+
+    /// Locks the user's row for the rest of the transaction. Deleted users
+    /// aren't found, and can't be deleted by someone else in the meantime.
+    pub fn lock(conn: &PgConnection, id: uuid::Uuid) -> QueryResult<Self> {
+        users::table
+            .find(id)
+            .filter(users::deleted_at.is_null())
+            .for_update()
+            .get_result(conn)
+    }
+
+    /// Replaces the user's recovery codes with new ones, returning them. Only
+    /// their hashes are kept.
+    fn replace_recovery_codes(conn: &PgConnection, id: uuid::Uuid) -> QueryResult<Vec<String>> {
+        let codes = totp::generate_recovery_codes();
+
+        diesel::delete(recovery_codes::table.filter(recovery_codes::user_id.eq(id)))
+            .execute(conn)?;
+        diesel::insert_into(recovery_codes::table)
+            .values(
+                codes
+                    .iter()
+                    .map(|code| {
+                        (
+                            recovery_codes::code_hash
+                                .eq(auth::hash_token(&totp::normalize_recovery_code(code))),
+                            recovery_codes::user_id.eq(id),
+                        )
+                    })
+                    .collect::<Vec<_>>(),
+            )
+            .execute(conn)?;
+
+        Ok(codes)
+    }
+
+    fn cascade(
+        conn: &PgConnection,
+        user_id: uuid::Uuid,
+        from: Option<DateTime>,
+        to: Option<DateTime>,
+    ) -> QueryResult<()> {
+        let blog_ids: Vec<uuid::Uuid> = diesel::update(
+            blogs::table
+                .filter(blogs::user_id.eq(user_id))
+                .filter(blogs::deleted_at.is_not_distinct_from(from)),
+        )
+        .set(blogs::deleted_at.eq(to))
+        .returning(blogs::id)
+        .get_results(conn)?;
+        Blog::cascade(conn, &blog_ids, from, to)?;
+
+        diesel::update(
+            email_accounts::table
+                .filter(email_accounts::user_id.eq(user_id))
+                .filter(email_accounts::deleted_at.is_not_distinct_from(from)),
+        )
+        .set(email_accounts::deleted_at.eq(to))
+        .execute(conn)?;
+        diesel::update(
+            oauth_accounts::table
+                .filter(oauth_accounts::user_id.eq(user_id))
+                .filter(oauth_accounts::deleted_at.is_not_distinct_from(from)),
+        )
+        .set(oauth_accounts::deleted_at.eq(to))
+        .execute(conn)?;
+
+        Ok(())
     }
 }
 
+fn open_totp_secret(keyring: &Keyring, secret: &Sealed) -> Result<Vec<u8>, CryptoError> {
+    base64::decode(keyring.open(secret)?).map_err(|_| CryptoError::MalformedValue)
+}
+
 impl Node for User {
-    fn cursor(&self) -> Cursor {
-        Cursor {
+    fn position(&self) -> Position {
+        Position {
             _rowid: self._rowid,
-            ty: String::from("User"),
+            created_at: None,
+            id: None,
+            publish_at: None,
         }
     }
 }
 
-#[derive(Debug, diesel::Insertable, graphql::InputObject)]
-#[table_name = "users"]
+#[derive(Debug, graphql::InputObject)]
 pub struct UserCreateInput {
+    pub avatar_url: Option<String>,
+    pub bio: Option<String>,
+    pub display_name: Option<String>,
+    pub role: UserRole,
+    pub username: String,
+}
+
+#[derive(Debug, diesel::Insertable)]
+#[table_name = "users"]
+pub struct NewUser {
+    pub avatar_url: Option<String>,
+    pub bio: Option<String>,
+    pub display_name: Option<String>,
     pub role: UserRole,
+    pub username: Option<String>,
+}
+
+impl TryFrom<UserCreateInput> for NewUser {
+    type Error = UserProfileError;
+
+    fn try_from(input: UserCreateInput) -> Result<Self, Self::Error> {
+        let user = Self {
+            avatar_url: input
+                .avatar_url
+                .map(normalize_avatar_url)
+                .transpose()?
+                .flatten(),
+            bio: input.bio.and_then(|bio| normalize_profile_text(&bio)),
+            display_name: input
+                .display_name
+                .and_then(|name| normalize_profile_text(&name)),
+            role: input.role,
+            username: Some(normalize_username(&input.username)?),
+        };
+
+        validate_profile_lengths(user.bio.as_deref(), user.display_name.as_deref())?;
+        Ok(user)
+    }
 }
 
 #[derive(Debug, graphql::SimpleObject)]
 pub struct UserCreateOutput {
+    /// Pass this to `emailAccountCreate` to attach the user's first sign-in
+    /// account. It works once, for `SIGNUP_TOKEN_LIFETIME_MINUTES`.
+    pub signup_token: String,
+    pub user: User,
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct UserDeleteInput {
+    pub id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct UserDeleteOutput {
+    pub user: User,
+}
+
+pub const MAX_AVATAR_URL_LENGTH: usize = 2048;
+pub const MAX_BIO_LENGTH: usize = 500;
+pub const MAX_DISPLAY_NAME_LENGTH: usize = 64;
+
+#[derive(Debug)]
+pub enum UserProfileError {
+    BioTooLong,
+    DisplayNameTooLong,
+    InvalidAvatarUrl,
+    InvalidUsername(InvalidUsernameError),
+    Patch(PatchError),
+    Query(diesel::result::Error),
+    UsernameTaken,
+}
+
+impl Display for UserProfileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::BioTooLong => write!(
+                f,
+                "bio exceeds the maximum length of {} characters",
+                MAX_BIO_LENGTH
+            ),
+            Self::DisplayNameTooLong => write!(
+                f,
+                "displayName exceeds the maximum length of {} characters",
+                MAX_DISPLAY_NAME_LENGTH
+            ),
+            Self::InvalidAvatarUrl => write!(
+                f,
+                "avatarUrl must be an http or https URL of at most {} characters",
+                MAX_AVATAR_URL_LENGTH
+            ),
+            Self::InvalidUsername(err) => err.fmt(f),
+            Self::Patch(err) => err.fmt(f),
+            Self::Query(err) => err.fmt(f),
+            Self::UsernameTaken => "that username is taken".fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for UserProfileError {}
+
+impl From<diesel::result::Error> for UserProfileError {
+    fn from(err: diesel::result::Error) -> Self {
+        use diesel::result::{DatabaseErrorKind, Error};
+
+        match &err {
+            Error::DatabaseError(DatabaseErrorKind::UniqueViolation, info)
+                if info.constraint_name() == Some("users_username_key") =>
+            {
+                Self::UsernameTaken
+            }
+            _ => Self::Query(err),
+        }
+    }
+}
+
+impl From<InvalidUsernameError> for UserProfileError {
+    fn from(err: InvalidUsernameError) -> Self {
+        Self::InvalidUsername(err)
+    }
+}
+
+impl From<PatchError> for UserProfileError {
+    fn from(err: PatchError) -> Self {
+        Self::Patch(err)
+    }
+}
+
+/// Trims a display name or bio, treating one that's left blank as unset.
+fn normalize_profile_text(text: &str) -> Option<String> {
+    Some(text.trim())
+        .filter(|text| !text.is_empty())
+        .map(String::from)
+}
+
+/// Avatars are referenced by URL rather than uploaded. A blank URL unsets the
+/// avatar.
+fn normalize_avatar_url(url: String) -> Result<Option<String>, UserProfileError> {
+    let url = url.trim();
+    if url.is_empty() {
+        return Ok(None);
+    }
+
+    match url::Url::parse(url) {
+        Ok(parsed)
+            if matches!(parsed.scheme(), "http" | "https")
+                && parsed.has_host()
+                && url.len() <= MAX_AVATAR_URL_LENGTH =>
+        {
+            Ok(Some(parsed.into()))
+        }
+        _ => Err(UserProfileError::InvalidAvatarUrl),
+    }
+}
+
+fn validate_profile_lengths(
+    bio: Option<&str>,
+    display_name: Option<&str>,
+) -> Result<(), UserProfileError> {
+    if bio.is_some_and(|bio| bio.chars().count() > MAX_BIO_LENGTH) {
+        Err(UserProfileError::BioTooLong)
+    } else if display_name.is_some_and(|name| name.chars().count() > MAX_DISPLAY_NAME_LENGTH) {
+        Err(UserProfileError::DisplayNameTooLong)
+    } else {
+        Ok(())
+    }
+}
+
+#[derive(Debug, graphql::InputObject)]
+pub struct UserRestoreInput {
+    pub id: uuid::Uuid,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct UserRestoreOutput {
+    pub user: User,
+}
+
+/// Only admins can change `role`. A blank `avatarUrl`, `bio` or
+/// `displayName` clears it, as `null` does.
+#[derive(Debug, graphql::InputObject)]
+pub struct UserUpdateInput {
+    pub avatar_url: MaybeUndefined<String>,
+    pub bio: MaybeUndefined<String>,
+    pub display_name: MaybeUndefined<String>,
+    pub expected_updated_at: DateTime,
+    pub id: uuid::Uuid,
+    pub role: MaybeUndefined<UserRole>,
+    pub username: MaybeUndefined<String>,
+}
+
+#[derive(Debug, Default, PartialEq, diesel::AsChangeset)]
+#[table_name = "users"]
+pub struct UserChangeset {
+    pub avatar_url: Option<Option<String>>,
+    pub bio: Option<Option<String>>,
+    pub display_name: Option<Option<String>>,
+    pub role: Option<UserRole>,
+    pub username: Option<String>,
+}
+
+impl TryFrom<UserUpdateInput> for UserChangeset {
+    type Error = UserProfileError;
+
+    fn try_from(input: UserUpdateInput) -> Result<Self, Self::Error> {
+        let changeset = Self {
+            avatar_url: nullable_field(input.avatar_url)
+                .map(|url| url.map(normalize_avatar_url).transpose())
+                .transpose()?
+                .map(Option::flatten),
+            bio: nullable_field(input.bio)
+                .map(|bio| bio.and_then(|bio| normalize_profile_text(&bio))),
+            display_name: nullable_field(input.display_name)
+                .map(|name| name.and_then(|name| normalize_profile_text(&name))),
+            role: required_field("role", input.role)?,
+            username: required_field("username", input.username)?
+                .map(|username| normalize_username(&username))
+                .transpose()?,
+        };
+
+        validate_profile_lengths(
+            changeset.bio.as_ref().and_then(Option::as_deref),
+            changeset.display_name.as_ref().and_then(Option::as_deref),
+        )?;
+        if changeset == Self::default() {
+            Err(PatchError::Empty.into())
+        } else {
+            Ok(changeset)
+        }
+    }
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct UserUpdateOutput {
     pub user: User,
 }
+
+pub const MIN_USERNAME_LENGTH: usize = 3;
+pub const MAX_USERNAME_LENGTH: usize = 32;
+
+/// Usernames that would be mistaken for the site speaking, or that clash with
+/// the web app's own pages.
+const RESERVED_USERNAMES: &[&str] = &[
+    "about",
+    "admin",
+    "administrator",
+    "api",
+    "app",
+    "authorize",
+    "blog",
+    "dashboard",
+    "explore",
+    "graphql",
+    "help",
+    "login",
+    "logout",
+    "me",
+    "moderator",
+    "new",
+    "oauth",
+    "privacy",
+    "reset-password",
+    "root",
+    "search",
+    "security",
+    "settings",
+    "signin",
+    "signup",
+    "staff",
+    "support",
+    "system",
+    "terms",
+    "tumblr",
+    "user",
+    "users",
+    "verify-email",
+    "www",
+];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InvalidUsernameError {
+    InvalidCharacter(char),
+    InvalidEnds,
+    Reserved(String),
+    TooLong,
+    TooShort,
+}
+
+impl Display for InvalidUsernameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidCharacter(c) => write!(
+                f,
+                "usernames may only contain letters, digits, hyphens and underscores, not {:?}",
+                c
+            ),
+            Self::InvalidEnds => "usernames must start and end with a letter or digit".fmt(f),
+            Self::Reserved(username) => write!(f, "the username {:?} is reserved", username),
+            Self::TooLong => write!(
+                f,
+                "usernames may be at most {} characters long",
+                MAX_USERNAME_LENGTH
+            ),
+            Self::TooShort => write!(
+                f,
+                "usernames must be at least {} characters long",
+                MIN_USERNAME_LENGTH
+            ),
+        }
+    }
+}
+
+impl std::error::Error for InvalidUsernameError {}
+
+/// Checks a username, returning it trimmed. Case is kept, since usernames are
+/// only compared ignoring it.
+pub fn normalize_username(username: &str) -> Result<String, InvalidUsernameError> {
+    let username = username.trim();
+
+    if let Some(c) = username
+        .chars()
+        .find(|&c| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+    {
+        Err(InvalidUsernameError::InvalidCharacter(c))
+    } else if username.len() < MIN_USERNAME_LENGTH {
+        Err(InvalidUsernameError::TooShort)
+    } else if username.len() > MAX_USERNAME_LENGTH {
+        Err(InvalidUsernameError::TooLong)
+    } else if !username.starts_with(|c: char| c.is_ascii_alphanumeric())
+        || !username.ends_with(|c: char| c.is_ascii_alphanumeric())
+    {
+        Err(InvalidUsernameError::InvalidEnds)
+    } else if RESERVED_USERNAMES.contains(&username.to_lowercase().as_str()) {
+        Err(InvalidUsernameError::Reserved(username.to_owned()))
+    } else {
+        Ok(username.to_owned())
+    }
+}
@@ -0,0 +1,197 @@
+use std::collections::HashMap;
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use diesel::prelude::*;
+use graphql::{Context, ErrorExtensions};
+
+use crate::models::{Blog, EmailAccount, OAuthAccount, Post, User};
+use crate::policy;
+use crate::schema::{blogs, email_accounts, oauth_accounts, posts, users};
+
+/// The object types that can be fetched by global ID.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NodeType {
+    Blog,
+    EmailAccount,
+    OAuthAccount,
+    Post,
+    User,
+}
+
+impl NodeType {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Blog => "Blog",
+            Self::EmailAccount => "EmailAccount",
+            Self::OAuthAccount => "OAuthAccount",
+            Self::Post => "Post",
+            Self::User => "User",
+        }
+    }
+}
+
+/// A globally unique object ID, exposed to clients as an opaque `ID` made of
+/// the object's type and UUID, e.g. `base64("Post:<uuid>")`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct GlobalId {
+    pub id: uuid::Uuid,
+    pub ty: NodeType,
+}
+
+impl GlobalId {
+    pub fn new(ty: NodeType, id: uuid::Uuid) -> Self {
+        Self { id, ty }
+    }
+}
+
+impl Display for GlobalId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        base64::encode(format!("{}:{}", self.ty.as_str(), self.id)).fmt(f)
+    }
+}
+
+impl FromStr for GlobalId {
+    type Err = InvalidGlobalIdError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidGlobalIdError(s.to_owned());
+
+        let decoded = base64::decode(s).map_err(|_| invalid())?;
+        let decoded = String::from_utf8(decoded).map_err(|_| invalid())?;
+        let (ty, id) = decoded.split_once(':').ok_or_else(invalid)?;
+
+        let ty = match ty {
+            "Blog" => NodeType::Blog,
+            "EmailAccount" => NodeType::EmailAccount,
+            "OAuthAccount" => NodeType::OAuthAccount,
+            "Post" => NodeType::Post,
+            "User" => NodeType::User,
+            _ => return Err(invalid()),
+        };
+
+        Ok(Self::new(ty, id.parse().map_err(|_| invalid())?))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InvalidGlobalIdError(String);
+
+impl Display for InvalidGlobalIdError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:?} is not a valid ID", self.0)
+    }
+}
+
+impl std::error::Error for InvalidGlobalIdError {}
+
+impl ErrorExtensions for InvalidGlobalIdError {
+    fn extend(&self) -> graphql::Error {
+        graphql::Error::new(self.to_string()).extend_with(|_, e| e.set("code", "BAD_USER_INPUT"))
+    }
+}
+
+/// An object with a global ID, per the Relay object identification spec.
+#[derive(Debug, Clone, graphql::Interface)]
+#[graphql(name = "Node", field(name = "id", type = "graphql::ID"))]
+pub enum AnyNode {
+    Blog(Blog),
+    EmailAccount(EmailAccount),
+    OAuthAccount(OAuthAccount),
+    Post(Post),
+    User(User),
+}
+
+impl AnyNode {
+    /// Fetches the objects for `ids`, in order, with `None` for any that
+    /// don't exist or have been deleted. Each type is fetched with a single
+    /// query.
+    pub fn load(conn: &PgConnection, ids: &[GlobalId]) -> QueryResult<Vec<Option<Self>>> {
+        let of_type = |ty| {
+            ids.iter()
+                .filter(|id| id.ty == ty)
+                .map(|id| id.id)
+                .collect::<Vec<_>>()
+        };
+
+        let mut nodes = HashMap::new();
+
+        let blog_ids = of_type(NodeType::Blog);
+        if !blog_ids.is_empty() {
+            for blog in blogs::table
+                .filter(blogs::id.eq_any(blog_ids))
+                .filter(blogs::deleted_at.is_null())
+                .load::<Blog>(conn)?
+            {
+                nodes.insert(GlobalId::new(NodeType::Blog, blog.id), Self::Blog(blog));
+            }
+        }
+
+        let email_account_ids = of_type(NodeType::EmailAccount);
+        if !email_account_ids.is_empty() {
+            for account in email_accounts::table
+                .filter(email_accounts::id.eq_any(email_account_ids))
+                .filter(email_accounts::deleted_at.is_null())
+                .load::<EmailAccount>(conn)?
+            {
+                nodes.insert(
+                    GlobalId::new(NodeType::EmailAccount, account.id),
+                    Self::EmailAccount(account),
+                );
+            }
+        }
+
+        let oauth_account_ids = of_type(NodeType::OAuthAccount);
+        if !oauth_account_ids.is_empty() {
+            for account in oauth_accounts::table
+                .filter(oauth_accounts::id.eq_any(oauth_account_ids))
+                .filter(oauth_accounts::deleted_at.is_null())
+                .load::<OAuthAccount>(conn)?
+            {
+                nodes.insert(
+                    GlobalId::new(NodeType::OAuthAccount, account.id),
+                    Self::OAuthAccount(account),
+                );
+            }
+        }
+
+        let post_ids = of_type(NodeType::Post);
+        if !post_ids.is_empty() {
+            for post in posts::table
+                .filter(posts::id.eq_any(post_ids))
+                .filter(posts::deleted_at.is_null())
+                .load::<Post>(conn)?
+            {
+                nodes.insert(GlobalId::new(NodeType::Post, post.id), Self::Post(post));
+            }
+        }
+
+        let user_ids = of_type(NodeType::User);
+        if !user_ids.is_empty() {
+            for user in users::table
+                .filter(users::id.eq_any(user_ids))
+                .filter(users::deleted_at.is_null())
+                .load::<User>(conn)?
+            {
+                nodes.insert(GlobalId::new(NodeType::User, user.id), Self::User(user));
+            }
+        }
+
+        Ok(ids.iter().map(|id| nodes.get(id).cloned()).collect())
+    }
+
+    /// Email and OAuth accounts are only visible to their user and admins, and
+    /// posts that aren't published only to their blog's owner and admins.
+    pub async fn is_visible(&self, ctx: &Context<'_>) -> graphql::Result<bool> {
+        Ok(match self {
+            Self::EmailAccount(account) => {
+                policy::is_user(policy::session_viewer(ctx), account.user_id)
+            }
+            Self::OAuthAccount(account) => {
+                policy::is_user(policy::session_viewer(ctx), account.user_id)
+            }
+            Self::Post(post) => policy::can_see_post(ctx, post).await?,
+            _ => true,
+        })
+    }
+}
@@ -0,0 +1,1429 @@
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt::{self, Display};
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use url::Url;
+use warp::filters::BoxedFilter;
+use warp::http::{header, StatusCode};
+use warp::reply::Response;
+use warp::Filter;
+
+use crate::auth::{self, ClientInfo, SessionConfig};
+use crate::crypto::{Keyring, Sealed};
+use crate::db::{Pool, RunError};
+use crate::jobs::{BoxError, JobHandler, JobPayload};
+use crate::models::{
+    DateTime, OAuthAccount, OAuthAccountProvider, OAuthLinkToken, OAuthSignInError, TotpError,
+};
+use crate::schema::oauth_accounts;
+
+/// How often accounts are checked for access tokens that need refreshing.
+pub const INTERVAL: Duration = Duration::from_secs(60);
+/// How long before an access token expires it's refreshed.
+pub const REFRESH_WINDOW: Duration = Duration::from_secs(10 * 60);
+/// How long after queueing a refresh an account can be queued again if the
+/// refresh hasn't succeeded by then.
+pub const REQUEUE_AFTER: Duration = Duration::from_secs(6 * 60 * 60);
+/// How long to wait for a provider's token endpoint.
+pub const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long access tokens are assumed to last when a provider doesn't say.
+pub const DEFAULT_TOKEN_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// How long a user has to finish signing in with a provider once started.
+pub const FLOW_LIFETIME: Duration = Duration::from_secs(10 * 60);
+
+/// The cookie holding a sign-in flow's state between `/auth/:provider/start`
+/// and `/auth/:provider/callback`.
+const FLOW_COOKIE: &str = "oauth_flow";
+
+/// Token endpoint errors that mean the grant itself is no good, e.g. because
+/// the user revoked access. GitHub has its own name for it.
+const REJECTED_ERRORS: &[&str] = &[
+    "bad_refresh_token",
+    "bad_verification_code",
+    "invalid_grant",
+];
+
+impl OAuthAccountProvider {
+    /// The provider's name in `/auth/:provider/...` paths.
+    pub fn slug(self) -> &'static str {
+        match self {
+            Self::APPLE => "apple",
+            Self::GITHUB => "github",
+            Self::GOOGLE => "google",
+        }
+    }
+
+    fn from_slug(slug: &str) -> Option<Self> {
+        Self::all()
+            .into_iter()
+            .find(|provider| provider.slug() == slug)
+    }
+
+    fn default_authorize_url(self) -> &'static str {
+        match self {
+            Self::APPLE => "https://appleid.apple.com/auth/authorize",
+            Self::GITHUB => "https://github.com/login/oauth/authorize",
+            Self::GOOGLE => "https://accounts.google.com/o/oauth2/v2/auth",
+        }
+    }
+
+    /// Apple asks for no scopes, since it only sends back the callback as a
+    /// `GET` when none are requested.
+    fn default_scopes(self) -> &'static str {
+        match self {
+            Self::APPLE => "",
+            Self::GITHUB => "read:user",
+            Self::GOOGLE => "openid",
+        }
+    }
+
+    fn default_token_url(self) -> &'static str {
+        match self {
+            Self::APPLE => "https://appleid.apple.com/auth/token",
+            Self::GITHUB => "https://github.com/login/oauth/access_token",
+            Self::GOOGLE => "https://oauth2.googleapis.com/token",
+        }
+    }
+
+    /// Apple has no userinfo endpoint, and identifies the account in the ID
+    /// token instead.
+    fn default_userinfo_url(self) -> Option<&'static str> {
+        match self {
+            Self::APPLE => None,
+            Self::GITHUB => Some("https://api.github.com/user"),
+            Self::GOOGLE => Some("https://openidconnect.googleapis.com/v1/userinfo"),
+        }
+    }
+}
+
+/// How to reach one provider, read from the environment by
+/// [`ProviderConfig::from_env`].
+#[derive(Clone, PartialEq, Eq)]
+pub struct ProviderConfig {
+    pub authorize_url: Url,
+    pub client_id: String,
+    /// For Apple, a client secret JWT signed ahead of time. Apple only accepts
+    /// ones that expire within six months, so it has to be replaced before
+    /// then.
+    pub client_secret: String,
+    /// Space-separated, as they're sent to the provider.
+    pub scopes: String,
+    pub token_url: Url,
+    pub userinfo_url: Option<Url>,
+}
+
+impl fmt::Debug for ProviderConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ProviderConfig")
+            .field("authorize_url", &self.authorize_url)
+            .field("client_id", &self.client_id)
+            .field("scopes", &self.scopes)
+            .field("token_url", &self.token_url)
+            .field("userinfo_url", &self.userinfo_url)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ProviderConfig {
+    /// Reads `OAUTH_<PROVIDER>_CLIENT_ID` and `OAUTH_<PROVIDER>_CLIENT_SECRET`,
+    /// e.g. `OAUTH_GITHUB_CLIENT_ID`, along with `OAUTH_<PROVIDER>_SCOPES` and
+    /// `OAUTH_<PROVIDER>_{AUTHORIZE,TOKEN,USERINFO}_URL`, which default to the
+    /// provider's own. Providers without a client ID aren't configured, and
+    /// give `None`.
+    pub fn from_env(provider: OAuthAccountProvider) -> Result<Option<Self>, OAuthConfigError> {
+        let name = |suffix| format!("OAUTH_{}_{}", provider, suffix);
+        let var = |suffix| std::env::var(name(suffix)).ok();
+        let url = |suffix, default: &str| match var(suffix) {
+            Some(url) => url
+                .parse()
+                .map_err(|_| OAuthConfigError::InvalidUrl(name(suffix))),
+            None => Ok(default.parse().expect("default URLs are valid")),
+        };
+
+        let client_id = match var("CLIENT_ID") {
+            Some(client_id) => client_id,
+            None => return Ok(None),
+        };
+
+        Ok(Some(Self {
+            authorize_url: url("AUTHORIZE_URL", provider.default_authorize_url())?,
+            client_id,
+            client_secret: var("CLIENT_SECRET")
+                .ok_or_else(|| OAuthConfigError::MissingClientSecret(name("CLIENT_SECRET")))?,
+            scopes: var("SCOPES").unwrap_or_else(|| provider.default_scopes().to_owned()),
+            token_url: url("TOKEN_URL", provider.default_token_url())?,
+            userinfo_url: match provider.default_userinfo_url() {
+                Some(default) => Some(url("USERINFO_URL", default)?),
+                None => None,
+            },
+        }))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum OAuthConfigError {
+    InvalidUrl(String),
+    MissingClientSecret(String),
+}
+
+impl Display for OAuthConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidUrl(name) => write!(f, "{} must be an absolute URL", name),
+            Self::MissingClientSecret(name) => {
+                write!(f, "{} must be set along with the client ID", name)
+            }
+        }
+    }
+}
+
+impl std::error::Error for OAuthConfigError {}
+
+/// Tokens issued by a provider's token endpoint.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Tokens {
+    pub access_token: String,
+    pub expires_at: DateTime,
+    /// A new refresh token, for providers that rotate them. Otherwise the old
+    /// one is still good.
+    pub refresh_token: Option<String>,
+}
+
+impl fmt::Debug for Tokens {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Tokens")
+            .field("expires_at", &self.expires_at)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A token endpoint response, which is either tokens or an error. GitHub
+/// sends errors with a 200 status, so both are parsed the same way.
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+    expires_in: Option<i64>,
+    id_token: Option<String>,
+    refresh_token: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ProviderError {
+    /// Anything that might work if tried again later, like a network error or
+    /// the provider being down.
+    Failed(String),
+    /// The provider won't accept the grant, e.g. a refresh token the user has
+    /// revoked, so the user has to sign in again.
+    Rejected(String),
+    /// The provider isn't configured.
+    Unconfigured(OAuthAccountProvider),
+}
+
+impl Display for ProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Failed(message) => write!(f, "provider request failed: {}", message),
+            Self::Rejected(message) => write!(f, "provider rejected the grant: {}", message),
+            Self::Unconfigured(provider) => write!(f, "{} sign-in isn't configured", provider),
+        }
+    }
+}
+
+impl std::error::Error for ProviderError {}
+
+impl From<reqwest::Error> for ProviderError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Failed(err.to_string())
+    }
+}
+
+/// The configured OAuth providers, and an HTTP client to talk to them with.
+#[derive(Debug, Clone)]
+pub struct Providers {
+    client: reqwest::Client,
+    configs: HashMap<OAuthAccountProvider, ProviderConfig>,
+    /// The server's own URL, which providers redirect back to.
+    public_url: Url,
+    /// Where to send users once they've signed in, if anywhere.
+    return_url: Option<Url>,
+}
+
+impl Providers {
+    pub fn new<I>(public_url: Url, return_url: Option<Url>, configs: I) -> Result<Self, BoxError>
+    where
+        I: IntoIterator<Item = (OAuthAccountProvider, ProviderConfig)>,
+    {
+        Ok(Self {
+            client: reqwest::Client::builder()
+                .timeout(REQUEST_TIMEOUT)
+                // GitHub's API turns away requests without one.
+                .user_agent(env!("CARGO_PKG_NAME"))
+                .build()?,
+            configs: configs.into_iter().collect(),
+            public_url,
+            return_url,
+        })
+    }
+
+    /// Configures each provider with [`ProviderConfig::from_env`]. The server's
+    /// URL is read from `PUBLIC_URL`, defaulting to `http://localhost:4000`,
+    /// and where to send users after signing in from `OAUTH_RETURN_URL`.
+    pub fn from_env() -> Result<Self, BoxError> {
+        let url = |name: &str| match std::env::var(name) {
+            Ok(url) => url
+                .parse()
+                .map(Some)
+                .map_err(|_| OAuthConfigError::InvalidUrl(name.to_owned())),
+            Err(_) => Ok(None),
+        };
+
+        let public_url =
+            url("PUBLIC_URL")?.unwrap_or_else(|| "http://localhost:4000".parse().expect("valid"));
+        let return_url = url("OAUTH_RETURN_URL")?;
+
+        let mut configs = Vec::new();
+        for provider in OAuthAccountProvider::all() {
+            if let Some(config) = ProviderConfig::from_env(provider)? {
+                configs.push((provider, config));
+            }
+        }
+
+        Self::new(public_url, return_url, configs)
+    }
+
+    pub fn configured(&self) -> Vec<OAuthAccountProvider> {
+        let mut providers: Vec<_> = self.configs.keys().copied().collect();
+        providers.sort_by_key(|provider| provider.to_string());
+        providers
+    }
+
+    fn config(&self, provider: OAuthAccountProvider) -> Result<&ProviderConfig, ProviderError> {
+        self.configs
+            .get(&provider)
+            .ok_or(ProviderError::Unconfigured(provider))
+    }
+
+    /// The `redirect_uri` registered with `provider`.
+    pub fn callback_url(&self, provider: OAuthAccountProvider) -> Url {
+        let mut url = self.public_url.clone();
+        url.path_segments_mut()
+            .expect("PUBLIC_URL is a base URL")
+            .pop_if_empty()
+            .extend(&["auth", provider.slug(), "callback"]);
+        url
+    }
+
+    /// Where to send the user to sign in with `provider`, using PKCE with the
+    /// S256 method.
+    pub fn authorize_url(
+        &self,
+        provider: OAuthAccountProvider,
+        state: &str,
+        code_challenge: &str,
+    ) -> Result<Url, ProviderError> {
+        let config = self.config(provider)?;
+
+        let mut url = config.authorize_url.clone();
+        {
+            let mut query = url.query_pairs_mut();
+            query
+                .append_pair("client_id", &config.client_id)
+                .append_pair("code_challenge", code_challenge)
+                .append_pair("code_challenge_method", "S256")
+                .append_pair("redirect_uri", self.callback_url(provider).as_str())
+                .append_pair("response_type", "code")
+                .append_pair("state", state);
+            if !config.scopes.is_empty() {
+                query.append_pair("scope", &config.scopes);
+            }
+            // Google only issues refresh tokens when asked to, and only the
+            // first time the user consents unless asked to consent again.
+            if provider == OAuthAccountProvider::GOOGLE {
+                query
+                    .append_pair("access_type", "offline")
+                    .append_pair("prompt", "consent");
+            }
+        }
+
+        Ok(url)
+    }
+
+    /// Exchanges an authorization code for tokens, returning them along with
+    /// the provider's ID for the account they belong to.
+    pub async fn exchange_code(
+        &self,
+        provider: OAuthAccountProvider,
+        code: &str,
+        code_verifier: &str,
+    ) -> Result<(Tokens, String), ProviderError> {
+        let config = self.config(provider)?;
+        let callback_url = self.callback_url(provider);
+
+        let (tokens, id_token) = self
+            .request_tokens(
+                config,
+                &[
+                    ("code", code),
+                    ("code_verifier", code_verifier),
+                    ("grant_type", "authorization_code"),
+                    ("redirect_uri", callback_url.as_str()),
+                ],
+            )
+            .await?;
+
+        let account_id = match &config.userinfo_url {
+            Some(userinfo_url) => {
+                let response = self
+                    .client
+                    .get(userinfo_url.clone())
+                    .bearer_auth(&tokens.access_token)
+                    .header(header::ACCEPT, "application/json")
+                    .send()
+                    .await?
+                    .error_for_status()?;
+                account_id(provider, &response.json().await?)
+            }
+            // The ID token came straight from the provider over TLS, so it
+            // doesn't need its signature checked (OpenID Connect Core
+            // 3.1.3.7).
+            None => id_token.as_deref().and_then(id_token_subject),
+        };
+
+        Ok((
+            tokens,
+            account_id.ok_or_else(|| {
+                ProviderError::Failed("the provider didn't identify the account".into())
+            })?,
+        ))
+    }
+
+    /// Exchanges `refresh_token` for a new access token with the refresh token
+    /// grant from RFC 6749, which all three providers support.
+    pub async fn refresh(
+        &self,
+        provider: OAuthAccountProvider,
+        refresh_token: &str,
+    ) -> Result<Tokens, ProviderError> {
+        let config = self.config(provider)?;
+
+        let (tokens, _) = self
+            .request_tokens(
+                config,
+                &[
+                    ("grant_type", "refresh_token"),
+                    ("refresh_token", refresh_token),
+                ],
+            )
+            .await?;
+
+        Ok(tokens)
+    }
+
+    /// Makes a token request with `params`, returning the tokens issued along
+    /// with the ID token, if there is one.
+    async fn request_tokens(
+        &self,
+        config: &ProviderConfig,
+        params: &[(&str, &str)],
+    ) -> Result<(Tokens, Option<String>), ProviderError> {
+        let mut form = vec![
+            ("client_id", config.client_id.as_str()),
+            ("client_secret", config.client_secret.as_str()),
+        ];
+        form.extend_from_slice(params);
+
+        let response = self
+            .client
+            .post(config.token_url.clone())
+            // GitHub responds with a form unless asked for JSON.
+            .header(header::ACCEPT, "application/json")
+            .form(&form)
+            .send()
+            .await?;
+        let status = response.status();
+        let body: TokenResponse = response.json().await.map_err(|err| {
+            ProviderError::Failed(format!("{} from the token endpoint: {}", status, err))
+        })?;
+
+        if let Some(error) = body.error {
+            let message = match body.error_description {
+                Some(description) => format!("{}: {}", error, description),
+                None => error.clone(),
+            };
+
+            return Err(if REJECTED_ERRORS.contains(&error.as_str()) {
+                ProviderError::Rejected(message)
+            } else {
+                ProviderError::Failed(message)
+            });
+        }
+
+        let access_token = match body.access_token {
+            Some(access_token) if status.is_success() => access_token,
+            _ => {
+                return Err(ProviderError::Failed(format!(
+                    "{} from the token endpoint without an access token",
+                    status
+                )))
+            }
+        };
+        let lifetime = body.expires_in.map_or_else(
+            || chrono::Duration::from_std(DEFAULT_TOKEN_LIFETIME).expect("in range"),
+            chrono::Duration::seconds,
+        );
+
+        Ok((
+            Tokens {
+                access_token,
+                expires_at: chrono::Utc::now() + lifetime,
+                refresh_token: body.refresh_token,
+            },
+            body.id_token,
+        ))
+    }
+}
+
+/// Finds the account ID in a userinfo response. GitHub's is a number called
+/// `id`, and OpenID Connect providers' a string called `sub`.
+fn account_id(provider: OAuthAccountProvider, userinfo: &serde_json::Value) -> Option<String> {
+    match (provider, userinfo) {
+        (OAuthAccountProvider::GITHUB, serde_json::Value::Object(userinfo)) => {
+            match userinfo.get("id")? {
+                serde_json::Value::Number(id) => Some(id.to_string()),
+                serde_json::Value::String(id) => Some(id.clone()),
+                _ => None,
+            }
+        }
+        (_, userinfo) => Some(userinfo.get("sub")?.as_str()?.to_owned()),
+    }
+}
+
+/// The `sub` claim of an ID token, without checking its signature.
+fn id_token_subject(id_token: &str) -> Option<String> {
+    let claims = id_token.split('.').nth(1)?;
+    let claims = base64::decode_config(claims, base64::URL_SAFE_NO_PAD).ok()?;
+    let claims: serde_json::Value = serde_json::from_slice(&claims).ok()?;
+
+    Some(claims.get("sub")?.as_str()?.to_owned())
+}
+
+/// Refreshes an OAuth account's access token.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+pub struct RefreshOAuthToken {
+    pub oauth_account_id: uuid::Uuid,
+}
+
+impl JobPayload for RefreshOAuthToken {
+    const KIND: &'static str = "refresh_oauth_token";
+}
+
+pub struct RefreshOAuthTokenHandler {
+    pub keyring: Keyring,
+    pub pool: Pool,
+    pub providers: Providers,
+}
+
+#[graphql::async_trait::async_trait]
+impl JobHandler for RefreshOAuthTokenHandler {
+    type Payload = RefreshOAuthToken;
+
+    /// Failures are recorded on the account. Ones worth retrying fail the job
+    /// so the queue retries it, and rejected refresh tokens complete it, since
+    /// retrying those won't help.
+    async fn run(&self, payload: RefreshOAuthToken) -> Result<(), BoxError> {
+        use diesel::prelude::*;
+
+        let id = payload.oauth_account_id;
+        let account: Option<OAuthAccount> = self
+            .pool
+            .run(move |conn| -> Result<_, BoxError> {
+                Ok(oauth_accounts::table
+                    .find(id)
+                    .filter(oauth_accounts::deleted_at.is_null())
+                    .get_result(conn)
+                    .optional()?)
+            })
+            .await?;
+        let account = match account {
+            Some(account) => account,
+            None => return Ok(()),
+        };
+
+        let refreshed = match self.keyring.open(&account.provider_refresh_token) {
+            Ok(refresh_token) if refresh_token.is_empty() => Err(ProviderError::Rejected(
+                "the provider never issued a refresh token".into(),
+            )),
+            Ok(refresh_token) => {
+                self.providers
+                    .refresh(account.provider, &refresh_token)
+                    .await
+            }
+            Err(err) => Err(ProviderError::Failed(err.to_string())),
+        };
+
+        match refreshed {
+            Ok(tokens) => {
+                let keyring = self.keyring.clone();
+                self.pool
+                    .run(move |conn| -> Result<_, BoxError> {
+                        Ok(account.record_refresh(conn, &keyring, &tokens)?)
+                    })
+                    .await
+            }
+            Err(err) => {
+                let error = err.to_string();
+                self.pool
+                    .run(move |conn| -> Result<_, BoxError> {
+                        Ok(OAuthAccount::record_refresh_failure(conn, id, &error)?)
+                    })
+                    .await?;
+
+                match err {
+                    ProviderError::Rejected(_) => {
+                        log::warn!("OAuth account {}: {}", id, err);
+                        Ok(())
+                    }
+                    _ => Err(err.into()),
+                }
+            }
+        }
+    }
+}
+
+/// Queues refreshes of access tokens nearing expiry for every configured
+/// provider, every `INTERVAL`, for as long as the server runs. Errors are
+/// logged and retried on the next run.
+pub async fn run(pool: Pool, providers: Providers) {
+    let providers = providers.configured();
+    if providers.is_empty() {
+        return;
+    }
+
+    let mut interval = tokio::time::interval(INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let providers = providers.clone();
+        let queued = pool
+            .run(move |conn| -> Result<_, BoxError> {
+                Ok(OAuthAccount::queue_refreshes(
+                    conn,
+                    &providers,
+                    chrono::Utc::now(),
+                )?)
+            })
+            .await;
+        match queued {
+            Ok(0) => {}
+            Ok(count) => log::info!("queued {} OAuth token refreshes", count),
+            Err(err) => log::error!("could not queue OAuth token refreshes: {}", err),
+        }
+    }
+}
+
+/// A sign-in flow in progress, kept sealed in the `oauth_flow` cookie so that
+/// only the browser that started it can finish it.
+#[derive(Serialize, Deserialize)]
+struct Flow {
+    code_verifier: String,
+    expires_at: DateTime,
+    /// The signed-in user who started the flow, to link the account to.
+    link_user_id: Option<uuid::Uuid>,
+    provider: String,
+    state: String,
+}
+
+#[derive(Deserialize)]
+struct StartQuery {
+    /// From `oauthLinkTokenCreate`, to link the account rather than sign in.
+    link_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct CallbackQuery {
+    code: Option<String>,
+    error: Option<String>,
+    error_description: Option<String>,
+    state: Option<String>,
+}
+
+/// Why a sign-in flow failed, as reported to the client.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct FlowError {
+    message: String,
+    status: StatusCode,
+}
+
+impl FlowError {
+    fn new<M: Into<String>>(status: StatusCode, message: M) -> Self {
+        Self {
+            message: message.into(),
+            status,
+        }
+    }
+
+    fn expired() -> Self {
+        Self::new(
+            StatusCode::BAD_REQUEST,
+            "this sign-in has expired or was already finished; start again",
+        )
+    }
+
+    fn internal<E: Display>(err: E) -> Self {
+        log::error!("OAuth sign-in failed: {}", err);
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "something went wrong; try again",
+        )
+    }
+}
+
+impl Display for FlowError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.message.fmt(f)
+    }
+}
+
+impl std::error::Error for FlowError {}
+
+impl From<diesel::result::Error> for FlowError {
+    fn from(err: diesel::result::Error) -> Self {
+        Self::internal(err)
+    }
+}
+
+impl From<OAuthSignInError> for FlowError {
+    fn from(err: OAuthSignInError) -> Self {
+        match err {
+            OAuthSignInError::Query(err) => Self::internal(err),
+            err => Self::new(StatusCode::CONFLICT, err.to_string()),
+        }
+    }
+}
+
+impl From<TotpError> for FlowError {
+    fn from(err: TotpError) -> Self {
+        match err {
+            TotpError::LockedOut(_) => Self::new(StatusCode::TOO_MANY_REQUESTS, err.to_string()),
+            err => Self::internal(err),
+        }
+    }
+}
+
+impl From<ProviderError> for FlowError {
+    fn from(err: ProviderError) -> Self {
+        match err {
+            ProviderError::Failed(_) => Self::new(StatusCode::BAD_GATEWAY, err.to_string()),
+            ProviderError::Rejected(_) => Self::new(StatusCode::BAD_REQUEST, err.to_string()),
+            ProviderError::Unconfigured(_) => Self::new(StatusCode::NOT_FOUND, err.to_string()),
+        }
+    }
+}
+
+impl From<RunError> for FlowError {
+    fn from(err: RunError) -> Self {
+        match err {
+            RunError::Busy => Self::new(StatusCode::SERVICE_UNAVAILABLE, err.to_string()),
+            err => Self::internal(err),
+        }
+    }
+}
+
+/// What the `/auth` endpoints share between requests.
+#[derive(Clone)]
+struct State {
+    keyring: Keyring,
+    pool: Pool,
+    providers: Providers,
+    sessions: SessionConfig,
+}
+
+/// What a finished sign-in flow leads to.
+#[derive(Debug, Serialize)]
+#[serde(untagged)]
+enum SignedIn {
+    #[serde(rename_all = "camelCase")]
+    Session {
+        expires_at: DateTime,
+        /// The bearer token for the session.
+        token: String,
+        user_id: uuid::Uuid,
+    },
+    /// For users with two-factor authentication on, a challenge to pass to
+    /// the `loginTotp` mutation with a code.
+    #[serde(rename_all = "camelCase")]
+    TotpRequired {
+        expires_at: DateTime,
+        totp_challenge: String,
+    },
+}
+
+/// The `/auth/:provider/start` and `/auth/:provider/callback` endpoints of the
+/// authorization code flow (RFC 6749 section 4.1, with PKCE from RFC 7636).
+///
+/// `start` redirects to the provider, which redirects back to `callback`. If
+/// `start` was opened with a `link_token` from `oauthLinkTokenCreate`, the
+/// provider account is linked to the user it was issued to; otherwise it signs
+/// in as the user it's linked to, or a new one. `callback` then starts a
+/// session, and redirects to `OAUTH_RETURN_URL` with `token`, `userId` and
+/// `expiresAt` in the fragment, or responds with them as JSON if it isn't set.
+/// Users with two-factor authentication on get `totpChallenge` and `expiresAt`
+/// instead, to finish signing in with the `loginTotp` mutation. Failures are
+/// reported the same way, as `error`.
+pub fn routes(
+    pool: Pool,
+    keyring: Keyring,
+    providers: Providers,
+    sessions: SessionConfig,
+) -> BoxedFilter<(Response,)> {
+    let state = State {
+        keyring,
+        pool,
+        providers,
+        sessions,
+    };
+
+    let start = warp::path!("auth" / String / "start")
+        .and(warp::get())
+        .and(warp::query::<StartQuery>())
+        .and_then({
+            let state = state.clone();
+            move |provider: String, query: StartQuery| {
+                let state = state.clone();
+                async move {
+                    let response = match start(&state, &provider, query).await {
+                        Ok(response) => response,
+                        Err(err) => respond(&state.providers, Err(err)),
+                    };
+                    Ok::<_, Infallible>(response)
+                }
+            }
+        });
+
+    let callback = warp::path!("auth" / String / "callback")
+        .and(warp::get())
+        .and(warp::query::<CallbackQuery>())
+        .and(warp::cookie::optional::<String>(FLOW_COOKIE))
+        .and(auth::client_info())
+        .and_then(
+            move |provider: String,
+                  query: CallbackQuery,
+                  flow: Option<String>,
+                  client: ClientInfo| {
+                let state = state.clone();
+                async move {
+                    let signed_in = callback(&state, &client, &provider, query, flow);
+                    let mut response = respond(&state.providers, signed_in.await);
+                    // The flow is over either way, so the cookie can go.
+                    response.headers_mut().insert(
+                        header::SET_COOKIE,
+                        flow_cookie(&state.providers, "", Duration::from_secs(0))
+                            .parse()
+                            .expect("cookies are valid header values"),
+                    );
+                    Ok::<_, Infallible>(response)
+                }
+            },
+        );
+
+    start.or(callback).unify().boxed()
+}
+
+async fn start(state: &State, provider: &str, query: StartQuery) -> Result<Response, FlowError> {
+    let State {
+        keyring,
+        pool,
+        providers,
+        ..
+    } = state;
+    let provider = OAuthAccountProvider::from_slug(provider)
+        .ok_or_else(|| FlowError::new(StatusCode::NOT_FOUND, "unknown OAuth provider"))?;
+
+    let link_user_id = match query.link_token {
+        Some(token) => Some(
+            pool.run(move |conn| -> Result<_, FlowError> {
+                Ok(OAuthLinkToken::redeem(conn, &token)?)
+            })
+            .await?
+            .ok_or_else(|| {
+                FlowError::new(
+                    StatusCode::UNAUTHORIZED,
+                    "this link token is invalid, expired or already used",
+                )
+            })?,
+        ),
+        None => None,
+    };
+
+    let flow = Flow {
+        code_verifier: auth::generate_token(),
+        expires_at: chrono::Utc::now()
+            + chrono::Duration::from_std(FLOW_LIFETIME).expect("FLOW_LIFETIME is in range"),
+        link_user_id,
+        provider: provider.to_string(),
+        state: auth::generate_token(),
+    };
+    let code_challenge = base64::encode_config(
+        Sha256::digest(flow.code_verifier.as_bytes()),
+        base64::URL_SAFE_NO_PAD,
+    );
+    let location = providers.authorize_url(provider, &flow.state, &code_challenge)?;
+    let sealed = keyring.seal(&serde_json::to_string(&flow).map_err(FlowError::internal)?);
+
+    let mut response = redirect(&location);
+    response.headers_mut().insert(
+        header::SET_COOKIE,
+        flow_cookie(providers, sealed.as_str(), FLOW_LIFETIME)
+            .parse()
+            .expect("cookies are valid header values"),
+    );
+    Ok(response)
+}
+
+async fn callback(
+    state: &State,
+    client: &ClientInfo,
+    provider: &str,
+    query: CallbackQuery,
+    flow: Option<String>,
+) -> Result<SignedIn, FlowError> {
+    let State {
+        keyring,
+        pool,
+        providers,
+        sessions,
+    } = state;
+    let provider = OAuthAccountProvider::from_slug(provider)
+        .ok_or_else(|| FlowError::new(StatusCode::NOT_FOUND, "unknown OAuth provider"))?;
+
+    let flow: Flow = flow
+        .and_then(|flow| keyring.open(&Sealed::from(flow)).ok())
+        .and_then(|flow| serde_json::from_str(&flow).ok())
+        .ok_or_else(FlowError::expired)?;
+    if flow.provider != provider.to_string()
+        || flow.expires_at <= chrono::Utc::now()
+        || !query
+            .state
+            .as_deref()
+            .is_some_and(|state| auth::verify_token(state, &auth::hash_token(&flow.state)))
+    {
+        return Err(FlowError::expired());
+    }
+
+    if let Some(error) = query.error {
+        return Err(FlowError::new(
+            StatusCode::BAD_REQUEST,
+            format!(
+                "{} sign-in failed: {}",
+                provider,
+                query.error_description.unwrap_or(error)
+            ),
+        ));
+    }
+    let code = query.code.ok_or_else(|| {
+        FlowError::new(StatusCode::BAD_REQUEST, "the provider didn't send a code")
+    })?;
+
+    let (tokens, account_id) = providers
+        .exchange_code(provider, &code, &flow.code_verifier)
+        .await?;
+
+    let (keyring, sessions, client) = (keyring.clone(), *sessions, client.clone());
+    pool.run(move |conn| -> Result<_, FlowError> {
+        let user = OAuthAccount::sign_in(
+            conn,
+            &keyring,
+            provider,
+            &account_id,
+            &tokens,
+            flow.link_user_id,
+        )?;
+        // Linking happens from an existing session, so it needs no second
+        // step.
+        if flow.link_user_id.is_some() {
+            let (session, token) = auth::create_session(conn, &sessions, &client, user.id)?;
+            return Ok(SignedIn::Session {
+                expires_at: session.expires_at,
+                token,
+                user_id: user.id,
+            });
+        }
+
+        Ok(match auth::sign_in(conn, &sessions, &client, &user)? {
+            auth::SignIn::Session(session, token) => SignedIn::Session {
+                expires_at: session.expires_at,
+                token,
+                user_id: user.id,
+            },
+            auth::SignIn::TotpRequired(challenge, totp_challenge) => SignedIn::TotpRequired {
+                expires_at: challenge.expires_at,
+                totp_challenge,
+            },
+        })
+    })
+    .await
+}
+
+/// A `Set-Cookie` value for the `oauth_flow` cookie. It's only sent back to
+/// the `/auth` endpoints, and is secure whenever the server is served over
+/// HTTPS.
+fn flow_cookie(providers: &Providers, value: &str, max_age: Duration) -> String {
+    let secure = if providers.public_url.scheme() == "https" {
+        "; Secure"
+    } else {
+        ""
+    };
+
+    format!(
+        "{}={}; Path=/auth; Max-Age={}; HttpOnly; SameSite=Lax{}",
+        FLOW_COOKIE,
+        value,
+        max_age.as_secs(),
+        secure
+    )
+}
+
+/// Reports the outcome of a sign-in flow, as described on [`routes`].
+fn respond(providers: &Providers, result: Result<SignedIn, FlowError>) -> Response {
+    let return_url = match &providers.return_url {
+        Some(return_url) => return_url,
+        None => {
+            let (status, body) = match result {
+                Ok(signed_in) => (StatusCode::OK, serde_json::json!(signed_in)),
+                Err(err) => (err.status, serde_json::json!({ "error": err.message })),
+            };
+
+            return warp::http::Response::builder()
+                .status(status)
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(body.to_string().into())
+                .expect("responses are valid");
+        }
+    };
+
+    let mut fragment = url::form_urlencoded::Serializer::new(String::new());
+    match result {
+        Ok(SignedIn::Session {
+            expires_at,
+            token,
+            user_id,
+        }) => fragment
+            .append_pair("expiresAt", &expires_at.to_rfc3339())
+            .append_pair("token", &token)
+            .append_pair("userId", &user_id.to_string()),
+        Ok(SignedIn::TotpRequired {
+            expires_at,
+            totp_challenge,
+        }) => fragment
+            .append_pair("expiresAt", &expires_at.to_rfc3339())
+            .append_pair("totpChallenge", &totp_challenge),
+        Err(err) => fragment.append_pair("error", &err.message),
+    };
+
+    let mut location = return_url.clone();
+    location.set_fragment(Some(&fragment.finish()));
+    redirect(&location)
+}
+
+fn redirect(location: &Url) -> Response {
+    warp::http::Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, location.as_str())
+        .body(Default::default())
+        .expect("responses are valid")
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::prelude::*;
+
+    use super::*;
+
+    const ACCESS_TOKEN: &str = "mock-access-token";
+    const CODE: &str = "mock-code";
+    const CODE_VERIFIER: &str = "mock-verifier";
+
+    /// An unsigned ID token for `sub`, like the ones Apple issues.
+    fn id_token(sub: &str) -> String {
+        let encode = |value: serde_json::Value| {
+            base64::encode_config(value.to_string(), base64::URL_SAFE_NO_PAD)
+        };
+        format!(
+            "{}.{}.",
+            encode(serde_json::json!({ "alg": "none" })),
+            encode(serde_json::json!({ "sub": sub }))
+        )
+    }
+
+    /// Answers token requests like a provider would. Authorization codes
+    /// other than `CODE` are rejected, and refreshes depend on how the
+    /// refresh token starts, as with `examples/mock_oauth.rs`.
+    fn token_response(form: &HashMap<String, String>) -> (StatusCode, serde_json::Value) {
+        let param = |name: &str| form.get(name).map(String::as_str).unwrap_or_default();
+        let error = |status, error: &str| (status, serde_json::json!({ "error": error }));
+
+        if param("client_id") != "mock-client" || param("client_secret") != "mock-secret" {
+            return error(StatusCode::UNAUTHORIZED, "invalid_client");
+        }
+
+        match param("grant_type") {
+            "authorization_code"
+                if param("code") == CODE
+                    && param("code_verifier") == CODE_VERIFIER
+                    && param("redirect_uri").starts_with("http://localhost:4000/auth/") =>
+            {
+                (
+                    StatusCode::OK,
+                    serde_json::json!({
+                        "access_token": ACCESS_TOKEN,
+                        "expires_in": 3600,
+                        "id_token": id_token("apple-account"),
+                        "refresh_token": "mock-refresh-token",
+                    }),
+                )
+            }
+            "authorization_code" => error(StatusCode::BAD_REQUEST, "invalid_grant"),
+            "refresh_token" => match param("refresh_token") {
+                token if token.starts_with("revoked") => {
+                    error(StatusCode::BAD_REQUEST, "invalid_grant")
+                }
+                token if token.starts_with("flaky") => {
+                    error(StatusCode::SERVICE_UNAVAILABLE, "temporarily_unavailable")
+                }
+                token if token.starts_with("rotating") => (
+                    StatusCode::OK,
+                    serde_json::json!({
+                        "access_token": "refreshed-access-token",
+                        "refresh_token": "rotating-2",
+                    }),
+                ),
+                _ => (
+                    StatusCode::OK,
+                    serde_json::json!({
+                        "access_token": "refreshed-access-token",
+                        "expires_in": 60,
+                    }),
+                ),
+            },
+            _ => error(StatusCode::BAD_REQUEST, "unsupported_grant_type"),
+        }
+    }
+
+    /// Starts a mock provider on a free port, returning its URL.
+    fn mock_provider() -> Url {
+        let token = warp::path("token")
+            .and(warp::post())
+            .and(warp::body::form())
+            .map(|form: HashMap<String, String>| {
+                let (status, body) = token_response(&form);
+                warp::reply::with_status(warp::reply::json(&body), status)
+            });
+        let userinfo = warp::path("userinfo")
+            .and(warp::get())
+            .and(warp::header::<String>("authorization"))
+            .map(|authorization: String| {
+                if authorization == format!("Bearer {}", ACCESS_TOKEN) {
+                    let body = serde_json::json!({ "id": 42, "login": "mock" });
+                    warp::reply::with_status(warp::reply::json(&body), StatusCode::OK)
+                } else {
+                    let body = serde_json::json!({ "message": "Bad credentials" });
+                    warp::reply::with_status(warp::reply::json(&body), StatusCode::UNAUTHORIZED)
+                }
+            });
+
+        let (address, server) = warp::serve(token.or(userinfo)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{}/", address).parse().unwrap()
+    }
+
+    /// GitHub and Apple, both pointed at a mock provider. Google isn't
+    /// configured.
+    fn providers() -> Providers {
+        let url = mock_provider();
+        let config = |userinfo_url| ProviderConfig {
+            authorize_url: url.join("authorize").unwrap(),
+            client_id: "mock-client".into(),
+            client_secret: "mock-secret".into(),
+            scopes: String::new(),
+            token_url: url.join("token").unwrap(),
+            userinfo_url,
+        };
+
+        Providers::new(
+            "http://localhost:4000".parse().unwrap(),
+            None,
+            vec![
+                (OAuthAccountProvider::APPLE, config(None)),
+                (
+                    OAuthAccountProvider::GITHUB,
+                    config(Some(url.join("userinfo").unwrap())),
+                ),
+            ],
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn exchange_code_identifies_the_account_with_userinfo() {
+        let (tokens, account_id) = providers()
+            .exchange_code(OAuthAccountProvider::GITHUB, CODE, CODE_VERIFIER)
+            .await
+            .unwrap();
+
+        assert_eq!(account_id, "42");
+        assert_eq!(tokens.access_token, ACCESS_TOKEN);
+        assert_eq!(tokens.refresh_token.as_deref(), Some("mock-refresh-token"));
+        assert!(tokens.expires_at > chrono::Utc::now() + chrono::Duration::minutes(59));
+    }
+
+    #[tokio::test]
+    async fn exchange_code_identifies_the_account_with_the_id_token() {
+        let (_, account_id) = providers()
+            .exchange_code(OAuthAccountProvider::APPLE, CODE, CODE_VERIFIER)
+            .await
+            .unwrap();
+
+        assert_eq!(account_id, "apple-account");
+    }
+
+    #[tokio::test]
+    async fn exchange_code_is_rejected_with_the_wrong_verifier() {
+        let err = providers()
+            .exchange_code(OAuthAccountProvider::GITHUB, CODE, "wrong")
+            .await
+            .unwrap_err();
+
+        assert_eq!(err, ProviderError::Rejected("invalid_grant".into()));
+    }
+
+    #[tokio::test]
+    async fn exchange_code_needs_the_provider_configured() {
+        let err = providers()
+            .exchange_code(OAuthAccountProvider::GOOGLE, CODE, CODE_VERIFIER)
+            .await
+            .unwrap_err();
+
+        assert_eq!(
+            err,
+            ProviderError::Unconfigured(OAuthAccountProvider::GOOGLE)
+        );
+    }
+
+    #[tokio::test]
+    async fn refresh_keeps_the_refresh_token_unless_rotated() {
+        let providers = providers();
+
+        let kept = providers
+            .refresh(OAuthAccountProvider::GITHUB, "kept-1")
+            .await
+            .unwrap();
+        assert_eq!(kept.access_token, "refreshed-access-token");
+        assert_eq!(kept.refresh_token, None);
+        assert!(kept.expires_at < chrono::Utc::now() + chrono::Duration::minutes(2));
+
+        let rotated = providers
+            .refresh(OAuthAccountProvider::GITHUB, "rotating-1")
+            .await
+            .unwrap();
+        assert_eq!(rotated.refresh_token.as_deref(), Some("rotating-2"));
+        // Without `expires_in`, the token is assumed to last the default.
+        assert!(rotated.expires_at > chrono::Utc::now() + chrono::Duration::minutes(59));
+    }
+
+    #[tokio::test]
+    async fn refresh_tells_rejected_grants_from_failures() {
+        let providers = providers();
+
+        let revoked = providers
+            .refresh(OAuthAccountProvider::GITHUB, "revoked-1")
+            .await
+            .unwrap_err();
+        assert_eq!(revoked, ProviderError::Rejected("invalid_grant".into()));
+
+        let flaky = providers
+            .refresh(OAuthAccountProvider::GITHUB, "flaky-1")
+            .await
+            .unwrap_err();
+        assert_eq!(
+            flaky,
+            ProviderError::Failed("temporarily_unavailable".into())
+        );
+    }
+
+    #[test]
+    fn account_id_reads_github_ids_and_openid_subjects() {
+        let github = OAuthAccountProvider::GITHUB;
+        let google = OAuthAccountProvider::GOOGLE;
+
+        assert_eq!(
+            account_id(github, &serde_json::json!({ "id": 42 })),
+            Some("42".into())
+        );
+        assert_eq!(
+            account_id(github, &serde_json::json!({ "id": "42" })),
+            Some("42".into())
+        );
+        assert_eq!(account_id(github, &serde_json::json!({ "id": null })), None);
+        assert_eq!(
+            account_id(github, &serde_json::json!({ "sub": "42" })),
+            None
+        );
+        assert_eq!(account_id(github, &serde_json::json!([42])), None);
+
+        assert_eq!(
+            account_id(google, &serde_json::json!({ "sub": "1234" })),
+            Some("1234".into())
+        );
+        assert_eq!(
+            account_id(google, &serde_json::json!({ "sub": 1234 })),
+            None
+        );
+        assert_eq!(
+            account_id(google, &serde_json::json!({ "id": "1234" })),
+            None
+        );
+    }
+
+    #[test]
+    fn id_token_subject_reads_the_sub_claim() {
+        assert_eq!(
+            id_token_subject(&id_token("apple-account")),
+            Some("apple-account".into())
+        );
+        assert_eq!(id_token_subject("not a token"), None);
+        assert_eq!(id_token_subject("header.!!!.signature"), None);
+
+        let no_sub = format!(
+            "header.{}.signature",
+            base64::encode_config(r#"{"iss":"apple"}"#, base64::URL_SAFE_NO_PAD)
+        );
+        assert_eq!(id_token_subject(&no_sub), None);
+    }
+
+    /// Runs a refresh job for a new GitHub account whose refresh token is
+    /// `refresh_token`, returning the job's result and the account afterwards.
+    /// It runs against the migrated database at `DATABASE_URL`, in a
+    /// transaction that's rolled back, or not at all if that isn't set.
+    async fn run_refresh(
+        refresh_token: &str,
+    ) -> Option<(Result<(), BoxError>, OAuthAccount, Keyring)> {
+        dotenv::dotenv().ok();
+        let database_url = match std::env::var("DATABASE_URL") {
+            Ok(database_url) => database_url,
+            Err(_) => {
+                eprintln!("skipped: DATABASE_URL isn't set");
+                return None;
+            }
+        };
+        let pool = Pool::test(database_url).unwrap();
+        let keyring = Keyring::new(vec![("test".to_owned(), [7; 32])]).unwrap();
+
+        let provider_account_id = uuid::Uuid::new_v4().to_string();
+        let tokens = Tokens {
+            access_token: "old-access-token".into(),
+            expires_at: chrono::Utc::now(),
+            refresh_token: Some(refresh_token.into()),
+        };
+        let account: OAuthAccount = {
+            let keyring = keyring.clone();
+            pool.run(move |conn| -> Result<_, BoxError> {
+                OAuthAccount::sign_in(
+                    conn,
+                    &keyring,
+                    OAuthAccountProvider::GITHUB,
+                    &provider_account_id,
+                    &tokens,
+                    None,
+                )?;
+                Ok(oauth_accounts::table
+                    .filter(oauth_accounts::provider_account_id.eq(provider_account_id))
+                    .get_result(conn)?)
+            })
+            .await
+            .unwrap()
+        };
+
+        let handler = RefreshOAuthTokenHandler {
+            keyring: keyring.clone(),
+            pool: pool.clone(),
+            providers: providers(),
+        };
+        let result = handler
+            .run(RefreshOAuthToken {
+                oauth_account_id: account.id,
+            })
+            .await;
+
+        let id = account.id;
+        let account = pool
+            .run(move |conn| -> Result<_, BoxError> {
+                Ok(oauth_accounts::table.find(id).get_result(conn)?)
+            })
+            .await
+            .unwrap();
+
+        Some((result, account, keyring))
+    }
+
+    #[tokio::test]
+    async fn refresh_handler_records_new_tokens() {
+        let (result, account, keyring) = match run_refresh("rotating-1").await {
+            Some(refreshed) => refreshed,
+            None => return,
+        };
+
+        assert!(result.is_ok());
+        assert_eq!(
+            keyring.open(&account.provider_access_token).unwrap(),
+            "refreshed-access-token"
+        );
+        assert_eq!(
+            keyring.open(&account.provider_refresh_token).unwrap(),
+            "rotating-2"
+        );
+        assert_eq!(account.refresh_error, None);
+        assert_eq!(account.refresh_failed_at, None);
+    }
+
+    #[tokio::test]
+    async fn refresh_handler_completes_rejected_refreshes() {
+        let (result, account, keyring) = match run_refresh("revoked-1").await {
+            Some(refreshed) => refreshed,
+            None => return,
+        };
+
+        // Retrying won't help, so the job completes with the error recorded.
+        assert!(result.is_ok());
+        assert_eq!(
+            account.refresh_error.as_deref(),
+            Some("provider rejected the grant: invalid_grant")
+        );
+        assert!(account.refresh_failed_at.is_some());
+        assert_eq!(
+            keyring.open(&account.provider_access_token).unwrap(),
+            "old-access-token"
+        );
+    }
+
+    #[tokio::test]
+    async fn refresh_handler_fails_retryable_refreshes() {
+        let (result, account, _) = match run_refresh("flaky-1").await {
+            Some(refreshed) => refreshed,
+            None => return,
+        };
+
+        assert!(result.is_err());
+        assert_eq!(
+            account.refresh_error.as_deref(),
+            Some("provider request failed: temporarily_unavailable")
+        );
+        assert!(account.refresh_failed_at.is_some());
+    }
+}
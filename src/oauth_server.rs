@@ -0,0 +1,522 @@
+//! The OAuth 2.0 authorization server (RFC 6749), which lets apps registered
+//! as OAuth clients act for the users who authorize them, within the scopes
+//! they're granted. Only the authorization code grant is supported, always
+//! with PKCE (RFC 7636), along with refresh tokens and revocation (RFC 7009).
+
+use std::convert::Infallible;
+use std::fmt::{self, Display};
+
+use diesel::prelude::*;
+use percent_encoding::percent_decode_str;
+use serde::{Deserialize, Serialize};
+use url::Url;
+use warp::filters::BoxedFilter;
+use warp::http::{header, StatusCode};
+use warp::reply::Response;
+use warp::Filter;
+
+use crate::auth;
+use crate::db::{Pool, RunError};
+use crate::models::{
+    self, AccessToken, OAuthAuthorizationCode, OAuthClient, OAuthGrantError, ParseScopeError, Scope,
+};
+use crate::schema::{access_tokens, oauth_clients};
+
+/// The largest request body the token and revocation endpoints accept.
+const MAX_FORM_BYTES: u64 = 16 * 1024;
+
+#[derive(Deserialize)]
+struct AuthorizeQuery {
+    client_id: Option<String>,
+    code_challenge: Option<String>,
+    code_challenge_method: Option<String>,
+    redirect_uri: Option<String>,
+    response_type: Option<String>,
+    scope: Option<String>,
+    state: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct TokenForm {
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    code: Option<String>,
+    code_verifier: Option<String>,
+    grant_type: Option<String>,
+    redirect_uri: Option<String>,
+    refresh_token: Option<String>,
+    scope: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct RevokeForm {
+    client_id: Option<String>,
+    client_secret: Option<String>,
+    token: Option<String>,
+}
+
+/// A successful response from the token endpoint (RFC 6749 section 5.1).
+#[derive(Debug, Serialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: i64,
+    refresh_token: String,
+    /// Space-separated, as in requests.
+    scope: String,
+    token_type: &'static str,
+}
+
+/// An error response from the token or revocation endpoint (RFC 6749 section
+/// 5.2), or from the authorization endpoint when it can't redirect back to
+/// the client.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+struct TokenError {
+    error: &'static str,
+    error_description: String,
+    #[serde(skip)]
+    status: StatusCode,
+}
+
+impl TokenError {
+    fn new<D: Into<String>>(status: StatusCode, error: &'static str, description: D) -> Self {
+        Self {
+            error,
+            error_description: description.into(),
+            status,
+        }
+    }
+
+    fn invalid_client() -> Self {
+        Self::new(
+            StatusCode::UNAUTHORIZED,
+            "invalid_client",
+            "unknown client, or the wrong client secret",
+        )
+    }
+
+    fn invalid_request<D: Into<String>>(description: D) -> Self {
+        Self::new(StatusCode::BAD_REQUEST, "invalid_request", description)
+    }
+
+    fn internal<E: Display>(err: E) -> Self {
+        log::error!("OAuth token request failed: {}", err);
+        Self::new(
+            StatusCode::INTERNAL_SERVER_ERROR,
+            "server_error",
+            "something went wrong; try again",
+        )
+    }
+}
+
+impl Display for TokenError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.error, self.error_description)
+    }
+}
+
+impl std::error::Error for TokenError {}
+
+impl From<diesel::result::Error> for TokenError {
+    fn from(err: diesel::result::Error) -> Self {
+        Self::internal(err)
+    }
+}
+
+impl From<OAuthGrantError> for TokenError {
+    fn from(err: OAuthGrantError) -> Self {
+        match err {
+            OAuthGrantError::Invalid => {
+                Self::new(StatusCode::BAD_REQUEST, "invalid_grant", err.to_string())
+            }
+            OAuthGrantError::InvalidScope => {
+                Self::new(StatusCode::BAD_REQUEST, "invalid_scope", err.to_string())
+            }
+            OAuthGrantError::Query(err) => Self::internal(err),
+        }
+    }
+}
+
+impl From<RunError> for TokenError {
+    fn from(err: RunError) -> Self {
+        match err {
+            RunError::Busy => Self::new(
+                StatusCode::SERVICE_UNAVAILABLE,
+                "temporarily_unavailable",
+                err.to_string(),
+            ),
+            err => Self::internal(err),
+        }
+    }
+}
+
+/// What the `/oauth` endpoints share between requests.
+#[derive(Clone)]
+struct State {
+    /// The web app's URL, which has the consent page.
+    app_url: Url,
+    pool: Pool,
+}
+
+/// The `/oauth/authorize`, `/oauth/token` and `/oauth/revoke` endpoints.
+///
+/// `authorize` checks the client and redirect URI, then sends the user on to
+/// the web app's consent page at `APP_URL/authorize`, with the `client_id`,
+/// `redirect_uri`, `scope`, `state` and `code_challenge` to pass to the
+/// `oauthAuthorize` mutation if they agree. Other problems with the request
+/// are reported back to the client's redirect URI, but problems with the
+/// client or redirect URI are only ever shown to the user, as JSON.
+///
+/// `token` exchanges codes and refresh tokens for new tokens, and `revoke`
+/// revokes them. Confidential clients authenticate to both with HTTP Basic
+/// authentication or `client_secret` in the form, and public clients with
+/// `client_id` alone.
+pub fn routes(pool: Pool, app_url: Url) -> BoxedFilter<(Response,)> {
+    let state = State { app_url, pool };
+
+    let authorize = warp::path!("oauth" / "authorize")
+        .and(warp::get())
+        .and(warp::query::<AuthorizeQuery>())
+        .and_then({
+            let state = state.clone();
+            move |query: AuthorizeQuery| {
+                let state = state.clone();
+                async move { Ok::<_, Infallible>(authorize(&state, query).await) }
+            }
+        });
+
+    let token = warp::path!("oauth" / "token")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::content_length_limit(MAX_FORM_BYTES))
+        .and(warp::body::form::<TokenForm>())
+        .and_then({
+            let state = state.clone();
+            move |authorization: Option<String>, form: TokenForm| {
+                let state = state.clone();
+                async move {
+                    let response = match token(&state, authorization, form).await {
+                        Ok(body) => json(StatusCode::OK, &body),
+                        Err(err) => json(err.status, &err),
+                    };
+                    Ok::<_, Infallible>(response)
+                }
+            }
+        });
+
+    let revoke = warp::path!("oauth" / "revoke")
+        .and(warp::post())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::body::content_length_limit(MAX_FORM_BYTES))
+        .and(warp::body::form::<RevokeForm>())
+        .and_then(move |authorization: Option<String>, form: RevokeForm| {
+            let state = state.clone();
+            async move {
+                let response = match revoke(&state, authorization, form).await {
+                    Ok(()) => json(StatusCode::OK, &serde_json::json!({})),
+                    Err(err) => json(err.status, &err),
+                };
+                Ok::<_, Infallible>(response)
+            }
+        });
+
+    authorize.or(token).unify().or(revoke).unify().boxed()
+}
+
+async fn authorize(state: &State, query: AuthorizeQuery) -> Response {
+    let client_id = query.client_id.as_deref().and_then(|id| id.parse().ok());
+    let client = match (client_id, query.redirect_uri.clone()) {
+        (Some(client_id), Some(redirect_uri)) => {
+            state
+                .pool
+                .run(move |conn| -> Result<_, TokenError> {
+                    Ok(OAuthClient::find_for_redirect(
+                        conn,
+                        client_id,
+                        &redirect_uri,
+                    )?)
+                })
+                .await
+        }
+        _ => Ok(None),
+    };
+    let client = match client {
+        Ok(Some(client)) => client,
+        Ok(None) => {
+            let err = TokenError::invalid_request(
+                "unknown client_id, or a redirect_uri the client hasn't registered",
+            );
+            return json(err.status, &err);
+        }
+        Err(err) => return json(err.status, &err),
+    };
+
+    // The redirect URI checks out, so from here on errors go back to it.
+    let redirect_uri = query.redirect_uri.as_deref().unwrap_or_default();
+    let fail = |error: &str, description: &str| {
+        let mut params = vec![("error", error), ("error_description", description)];
+        if let Some(state) = &query.state {
+            params.push(("state", state));
+        }
+        redirect(&self::redirect_uri(redirect_uri, &params))
+    };
+
+    if query.response_type.as_deref() != Some("code") {
+        return fail("unsupported_response_type", "response_type must be code");
+    }
+    let code_challenge = match (
+        query.code_challenge.as_deref(),
+        query.code_challenge_method.as_deref(),
+    ) {
+        (Some(code_challenge), Some("S256")) if models::is_code_challenge(code_challenge) => {
+            code_challenge
+        }
+        _ => {
+            return fail(
+                "invalid_request",
+                "a code_challenge is required, with code_challenge_method S256",
+            )
+        }
+    };
+    let scopes = match query.scope.as_deref().map(parse_scopes) {
+        Some(Ok(scopes)) if !scopes.is_empty() => scopes,
+        _ => return fail("invalid_scope", "scope must list one or more known scopes"),
+    };
+
+    let mut location = state.app_url.clone();
+    location
+        .path_segments_mut()
+        .expect("APP_URL is a base URL")
+        .pop_if_empty()
+        .push("authorize");
+    {
+        let mut params = location.query_pairs_mut();
+        params
+            .append_pair("client_id", &client.id.to_string())
+            .append_pair("code_challenge", code_challenge)
+            .append_pair("redirect_uri", redirect_uri)
+            .append_pair("scope", &format_scopes(&scopes));
+        if let Some(state) = &query.state {
+            params.append_pair("state", state);
+        }
+    }
+    redirect(&location)
+}
+
+async fn token(
+    state: &State,
+    authorization: Option<String>,
+    form: TokenForm,
+) -> Result<TokenResponse, TokenError> {
+    let (client_id, client_secret) = client_credentials(
+        authorization.as_deref(),
+        &form.client_id,
+        &form.client_secret,
+    )?;
+
+    state
+        .pool
+        .run(move |conn| -> Result<_, TokenError> {
+            let client = authenticate_client(conn, &client_id, client_secret.as_deref())?;
+
+            let (access_token, token, refresh_token) = match form.grant_type.as_deref() {
+                Some("authorization_code") => {
+                    match (form.code, form.redirect_uri, form.code_verifier) {
+                        (Some(code), Some(redirect_uri), Some(code_verifier)) => {
+                            OAuthAuthorizationCode::exchange(
+                                conn,
+                                client.id,
+                                &code,
+                                &redirect_uri,
+                                &code_verifier,
+                            )?
+                        }
+                        _ => {
+                            return Err(TokenError::invalid_request(
+                                "code, redirect_uri and code_verifier are required",
+                            ))
+                        }
+                    }
+                }
+                Some("refresh_token") => {
+                    let refresh_token = form
+                        .refresh_token
+                        .ok_or_else(|| TokenError::invalid_request("refresh_token is required"))?;
+                    let scopes = form
+                        .scope
+                        .as_deref()
+                        .map(parse_scopes)
+                        .transpose()
+                        .map_err(|err| {
+                            TokenError::new(
+                                StatusCode::BAD_REQUEST,
+                                "invalid_scope",
+                                err.to_string(),
+                            )
+                        })?;
+                    AccessToken::refresh(conn, client.id, &refresh_token, scopes.as_deref())?
+                }
+                _ => {
+                    return Err(TokenError::new(
+                        StatusCode::BAD_REQUEST,
+                        "unsupported_grant_type",
+                        "grant_type must be authorization_code or refresh_token",
+                    ))
+                }
+            };
+
+            Ok(TokenResponse {
+                access_token: token,
+                expires_in: models::OAUTH_ACCESS_TOKEN_LIFETIME_MINUTES * 60,
+                refresh_token,
+                scope: format_scopes(&access_token.scopes),
+                token_type: "Bearer",
+            })
+        })
+        .await
+}
+
+/// Revokes an access token or refresh token issued to the client, along with
+/// the other token issued with it. Tokens that are unknown, or were issued to
+/// another client, are ignored, as RFC 7009 asks.
+async fn revoke(
+    state: &State,
+    authorization: Option<String>,
+    form: RevokeForm,
+) -> Result<(), TokenError> {
+    let (client_id, client_secret) = client_credentials(
+        authorization.as_deref(),
+        &form.client_id,
+        &form.client_secret,
+    )?;
+    let token = form
+        .token
+        .ok_or_else(|| TokenError::invalid_request("token is required"))?;
+
+    state
+        .pool
+        .run(move |conn| -> Result<_, TokenError> {
+            let client = authenticate_client(conn, &client_id, client_secret.as_deref())?;
+
+            let token_hash = auth::hash_token(&token);
+            diesel::delete(
+                access_tokens::table
+                    .filter(access_tokens::client_id.eq(Some(client.id)))
+                    .filter(
+                        access_tokens::token_hash
+                            .eq(&token_hash)
+                            .or(access_tokens::refresh_token_hash.eq(Some(&token_hash))),
+                    ),
+            )
+            .execute(conn)?;
+            Ok(())
+        })
+        .await
+}
+
+/// The client's ID and secret, from HTTP Basic authentication (RFC 6749
+/// section 2.3.1) if the request has it, and from the form otherwise.
+fn client_credentials(
+    authorization: Option<&str>,
+    client_id: &Option<String>,
+    client_secret: &Option<String>,
+) -> Result<(String, Option<String>), TokenError> {
+    let authorization = match authorization {
+        Some(authorization) => authorization,
+        None => {
+            let client_id = client_id.clone().ok_or_else(TokenError::invalid_client)?;
+            return Ok((client_id, client_secret.clone()));
+        }
+    };
+
+    let credentials = authorization
+        .trim()
+        .split_once(' ')
+        .filter(|(scheme, _)| scheme.eq_ignore_ascii_case("basic"))
+        .and_then(|(_, credentials)| base64::decode(credentials.trim()).ok())
+        .and_then(|credentials| String::from_utf8(credentials).ok())
+        .ok_or_else(TokenError::invalid_client)?;
+    let (client_id, client_secret) = credentials
+        .split_once(':')
+        .ok_or_else(TokenError::invalid_client)?;
+
+    // Both are form-encoded before they're put together.
+    let decode = |s: &str| {
+        percent_decode_str(&s.replace('+', " "))
+            .decode_utf8()
+            .map(String::from)
+            .map_err(|_| TokenError::invalid_client())
+    };
+    let client_secret = decode(client_secret)?;
+    Ok((
+        decode(client_id)?,
+        Some(client_secret).filter(|secret| !secret.is_empty()),
+    ))
+}
+
+fn authenticate_client(
+    conn: &PgConnection,
+    client_id: &str,
+    client_secret: Option<&str>,
+) -> Result<OAuthClient, TokenError> {
+    let client_id: uuid::Uuid = client_id
+        .parse()
+        .map_err(|_| TokenError::invalid_client())?;
+
+    oauth_clients::table
+        .find(client_id)
+        .filter(oauth_clients::deleted_at.is_null())
+        .get_result::<OAuthClient>(conn)
+        .optional()?
+        .filter(|client| client.authenticate(client_secret))
+        .ok_or_else(TokenError::invalid_client)
+}
+
+/// Parses a space-separated `scope` parameter.
+fn parse_scopes(scope: &str) -> Result<Vec<Scope>, ParseScopeError> {
+    let scopes = scope
+        .split(' ')
+        .filter(|scope| !scope.is_empty())
+        .map(str::parse)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    Ok(models::normalize_scopes(&scopes))
+}
+
+fn format_scopes(scopes: &[Scope]) -> String {
+    scopes
+        .iter()
+        .map(Scope::to_string)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// A client's `redirect_uri` with `params` added to its query, for sending
+/// users back to the client.
+pub fn redirect_uri(redirect_uri: &str, params: &[(&str, &str)]) -> Url {
+    let mut url = Url::parse(redirect_uri).expect("registered redirect URIs are valid");
+    url.query_pairs_mut().extend_pairs(params);
+    url
+}
+
+fn redirect(location: &Url) -> Response {
+    warp::http::Response::builder()
+        .status(StatusCode::SEE_OTHER)
+        .header(header::LOCATION, location.as_str())
+        .body(Default::default())
+        .expect("responses are valid")
+}
+
+/// A JSON response. Responses with tokens in them mustn't be cached, so none
+/// are.
+fn json<T: Serialize>(status: StatusCode, body: &T) -> Response {
+    warp::http::Response::builder()
+        .status(status)
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::CACHE_CONTROL, "no-store")
+        .body(
+            serde_json::to_string(body)
+                .expect("responses serialize")
+                .into(),
+        )
+        .expect("responses are valid")
+}
@@ -0,0 +1,663 @@
+use std::fmt::{self, Display};
+use std::str::FromStr;
+
+use diesel::expression::{NonAggregate, SelectableExpression};
+use diesel::pg::Pg;
+use diesel::prelude::*;
+use diesel::query_builder::{BoxedSelectStatement, QueryFragment};
+use diesel::sql_types::Integer;
+use graphql::{Context, ErrorExtensions};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::jobs::Job;
+use crate::models::{Blog, DateTime, Note, Post, Session, User};
+
+/// Page size used when neither `first` nor `last` is given.
+pub const DEFAULT_PAGE_SIZE: i64 = 20;
+pub const MAX_PAGE_SIZE: i64 = 100;
+const MIN_CURSOR_KEY_LENGTH: usize = 32;
+
+pub trait Node
+where
+    Self: graphql::OutputType,
+{
+    fn position(&self) -> Position;
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+#[graphql(complex)]
+#[graphql(concrete(name = "BlogConnection", params(Blog)))]
+#[graphql(concrete(name = "JobConnection", params(Job)))]
+#[graphql(concrete(name = "NoteConnection", params(Note)))]
+#[graphql(concrete(name = "PostConnection", params(Post)))]
+#[graphql(concrete(name = "SessionConnection", params(Session)))]
+#[graphql(concrete(name = "UserConnection", params(User)))]
+pub struct Connection<T: Node>
+where
+    Edge<T>: graphql::OutputType,
+{
+    pub edges: Vec<Edge<T>>,
+    pub page_info: PageInfo,
+    /// Only counted when selected; see [`paginate`].
+    #[graphql(skip)]
+    pub total_count: Option<i64>,
+}
+
+#[graphql::ComplexObject]
+impl<T: Node> Connection<T>
+where
+    Edge<T>: graphql::OutputType,
+{
+    /// The number of nodes in the whole connection, ignoring `first`, `last`,
+    /// `after` and `before`.
+    pub async fn total_count(&self) -> i64 {
+        self.total_count.unwrap_or_default()
+    }
+}
+
+/// An opaque, signed pointer to a node within a connection.
+///
+/// Cursors are only issued and checked by [`paginate`], which binds them to
+/// the connection that issued them: its field, the object it's a field of,
+/// its node type and its [`Order`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cursor(String);
+
+#[graphql::Scalar]
+impl graphql::ScalarType for Cursor {
+    fn parse(value: graphql::Value) -> graphql::InputValueResult<Self> {
+        if let graphql::Value::String(value) = value {
+            Ok(Self(value))
+        } else {
+            Err(graphql::InputValueError::expected_type(value))
+        }
+    }
+
+    fn to_value(&self) -> graphql::Value {
+        graphql::Value::String(self.0.clone())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CursorError {
+    Malformed(&'static str),
+    Tampered(&'static str),
+    WrongConnection(&'static str),
+}
+
+impl Display for CursorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Malformed(arg) => write!(f, "{} is not a valid cursor", arg),
+            Self::Tampered(arg) => write!(f, "{} cursor has been tampered with", arg),
+            Self::WrongConnection(arg) => {
+                write!(f, "{} cursor was issued by a different connection", arg)
+            }
+        }
+    }
+}
+
+impl std::error::Error for CursorError {}
+
+impl ErrorExtensions for CursorError {
+    fn extend(&self) -> graphql::Error {
+        let argument = match self {
+            Self::Malformed(arg) | Self::Tampered(arg) | Self::WrongConnection(arg) => *arg,
+        };
+
+        graphql::Error::new(self.to_string()).extend_with(|_, e| {
+            e.set("code", "BAD_USER_INPUT");
+            e.set("argument", argument);
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InvalidCursorKeyError;
+
+impl Display for InvalidCursorKeyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "cursor signing keys must be at least {} base64 bytes",
+            MIN_CURSOR_KEY_LENGTH
+        )
+    }
+}
+
+impl std::error::Error for InvalidCursorKeyError {}
+
+/// The server-side key cursors are signed with. Changing it invalidates every
+/// cursor issued so far, which clients recover from by starting over.
+#[derive(Clone)]
+pub struct CursorKey(Vec<u8>);
+
+impl CursorKey {
+    fn mac(&self, payload: &[u8]) -> Hmac<Sha256> {
+        let mut mac =
+            Hmac::<Sha256>::new_from_slice(&self.0).expect("HMAC accepts keys of any length");
+        mac.update(payload);
+        mac
+    }
+
+    fn sign(&self, scope: &CursorScope, position: Position) -> Cursor {
+        let payload = serde_json::to_vec(&SignedPosition {
+            position,
+            scope: scope.clone(),
+        })
+        .expect("positions always serialize");
+        let signature = self.mac(&payload).finalize().into_bytes();
+
+        Cursor(format!(
+            "{}.{}",
+            base64::encode_config(&payload, base64::URL_SAFE_NO_PAD),
+            base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+        ))
+    }
+
+    fn verify(
+        &self,
+        scope: &CursorScope,
+        arg: &'static str,
+        cursor: &Cursor,
+    ) -> Result<Position, CursorError> {
+        let decode = |part: &str| {
+            base64::decode_config(part, base64::URL_SAFE_NO_PAD)
+                .map_err(|_| CursorError::Malformed(arg))
+        };
+
+        let (payload, signature) = cursor
+            .0
+            .split_once('.')
+            .ok_or(CursorError::Malformed(arg))?;
+        let (payload, signature) = (decode(payload)?, decode(signature)?);
+        self.mac(&payload)
+            .verify_slice(&signature)
+            .map_err(|_| CursorError::Tampered(arg))?;
+
+        let signed: SignedPosition =
+            serde_json::from_slice(&payload).map_err(|_| CursorError::Malformed(arg))?;
+        if signed.scope != *scope {
+            return Err(CursorError::WrongConnection(arg));
+        }
+
+        Ok(signed.position)
+    }
+}
+
+impl fmt::Debug for CursorKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("CursorKey(..)")
+    }
+}
+
+impl FromStr for CursorKey {
+    type Err = InvalidCursorKeyError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let key = base64::decode(s.trim())
+            .or_else(|_| base64::decode_config(s.trim(), base64::URL_SAFE_NO_PAD))
+            .map_err(|_| InvalidCursorKeyError)?;
+
+        if key.len() < MIN_CURSOR_KEY_LENGTH {
+            Err(InvalidCursorKeyError)
+        } else {
+            Ok(Self(key))
+        }
+    }
+}
+
+/// The sort orders connections can be paginated in. Cursors only work with
+/// the order they were issued for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub enum Order {
+    /// Oldest first, by `_rowid`.
+    RowidAsc,
+    /// Newest first, by `_rowid`.
+    RowidDesc,
+    /// Newest first, by `(created_at, id)`.
+    CreatedAtDesc,
+    /// Most recently published first, by `(publish_at, id)`.
+    PublishAtDesc,
+}
+
+/// Where a node sits in a connection, as encoded in its cursor.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+pub struct Position {
+    pub _rowid: i32,
+    /// Only needed for `Order::CreatedAtDesc`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<uuid::Uuid>,
+    /// Only needed for `Order::PublishAtDesc`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub publish_at: Option<DateTime>,
+}
+
+#[derive(serde::Serialize, serde::Deserialize)]
+struct SignedPosition {
+    position: Position,
+    #[serde(flatten)]
+    scope: CursorScope,
+}
+
+/// Identifies a connection, so its cursors can't be used with another.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+struct CursorScope {
+    /// The name of the connection's field, ignoring any alias.
+    #[serde(default)]
+    field: String,
+    node: String,
+    order: Order,
+    /// The ID of the object the connection is a field of, if it isn't a root
+    /// field.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent: Option<uuid::Uuid>,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+#[graphql(concrete(name = "BlogEdge", params(Blog)))]
+#[graphql(concrete(name = "JobEdge", params(Job)))]
+#[graphql(concrete(name = "NoteEdge", params(Note)))]
+#[graphql(concrete(name = "PostEdge", params(Post)))]
+#[graphql(concrete(name = "SessionEdge", params(Session)))]
+#[graphql(concrete(name = "UserEdge", params(User)))]
+pub struct Edge<T: Node> {
+    pub cursor: Cursor,
+    pub node: T,
+}
+
+#[derive(Debug, graphql::SimpleObject)]
+pub struct PageInfo {
+    pub end_cursor: Option<Cursor>,
+    pub has_next_page: bool,
+    pub has_previous_page: bool,
+    pub start_cursor: Option<Cursor>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PaginationError {
+    FirstAndLast,
+    Negative(&'static str),
+    TooLarge(&'static str),
+}
+
+impl Display for PaginationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::FirstAndLast => "only one of first and last may be given".fmt(f),
+            Self::Negative(arg) => write!(f, "{} must not be negative", arg),
+            Self::TooLarge(arg) => write!(f, "{} must be at most {}", arg, MAX_PAGE_SIZE),
+        }
+    }
+}
+
+impl std::error::Error for PaginationError {}
+
+/// The standard `first`/`after`/`last`/`before` connection arguments.
+#[derive(Debug, Default)]
+pub struct PageArgs {
+    pub after: Option<Cursor>,
+    pub before: Option<Cursor>,
+    pub first: Option<i64>,
+    pub last: Option<i64>,
+}
+
+impl PageArgs {
+    pub fn new(
+        first: Option<i64>,
+        after: Option<Cursor>,
+        last: Option<i64>,
+        before: Option<Cursor>,
+    ) -> Result<Self, PaginationError> {
+        for (arg, value) in [("first", first), ("last", last)] {
+            match value {
+                Some(value) if value < 0 => return Err(PaginationError::Negative(arg)),
+                Some(value) if value > MAX_PAGE_SIZE => return Err(PaginationError::TooLarge(arg)),
+                _ => {}
+            }
+        }
+        if first.is_some() && last.is_some() {
+            return Err(PaginationError::FirstAndLast);
+        }
+
+        Ok(Self {
+            after,
+            before,
+            first,
+            last,
+        })
+    }
+}
+
+/// A slice of a connection for a resolver to load.
+///
+/// `after` and `before` are exclusive bounds in the connection's `order`. A
+/// `backward` window must be loaded in reverse order (nearest `before`
+/// first), so that `limit` keeps the nodes closest to it.
+#[derive(Debug, Clone, Copy)]
+pub struct Window<'a> {
+    pub after: Option<&'a Position>,
+    pub backward: bool,
+    pub before: Option<&'a Position>,
+    pub limit: i64,
+    pub order: Order,
+}
+
+/// Loads one page of a connection sorted by `order`. `parent` is the ID of
+/// the object the connection is a field of, if any; cursors only work with
+/// the same field of the same parent.
+///
+/// `load` is called for the page itself and, when a cursor was given, once
+/// more with a one-node window to find out whether anything lies on the other
+/// side of the page. `count` is only called when `totalCount` is selected.
+/// Both run on the database pool with the same connection.
+pub async fn paginate<T, L, C>(
+    ctx: &Context<'_>,
+    args: &PageArgs,
+    parent: Option<uuid::Uuid>,
+    order: Order,
+    load: L,
+    count: C,
+) -> graphql::Result<Connection<T>>
+where
+    T: Node + Send + 'static,
+    Edge<T>: graphql::OutputType,
+    L: Fn(&PgConnection, Window<'_>) -> QueryResult<Vec<T>> + Send + 'static,
+    C: FnOnce(&PgConnection) -> QueryResult<i64> + Send + 'static,
+{
+    let key = ctx.data_unchecked::<CursorKey>();
+    let scope = CursorScope {
+        field: ctx.field().name().to_owned(),
+        node: T::type_name().into_owned(),
+        order,
+        parent,
+    };
+    let verify = |arg, cursor: &Option<Cursor>| {
+        cursor
+            .as_ref()
+            .map(|cursor| key.verify(&scope, arg, cursor))
+            .transpose()
+            .map_err(|err| err.extend())
+    };
+    let after = verify("after", &args.after)?;
+    let before = verify("before", &args.before)?;
+
+    let backward = args.last.is_some();
+    let size = args.first.or(args.last).unwrap_or(DEFAULT_PAGE_SIZE);
+    let with_count = ctx.look_ahead().field("totalCount").exists();
+
+    let pool = ctx.data_unchecked::<crate::db::Pool>();
+    let (nodes, has_previous_page, has_next_page, total_count) = pool
+        .run(move |conn| -> graphql::Result<_> {
+            let mut nodes = load(
+                conn,
+                Window {
+                    after: after.as_ref(),
+                    backward,
+                    before: before.as_ref(),
+                    limit: size + 1,
+                    order,
+                },
+            )?;
+            let has_more = nodes.len() as i64 > size;
+            nodes.truncate(size as usize);
+            if backward {
+                nodes.reverse();
+            }
+
+            let start = nodes.first().map(Node::position);
+            let end = nodes.last().map(Node::position);
+
+            // Anything before the first node (or, for an empty page, anything
+            // within the other bound) is on the previous page, and vice versa.
+            let (has_previous_page, has_next_page) = if backward {
+                let has_next_page = before.is_some()
+                    && !load(
+                        conn,
+                        Window {
+                            after: end.as_ref().or(after.as_ref()),
+                            backward: false,
+                            before: None,
+                            limit: 1,
+                            order,
+                        },
+                    )?
+                    .is_empty();
+                (has_more, has_next_page)
+            } else {
+                let has_previous_page = after.is_some()
+                    && !load(
+                        conn,
+                        Window {
+                            after: None,
+                            backward: true,
+                            before: start.as_ref().or(before.as_ref()),
+                            limit: 1,
+                            order,
+                        },
+                    )?
+                    .is_empty();
+                (has_previous_page, has_more)
+            };
+
+            let total_count = if with_count { Some(count(conn)?) } else { None };
+
+            Ok((nodes, has_previous_page, has_next_page, total_count))
+        })
+        .await?;
+
+    let start = nodes.first().map(Node::position);
+    let end = nodes.last().map(Node::position);
+
+    Ok(Connection {
+        edges: nodes
+            .into_iter()
+            .map(|node| Edge {
+                cursor: key.sign(&scope, node.position()),
+                node,
+            })
+            .collect(),
+        page_info: PageInfo {
+            end_cursor: end.map(|position| key.sign(&scope, position)),
+            has_next_page,
+            has_previous_page,
+            start_cursor: start.map(|position| key.sign(&scope, position)),
+        },
+        total_count,
+    })
+}
+
+/// Applies `window` to a query over a connection ordered by `_rowid`.
+pub fn rowid_window<'a, ST, QS, C>(
+    mut query: BoxedSelectStatement<'a, ST, QS, Pg>,
+    rowid: C,
+    window: Window<'_>,
+) -> BoxedSelectStatement<'a, ST, QS, Pg>
+where
+    C: ExpressionMethods
+        + Expression<SqlType = Integer>
+        + SelectableExpression<QS>
+        + NonAggregate
+        + QueryFragment<Pg>
+        + Copy
+        + Send
+        + 'a,
+{
+    let descending = window.order == Order::RowidDesc;
+
+    if let Some(after) = window.after {
+        query = if descending {
+            query.filter(rowid.lt(after._rowid))
+        } else {
+            query.filter(rowid.gt(after._rowid))
+        };
+    }
+    if let Some(before) = window.before {
+        query = if descending {
+            query.filter(rowid.gt(before._rowid))
+        } else {
+            query.filter(rowid.lt(before._rowid))
+        };
+    }
+
+    if descending != window.backward {
+        query.order_by(rowid.desc()).limit(window.limit)
+    } else {
+        query.order_by(rowid.asc()).limit(window.limit)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    fn key(byte: u8) -> CursorKey {
+        CursorKey(vec![byte; MIN_CURSOR_KEY_LENGTH])
+    }
+
+    fn scope() -> CursorScope {
+        CursorScope {
+            field: "notes".to_owned(),
+            node: "Note".to_owned(),
+            order: Order::CreatedAtDesc,
+            parent: Some(uuid::Uuid::from_u128(1)),
+        }
+    }
+
+    fn position() -> Position {
+        Position {
+            _rowid: 7,
+            created_at: Some(chrono::Utc.timestamp(1_600_000_000, 0)),
+            id: Some(uuid::Uuid::from_u128(2)),
+            publish_at: None,
+        }
+    }
+
+    /// Re-encodes the cursor's payload after `edit`, keeping its signature.
+    fn tamper(cursor: &Cursor, edit: impl FnOnce(&mut serde_json::Value)) -> Cursor {
+        let (payload, signature) = cursor.0.split_once('.').unwrap();
+        let mut value: serde_json::Value = serde_json::from_slice(
+            &base64::decode_config(payload, base64::URL_SAFE_NO_PAD).unwrap(),
+        )
+        .unwrap();
+        edit(&mut value);
+
+        Cursor(format!(
+            "{}.{}",
+            base64::encode_config(value.to_string(), base64::URL_SAFE_NO_PAD),
+            signature
+        ))
+    }
+
+    #[test]
+    fn verify_round_trips_signed_positions() {
+        let cursor = key(1).sign(&scope(), position());
+        let verified = key(1).verify(&scope(), "after", &cursor).unwrap();
+
+        assert_eq!(verified._rowid, 7);
+        assert_eq!(verified.created_at, position().created_at);
+        assert_eq!(verified.id, position().id);
+        assert_eq!(verified.publish_at, None);
+    }
+
+    #[test]
+    fn verify_rejects_tampered_payloads() {
+        let cursor = key(1).sign(&scope(), position());
+        let tampered = tamper(&cursor, |value| value["position"]["_rowid"] = 8.into());
+
+        assert_eq!(
+            key(1).verify(&scope(), "after", &tampered).unwrap_err(),
+            CursorError::Tampered("after")
+        );
+    }
+
+    #[test]
+    fn verify_rejects_cursors_signed_with_another_key() {
+        let cursor = key(2).sign(&scope(), position());
+
+        assert_eq!(
+            key(1).verify(&scope(), "before", &cursor).unwrap_err(),
+            CursorError::Tampered("before")
+        );
+    }
+
+    #[test]
+    fn verify_rejects_cursors_from_other_connections() {
+        let cursor = key(1).sign(&scope(), position());
+        let others = [
+            CursorScope {
+                field: "posts".to_owned(),
+                ..scope()
+            },
+            CursorScope {
+                node: "Post".to_owned(),
+                ..scope()
+            },
+            CursorScope {
+                order: Order::PublishAtDesc,
+                ..scope()
+            },
+            CursorScope {
+                parent: Some(uuid::Uuid::from_u128(3)),
+                ..scope()
+            },
+            CursorScope {
+                parent: None,
+                ..scope()
+            },
+        ];
+
+        for other in &others {
+            assert_eq!(
+                key(1).verify(other, "after", &cursor).unwrap_err(),
+                CursorError::WrongConnection("after"),
+                "{:?}",
+                other
+            );
+        }
+    }
+
+    #[test]
+    fn verify_rejects_malformed_cursors() {
+        let key = key(1);
+        let signed_garbage = {
+            let signature = key.mac(b"not json").finalize().into_bytes();
+            format!(
+                "{}.{}",
+                base64::encode_config(b"not json", base64::URL_SAFE_NO_PAD),
+                base64::encode_config(signature, base64::URL_SAFE_NO_PAD)
+            )
+        };
+
+        for cursor in ["", "no-separator", "!!!.???", "e30.!!!", &signed_garbage] {
+            assert_eq!(
+                key.verify(&scope(), "after", &Cursor(cursor.to_owned()))
+                    .unwrap_err(),
+                CursorError::Malformed("after"),
+                "{:?}",
+                cursor
+            );
+        }
+    }
+
+    #[test]
+    fn keys_must_be_long_enough() {
+        let short = base64::encode([0; MIN_CURSOR_KEY_LENGTH - 1]);
+        let long = base64::encode([0; MIN_CURSOR_KEY_LENGTH]);
+        let url_safe =
+            base64::encode_config([0xfb; MIN_CURSOR_KEY_LENGTH], base64::URL_SAFE_NO_PAD);
+
+        assert_eq!(
+            short.parse::<CursorKey>().unwrap_err(),
+            InvalidCursorKeyError
+        );
+        assert!(long.parse::<CursorKey>().is_ok());
+        assert!(url_safe.parse::<CursorKey>().is_ok());
+        assert!("not base64!".parse::<CursorKey>().is_err());
+    }
+}
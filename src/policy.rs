@@ -0,0 +1,216 @@
+use diesel::prelude::*;
+use graphql::dataloader::DataLoader;
+use graphql::{Context, ErrorExtensions};
+
+use crate::auth::{self, Viewer};
+use crate::loaders::BlogLoader;
+use crate::models::{Post, PostState, Scope, Session, UserRole};
+use crate::schema::{blogs, users};
+
+/// The error for fields that need a viewer when the request has none. Clients
+/// can match on `extensions.code` to prompt for sign-in.
+pub fn unauthenticated() -> graphql::Error {
+    graphql::Error::new("not signed in").extend_with(|_, e| e.set("code", "UNAUTHENTICATED"))
+}
+
+/// The error for fields the viewer is signed in for but not allowed to use.
+pub fn forbidden() -> graphql::Error {
+    graphql::Error::new("not allowed").extend_with(|_, e| e.set("code", "FORBIDDEN"))
+}
+
+/// The error for fields an access token is used for without the scope they
+/// need. `extensions.scope` names the missing scope.
+pub fn insufficient_scope(scope: Scope) -> graphql::Error {
+    graphql::Error::new(format!(
+        "this access token doesn't have the {} scope",
+        scope
+    ))
+    .extend_with(|_, e| {
+        e.set("code", "INSUFFICIENT_SCOPE");
+        e.set("scope", scope.to_string());
+    })
+}
+
+/// The error for fields that only work when signed in with a session, not an
+/// access token.
+pub fn session_required() -> graphql::Error {
+    graphql::Error::new("access tokens can't be used for this; sign in instead")
+        .extend_with(|_, e| e.set("code", "FORBIDDEN"))
+}
+
+/// Rejects requests without a viewer.
+#[derive(Debug, Default)]
+pub struct SignedInGuard;
+
+#[graphql::async_trait::async_trait]
+impl graphql::guard::Guard for SignedInGuard {
+    async fn check(&self, ctx: &Context<'_>) -> graphql::Result<()> {
+        auth::require_viewer(ctx).map(|_| ())
+    }
+}
+
+/// Rejects requests unless the viewer has `role` and is signed in with a
+/// session, since access tokens never carry a role's rights.
+#[derive(Debug)]
+pub struct RoleGuard {
+    pub role: UserRole,
+}
+
+#[graphql::async_trait::async_trait]
+impl graphql::guard::Guard for RoleGuard {
+    async fn check(&self, ctx: &Context<'_>) -> graphql::Result<()> {
+        let viewer = auth::require_viewer(ctx)?;
+
+        if viewer.user.role == self.role && viewer.session().is_some() {
+            Ok(())
+        } else {
+            Err(forbidden())
+        }
+    }
+}
+
+/// Whether the viewer is an admin. Access tokens never carry admin rights,
+/// whatever their user's role.
+pub fn is_admin(viewer: &Viewer) -> bool {
+    viewer.user.role == UserRole::ADMIN && viewer.session().is_some()
+}
+
+/// The viewer, if they signed in with a session rather than an access token.
+pub fn session_viewer<'a>(ctx: &Context<'a>) -> Option<&'a Viewer> {
+    auth::viewer(ctx).filter(|viewer| viewer.session().is_some())
+}
+
+/// The viewer, if they can act with `scope`. Reads that show the viewer more
+/// than they'd see signed out, like their own unpublished posts, treat access
+/// tokens without the scope as signed out.
+pub fn scoped_viewer<'a>(ctx: &Context<'a>, scope: Scope) -> Option<&'a Viewer> {
+    auth::viewer(ctx).filter(|viewer| viewer.has_scope(scope))
+}
+
+/// Rejects access tokens without `scope`. Requests without a viewer are left
+/// for the field's other checks to reject.
+pub fn require_scope(ctx: &Context<'_>, scope: Scope) -> graphql::Result<()> {
+    match auth::viewer(ctx) {
+        Some(viewer) if !viewer.has_scope(scope) => Err(insufficient_scope(scope)),
+        _ => Ok(()),
+    }
+}
+
+/// Rejects access tokens, for fields that manage how users sign in and what
+/// can act for them. Requests without a viewer are left for the field's other
+/// checks, since some of these fields also serve sign-up.
+pub fn require_first_party(ctx: &Context<'_>) -> graphql::Result<()> {
+    match auth::viewer(ctx) {
+        Some(viewer) if viewer.session().is_none() => Err(session_required()),
+        _ => Ok(()),
+    }
+}
+
+/// Requires a viewer signed in with a session, returning the session.
+pub fn require_session<'a>(ctx: &Context<'a>) -> graphql::Result<&'a Session> {
+    auth::require_viewer(ctx)?
+        .session()
+        .ok_or_else(session_required)
+}
+
+/// Whether the viewer is `user_id`, or an admin acting on their behalf.
+pub fn is_user(viewer: Option<&Viewer>, user_id: uuid::Uuid) -> bool {
+    viewer.is_some_and(|viewer| viewer.user.id == user_id || is_admin(viewer))
+}
+
+/// Requires the viewer to be `user_id`, or an admin acting on their behalf.
+pub fn require_user<'a>(ctx: &Context<'a>, user_id: uuid::Uuid) -> graphql::Result<&'a Viewer> {
+    let viewer = auth::require_viewer(ctx)?;
+
+    if viewer.user.id == user_id || is_admin(viewer) {
+        Ok(viewer)
+    } else {
+        Err(forbidden())
+    }
+}
+
+/// Like [`require_user`], but only with a session: a user's sign-in methods,
+/// sessions and access tokens are off limits to access tokens.
+pub fn require_user_session<'a>(
+    ctx: &Context<'a>,
+    user_id: uuid::Uuid,
+) -> graphql::Result<&'a Viewer> {
+    require_session(ctx)?;
+    require_user(ctx, user_id)
+}
+
+/// Requires the viewer to own `blog_id`, or be an admin.
+pub async fn require_blog_owner<'a>(
+    ctx: &Context<'a>,
+    blog_id: uuid::Uuid,
+) -> graphql::Result<&'a Viewer> {
+    let viewer = auth::require_viewer(ctx)?;
+
+    let pool = ctx.data_unchecked::<crate::db::Pool>();
+    let owner_id: uuid::Uuid = pool
+        .run(move |conn| -> graphql::Result<_> {
+            Ok(blogs::table
+                .find(blog_id)
+                .filter(blogs::deleted_at.is_null())
+                .select(blogs::user_id)
+                .get_result(conn)
+                .optional()?)
+        })
+        .await?
+        .ok_or("blog not found")?;
+
+    if viewer.user.id == owner_id || is_admin(viewer) {
+        Ok(viewer)
+    } else {
+        Err(forbidden())
+    }
+}
+
+/// Whether the viewer can see `post`: published posts are public, and the rest
+/// are only visible to the blog's owner and admins, and to access tokens of
+/// theirs with the `posts:read` scope.
+pub async fn can_see_post(ctx: &Context<'_>, post: &Post) -> graphql::Result<bool> {
+    if post.state == PostState::PUBLISHED {
+        return Ok(true);
+    }
+
+    let loader = ctx.data_unchecked::<DataLoader<BlogLoader>>();
+    let blog = loader.load_one(post.blog_id).await?;
+
+    Ok(blog.is_some_and(|blog| is_user(scoped_viewer(ctx, Scope::POSTS_READ), blog.user_id)))
+}
+
+/// Requires the viewer to be allowed to attach sign-in accounts to `user_id`:
+/// the user themselves, or an admin, signed in with a session. During sign-up,
+/// before the user has any way to sign in, the `signupToken` from `userCreate`
+/// stands in for the session; it's redeemed with `User::redeem_signup_token`
+/// as the account is created. Deleted users can't get new accounts, and
+/// access tokens never may attach them.
+pub async fn require_account_owner(
+    ctx: &Context<'_>,
+    user_id: uuid::Uuid,
+    signup_token: Option<&str>,
+) -> graphql::Result<()> {
+    require_first_party(ctx)?;
+    if signup_token.is_none() {
+        require_user_session(ctx, user_id)?;
+    }
+
+    let pool = ctx.data_unchecked::<crate::db::Pool>();
+    let exists = pool
+        .run(move |conn| -> graphql::Result<_> {
+            Ok(diesel::select(diesel::dsl::exists(
+                users::table
+                    .find(user_id)
+                    .filter(users::deleted_at.is_null()),
+            ))
+            .get_result::<bool>(conn)?)
+        })
+        .await?;
+
+    if exists {
+        Ok(())
+    } else {
+        Err("user not found".into())
+    }
+}
@@ -0,0 +1,31 @@
+use std::time::Duration;
+
+use crate::db::Pool;
+use crate::models::Post;
+
+/// How often the publisher looks for posts that are due.
+pub const INTERVAL: Duration = Duration::from_secs(30);
+
+/// Publishes scheduled and queued posts as they come due, every `INTERVAL`,
+/// for as long as the server runs. Errors are logged and retried on the next
+/// run.
+pub async fn run(pool: Pool) {
+    let mut interval = tokio::time::interval(INTERVAL);
+
+    loop {
+        interval.tick().await;
+
+        let published = pool
+            .run(
+                |conn| -> Result<_, Box<dyn std::error::Error + Send + Sync>> {
+                    Ok(Post::publish_due(conn, chrono::Utc::now())?)
+                },
+            )
+            .await;
+        match published {
+            Ok(0) => {}
+            Ok(count) => log::info!("published {} due posts", count),
+            Err(err) => log::error!("could not publish due posts: {}", err),
+        }
+    }
+}
@@ -1,3 +1,21 @@
+table! {
+    access_tokens (id) {
+        _rowid -> Int4,
+        client_id -> Nullable<Uuid>,
+        created_at -> Timestamptz,
+        expires_at -> Nullable<Timestamptz>,
+        id -> Uuid,
+        last_used_at -> Nullable<Timestamptz>,
+        name -> Nullable<Text>,
+        refresh_expires_at -> Nullable<Timestamptz>,
+        refresh_token_hash -> Nullable<Text>,
+        scopes -> Array<Text>,
+        token_hash -> Text,
+        updated_at -> Timestamptz,
+        user_id -> Uuid,
+    }
+}
+
 table! {
     blogs (id) {
         _rowid -> Int4,
@@ -8,6 +26,8 @@ table! {
         title -> Text,
         updated_at -> Timestamptz,
         user_id -> Uuid,
+        queue_interval_minutes -> Int4,
+        queue_published_at -> Nullable<Timestamptz>,
     }
 }
 
@@ -20,6 +40,76 @@ table! {
         provider_account_id -> Text,
         updated_at -> Timestamptz,
         user_id -> Uuid,
+        password_hash -> Nullable<Text>,
+        verified_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    email_tokens (id) {
+        _rowid -> Int4,
+        created_at -> Timestamptz,
+        email -> Text,
+        email_account_id -> Uuid,
+        expires_at -> Timestamptz,
+        id -> Uuid,
+        purpose -> Text,
+        token_hash -> Text,
+        updated_at -> Timestamptz,
+        used_at -> Nullable<Timestamptz>,
+    }
+}
+
+table! {
+    follows (id) {
+        _rowid -> Int4,
+        blog_id -> Uuid,
+        created_at -> Timestamptz,
+        id -> Uuid,
+        updated_at -> Timestamptz,
+        user_id -> Uuid,
+    }
+}
+
+table! {
+    jobs (id) {
+        _rowid -> Int4,
+        attempts -> Int4,
+        created_at -> Timestamptz,
+        id -> Uuid,
+        kind -> Text,
+        last_error -> Nullable<Text>,
+        locked_until -> Nullable<Timestamptz>,
+        max_attempts -> Int4,
+        payload -> Jsonb,
+        run_at -> Timestamptz,
+        state -> Text,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    likes (id) {
+        _rowid -> Int4,
+        created_at -> Timestamptz,
+        id -> Uuid,
+        post_id -> Uuid,
+        updated_at -> Timestamptz,
+        user_id -> Uuid,
+    }
+}
+
+table! {
+    login_challenges (id) {
+        _rowid -> Int4,
+        attempts -> Int4,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        id -> Uuid,
+        token_hash -> Text,
+        updated_at -> Timestamptz,
+        used_at -> Nullable<Timestamptz>,
+        user_id -> Uuid,
     }
 }
 
@@ -34,11 +124,67 @@ table! {
         provider_access_token_expires_at -> Timestamptz,
         provider_account_id -> Text,
         provider_refresh_token -> Text,
+        refresh_error -> Nullable<Text>,
+        refresh_failed_at -> Nullable<Timestamptz>,
+        refresh_queued_at -> Nullable<Timestamptz>,
+        updated_at -> Timestamptz,
+        user_id -> Uuid,
+    }
+}
+
+table! {
+    oauth_authorization_codes (id) {
+        _rowid -> Int4,
+        client_id -> Uuid,
+        code_challenge -> Text,
+        code_hash -> Text,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        id -> Uuid,
+        redirect_uri -> Text,
+        scopes -> Array<Text>,
         updated_at -> Timestamptz,
+        used_at -> Nullable<Timestamptz>,
         user_id -> Uuid,
     }
 }
 
+table! {
+    oauth_clients (id) {
+        _rowid -> Int4,
+        created_at -> Timestamptz,
+        deleted_at -> Nullable<Timestamptz>,
+        id -> Uuid,
+        name -> Text,
+        redirect_uris -> Array<Text>,
+        secret_hash -> Nullable<Text>,
+        updated_at -> Timestamptz,
+        user_id -> Uuid,
+    }
+}
+
+table! {
+    oauth_link_tokens (id) {
+        _rowid -> Int4,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        id -> Uuid,
+        session_id -> Uuid,
+        token_hash -> Text,
+        updated_at -> Timestamptz,
+        user_id -> Uuid,
+    }
+}
+
+table! {
+    post_tags (post_id, tag_id) {
+        created_at -> Timestamptz,
+        position -> Int4,
+        post_id -> Uuid,
+        tag_id -> Uuid,
+    }
+}
+
 table! {
     posts (id) {
         _rowid -> Int4,
@@ -48,6 +194,62 @@ table! {
         id -> Uuid,
         slug -> Text,
         updated_at -> Timestamptz,
+        content -> Jsonb,
+        parent_post_id -> Nullable<Uuid>,
+        root_post_id -> Nullable<Uuid>,
+        publish_at -> Nullable<Timestamptz>,
+        state -> Text,
+    }
+}
+
+table! {
+    recovery_codes (id) {
+        _rowid -> Int4,
+        code_hash -> Text,
+        created_at -> Timestamptz,
+        id -> Uuid,
+        updated_at -> Timestamptz,
+        used_at -> Nullable<Timestamptz>,
+        user_id -> Uuid,
+    }
+}
+
+table! {
+    replies (id) {
+        _rowid -> Int4,
+        blog_id -> Uuid,
+        created_at -> Timestamptz,
+        deleted_at -> Nullable<Timestamptz>,
+        id -> Uuid,
+        post_id -> Uuid,
+        text -> Text,
+        updated_at -> Timestamptz,
+    }
+}
+
+table! {
+    sessions (id) {
+        _rowid -> Int4,
+        created_at -> Timestamptz,
+        expires_at -> Timestamptz,
+        id -> Uuid,
+        token_hash -> Text,
+        updated_at -> Timestamptz,
+        user_id -> Uuid,
+        ip_address -> Nullable<Text>,
+        last_seen_at -> Timestamptz,
+        user_agent -> Nullable<Text>,
+    }
+}
+
+table! {
+    tags (id) {
+        _rowid -> Int4,
+        created_at -> Timestamptz,
+        deleted_at -> Nullable<Timestamptz>,
+        id -> Uuid,
+        name -> Text,
+        updated_at -> Timestamptz,
     }
 }
 
@@ -59,18 +261,61 @@ table! {
         id -> Uuid,
         role -> Text,
         updated_at -> Timestamptz,
+        totp_enabled_at -> Nullable<Timestamptz>,
+        totp_last_step -> Nullable<Int8>,
+        totp_secret -> Nullable<Text>,
+        avatar_url -> Nullable<Text>,
+        bio -> Nullable<Text>,
+        display_name -> Nullable<Text>,
+        username -> Nullable<Text>,
+        signup_token_expires_at -> Nullable<Timestamptz>,
+        signup_token_hash -> Nullable<Text>,
+        second_factor_failures -> Int4,
+        second_factor_locked_until -> Nullable<Timestamptz>,
     }
 }
 
+joinable!(access_tokens -> oauth_clients (client_id));
+joinable!(access_tokens -> users (user_id));
 joinable!(blogs -> users (user_id));
 joinable!(email_accounts -> users (user_id));
+joinable!(email_tokens -> email_accounts (email_account_id));
+joinable!(follows -> blogs (blog_id));
+joinable!(follows -> users (user_id));
+joinable!(likes -> posts (post_id));
+joinable!(likes -> users (user_id));
+joinable!(login_challenges -> users (user_id));
 joinable!(oauth_accounts -> users (user_id));
+joinable!(oauth_authorization_codes -> oauth_clients (client_id));
+joinable!(oauth_authorization_codes -> users (user_id));
+joinable!(oauth_clients -> users (user_id));
+joinable!(oauth_link_tokens -> users (user_id));
+joinable!(post_tags -> posts (post_id));
+joinable!(post_tags -> tags (tag_id));
 joinable!(posts -> blogs (blog_id));
+joinable!(recovery_codes -> users (user_id));
+joinable!(replies -> blogs (blog_id));
+joinable!(replies -> posts (post_id));
+joinable!(sessions -> users (user_id));
 
 allow_tables_to_appear_in_same_query!(
+    access_tokens,
     blogs,
     email_accounts,
+    email_tokens,
+    follows,
+    jobs,
+    likes,
+    login_challenges,
     oauth_accounts,
+    oauth_authorization_codes,
+    oauth_clients,
+    oauth_link_tokens,
+    post_tags,
     posts,
+    recovery_codes,
+    replies,
+    sessions,
+    tags,
     users,
 );
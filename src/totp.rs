@@ -0,0 +1,262 @@
+//! Time-based one-time passwords (RFC 6238), as generated by authenticator
+//! apps, and the recovery codes handed out alongside them.
+
+use hmac::{Hmac, Mac};
+use rand::rngs::OsRng;
+use rand::RngCore;
+use sha1::Sha1;
+use url::Url;
+
+use crate::models::DateTime;
+
+/// The name authenticator apps list accounts under.
+pub const ISSUER: &str = "Tumblr";
+pub const DIGITS: u32 = 6;
+pub const STEP_SECONDS: i64 = 30;
+/// How many steps either side of the current one are accepted, to allow for
+/// clock drift and codes typed just as they roll over.
+pub const SKEW_STEPS: i64 = 1;
+pub const SECRET_LENGTH: usize = 20;
+pub const RECOVERY_CODE_COUNT: usize = 10;
+
+const BASE32_ALPHABET: &[u8; 32] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZ234567";
+
+pub fn generate_secret() -> Vec<u8> {
+    let mut secret = vec![0; SECRET_LENGTH];
+    OsRng.fill_bytes(&mut secret);
+    secret
+}
+
+/// Encodes `bytes` as unpadded RFC 4648 base32, the form authenticator apps
+/// take secrets in.
+pub fn base32(bytes: &[u8]) -> String {
+    let mut encoded = String::with_capacity((bytes.len() * 8).div_ceil(5));
+    let (mut buffer, mut bits) = (0u32, 0);
+
+    for &byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            encoded.push(BASE32_ALPHABET[(buffer >> bits) as usize & 31].into());
+        }
+    }
+    if bits > 0 {
+        encoded.push(BASE32_ALPHABET[(buffer << (5 - bits)) as usize & 31].into());
+    }
+
+    encoded
+}
+
+/// The `otpauth://` URI to show as a QR code, so an authenticator app can add
+/// the account by scanning it.
+pub fn otpauth_uri(secret: &[u8], account: &str) -> Url {
+    let mut uri = Url::parse("otpauth://totp/").expect("the base URI is valid");
+    uri.set_path(&format!("/{}:{}", ISSUER, account));
+    uri.query_pairs_mut()
+        .append_pair("secret", &base32(secret))
+        .append_pair("issuer", ISSUER)
+        .append_pair("algorithm", "SHA1")
+        .append_pair("digits", &DIGITS.to_string())
+        .append_pair("period", &STEP_SECONDS.to_string());
+    uri
+}
+
+/// The time step `at` falls in.
+pub fn step(at: DateTime) -> i64 {
+    at.timestamp().div_euclid(STEP_SECONDS)
+}
+
+/// The code for `step` (RFC 4226 section 5.3).
+pub fn code(secret: &[u8], step: i64) -> String {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).expect("HMAC accepts keys of any length");
+    mac.update(&step.to_be_bytes());
+    let digest = mac.finalize().into_bytes();
+
+    let offset = usize::from(digest[digest.len() - 1] & 0xf);
+    let truncated = u32::from_be_bytes([
+        digest[offset] & 0x7f,
+        digest[offset + 1],
+        digest[offset + 2],
+        digest[offset + 3],
+    ]);
+
+    format!(
+        "{:0width$}",
+        truncated % 10u32.pow(DIGITS),
+        width = DIGITS as usize
+    )
+}
+
+/// Checks `code` against the steps around `now`, returning the step it
+/// matched. Steps up to and including `last_step` are skipped, so each code
+/// only works once.
+pub fn verify(secret: &[u8], code: &str, now: DateTime, last_step: Option<i64>) -> Option<i64> {
+    let code: String = code.chars().filter(|c| !c.is_whitespace()).collect();
+    if code.len() != DIGITS as usize {
+        return None;
+    }
+
+    let now = step(now);
+    (now - SKEW_STEPS..=now + SKEW_STEPS)
+        .filter(|&step| last_step.is_none_or(|last_step| step > last_step))
+        .find(|&step| self::code(secret, step) == code)
+}
+
+/// Generates a fresh set of recovery codes, like `k4vq7-x2mfa`. Each has 50
+/// bits of entropy.
+pub fn generate_recovery_codes() -> Vec<String> {
+    (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let mut bytes = [0; 8];
+            OsRng.fill_bytes(&mut bytes);
+            let code = base32(&bytes)[..10].to_lowercase();
+            format!("{}-{}", &code[..5], &code[5..])
+        })
+        .collect()
+}
+
+/// Recovery codes are matched ignoring case, spaces and dashes, since they're
+/// typed in by hand.
+pub fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Whether `code` looks like a TOTP code rather than a recovery code.
+pub fn is_totp_code(code: &str) -> bool {
+    let mut code = code.chars().filter(|c| !c.is_whitespace());
+    code.clone().count() == DIGITS as usize && code.all(|c| c.is_ascii_digit())
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+
+    use super::*;
+
+    /// The SHA-1 secret from RFC 6238 Appendix B.
+    const RFC_SECRET: &[u8] = b"12345678901234567890";
+
+    fn at(timestamp: i64) -> DateTime {
+        chrono::Utc.timestamp(timestamp, 0)
+    }
+
+    #[test]
+    fn code_matches_rfc_6238_vectors() {
+        // Appendix B gives eight digits; six-digit codes are the last six.
+        let vectors = [
+            (59, "94287082"),
+            (1111111109, "07081804"),
+            (1111111111, "14050471"),
+            (1234567890, "89005924"),
+            (2000000000, "69279037"),
+            (20000000000, "65353130"),
+        ];
+
+        for (timestamp, expected) in vectors {
+            assert_eq!(
+                code(RFC_SECRET, step(at(timestamp))),
+                expected[2..],
+                "at {}",
+                timestamp
+            );
+        }
+    }
+
+    #[test]
+    fn verify_accepts_codes_within_the_skew() {
+        let now = at(1111111111);
+        let current = step(now);
+
+        for step in current - SKEW_STEPS..=current + SKEW_STEPS {
+            assert_eq!(
+                verify(RFC_SECRET, &code(RFC_SECRET, step), now, None),
+                Some(step)
+            );
+        }
+    }
+
+    #[test]
+    fn verify_rejects_codes_outside_the_skew() {
+        let now = at(1111111111);
+        let current = step(now);
+
+        for step in [current - SKEW_STEPS - 1, current + SKEW_STEPS + 1] {
+            assert_eq!(verify(RFC_SECRET, &code(RFC_SECRET, step), now, None), None);
+        }
+    }
+
+    #[test]
+    fn verify_rejects_replayed_steps() {
+        let now = at(1111111111);
+        let current = step(now);
+        let code = code(RFC_SECRET, current);
+
+        assert_eq!(
+            verify(RFC_SECRET, &code, now, Some(current - 1)),
+            Some(current)
+        );
+        assert_eq!(verify(RFC_SECRET, &code, now, Some(current)), None);
+        // An earlier code can't be used once a later one has been.
+        let earlier = self::code(RFC_SECRET, current - 1);
+        assert_eq!(verify(RFC_SECRET, &earlier, now, Some(current)), None);
+    }
+
+    #[test]
+    fn verify_ignores_spaces() {
+        let now = at(1111111111);
+
+        assert_eq!(verify(RFC_SECRET, "050 471", now, None), Some(step(now)));
+        assert_eq!(
+            verify(RFC_SECRET, " 05 04 71\t", now, None),
+            Some(step(now))
+        );
+        assert_eq!(verify(RFC_SECRET, "05047", now, None), None);
+        assert_eq!(verify(RFC_SECRET, "0504710", now, None), None);
+        assert_eq!(verify(RFC_SECRET, "050-471", now, None), None);
+    }
+
+    #[test]
+    fn base32_matches_rfc_4648_vectors() {
+        let vectors = [
+            ("", ""),
+            ("f", "MY"),
+            ("fo", "MZXQ"),
+            ("foo", "MZXW6"),
+            ("foob", "MZXW6YQ"),
+            ("fooba", "MZXW6YTB"),
+            ("foobar", "MZXW6YTBOI"),
+        ];
+
+        for (input, expected) in vectors {
+            assert_eq!(base32(input.as_bytes()), expected);
+        }
+        assert_eq!(base32(RFC_SECRET), "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ");
+    }
+
+    #[test]
+    fn normalize_recovery_code_ignores_case_spaces_and_dashes() {
+        assert_eq!(normalize_recovery_code("k4vq7-x2mfa"), "k4vq7x2mfa");
+        assert_eq!(normalize_recovery_code(" K4VQ7 X2MFA "), "k4vq7x2mfa");
+        assert_eq!(normalize_recovery_code("k4vq7--x2m-fa"), "k4vq7x2mfa");
+    }
+
+    #[test]
+    fn generated_recovery_codes_are_normalized_and_distinct() {
+        let codes = generate_recovery_codes();
+
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        for code in &codes {
+            assert_eq!(code.len(), 11);
+            assert_eq!(normalize_recovery_code(code), code.replace('-', ""));
+            assert!(!is_totp_code(code));
+        }
+        let mut distinct = codes.clone();
+        distinct.sort();
+        distinct.dedup();
+        assert_eq!(distinct.len(), codes.len());
+    }
+}